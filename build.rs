@@ -0,0 +1,96 @@
+use tonic_build::manual::{Builder, Method, Service};
+
+// Generates the gRPC service from proto/rqs.proto. The messages are declared by hand in
+// src/grpc_api/proto.rs, so building needs no protoc.
+fn main() {
+    let service = Service::builder()
+        .name("Rqs")
+        .package("rqs")
+        .method(method(
+            "new_queue",
+            "NewQueue",
+            "NewQueueRequest",
+            "NewQueueReply",
+        ))
+        .method(method(
+            "list_queues",
+            "ListQueues",
+            "ListQueuesRequest",
+            "ListQueuesReply",
+        ))
+        .method(method(
+            "publish",
+            "Publish",
+            "PublishRequest",
+            "PublishReply",
+        ))
+        .method(method(
+            "receive",
+            "Receive",
+            "ReceiveRequest",
+            "ReceiveReply",
+        ))
+        .method(streaming_method(
+            "receive_stream",
+            "ReceiveStream",
+            "ReceiveRequest",
+            "Message",
+        ))
+        .method(method(
+            "delete_message",
+            "DeleteMessage",
+            "DeleteMessageRequest",
+            "DeleteMessageReply",
+        ))
+        .method(method(
+            "new_exchange",
+            "NewExchange",
+            "NewExchangeRequest",
+            "NewExchangeReply",
+        ))
+        .method(method(
+            "list_exchanges",
+            "ListExchanges",
+            "ListExchangesRequest",
+            "ListExchangesReply",
+        ))
+        .method(method(
+            "bind_exchange",
+            "BindExchange",
+            "BindExchangeRequest",
+            "BindExchangeReply",
+        ))
+        .method(method(
+            "publish_to_exchange",
+            "PublishToExchange",
+            "PublishToExchangeRequest",
+            "PublishReply",
+        ))
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+fn method_builder(
+    name: &str,
+    route_name: &str,
+    input: &str,
+    output: &str,
+) -> tonic_build::manual::MethodBuilder {
+    Method::builder()
+        .name(name)
+        .route_name(route_name)
+        .input_type(format!("crate::grpc_api::proto::{}", input))
+        .output_type(format!("crate::grpc_api::proto::{}", output))
+        .codec_path("tonic::codec::ProstCodec")
+}
+
+fn method(name: &str, route_name: &str, input: &str, output: &str) -> Method {
+    method_builder(name, route_name, input, output).build()
+}
+
+fn streaming_method(name: &str, route_name: &str, input: &str, output: &str) -> Method {
+    method_builder(name, route_name, input, output)
+        .server_streaming()
+        .build()
+}
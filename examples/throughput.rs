@@ -0,0 +1,278 @@
+// Compares how fast messages go through a queue over the HTTP API and over the binary protocol.
+// Start the server with RQS_BINARY_PORT=5680, then run
+//
+//     cargo run --release --example throughput
+//
+// RQS_URL and RQS_BINARY_ADDR point the comparison at another server.
+use std::env;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+const MESSAGES: usize = 20_000;
+const BATCH: usize = 100;
+const CONTENT_SIZE: usize = 100;
+// requests the binary client keeps in flight
+const WINDOW: usize = 16;
+
+const HELLO: u8 = 0x01;
+const PUBLISH: u8 = 0x02;
+const RECEIVE: u8 = 0x03;
+const DELETE: u8 = 0x04;
+const MESSAGES_RECEIVED: u8 = 0x83;
+const ERROR: u8 = 0xFF;
+
+type Error = Box<dyn std::error::Error>;
+
+#[actix_web::main]
+async fn main() -> Result<(), Error> {
+    let url = env::var("RQS_URL").unwrap_or_else(|_| String::from("http://127.0.0.1:8080"));
+    let address = env::var("RQS_BINARY_ADDR").unwrap_or_else(|_| String::from("127.0.0.1:5680"));
+    let http = reqwest::Client::new();
+    let content = "x".repeat(CONTENT_SIZE);
+
+    let queue_id = new_queue(&http, &url, "throughput-http").await?;
+    let http_publish = http_publish(&http, &url, &queue_id, &content).await?;
+    let http_consume = http_consume(&http, &url, &queue_id).await?;
+
+    let queue_id = new_queue(&http, &url, "throughput-binary").await?;
+    let mut client = BinaryClient::connect(&address).await?;
+    let binary_publish = client.publish(&queue_id, &content).await?;
+    let binary_consume = client.consume(&queue_id).await?;
+
+    println!(
+        "{} messages of {} bytes, in batches of {}",
+        MESSAGES, CONTENT_SIZE, BATCH
+    );
+    println!("{:<8}{:>16}{:>16}", "", "publish msg/s", "consume msg/s");
+    for (name, publish, consume) in [
+        ("HTTP", http_publish, http_consume),
+        ("binary", binary_publish, binary_consume),
+    ] {
+        println!(
+            "{:<8}{:>16.0}{:>16.0}",
+            name,
+            MESSAGES as f64 / publish.as_secs_f64(),
+            MESSAGES as f64 / consume.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+async fn new_queue(http: &reqwest::Client, url: &str, prefix: &str) -> Result<String, Error> {
+    let queue_id = format!("{}-{}", prefix, std::process::id());
+    let body = json!({ "queueId": queue_id, "readTimeout": 60, "maxBatch": BATCH });
+    post(http, &format!("{}/queue/new", url), &body).await?;
+    Ok(queue_id)
+}
+
+async fn post(http: &reqwest::Client, url: &str, body: &Value) -> Result<Value, Error> {
+    let response = http
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await?;
+    check(response.text().await?)
+}
+
+fn check(text: String) -> Result<Value, Error> {
+    let response: Value = serde_json::from_str(&text)?;
+    match &response["error"] {
+        Value::Null => Ok(response["data"].to_owned()),
+        e => Err(e.to_string().into()),
+    }
+}
+
+async fn http_publish(
+    http: &reqwest::Client,
+    url: &str,
+    queue_id: &str,
+    content: &str,
+) -> Result<Duration, Error> {
+    let started = Instant::now();
+    for batch in 0..MESSAGES / BATCH {
+        let messages = (0..BATCH)
+            .map(|i| json!({ "messageId": format!("{}", batch * BATCH + i), "content": content }))
+            .collect::<Vec<Value>>();
+        let body = json!({ "queueId": queue_id, "messages": messages });
+        post(http, &format!("{}/message/new", url), &body).await?;
+    }
+    Ok(started.elapsed())
+}
+
+// the HTTP API deletes one message per request
+async fn http_consume(
+    http: &reqwest::Client,
+    url: &str,
+    queue_id: &str,
+) -> Result<Duration, Error> {
+    let started = Instant::now();
+    let mut consumed = 0;
+    while consumed < MESSAGES {
+        let text = http
+            .get(format!("{}/message/get?queueId={}", url, queue_id))
+            .send()
+            .await?
+            .text()
+            .await?;
+        let messages = check(text)?;
+        for message in messages.as_array().into_iter().flatten() {
+            let body = json!({ "queueId": queue_id, "messageUuid": message["uuid"] });
+            post(http, &format!("{}/message/delete", url), &body).await?;
+            consumed += 1;
+        }
+    }
+    Ok(started.elapsed())
+}
+
+struct BinaryClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    next_request_id: u32,
+}
+
+impl BinaryClient {
+    async fn connect(address: &str) -> Result<Self, Error> {
+        let socket = TcpStream::connect(address).await?;
+        socket.set_nodelay(true)?;
+        let (reader, writer) = socket.into_split();
+        let mut client = BinaryClient {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            next_request_id: 0,
+        };
+        client.send(HELLO, vec![1]).await?;
+        client.writer.flush().await?;
+        client.read().await?;
+        Ok(client)
+    }
+
+    async fn send(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), Error> {
+        self.next_request_id += 1;
+        self.writer.write_u32(5 + payload.len() as u32).await?;
+        self.writer.write_u8(opcode).await?;
+        self.writer.write_u32(self.next_request_id).await?;
+        self.writer.write_all(&payload).await?;
+        Ok(())
+    }
+
+    // Reads the next answer, returning its opcode and payload
+    async fn read(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let size = self.reader.read_u32().await? as usize;
+        let opcode = self.reader.read_u8().await?;
+        self.reader.read_u32().await?;
+        let mut payload = vec![0u8; size - 5];
+        self.reader.read_exact(&mut payload).await?;
+        if opcode == ERROR {
+            return Err(String::from_utf8_lossy(&payload[4..]).into());
+        }
+        Ok((opcode, payload))
+    }
+
+    // publishes are pipelined, with up to WINDOW batches waiting on an answer
+    async fn publish(&mut self, queue_id: &str, content: &str) -> Result<Duration, Error> {
+        let started = Instant::now();
+        let batches = MESSAGES / BATCH;
+        let (mut sent, mut answered) = (0, 0);
+        while answered < batches {
+            if sent < batches && sent - answered < WINDOW {
+                let mut payload = string(queue_id);
+                payload.extend_from_slice(&(BATCH as u16).to_be_bytes());
+                for i in 0..BATCH {
+                    payload.extend(string(&format!("{}", sent * BATCH + i)));
+                    // no reply to, correlation id or attributes
+                    payload.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+                    payload.extend_from_slice(&(content.len() as u32).to_be_bytes());
+                    payload.extend_from_slice(content.as_bytes());
+                }
+                self.send(PUBLISH, payload).await?;
+                sent += 1;
+                continue;
+            }
+            self.writer.flush().await?;
+            self.read().await?;
+            answered += 1;
+        }
+        Ok(started.elapsed())
+    }
+
+    // receives are pipelined too, and each batch received is deleted with a single request
+    async fn consume(&mut self, queue_id: &str) -> Result<Duration, Error> {
+        let started = Instant::now();
+        let (mut in_flight, mut consumed) = (0, 0);
+        let mut receive = string(queue_id);
+        receive.extend(string(""));
+        receive.extend_from_slice(&(BATCH as u16).to_be_bytes());
+        while consumed < MESSAGES {
+            if in_flight < WINDOW {
+                self.send(RECEIVE, receive.clone()).await?;
+                in_flight += 1;
+                continue;
+            }
+            self.writer.flush().await?;
+            let (opcode, payload) = self.read().await?;
+            in_flight -= 1;
+            if opcode != MESSAGES_RECEIVED {
+                continue;
+            }
+            let uuids = uuids(&payload);
+            if uuids.is_empty() {
+                continue;
+            }
+            consumed += uuids.len();
+            let mut delete = string(queue_id);
+            delete.extend(string(""));
+            delete.extend_from_slice(&(uuids.len() as u16).to_be_bytes());
+            for uuid in uuids.iter() {
+                delete.extend(string(uuid));
+            }
+            self.send(DELETE, delete).await?;
+            in_flight += 1;
+        }
+        self.writer.flush().await?;
+        while in_flight > 0 {
+            self.read().await?;
+            in_flight -= 1;
+        }
+        Ok(started.elapsed())
+    }
+}
+
+fn string(value: &str) -> Vec<u8> {
+    let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(value.as_bytes());
+    bytes
+}
+
+// Takes the uuids out of a MESSAGES answer, skipping the rest of each message
+fn uuids(payload: &[u8]) -> Vec<String> {
+    let mut position = 0;
+    let short = |position: &mut usize| {
+        let value = u16::from_be_bytes([payload[*position], payload[*position + 1]]) as usize;
+        *position += 2;
+        value
+    };
+    let count = short(&mut position);
+    let mut uuids = vec![];
+    for _ in 0..count {
+        let size = short(&mut position);
+        uuids.push(String::from_utf8_lossy(&payload[position..position + size]).into_owned());
+        position += size;
+        // the message id, reply to and correlation id
+        for _ in 0..3 {
+            let size = short(&mut position);
+            position += size;
+        }
+        for _ in 0..short(&mut position) * 2 {
+            let size = short(&mut position);
+            position += size;
+        }
+        let b = &payload[position..position + 4];
+        position += 4 + u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+    }
+    uuids
+}
@@ -0,0 +1,23 @@
+use std::net::SocketAddr;
+
+use actix_web::rt::net::TcpListener;
+use actix_web::web;
+
+use crate::app_types::AppState;
+
+mod connection;
+mod frame;
+mod method;
+
+// Serves AMQP 0-9-1 clients, sharing the REST API's state
+pub async fn serve(data: web::Data<AppState>, address: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        // a failed accept only affects the client being accepted
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(_) => continue,
+        };
+        actix_web::rt::spawn(connection::run(data.clone(), socket));
+    }
+}
@@ -0,0 +1,1334 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::time::timeout;
+use actix_web::web;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc::{self, Receiver};
+use uuid::Uuid;
+
+use crate::app_types::{ApiError, AppState};
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::authenticate_key;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::identity::Identity;
+use crate::consumer_api::wakeup::Wakeup;
+use crate::exchange_api::exchange::ExchangeType;
+use crate::exchange_api::request as exchange_request;
+use crate::exchange_api::{
+    bind_to_exchange, binding_detail, create_exchange, publish_to_exchange_as,
+};
+use crate::message_api::publish_messages;
+use crate::message_api::request as message_request;
+use crate::namespace_api::check_quota;
+use crate::queue_api::create_queue;
+use crate::queue_api::queue::DecryptedMessage;
+use crate::queue_api::request::{NewQueueRequest, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::rate_limit::RateLimit;
+
+use super::frame::{
+    read_frame, Frame, FrameError, FRAME_BODY, FRAME_HEADER, FRAME_HEARTBEAT, FRAME_METHOD,
+    FRAME_OVERHEAD, PROTOCOL_HEADER,
+};
+use super::method::{self, ContentHeader, Method};
+
+// what the server proposes when tuning the connection
+const CHANNEL_MAX: u16 = 2047;
+const FRAME_MAX: u32 = 131_072;
+const HEARTBEAT: u16 = 60;
+
+// the smallest frame max a client may ask for
+const FRAME_MIN_SIZE: u32 = 4096;
+
+// how many frames may be read ahead of the connection handling them
+const FRAME_BUFFER: usize = 64;
+
+// the largest message body a client may publish, as for STOMP frames and MQTT packets
+const MAX_BODY_SIZE: u64 = 1_048_576;
+
+// how long to wait for a client to confirm the server closing the connection
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// what queues declared over AMQP are created with
+const DECLARED_READ_TIMEOUT: u32 = 30;
+const DECLARED_MAX_BATCH: u32 = 10;
+
+const NO_ROUTE: u16 = 312;
+const ACCESS_REFUSED: u16 = 403;
+const NOT_FOUND: u16 = 404;
+const PRECONDITION_FAILED: u16 = 406;
+const FRAME_ERROR: u16 = 501;
+const SYNTAX_ERROR: u16 = 502;
+const COMMAND_INVALID: u16 = 503;
+const CHANNEL_ERROR: u16 = 504;
+const UNEXPECTED_FRAME: u16 = 505;
+const NOT_ALLOWED: u16 = 530;
+const NOT_IMPLEMENTED: u16 = 540;
+const INTERNAL_ERROR: u16 = 541;
+
+// Why handling a frame failed. Channel exceptions close the channel, the rest the connection.
+enum Exception {
+    Channel(u16, String),
+    Connection(u16, String),
+    Closed, // the client went away or closed the connection
+}
+
+impl From<ApiError> for Exception {
+    fn from(error: ApiError) -> Self {
+        let code = match error.get_status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ACCESS_REFUSED,
+            StatusCode::NOT_FOUND => NOT_FOUND,
+            _ => PRECONDITION_FAILED,
+        };
+        Exception::Channel(code, error.get_message().to_owned())
+    }
+}
+
+impl From<FrameError> for Exception {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Closed => Exception::Closed,
+            FrameError::TooLarge(_, _) => Exception::Connection(FRAME_ERROR, error.to_string()),
+            FrameError::Malformed(_) => Exception::Connection(SYNTAX_ERROR, error.to_string()),
+        }
+    }
+}
+
+struct Consumer {
+    tag: String,
+    queue: String, // as the client named it
+    queue_id: String,
+    no_ack: bool,
+}
+
+// A delivered message the client has yet to ack, reject or nack
+struct Unacked {
+    delivery_tag: u64,
+    queue_id: String,
+    uuid: String,
+}
+
+// A basic.publish waiting for its content header and body
+struct Publish {
+    exchange: String,
+    routing_key: String,
+    mandatory: bool,
+    header: Option<ContentHeader>,
+    body: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Channel {
+    consumers: Vec<Consumer>,
+    unacked: Vec<Unacked>,
+    last_delivery_tag: u64,
+    prefetch_count: u16, // the most unacked messages consumers may hold, or 0 for no limit
+    publish: Option<Publish>,
+    closing: bool, // the server closed the channel and is waiting for the client to confirm
+}
+
+impl Channel {
+    // How many more messages may be pushed to the channel's consumers at once
+    fn credit(&self, max_batch: u32) -> usize {
+        match self.prefetch_count {
+            0 => max_batch as usize,
+            n => (n as usize).saturating_sub(self.unacked.len()),
+        }
+    }
+}
+
+struct Connection {
+    data: web::Data<AppState>,
+    identity: Identity,
+    writer: OwnedWriteHalf,
+    frame_max: u32,
+    heartbeat: Option<Duration>,
+    last_sent: Instant,
+    channels: HashMap<u16, Channel>,
+}
+
+// Serves one client until it closes the connection or breaks the protocol. Whatever its
+// channels still hold when it ends is returned to the queues.
+pub async fn run(data: web::Data<AppState>, mut socket: TcpStream) {
+    let mut header = [0u8; 8];
+    if socket.read_exact(&mut header).await.is_err() {
+        return;
+    }
+    if header != *PROTOCOL_HEADER {
+        // clients speaking another version are told which one the server speaks
+        let _ = socket.write_all(PROTOCOL_HEADER).await;
+        return;
+    }
+    let (mut reader, writer) = socket.into_split();
+    let (sender, mut frames) = mpsc::channel(FRAME_BUFFER);
+    // frames are read on their own task, as a read cut short by a wakeup would lose data
+    actix_web::rt::spawn(async move {
+        loop {
+            let frame = read_frame(&mut reader, FRAME_MAX).await;
+            let failed = frame.is_err();
+            if sender.send(frame).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut connection = Connection {
+        data,
+        identity: Identity::default(),
+        writer,
+        frame_max: FRAME_MAX,
+        heartbeat: None,
+        last_sent: Instant::now(),
+        channels: HashMap::new(),
+    };
+    let ended = connection.serve(&mut frames).await;
+    connection.release_all().await;
+    if let Err(Exception::Connection(code, text)) = ended {
+        let payload = method::connection_close(code, &text);
+        if connection.send_method(0, payload).await.is_ok() {
+            // the client should confirm, but the connection is closed either way
+            let _ = timeout(CLOSE_TIMEOUT, frames.recv()).await;
+        }
+    }
+}
+
+impl Connection {
+    async fn serve(
+        &mut self,
+        frames: &mut Receiver<Result<Frame, FrameError>>,
+    ) -> Result<(), Exception> {
+        self.handshake(frames).await?;
+        loop {
+            let mut wakeup = self.push_messages().await?;
+            self.send_heartbeat().await?;
+            if let Some(interval) = self.heartbeat {
+                wakeup.within((interval / 2).saturating_sub(self.last_sent.elapsed()));
+            }
+            let frame = match wakeup.wait_for(frames.recv()).await {
+                None => continue, // the consumers or heart-beat need seeing to
+                Some(None) => return Err(Exception::Closed),
+                Some(Some(frame)) => frame?,
+            };
+            let channel_id = frame.channel;
+            match self.handle_frame(frame).await {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(Exception::Channel(code, text)) => {
+                    self.close_channel(channel_id, code, &text).await?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn handshake(
+        &mut self,
+        frames: &mut Receiver<Result<Frame, FrameError>>,
+    ) -> Result<(), Exception> {
+        self.send_method(0, method::connection_start()).await?;
+        // the password is the API key, and the login is not used
+        let key = match next_method(frames).await? {
+            Method::ConnectionStartOk {
+                mechanism,
+                response,
+            } => method::sasl_password(&mechanism, &response),
+            _ => return Err(unexpected_method("connection.start-ok")),
+        };
+        let tune = method::connection_tune(CHANNEL_MAX, FRAME_MAX, HEARTBEAT);
+        self.send_method(0, tune).await?;
+        match next_method(frames).await? {
+            Method::ConnectionTuneOk {
+                frame_max,
+                heartbeat,
+            } => {
+                // 0 means the client sets no limit of its own
+                self.frame_max = match frame_max {
+                    0 => FRAME_MAX,
+                    f => f.clamp(FRAME_MIN_SIZE, FRAME_MAX),
+                };
+                self.heartbeat = match heartbeat {
+                    0 => None,
+                    h => Some(Duration::from_secs(h as u64)),
+                };
+            }
+            _ => return Err(unexpected_method("connection.tune-ok")),
+        }
+        // the virtual host names the namespace, and the default one, `/`, is the key's
+        let namespace = match next_method(frames).await? {
+            Method::ConnectionOpen { virtual_host } => match virtual_host.trim_start_matches('/') {
+                "" => None,
+                name => Some(name.to_owned()),
+            },
+            _ => return Err(unexpected_method("connection.open")),
+        };
+        self.identity =
+            match authenticate_key(&self.data, key.as_deref(), namespace.as_deref()).await {
+                Ok(identity) => identity,
+                Err(e) if e.get_status() == StatusCode::NOT_FOUND => {
+                    return Err(Exception::Connection(
+                        NOT_ALLOWED,
+                        e.get_message().to_owned(),
+                    ))
+                }
+                Err(e) => {
+                    return Err(Exception::Connection(
+                        ACCESS_REFUSED,
+                        e.get_message().to_owned(),
+                    ))
+                }
+            };
+        self.send_method(0, method::connection_open_ok()).await
+    }
+
+    // Handles a frame from the client, returning false once the connection has been closed
+    async fn handle_frame(&mut self, frame: Frame) -> Result<bool, Exception> {
+        match frame.frame_type {
+            FRAME_HEARTBEAT => Ok(true),
+            FRAME_METHOD => {
+                let method = Method::decode(&frame.payload)?;
+                match frame.channel {
+                    0 => self.handle_connection_method(method).await,
+                    channel_id => {
+                        self.handle_channel_method(channel_id, method).await?;
+                        Ok(true)
+                    }
+                }
+            }
+            FRAME_HEADER | FRAME_BODY => {
+                self.handle_content(frame).await?;
+                Ok(true)
+            }
+            frame_type => Err(Exception::Connection(
+                FRAME_ERROR,
+                format!("The frame type {} is unknown", frame_type),
+            )),
+        }
+    }
+
+    async fn handle_connection_method(&mut self, method: Method) -> Result<bool, Exception> {
+        match method {
+            Method::ConnectionClose => {
+                self.release_all().await;
+                self.send_method(0, method::connection_close_ok()).await?;
+                Ok(false)
+            }
+            Method::ConnectionCloseOk => Ok(false),
+            _ => Err(Exception::Connection(
+                COMMAND_INVALID,
+                String::from("Only connection methods may be sent on channel 0"),
+            )),
+        }
+    }
+
+    async fn handle_channel_method(
+        &mut self,
+        channel_id: u16,
+        method: Method,
+    ) -> Result<(), Exception> {
+        if let Method::ChannelOpen = method {
+            if channel_id > CHANNEL_MAX || self.channels.contains_key(&channel_id) {
+                return Err(Exception::Connection(
+                    CHANNEL_ERROR,
+                    format!("The channel {} cannot be opened", channel_id),
+                ));
+            }
+            self.channels.insert(channel_id, Channel::default());
+            return self
+                .send_method(channel_id, method::channel_open_ok())
+                .await;
+        }
+        let channel = self.get_channel(channel_id)?;
+        if channel.closing {
+            // until the client confirms the close, everything else it sends is dropped
+            if let Method::ChannelCloseOk | Method::ChannelClose = method {
+                self.channels.remove(&channel_id);
+            }
+            return Ok(());
+        }
+        if channel.publish.is_some() {
+            return Err(Exception::Connection(
+                UNEXPECTED_FRAME,
+                String::from("Expected the content of the published message"),
+            ));
+        }
+
+        match method {
+            Method::ChannelClose => {
+                self.release_channel(channel_id).await;
+                self.channels.remove(&channel_id);
+                self.send_method(channel_id, method::channel_close_ok())
+                    .await
+            }
+            Method::ChannelCloseOk => Ok(()),
+            Method::ExchangeDeclare {
+                exchange,
+                exchange_type,
+                passive,
+                no_wait,
+            } => {
+                self.declare_exchange(&exchange, &exchange_type, passive)
+                    .await?;
+                self.reply(channel_id, no_wait, method::exchange_declare_ok())
+                    .await
+            }
+            Method::QueueDeclare {
+                queue,
+                passive,
+                no_wait,
+            } => {
+                let (queue_id, message_count) = self.declare_queue(queue, passive).await?;
+                let reply = method::queue_declare_ok(&queue_id, message_count);
+                self.reply(channel_id, no_wait, reply).await
+            }
+            Method::QueueBind {
+                queue,
+                exchange,
+                routing_key,
+                no_wait,
+            } => {
+                self.bind_queue(queue, exchange, routing_key).await?;
+                self.reply(channel_id, no_wait, method::queue_bind_ok())
+                    .await
+            }
+            Method::BasicQos { prefetch_count } => {
+                self.get_channel(channel_id)?.prefetch_count = prefetch_count;
+                self.send_method(channel_id, method::basic_qos_ok()).await
+            }
+            Method::BasicConsume {
+                queue,
+                consumer_tag,
+                no_ack,
+                no_wait,
+            } => {
+                let consumer_tag = self
+                    .consume(channel_id, queue, consumer_tag, no_ack)
+                    .await?;
+                let reply = method::basic_consume_ok(&consumer_tag);
+                self.reply(channel_id, no_wait, reply).await
+            }
+            Method::BasicCancel {
+                consumer_tag,
+                no_wait,
+            } => {
+                // messages already delivered to the consumer stay unacked until settled
+                let channel = self.get_channel(channel_id)?;
+                channel.consumers.retain(|c| c.tag != consumer_tag);
+                let reply = method::basic_cancel_ok(&consumer_tag);
+                self.reply(channel_id, no_wait, reply).await
+            }
+            Method::BasicPublish {
+                exchange,
+                routing_key,
+                mandatory,
+            } => {
+                if !exchange.is_empty() && !self.exchange_exists(&self.qualify(&exchange)?).await {
+                    return Err(no_exchange(&exchange));
+                }
+                self.get_channel(channel_id)?.publish = Some(Publish {
+                    exchange,
+                    routing_key,
+                    mandatory,
+                    header: None,
+                    body: vec![],
+                });
+                Ok(())
+            }
+            Method::BasicGet { queue, no_ack } => self.get(channel_id, queue, no_ack).await,
+            Method::BasicAck {
+                delivery_tag,
+                multiple,
+            } => {
+                let settled = self.take_unacked(channel_id, delivery_tag, multiple)?;
+                self.settle_for_client(settled, false).await
+            }
+            Method::BasicReject {
+                delivery_tag,
+                requeue,
+            } => {
+                let settled = self.take_unacked(channel_id, delivery_tag, false)?;
+                self.settle_for_client(settled, requeue).await
+            }
+            Method::BasicNack {
+                delivery_tag,
+                multiple,
+                requeue,
+            } => {
+                let settled = self.take_unacked(channel_id, delivery_tag, multiple)?;
+                self.settle_for_client(settled, requeue).await
+            }
+            Method::Unsupported(class_id, method_id) => Err(Exception::Connection(
+                NOT_IMPLEMENTED,
+                format!("The method {}.{} is not supported", class_id, method_id),
+            )),
+            Method::ConnectionStartOk { .. }
+            | Method::ConnectionTuneOk { .. }
+            | Method::ConnectionOpen { .. }
+            | Method::ConnectionClose
+            | Method::ConnectionCloseOk
+            | Method::ChannelOpen => Err(Exception::Connection(
+                COMMAND_INVALID,
+                String::from("Connection methods may only be sent on channel 0"),
+            )),
+        }
+    }
+
+    // Collects a published message's content header and body, publishing it once complete
+    async fn handle_content(&mut self, frame: Frame) -> Result<(), Exception> {
+        let channel_id = frame.channel;
+        let channel = self.get_channel(channel_id)?;
+        if channel.closing {
+            return Ok(());
+        }
+        let publish = match channel.publish.as_mut() {
+            None => {
+                return Err(Exception::Connection(
+                    UNEXPECTED_FRAME,
+                    String::from("Content must follow a basic.publish"),
+                ))
+            }
+            Some(p) => p,
+        };
+        match (frame.frame_type, &publish.header) {
+            (FRAME_HEADER, None) => {
+                let header = ContentHeader::decode(&frame.payload)?;
+                // refused before any of the body is buffered, and the rest of it is ignored once
+                // the channel is closing
+                if header.body_size > MAX_BODY_SIZE {
+                    return Err(Exception::Channel(
+                        PRECONDITION_FAILED,
+                        format!(
+                            "The body is {} bytes, which exceeds the limit of {} bytes",
+                            header.body_size, MAX_BODY_SIZE
+                        ),
+                    ));
+                }
+                publish.header = Some(header);
+            }
+            (FRAME_BODY, Some(_)) => publish.body.extend(frame.payload),
+            _ => {
+                return Err(Exception::Connection(
+                    UNEXPECTED_FRAME,
+                    String::from("A content header must be followed by its body"),
+                ))
+            }
+        }
+        let body_size = match &publish.header {
+            Some(h) => h.body_size as usize,
+            None => return Ok(()),
+        };
+        if publish.body.len() > body_size {
+            return Err(Exception::Connection(
+                FRAME_ERROR,
+                String::from("The body is larger than its content header said"),
+            ));
+        }
+        if publish.body.len() < body_size {
+            return Ok(());
+        }
+        match channel.publish.take() {
+            Some(p) => self.publish(channel_id, p).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn publish(&mut self, channel_id: u16, publish: Publish) -> Result<(), Exception> {
+        let header = match publish.header {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        let content = match String::from_utf8(publish.body) {
+            Ok(c) => c,
+            Err(_) => {
+                return Err(Exception::Channel(
+                    PRECONDITION_FAILED,
+                    String::from("Message bodies must be UTF-8 text"),
+                ))
+            }
+        };
+        let message_id = match &header.message_id {
+            Some(id) => id.to_owned(),
+            None => Uuid::new_v4().to_string(),
+        };
+
+        let (target_id, produced) = if publish.exchange.is_empty() {
+            // the default exchange sends each message to the queue named by its routing key
+            let queue_id = self.qualify(&publish.routing_key)?;
+            self.authorize(ResourceType::QUEUE, &queue_id, Permission::PUBLISH)
+                .await?;
+            let queue_exists = self.data.get_queues().lock().await.contains_key(&queue_id);
+            match queue_exists {
+                false => (queue_id, vec![]),
+                true => {
+                    let request = message_request::NewMessageRequest {
+                        queue_id: queue_id.to_owned(),
+                        messages: vec![message_request::NewMessage {
+                            message_id,
+                            content: content.to_owned(),
+                            reply_to: header.reply_to.to_owned(),
+                            correlation_id: header.correlation_id.to_owned(),
+                            attributes: header.headers.to_owned(),
+                        }],
+                    };
+                    let published = publish_messages(&self.data, &request);
+                    let uuids = self
+                        .identity
+                        .publish_admitted(&self.data, 1, published)
+                        .await?;
+                    (queue_id, uuids)
+                }
+            }
+        } else {
+            let exchange_id = self.qualify(&publish.exchange)?;
+            self.authorize(ResourceType::EXCHANGE, &exchange_id, Permission::PUBLISH)
+                .await?;
+            // unroutable messages are dropped here, and returned below if they were mandatory
+            let request = exchange_request::NewMessageRequest {
+                exchange_id: exchange_id.to_owned(),
+                messages: vec![exchange_request::NewMessage {
+                    message_id,
+                    content: content.to_owned(),
+                    reply_to: header.reply_to.to_owned(),
+                    correlation_id: header.correlation_id.to_owned(),
+                    routing_key: Some(publish.routing_key.to_owned()),
+                    attributes: header.headers.to_owned(),
+                }],
+                mandatory: false,
+            };
+            // messages only reach the queues the key may publish to
+            let api_key = self.identity.current_key(&self.data).await?;
+            let published = publish_to_exchange_as(&self.data, &request, api_key.as_ref());
+            let produced = self
+                .identity
+                .publish_admitted(&self.data, 1, published)
+                .await?;
+            (exchange_id, produced)
+        };
+        if !produced.is_empty() {
+            let detail = Some(produced.join(", "));
+            self.identity
+                .audit(
+                    &self.data,
+                    AuditAction::MessagesPublished,
+                    &target_id,
+                    detail,
+                )
+                .await;
+        }
+
+        if produced.is_empty() && publish.mandatory {
+            let method = method::basic_return(
+                NO_ROUTE,
+                "NO_ROUTE",
+                &publish.exchange,
+                &publish.routing_key,
+            );
+            let frames = content_frames(channel_id, method, header, content, self.frame_max);
+            self.send(frames).await?;
+        }
+        Ok(())
+    }
+
+    async fn declare_exchange(
+        &mut self,
+        exchange: &String,
+        exchange_type: &str,
+        passive: bool,
+    ) -> Result<(), Exception> {
+        if exchange.is_empty() {
+            return Err(Exception::Channel(
+                ACCESS_REFUSED,
+                String::from("The default exchange cannot be declared"),
+            ));
+        }
+        let exchange_id = &self.qualify(exchange)?;
+        let existing_type = self
+            .data
+            .get_exchanges()
+            .lock()
+            .await
+            .get(exchange_id)
+            .map(|e| e.exchange_type);
+        if passive {
+            return match existing_type {
+                Some(_) => Ok(()),
+                None => Err(no_exchange(exchange)),
+            };
+        }
+        let exchange_type = match exchange_type {
+            "fanout" => ExchangeType::FANOUT,
+            "direct" => ExchangeType::DIRECT,
+            t => {
+                return Err(Exception::Connection(
+                    COMMAND_INVALID,
+                    format!(
+                        "The exchange type {} is not supported, only fanout and direct are",
+                        t
+                    ),
+                ))
+            }
+        };
+        match existing_type {
+            Some(t) if t == exchange_type => Ok(()),
+            Some(_) => Err(Exception::Channel(
+                PRECONDITION_FAILED,
+                format!(
+                    "The exchange with id {} already exists with another type",
+                    exchange
+                ),
+            )),
+            None => {
+                self.authorize(ResourceType::EXCHANGE, exchange_id, Permission::MANAGE)
+                    .await?;
+                // held until the exchange is created
+                let _namespaces = check_quota(
+                    &self.data,
+                    &self.identity.namespace.name,
+                    ResourceType::EXCHANGE,
+                )
+                .await?;
+                let request = exchange_request::NewExchangeRequest {
+                    id: exchange_id.to_owned(),
+                    queue_ids: vec![],
+                    exchange_ids: vec![],
+                    bindings: vec![],
+                    exchange_type,
+                    alternate_queue_id: None,
+                    alternate_exchange_id: None,
+                };
+                create_exchange(&self.data, &request).await?;
+                let detail = Some(format!("{:?}", exchange_type));
+                self.identity
+                    .audit(
+                        &self.data,
+                        AuditAction::ExchangeCreated,
+                        exchange_id,
+                        detail,
+                    )
+                    .await;
+                Ok(())
+            }
+        }
+    }
+
+    // Declares a queue if it does not exist yet, returning its name and how many messages it holds
+    async fn declare_queue(
+        &mut self,
+        queue: String,
+        passive: bool,
+    ) -> Result<(String, u32), Exception> {
+        // the client may leave naming the queue to the server
+        let queue = match queue.is_empty() {
+            true => format!("amq.gen-{}", Uuid::new_v4()),
+            false => queue,
+        };
+        let queue_id = self.qualify(&queue)?;
+        let message_count = self
+            .data
+            .get_queues()
+            .lock()
+            .await
+            .get(&queue_id)
+            .map(|q| q.get_size());
+        match (message_count, passive) {
+            (Some(count), _) => Ok((queue, count)),
+            (None, true) => Err(no_queue(&queue)),
+            (None, false) => {
+                self.authorize(ResourceType::QUEUE, &queue_id, Permission::MANAGE)
+                    .await?;
+                // held until the queue is created
+                let _namespaces = check_quota(
+                    &self.data,
+                    &self.identity.namespace.name,
+                    ResourceType::QUEUE,
+                )
+                .await?;
+                let request = NewQueueRequest {
+                    read_timeout: DECLARED_READ_TIMEOUT,
+                    queue_id: queue_id.to_owned(),
+                    max_batch: DECLARED_MAX_BATCH,
+                    max_message_size: None,
+                    offload_large_messages: false,
+                    compression: None,
+                    compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+                    max_messages: None,
+                    max_bytes: None,
+                    overflow_policy: Default::default(),
+                    dead_letter_queue_id: None,
+                    rate_limit: RateLimit::default(),
+                };
+                create_queue(&self.data, &request).await?;
+                self.identity
+                    .audit(&self.data, AuditAction::QueueCreated, &queue_id, None)
+                    .await;
+                Ok((queue, 0))
+            }
+        }
+    }
+
+    async fn bind_queue(
+        &mut self,
+        queue: String,
+        exchange: String,
+        routing_key: String,
+    ) -> Result<(), Exception> {
+        if exchange.is_empty() {
+            return Err(Exception::Channel(
+                ACCESS_REFUSED,
+                String::from("Queues cannot be bound to the default exchange"),
+            ));
+        }
+        let exchange_id = self.qualify(&exchange)?;
+        let queue_id = self.qualify(&queue)?;
+        self.authorize(ResourceType::EXCHANGE, &exchange_id, Permission::MANAGE)
+            .await?;
+        let exchange_type = match self.data.get_exchanges().lock().await.get(&exchange_id) {
+            None => return Err(no_exchange(&exchange)),
+            Some(e) => e.exchange_type,
+        };
+        if !self.data.get_queues().lock().await.contains_key(&queue_id) {
+            return Err(no_queue(&queue));
+        }
+        // the routing key only means something to DIRECT exchanges
+        let key = match exchange_type {
+            ExchangeType::DIRECT => Some(routing_key),
+            _ => None,
+        };
+        let request = exchange_request::BindExchangeRequest {
+            exchange_id,
+            queue_id: Some(queue_id),
+            destination_exchange_id: None,
+            weight: 1,
+            filter: None,
+            key,
+        };
+        match bind_to_exchange(&self.data, &request).await {
+            // binding twice is not an error in AMQP
+            Err(e) if e.get_status() == StatusCode::CONFLICT => Ok(()),
+            Err(e) => Err(Exception::from(e)),
+            Ok(_) => {
+                let detail = binding_detail(&self.identity.namespace, &request);
+                self.identity
+                    .audit(
+                        &self.data,
+                        AuditAction::ExchangeBound,
+                        &request.exchange_id,
+                        detail,
+                    )
+                    .await;
+                Ok(())
+            }
+        }
+    }
+
+    // Starts pushing a queue's messages to the channel, returning the consumer's tag
+    async fn consume(
+        &mut self,
+        channel_id: u16,
+        queue: String,
+        consumer_tag: String,
+        no_ack: bool,
+    ) -> Result<String, Exception> {
+        let queue_id = self.qualify(&queue)?;
+        self.authorize_consume(&queue_id, no_ack).await?;
+        let accepts = self
+            .data
+            .get_queues()
+            .lock()
+            .await
+            .get(&queue_id)
+            .map(|q| q.accepts_consumer(&None));
+        match accepts {
+            None => return Err(no_queue(&queue)),
+            Some(false) => return Err(exclusive_queue(&queue)),
+            Some(true) => (),
+        }
+        let consumer_tag = match consumer_tag.is_empty() {
+            true => format!("amq.ctag-{}", Uuid::new_v4()),
+            false => consumer_tag,
+        };
+        let tag_in_use = self
+            .channels
+            .values()
+            .any(|c| c.consumers.iter().any(|c| c.tag == consumer_tag));
+        if tag_in_use {
+            return Err(Exception::Connection(
+                NOT_ALLOWED,
+                format!("The consumer tag {} is already in use", consumer_tag),
+            ));
+        }
+        self.get_channel(channel_id)?.consumers.push(Consumer {
+            tag: consumer_tag.to_owned(),
+            queue,
+            queue_id,
+            no_ack,
+        });
+        Ok(consumer_tag)
+    }
+
+    async fn get(
+        &mut self,
+        channel_id: u16,
+        queue_name: String,
+        no_ack: bool,
+    ) -> Result<(), Exception> {
+        let queue_id = self.qualify(&queue_name)?;
+        self.authorize_consume(&queue_id, no_ack).await?;
+        let (message, message_count) = {
+            let mut queues = self.data.get_queues().lock().await;
+            let queue = match queues.get_mut(&queue_id) {
+                None => return Err(no_queue(&queue_name)),
+                Some(q) => q,
+            };
+            if !queue.accepts_consumer(&None) {
+                return Err(exclusive_queue(&queue_name));
+            }
+            let cipher = self.data.get_cipher().lock().await;
+            let message = match queue.dispatch_up_to(&cipher, 1) {
+                Ok(m) => m.into_iter().next(),
+                Err(e) => return Err(Exception::from(ApiError::from(&e))),
+            };
+            if let (Some(m), true) = (&message, no_ack) {
+                queue.rem_from_queue(&m.get_uuid());
+            }
+            (message, queue.get_size())
+        };
+        let message = match message {
+            None => {
+                return self
+                    .send_method(channel_id, method::basic_get_empty())
+                    .await
+            }
+            Some(m) => m,
+        };
+        if no_ack {
+            let detail = Some(message.get_uuid());
+            self.identity
+                .audit(&self.data, AuditAction::MessagesDeleted, &queue_id, detail)
+                .await;
+        }
+        let channel = self.get_channel(channel_id)?;
+        channel.last_delivery_tag += 1;
+        let delivery_tag = channel.last_delivery_tag;
+        if !no_ack {
+            channel.unacked.push(Unacked {
+                delivery_tag,
+                queue_id: queue_id.to_owned(),
+                uuid: message.get_uuid(),
+            });
+        }
+        let routing_key = message_routing_key(&message, &queue_name);
+        let method = method::basic_get_ok(delivery_tag, &routing_key, message_count);
+        let frames = delivery_frames(channel_id, method, &message, self.frame_max);
+        self.send(frames).await
+    }
+
+    // Renews the messages the channels hold, and pushes new ones to consumers with credit for them,
+    // returning what to wait for before doing so again. The key is checked on every push, so a
+    // channel is closed once it may no longer consume, or delete without acks, from a queue.
+    async fn push_messages(&mut self) -> Result<Wakeup, Exception> {
+        let mut refused = vec![];
+        for (channel_id, channel) in self.channels.iter() {
+            for consumer in channel.consumers.iter() {
+                let authorized = self
+                    .authorize_consume(&consumer.queue_id, consumer.no_ack)
+                    .await;
+                if let Err(Exception::Channel(code, text)) = authorized {
+                    refused.push((*channel_id, code, text));
+                    break;
+                }
+            }
+        }
+        let data = self.data.clone();
+        let frame_max = self.frame_max;
+        let mut frames = vec![];
+        let mut lost_queues = vec![];
+        let mut deleted = vec![]; // the queue id and uuids of messages sent without acks
+        let mut wakeup = Wakeup::new();
+        {
+            let mut queues = data.get_queues().lock().await;
+            let cipher = data.get_cipher().lock().await;
+            for (channel_id, channel) in self.channels.iter_mut() {
+                if channel.closing || refused.iter().any(|(id, _, _)| id == channel_id) {
+                    continue;
+                }
+                for unacked in channel.unacked.iter() {
+                    if let Some(queue) = queues.get_mut(&unacked.queue_id) {
+                        queue.renew(&[unacked.uuid.to_owned()]);
+                        wakeup.hold(queue);
+                    }
+                }
+                for consumer in channel.consumers.iter() {
+                    let queue = match queues.get_mut(&consumer.queue_id) {
+                        None => {
+                            lost_queues.push((*channel_id, consumer.queue.to_owned()));
+                            continue;
+                        }
+                        Some(q) => q,
+                    };
+                    // without a prefetch count, each push still sends at most a batch
+                    let credit = channel.credit(queue.get_max_batch());
+                    let messages = match queue.dispatch_up_to(&cipher, credit) {
+                        Ok(m) => m,
+                        Err(e) => return Err(Exception::Connection(INTERNAL_ERROR, e.to_string())),
+                    };
+                    let messages_sent = messages.len();
+                    let mut sent = vec![];
+                    for message in messages {
+                        channel.last_delivery_tag += 1;
+                        let delivery_tag = channel.last_delivery_tag;
+                        match consumer.no_ack {
+                            true => {
+                                queue.rem_from_queue(&message.get_uuid());
+                                sent.push(message.get_uuid());
+                            }
+                            false => channel.unacked.push(Unacked {
+                                delivery_tag,
+                                queue_id: consumer.queue_id.to_owned(),
+                                uuid: message.get_uuid(),
+                            }),
+                        }
+                        let routing_key = message_routing_key(&message, &consumer.queue);
+                        let method =
+                            method::basic_deliver(&consumer.tag, delivery_tag, &routing_key);
+                        frames.extend(delivery_frames(*channel_id, method, &message, frame_max));
+                    }
+                    if !sent.is_empty() {
+                        deleted.push((consumer.queue_id.to_owned(), sent));
+                    }
+                    if channel.credit(queue.get_max_batch()) > 0 {
+                        wakeup.watch(queue, messages_sent == credit);
+                    }
+                }
+            }
+        }
+        for (queue_id, uuids) in deleted {
+            let detail = Some(uuids.join(", "));
+            self.identity
+                .audit(&self.data, AuditAction::MessagesDeleted, &queue_id, detail)
+                .await;
+        }
+        self.send(frames).await?;
+        // consumers cannot be told their queue is gone, so their channel is closed instead
+        for (channel_id, queue) in lost_queues {
+            if matches!(self.channels.get(&channel_id), Some(c) if !c.closing) {
+                let text = format!("The queue with id {} was deleted", queue);
+                self.close_channel(channel_id, NOT_FOUND, &text).await?;
+            }
+        }
+        for (channel_id, code, text) in refused {
+            if matches!(self.channels.get(&channel_id), Some(c) if !c.closing) {
+                self.close_channel(channel_id, code, &text).await?;
+            }
+        }
+        Ok(wakeup)
+    }
+
+    // Removes the unacked messages a delivery tag refers to from the channel. With `multiple`,
+    // that is every message up to and including the tag, or every message if the tag is 0.
+    fn take_unacked(
+        &mut self,
+        channel_id: u16,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> Result<Vec<Unacked>, Exception> {
+        let channel = self.get_channel(channel_id)?;
+        let (settled, kept) =
+            channel
+                .unacked
+                .drain(..)
+                .partition::<Vec<Unacked>, _>(|u| match multiple {
+                    true => delivery_tag == 0 || u.delivery_tag <= delivery_tag,
+                    false => u.delivery_tag == delivery_tag,
+                });
+        channel.unacked = kept;
+        if settled.is_empty() && !(multiple && delivery_tag == 0) {
+            return Err(Exception::Channel(
+                PRECONDITION_FAILED,
+                format!("The delivery tag {} is unknown", delivery_tag),
+            ));
+        }
+        Ok(settled)
+    }
+
+    // Turns a queue or exchange name the client gave into the id it is stored under
+    fn qualify(&self, name: &str) -> Result<String, Exception> {
+        Ok(self.identity.namespace.qualify(name)?)
+    }
+
+    async fn authorize(
+        &self,
+        resource_type: ResourceType,
+        id: &str,
+        permission: Permission,
+    ) -> Result<(), Exception> {
+        Ok(self
+            .identity
+            .authorize(&self.data, resource_type, id, permission)
+            .await?)
+    }
+
+    // Consuming without acks deletes messages as they are sent, so it needs both permissions
+    async fn authorize_consume(&self, queue_id: &str, no_ack: bool) -> Result<(), Exception> {
+        self.authorize(ResourceType::QUEUE, queue_id, Permission::CONSUME)
+            .await?;
+        if no_ack {
+            self.authorize(ResourceType::QUEUE, queue_id, Permission::DELETE)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Settles messages as the client asked, which deletes them unless they are requeued. If the
+    // key may not delete from one of their queues, they are all returned to be delivered again.
+    async fn settle_for_client(
+        &self,
+        settled: Vec<Unacked>,
+        requeue: bool,
+    ) -> Result<(), Exception> {
+        if !requeue {
+            for unacked in settled.iter() {
+                let authorized = self
+                    .authorize(ResourceType::QUEUE, &unacked.queue_id, Permission::DELETE)
+                    .await;
+                if authorized.is_err() {
+                    self.settle(settled, true).await;
+                    return authorized;
+                }
+            }
+        }
+        let deleted = self.settle(settled, requeue).await;
+        let mut by_queue: Vec<(String, Vec<String>)> = vec![];
+        for unacked in deleted {
+            match by_queue.iter_mut().find(|(id, _)| *id == unacked.queue_id) {
+                Some((_, uuids)) => uuids.push(unacked.uuid),
+                None => by_queue.push((unacked.queue_id, vec![unacked.uuid])),
+            }
+        }
+        for (queue_id, uuids) in by_queue {
+            let detail = Some(uuids.join(", "));
+            self.identity
+                .audit(&self.data, AuditAction::MessagesDeleted, &queue_id, detail)
+                .await;
+        }
+        Ok(())
+    }
+
+    // Deletes settled messages from their queues, or returns them to be delivered again,
+    // returning those that were deleted
+    async fn settle(&self, settled: Vec<Unacked>, requeue: bool) -> Vec<Unacked> {
+        let mut deleted = vec![];
+        let mut queues = self.data.get_queues().lock().await;
+        for unacked in settled {
+            if let Some(queue) = queues.get_mut(&unacked.queue_id) {
+                match requeue {
+                    true => {
+                        queue.release(&unacked.uuid);
+                    }
+                    false => {
+                        if queue.rem_from_queue(&unacked.uuid).is_some() {
+                            deleted.push(unacked);
+                        }
+                    }
+                }
+            }
+        }
+        deleted
+    }
+
+    // Returns what a channel holds to the queues and stops it, then tells the client why
+    async fn close_channel(
+        &mut self,
+        channel_id: u16,
+        code: u16,
+        text: &str,
+    ) -> Result<(), Exception> {
+        self.release_channel(channel_id).await;
+        if let Some(channel) = self.channels.get_mut(&channel_id) {
+            channel.consumers.clear();
+            channel.publish = None;
+            channel.closing = true;
+        }
+        self.send_method(channel_id, method::channel_close(code, text))
+            .await
+    }
+
+    async fn release_channel(&mut self, channel_id: u16) {
+        let settled = match self.channels.get_mut(&channel_id) {
+            Some(channel) => channel.unacked.drain(..).collect(),
+            None => vec![],
+        };
+        self.settle(settled, true).await;
+    }
+
+    async fn release_all(&mut self) {
+        let settled = self
+            .channels
+            .values_mut()
+            .flat_map(|c| c.unacked.drain(..))
+            .collect();
+        self.settle(settled, true).await;
+    }
+
+    async fn exchange_exists(&self, exchange_id: &String) -> bool {
+        self.data
+            .get_exchanges()
+            .lock()
+            .await
+            .contains_key(exchange_id)
+    }
+
+    fn get_channel(&mut self, channel_id: u16) -> Result<&mut Channel, Exception> {
+        match self.channels.get_mut(&channel_id) {
+            Some(c) => Ok(c),
+            None => Err(Exception::Connection(
+                CHANNEL_ERROR,
+                format!("The channel {} is not open", channel_id),
+            )),
+        }
+    }
+
+    // Answers a method unless the client asked not to wait for an answer
+    async fn reply(
+        &mut self,
+        channel_id: u16,
+        no_wait: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), Exception> {
+        match no_wait {
+            true => Ok(()),
+            false => self.send_method(channel_id, payload).await,
+        }
+    }
+
+    async fn send_method(&mut self, channel_id: u16, payload: Vec<u8>) -> Result<(), Exception> {
+        self.send(vec![Frame::new(FRAME_METHOD, channel_id, payload)])
+            .await
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), Exception> {
+        match self.heartbeat {
+            Some(interval) if self.last_sent.elapsed() >= interval / 2 => {
+                self.send(vec![Frame::new(FRAME_HEARTBEAT, 0, vec![])])
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn send(&mut self, frames: Vec<Frame>) -> Result<(), Exception> {
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let bytes = frames
+            .iter()
+            .flat_map(|f| f.to_bytes())
+            .collect::<Vec<u8>>();
+        self.writer
+            .write_all(&bytes)
+            .await
+            .map_err(|_| Exception::Closed)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+// Waits for the next method on channel 0, skipping heartbeats
+async fn next_method(
+    frames: &mut Receiver<Result<Frame, FrameError>>,
+) -> Result<Method, Exception> {
+    loop {
+        let frame = match frames.recv().await {
+            None => return Err(Exception::Closed),
+            Some(frame) => frame?,
+        };
+        match (frame.frame_type, frame.channel) {
+            (FRAME_HEARTBEAT, _) => continue,
+            (FRAME_METHOD, 0) => return Ok(Method::decode(&frame.payload)?),
+            _ => {
+                return Err(Exception::Connection(
+                    UNEXPECTED_FRAME,
+                    String::from("Expected a connection method on channel 0"),
+                ))
+            }
+        }
+    }
+}
+
+// The frames of a method carrying a message: the method, its content header and its body
+fn content_frames(
+    channel_id: u16,
+    method: Vec<u8>,
+    header: ContentHeader,
+    content: String,
+    frame_max: u32,
+) -> Vec<Frame> {
+    let mut frames = vec![
+        Frame::new(FRAME_METHOD, channel_id, method),
+        Frame::new(FRAME_HEADER, channel_id, header.encode()),
+    ];
+    let chunk_size = frame_max as usize - FRAME_OVERHEAD;
+    for chunk in content.as_bytes().chunks(chunk_size) {
+        frames.push(Frame::new(FRAME_BODY, channel_id, chunk.to_vec()));
+    }
+    frames
+}
+
+fn delivery_frames(
+    channel_id: u16,
+    method: Vec<u8>,
+    message: &DecryptedMessage,
+    frame_max: u32,
+) -> Vec<Frame> {
+    let properties = message.get_properties();
+    let content = message.get_content();
+    let header = ContentHeader {
+        body_size: content.len() as u64,
+        message_id: Some(message.get_id()),
+        correlation_id: properties.correlation_id,
+        reply_to: properties.reply_to,
+        headers: properties.attributes,
+    };
+    content_frames(channel_id, method, header, content, frame_max)
+}
+
+// messages sent straight to a queue were routed by the queue's name
+fn message_routing_key(message: &DecryptedMessage, queue_name: &String) -> String {
+    match message.get_properties().routing_key {
+        Some(key) => key,
+        None => queue_name.to_owned(),
+    }
+}
+
+fn unexpected_method(expected: &str) -> Exception {
+    Exception::Connection(
+        COMMAND_INVALID,
+        format!("Expected {} during the handshake", expected),
+    )
+}
+
+fn no_queue(queue_id: &String) -> Exception {
+    Exception::Channel(
+        NOT_FOUND,
+        format!("No queue with id {} was found", queue_id),
+    )
+}
+
+fn no_exchange(exchange_id: &String) -> Exception {
+    Exception::Channel(
+        NOT_FOUND,
+        format!("No exchange with id {} was found", exchange_id),
+    )
+}
+
+fn exclusive_queue(queue_id: &String) -> Exception {
+    Exception::Channel(
+        ACCESS_REFUSED,
+        format!(
+            "The queue with id {} is exclusive to another consumer",
+            queue_id
+        ),
+    )
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// what a client sends before its first frame
+pub const PROTOCOL_HEADER: &[u8; 8] = b"AMQP\x00\x00\x09\x01";
+
+pub const FRAME_METHOD: u8 = 1;
+pub const FRAME_HEADER: u8 = 2;
+pub const FRAME_BODY: u8 = 3;
+pub const FRAME_HEARTBEAT: u8 = 8;
+const FRAME_END: u8 = 0xCE;
+
+// the type, channel and size that precede every frame's payload
+const FRAME_PREFIX_SIZE: usize = 7;
+// the prefix and end byte, which count towards the negotiated frame max
+pub const FRAME_OVERHEAD: usize = FRAME_PREFIX_SIZE + 1;
+
+pub enum FrameError {
+    Closed,
+    TooLarge(usize, u32),
+    Malformed(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Closed => write!(f, "The connection was closed"),
+            FrameError::TooLarge(size, limit) => write!(
+                f,
+                "The frame of {} bytes exceeds the frame max of {} bytes",
+                size, limit
+            ),
+            FrameError::Malformed(s) => write!(f, "The frame was malformed: {}", s),
+        }
+    }
+}
+
+pub struct Frame {
+    pub frame_type: u8,
+    pub channel: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(frame_type: u8, channel: u16, payload: Vec<u8>) -> Self {
+        Frame {
+            frame_type,
+            channel,
+            payload,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + FRAME_OVERHEAD);
+        bytes.push(self.frame_type);
+        bytes.extend_from_slice(&self.channel.to_be_bytes());
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes.push(FRAME_END);
+        bytes
+    }
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    frame_max: u32,
+) -> Result<Frame, FrameError> {
+    let mut prefix = [0u8; FRAME_PREFIX_SIZE];
+    reader
+        .read_exact(&mut prefix)
+        .await
+        .map_err(|_| FrameError::Closed)?;
+    let frame_type = prefix[0];
+    let channel = u16::from_be_bytes([prefix[1], prefix[2]]);
+    let size = u32::from_be_bytes([prefix[3], prefix[4], prefix[5], prefix[6]]) as usize;
+    if size + FRAME_OVERHEAD > frame_max as usize {
+        return Err(FrameError::TooLarge(size + FRAME_OVERHEAD, frame_max));
+    }
+    let mut payload = vec![0u8; size + 1];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(|_| FrameError::Closed)?;
+    if payload.pop() != Some(FRAME_END) {
+        return Err(FrameError::Malformed(String::from(
+            "the frame end byte is missing",
+        )));
+    }
+    Ok(Frame::new(frame_type, channel, payload))
+}
+
+// Reads the fields of a method or content header payload in order
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], FrameError> {
+        if self.position + count > self.bytes.len() {
+            return Err(FrameError::Malformed(String::from(
+                "a field runs past the end of the frame",
+            )));
+        }
+        let taken = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(taken)
+    }
+
+    pub fn octet(&mut self) -> Result<u8, FrameError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn short(&mut self) -> Result<u16, FrameError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn long(&mut self) -> Result<u32, FrameError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn longlong(&mut self) -> Result<u64, FrameError> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    pub fn shortstr(&mut self) -> Result<String, FrameError> {
+        let size = self.octet()? as usize;
+        let bytes = self.take(size)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| FrameError::Malformed(String::from("a short string is not UTF-8")))
+    }
+
+    pub fn longstr(&mut self) -> Result<Vec<u8>, FrameError> {
+        let size = self.long()? as usize;
+        Ok(self.take(size)?.to_vec())
+    }
+
+    // Reads a field table, keeping the values that have a sensible string form
+    pub fn table(&mut self) -> Result<HashMap<String, String>, FrameError> {
+        let size = self.long()? as usize;
+        let mut decoder = Decoder::new(self.take(size)?);
+        let mut table = HashMap::new();
+        while decoder.position < decoder.bytes.len() {
+            let name = decoder.shortstr()?;
+            if let Some(value) = decoder.field_value()? {
+                table.insert(name, value);
+            }
+        }
+        Ok(table)
+    }
+
+    fn field_value(&mut self) -> Result<Option<String>, FrameError> {
+        let value = match self.octet()? {
+            b't' => Some((self.octet()? != 0).to_string()),
+            b'b' => Some((self.octet()? as i8).to_string()),
+            b'B' => Some(self.octet()?.to_string()),
+            b's' => Some((self.short()? as i16).to_string()),
+            b'u' => Some(self.short()?.to_string()),
+            b'I' => Some((self.long()? as i32).to_string()),
+            b'i' => Some(self.long()?.to_string()),
+            b'l' => Some((self.longlong()? as i64).to_string()),
+            b'L' | b'T' => Some(self.longlong()?.to_string()),
+            b'f' => Some(f32::from_bits(self.long()?).to_string()),
+            b'd' => Some(f64::from_bits(self.longlong()?).to_string()),
+            b'S' => Some(String::from_utf8_lossy(&self.longstr()?).into_owned()),
+            b'D' => {
+                self.take(5)?;
+                None
+            }
+            b'x' => {
+                self.longstr()?;
+                None
+            }
+            b'F' => {
+                self.table()?;
+                None
+            }
+            b'A' => {
+                let size = self.long()? as usize;
+                self.take(size)?;
+                None
+            }
+            b'V' => None,
+            t => {
+                return Err(FrameError::Malformed(format!(
+                    "unknown field type {}",
+                    t as char
+                )))
+            }
+        };
+        Ok(value)
+    }
+}
+
+// Writes the fields of a method or content header payload in order
+#[derive(Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder { bytes: vec![] }
+    }
+
+    // Starts the payload of a method frame
+    pub fn method(class_id: u16, method_id: u16) -> Self {
+        Encoder::new().short(class_id).short(method_id)
+    }
+
+    pub fn octet(mut self, value: u8) -> Self {
+        self.bytes.push(value);
+        self
+    }
+
+    pub fn short(mut self, value: u16) -> Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn long(mut self, value: u32) -> Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn longlong(mut self, value: u64) -> Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    // short strings are at most 255 bytes, so longer values are cut at a character boundary
+    pub fn shortstr(mut self, value: &str) -> Self {
+        let mut end = value.len().min(u8::MAX as usize);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes.push(end as u8);
+        self.bytes.extend_from_slice(&value.as_bytes()[..end]);
+        self
+    }
+
+    pub fn longstr(mut self, value: &[u8]) -> Self {
+        self.bytes
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.bytes.extend_from_slice(value);
+        self
+    }
+
+    // Writes a field table with every value as a long string
+    pub fn table(self, table: &HashMap<String, String>) -> Self {
+        let mut entries = Encoder::new();
+        for (name, value) in table.iter() {
+            entries = entries.shortstr(name).octet(b'S').longstr(value.as_bytes());
+        }
+        self.longstr(&entries.bytes)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn frames_read_back_as_written() {
+        let bytes = Frame::new(FRAME_METHOD, 3, vec![1, 2, 3]).to_bytes();
+        let frame = match read_frame(&mut bytes.as_slice(), 4096).await {
+            Ok(f) => f,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(frame.frame_type, FRAME_METHOD);
+        assert_eq!(frame.channel, 3);
+        assert_eq!(frame.payload, vec![1, 2, 3]);
+    }
+
+    #[actix_web::test]
+    async fn frames_past_the_frame_max_or_without_an_end_are_refused() {
+        let bytes = Frame::new(FRAME_BODY, 1, vec![0; 100]).to_bytes();
+        let result = read_frame(&mut bytes.as_slice(), 64).await;
+        assert!(matches!(result, Err(FrameError::TooLarge(108, 64))));
+
+        let mut bytes = Frame::new(FRAME_BODY, 1, vec![0; 4]).to_bytes();
+        bytes.pop();
+        bytes.push(0);
+        let result = read_frame(&mut bytes.as_slice(), 4096).await;
+        assert!(matches!(result, Err(FrameError::Malformed(_))));
+
+        let result = read_frame(&mut &bytes[..5], 4096).await;
+        assert!(matches!(result, Err(FrameError::Closed)));
+    }
+
+    #[test]
+    fn fields_read_back_as_written() {
+        let mut table = HashMap::new();
+        table.insert(String::from("region"), String::from("eu"));
+        let bytes = Encoder::new()
+            .octet(7)
+            .short(300)
+            .long(70_000)
+            .longlong(1 << 40)
+            .shortstr("orders")
+            .longstr(b"content")
+            .table(&table)
+            .into_bytes();
+        let mut d = Decoder::new(&bytes);
+        assert_eq!(d.octet().ok(), Some(7));
+        assert_eq!(d.short().ok(), Some(300));
+        assert_eq!(d.long().ok(), Some(70_000));
+        assert_eq!(d.longlong().ok(), Some(1 << 40));
+        assert_eq!(d.shortstr().ok().as_deref(), Some("orders"));
+        assert_eq!(d.longstr().ok().as_deref(), Some(&b"content"[..]));
+        assert_eq!(d.table().ok(), Some(table));
+        assert!(d.octet().is_err());
+    }
+
+    #[test]
+    fn long_short_strings_are_cut_at_a_character_boundary() {
+        let value = "é".repeat(200);
+        let bytes = Encoder::new().shortstr(&value).into_bytes();
+        assert_eq!(bytes[0], 254);
+        let decoded = Decoder::new(&bytes).shortstr().ok().unwrap();
+        assert_eq!(decoded, "é".repeat(127));
+    }
+
+    #[test]
+    fn table_values_are_kept_as_strings() {
+        let entries = Encoder::new()
+            .shortstr("durable")
+            .octet(b't')
+            .octet(1)
+            .shortstr("count")
+            .octet(b'I')
+            .long(-5i32 as u32)
+            .shortstr("nested")
+            .octet(b'F')
+            .long(0)
+            .shortstr("nothing")
+            .octet(b'V')
+            .into_bytes();
+        let bytes = Encoder::new().longstr(&entries).into_bytes();
+        let table = Decoder::new(&bytes).table().ok().unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table["durable"], "true");
+        assert_eq!(table["count"], "-5");
+
+        let entries = Encoder::new().shortstr("odd").octet(b'?').into_bytes();
+        let bytes = Encoder::new().longstr(&entries).into_bytes();
+        assert!(Decoder::new(&bytes).table().is_err());
+    }
+}
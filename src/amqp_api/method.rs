@@ -0,0 +1,596 @@
+use std::collections::HashMap;
+
+use super::frame::{Decoder, Encoder, FrameError};
+
+const CLASS_CONNECTION: u16 = 10;
+const CLASS_CHANNEL: u16 = 20;
+const CLASS_EXCHANGE: u16 = 40;
+const CLASS_QUEUE: u16 = 50;
+const CLASS_BASIC: u16 = 60;
+
+// the content header property flags up to the last property RQS keeps, in the order they are written
+const PROPERTY_CONTENT_TYPE: u16 = 1 << 15;
+const PROPERTY_CONTENT_ENCODING: u16 = 1 << 14;
+const PROPERTY_HEADERS: u16 = 1 << 13;
+const PROPERTY_DELIVERY_MODE: u16 = 1 << 12;
+const PROPERTY_PRIORITY: u16 = 1 << 11;
+const PROPERTY_CORRELATION_ID: u16 = 1 << 10;
+const PROPERTY_REPLY_TO: u16 = 1 << 9;
+const PROPERTY_EXPIRATION: u16 = 1 << 8;
+const PROPERTY_MESSAGE_ID: u16 = 1 << 7;
+
+// The methods a client may send. Arguments RQS has no use for are read past and dropped.
+pub enum Method {
+    ConnectionStartOk {
+        mechanism: String,
+        response: Vec<u8>,
+    },
+    ConnectionTuneOk {
+        frame_max: u32,
+        heartbeat: u16,
+    },
+    ConnectionOpen {
+        virtual_host: String,
+    },
+    ConnectionClose,
+    ConnectionCloseOk,
+    ChannelOpen,
+    ChannelClose,
+    ChannelCloseOk,
+    ExchangeDeclare {
+        exchange: String,
+        exchange_type: String,
+        passive: bool,
+        no_wait: bool,
+    },
+    QueueDeclare {
+        queue: String,
+        passive: bool,
+        no_wait: bool,
+    },
+    QueueBind {
+        queue: String,
+        exchange: String,
+        routing_key: String,
+        no_wait: bool,
+    },
+    BasicQos {
+        prefetch_count: u16,
+    },
+    BasicConsume {
+        queue: String,
+        consumer_tag: String,
+        no_ack: bool,
+        no_wait: bool,
+    },
+    BasicCancel {
+        consumer_tag: String,
+        no_wait: bool,
+    },
+    BasicPublish {
+        exchange: String,
+        routing_key: String,
+        mandatory: bool,
+    },
+    BasicGet {
+        queue: String,
+        no_ack: bool,
+    },
+    BasicAck {
+        delivery_tag: u64,
+        multiple: bool,
+    },
+    BasicReject {
+        delivery_tag: u64,
+        requeue: bool,
+    },
+    BasicNack {
+        delivery_tag: u64,
+        multiple: bool,
+        requeue: bool,
+    },
+    Unsupported(u16, u16),
+}
+
+impl Method {
+    pub fn decode(payload: &[u8]) -> Result<Self, FrameError> {
+        let mut d = Decoder::new(payload);
+        let class_id = d.short()?;
+        let method_id = d.short()?;
+        let method = match (class_id, method_id) {
+            (CLASS_CONNECTION, 11) => {
+                d.table()?; // client properties
+                Method::ConnectionStartOk {
+                    mechanism: d.shortstr()?,
+                    response: d.longstr()?,
+                }
+            }
+            (CLASS_CONNECTION, 31) => {
+                d.short()?; // channel max
+                Method::ConnectionTuneOk {
+                    frame_max: d.long()?,
+                    heartbeat: d.short()?,
+                }
+            }
+            (CLASS_CONNECTION, 40) => Method::ConnectionOpen {
+                virtual_host: d.shortstr()?,
+            },
+            (CLASS_CONNECTION, 50) => Method::ConnectionClose,
+            (CLASS_CONNECTION, 51) => Method::ConnectionCloseOk,
+            (CLASS_CHANNEL, 10) => Method::ChannelOpen,
+            (CLASS_CHANNEL, 40) => Method::ChannelClose,
+            (CLASS_CHANNEL, 41) => Method::ChannelCloseOk,
+            (CLASS_EXCHANGE, 10) => {
+                d.short()?;
+                let exchange = d.shortstr()?;
+                let exchange_type = d.shortstr()?;
+                let bits = d.octet()?;
+                Method::ExchangeDeclare {
+                    exchange,
+                    exchange_type,
+                    passive: bits & 1 != 0,
+                    no_wait: bits & (1 << 4) != 0,
+                }
+            }
+            (CLASS_QUEUE, 10) => {
+                d.short()?;
+                let queue = d.shortstr()?;
+                let bits = d.octet()?;
+                Method::QueueDeclare {
+                    queue,
+                    passive: bits & 1 != 0,
+                    no_wait: bits & (1 << 4) != 0,
+                }
+            }
+            (CLASS_QUEUE, 20) => {
+                d.short()?;
+                Method::QueueBind {
+                    queue: d.shortstr()?,
+                    exchange: d.shortstr()?,
+                    routing_key: d.shortstr()?,
+                    no_wait: d.octet()? & 1 != 0,
+                }
+            }
+            (CLASS_BASIC, 10) => {
+                d.long()?; // prefetch size
+                Method::BasicQos {
+                    prefetch_count: d.short()?,
+                }
+            }
+            (CLASS_BASIC, 20) => {
+                d.short()?;
+                let queue = d.shortstr()?;
+                let consumer_tag = d.shortstr()?;
+                let bits = d.octet()?;
+                Method::BasicConsume {
+                    queue,
+                    consumer_tag,
+                    no_ack: bits & (1 << 1) != 0,
+                    no_wait: bits & (1 << 3) != 0,
+                }
+            }
+            (CLASS_BASIC, 30) => Method::BasicCancel {
+                consumer_tag: d.shortstr()?,
+                no_wait: d.octet()? & 1 != 0,
+            },
+            (CLASS_BASIC, 40) => {
+                d.short()?;
+                Method::BasicPublish {
+                    exchange: d.shortstr()?,
+                    routing_key: d.shortstr()?,
+                    mandatory: d.octet()? & 1 != 0,
+                }
+            }
+            (CLASS_BASIC, 70) => {
+                d.short()?;
+                Method::BasicGet {
+                    queue: d.shortstr()?,
+                    no_ack: d.octet()? & 1 != 0,
+                }
+            }
+            (CLASS_BASIC, 80) => Method::BasicAck {
+                delivery_tag: d.longlong()?,
+                multiple: d.octet()? & 1 != 0,
+            },
+            (CLASS_BASIC, 90) => Method::BasicReject {
+                delivery_tag: d.longlong()?,
+                requeue: d.octet()? & 1 != 0,
+            },
+            (CLASS_BASIC, 120) => {
+                let delivery_tag = d.longlong()?;
+                let bits = d.octet()?;
+                Method::BasicNack {
+                    delivery_tag,
+                    multiple: bits & 1 != 0,
+                    requeue: bits & (1 << 1) != 0,
+                }
+            }
+            (class_id, method_id) => Method::Unsupported(class_id, method_id),
+        };
+        Ok(method)
+    }
+}
+
+// The properties of a published message that RQS keeps
+pub struct ContentHeader {
+    pub body_size: u64,
+    pub message_id: Option<String>,
+    pub correlation_id: Option<String>,
+    pub reply_to: Option<String>,
+    pub headers: HashMap<String, String>,
+}
+
+impl ContentHeader {
+    pub fn decode(payload: &[u8]) -> Result<Self, FrameError> {
+        let mut d = Decoder::new(payload);
+        let class_id = d.short()?;
+        if class_id != CLASS_BASIC {
+            return Err(FrameError::Malformed(format!(
+                "content headers of class {} are not supported",
+                class_id
+            )));
+        }
+        d.short()?; // weight
+        let mut header = ContentHeader {
+            body_size: d.longlong()?,
+            message_id: None,
+            correlation_id: None,
+            reply_to: None,
+            headers: HashMap::new(),
+        };
+        let flags = d.short()?;
+        if flags & PROPERTY_CONTENT_TYPE != 0 {
+            d.shortstr()?;
+        }
+        if flags & PROPERTY_CONTENT_ENCODING != 0 {
+            d.shortstr()?;
+        }
+        if flags & PROPERTY_HEADERS != 0 {
+            header.headers = d.table()?;
+        }
+        if flags & PROPERTY_DELIVERY_MODE != 0 {
+            d.octet()?;
+        }
+        if flags & PROPERTY_PRIORITY != 0 {
+            d.octet()?;
+        }
+        if flags & PROPERTY_CORRELATION_ID != 0 {
+            header.correlation_id = Some(d.shortstr()?);
+        }
+        if flags & PROPERTY_REPLY_TO != 0 {
+            header.reply_to = Some(d.shortstr()?);
+        }
+        if flags & PROPERTY_EXPIRATION != 0 {
+            d.shortstr()?;
+        }
+        if flags & PROPERTY_MESSAGE_ID != 0 {
+            header.message_id = Some(d.shortstr()?);
+        }
+        // the remaining properties all come after the ones RQS keeps, so they are left unread
+        Ok(header)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = PROPERTY_MESSAGE_ID;
+        if !self.headers.is_empty() {
+            flags |= PROPERTY_HEADERS;
+        }
+        if self.correlation_id.is_some() {
+            flags |= PROPERTY_CORRELATION_ID;
+        }
+        if self.reply_to.is_some() {
+            flags |= PROPERTY_REPLY_TO;
+        }
+        let mut e = Encoder::new()
+            .short(CLASS_BASIC)
+            .short(0)
+            .longlong(self.body_size)
+            .short(flags);
+        if !self.headers.is_empty() {
+            e = e.table(&self.headers);
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            e = e.shortstr(correlation_id);
+        }
+        if let Some(reply_to) = &self.reply_to {
+            e = e.shortstr(reply_to);
+        }
+        e.shortstr(self.message_id.as_deref().unwrap_or_default())
+            .into_bytes()
+    }
+}
+
+// The password in a connection.start-ok's SASL response, which PLAIN gives as
+// `\0<login>\0<password>` and AMQPLAIN as a field table without its size
+pub fn sasl_password(mechanism: &str, response: &[u8]) -> Option<String> {
+    match mechanism {
+        "PLAIN" => {
+            let mut fields = response.split(|b| *b == 0).skip(2);
+            let password = fields.next()?;
+            String::from_utf8(password.to_vec()).ok()
+        }
+        "AMQPLAIN" => {
+            let table = Encoder::new().longstr(response).into_bytes();
+            Decoder::new(&table).table().ok()?.remove("PASSWORD")
+        }
+        _ => None,
+    }
+}
+
+pub fn connection_start() -> Vec<u8> {
+    let mut properties = HashMap::new();
+    properties.insert(String::from("product"), String::from("RQS"));
+    Encoder::method(CLASS_CONNECTION, 10)
+        .octet(0)
+        .octet(9)
+        .table(&properties)
+        .longstr(b"PLAIN AMQPLAIN")
+        .longstr(b"en_US")
+        .into_bytes()
+}
+
+pub fn connection_tune(channel_max: u16, frame_max: u32, heartbeat: u16) -> Vec<u8> {
+    Encoder::method(CLASS_CONNECTION, 30)
+        .short(channel_max)
+        .long(frame_max)
+        .short(heartbeat)
+        .into_bytes()
+}
+
+pub fn connection_open_ok() -> Vec<u8> {
+    Encoder::method(CLASS_CONNECTION, 41)
+        .shortstr("")
+        .into_bytes()
+}
+
+pub fn connection_close(reply_code: u16, reply_text: &str) -> Vec<u8> {
+    Encoder::method(CLASS_CONNECTION, 50)
+        .short(reply_code)
+        .shortstr(reply_text)
+        .short(0)
+        .short(0)
+        .into_bytes()
+}
+
+pub fn connection_close_ok() -> Vec<u8> {
+    Encoder::method(CLASS_CONNECTION, 51).into_bytes()
+}
+
+pub fn channel_open_ok() -> Vec<u8> {
+    Encoder::method(CLASS_CHANNEL, 11).longstr(b"").into_bytes()
+}
+
+pub fn channel_close(reply_code: u16, reply_text: &str) -> Vec<u8> {
+    Encoder::method(CLASS_CHANNEL, 40)
+        .short(reply_code)
+        .shortstr(reply_text)
+        .short(0)
+        .short(0)
+        .into_bytes()
+}
+
+pub fn channel_close_ok() -> Vec<u8> {
+    Encoder::method(CLASS_CHANNEL, 41).into_bytes()
+}
+
+pub fn exchange_declare_ok() -> Vec<u8> {
+    Encoder::method(CLASS_EXCHANGE, 11).into_bytes()
+}
+
+pub fn queue_declare_ok(queue: &str, message_count: u32) -> Vec<u8> {
+    Encoder::method(CLASS_QUEUE, 11)
+        .shortstr(queue)
+        .long(message_count)
+        .long(0)
+        .into_bytes()
+}
+
+pub fn queue_bind_ok() -> Vec<u8> {
+    Encoder::method(CLASS_QUEUE, 21).into_bytes()
+}
+
+pub fn basic_qos_ok() -> Vec<u8> {
+    Encoder::method(CLASS_BASIC, 11).into_bytes()
+}
+
+pub fn basic_consume_ok(consumer_tag: &str) -> Vec<u8> {
+    Encoder::method(CLASS_BASIC, 21)
+        .shortstr(consumer_tag)
+        .into_bytes()
+}
+
+pub fn basic_cancel_ok(consumer_tag: &str) -> Vec<u8> {
+    Encoder::method(CLASS_BASIC, 31)
+        .shortstr(consumer_tag)
+        .into_bytes()
+}
+
+pub fn basic_return(
+    reply_code: u16,
+    reply_text: &str,
+    exchange: &str,
+    routing_key: &str,
+) -> Vec<u8> {
+    Encoder::method(CLASS_BASIC, 50)
+        .short(reply_code)
+        .shortstr(reply_text)
+        .shortstr(exchange)
+        .shortstr(routing_key)
+        .into_bytes()
+}
+
+pub fn basic_deliver(consumer_tag: &str, delivery_tag: u64, routing_key: &str) -> Vec<u8> {
+    Encoder::method(CLASS_BASIC, 60)
+        .shortstr(consumer_tag)
+        .longlong(delivery_tag)
+        .octet(0)
+        .shortstr("")
+        .shortstr(routing_key)
+        .into_bytes()
+}
+
+pub fn basic_get_ok(delivery_tag: u64, routing_key: &str, message_count: u32) -> Vec<u8> {
+    Encoder::method(CLASS_BASIC, 71)
+        .longlong(delivery_tag)
+        .octet(0)
+        .shortstr("")
+        .shortstr(routing_key)
+        .long(message_count)
+        .into_bytes()
+}
+
+pub fn basic_get_empty() -> Vec<u8> {
+    Encoder::method(CLASS_BASIC, 72).shortstr("").into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(payload: Vec<u8>) -> Method {
+        match Method::decode(&payload) {
+            Ok(m) => m,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn connection_methods_are_decoded() {
+        let payload = Encoder::method(CLASS_CONNECTION, 11)
+            .table(&HashMap::new())
+            .shortstr("PLAIN")
+            .longstr(b"\0guest\0secret")
+            .shortstr("en_US")
+            .into_bytes();
+        match decode(payload) {
+            Method::ConnectionStartOk {
+                mechanism,
+                response,
+            } => {
+                assert_eq!(mechanism, "PLAIN");
+                assert_eq!(response, b"\0guest\0secret");
+            }
+            _ => panic!("expected connection.start-ok"),
+        }
+
+        let payload = Encoder::method(CLASS_CONNECTION, 40)
+            .shortstr("/tenant")
+            .shortstr("")
+            .octet(0)
+            .into_bytes();
+        match decode(payload) {
+            Method::ConnectionOpen { virtual_host } => assert_eq!(virtual_host, "/tenant"),
+            _ => panic!("expected connection.open"),
+        }
+    }
+
+    #[test]
+    fn method_flags_are_decoded() {
+        let payload = Encoder::method(CLASS_BASIC, 20)
+            .short(0)
+            .shortstr("orders")
+            .shortstr("tag")
+            .octet(0b1010)
+            .table(&HashMap::new())
+            .into_bytes();
+        match decode(payload) {
+            Method::BasicConsume {
+                queue,
+                consumer_tag,
+                no_ack,
+                no_wait,
+            } => {
+                assert_eq!(queue, "orders");
+                assert_eq!(consumer_tag, "tag");
+                assert!(no_ack && no_wait);
+            }
+            _ => panic!("expected basic.consume"),
+        }
+
+        let payload = Encoder::method(CLASS_BASIC, 120)
+            .longlong(9)
+            .octet(0b10)
+            .into_bytes();
+        match decode(payload) {
+            Method::BasicNack {
+                delivery_tag,
+                multiple,
+                requeue,
+            } => assert!(delivery_tag == 9 && !multiple && requeue),
+            _ => panic!("expected basic.nack"),
+        }
+    }
+
+    #[test]
+    fn unknown_and_truncated_methods() {
+        let payload = Encoder::method(CLASS_QUEUE, 40).into_bytes();
+        assert!(matches!(decode(payload), Method::Unsupported(50, 40)));
+
+        let payload = Encoder::method(CLASS_BASIC, 80).long(1).into_bytes();
+        assert!(Method::decode(&payload).is_err());
+    }
+
+    #[test]
+    fn content_headers_read_back_as_written() {
+        let mut headers = HashMap::new();
+        headers.insert(String::from("topic"), String::from("orders"));
+        let header = ContentHeader {
+            body_size: 12,
+            message_id: Some(String::from("m1")),
+            correlation_id: Some(String::from("c1")),
+            reply_to: None,
+            headers,
+        };
+        let decoded = match ContentHeader::decode(&header.encode()) {
+            Ok(h) => h,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(decoded.body_size, 12);
+        assert_eq!(decoded.message_id.as_deref(), Some("m1"));
+        assert_eq!(decoded.correlation_id.as_deref(), Some("c1"));
+        assert_eq!(decoded.reply_to, None);
+        assert_eq!(decoded.headers, header.headers);
+    }
+
+    #[test]
+    fn content_headers_skip_properties_that_are_not_kept() {
+        let payload = Encoder::new()
+            .short(CLASS_BASIC)
+            .short(0)
+            .longlong(3)
+            .short(PROPERTY_CONTENT_TYPE | PROPERTY_DELIVERY_MODE | PROPERTY_REPLY_TO)
+            .shortstr("text/plain")
+            .octet(2)
+            .shortstr("replies")
+            .into_bytes();
+        let header = match ContentHeader::decode(&payload) {
+            Ok(h) => h,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(header.reply_to.as_deref(), Some("replies"));
+        assert_eq!(header.message_id, None);
+
+        let payload = Encoder::new().short(CLASS_QUEUE).into_bytes();
+        assert!(ContentHeader::decode(&payload).is_err());
+    }
+
+    #[test]
+    fn sasl_passwords_are_read_from_plain_and_amqplain() {
+        assert_eq!(
+            sasl_password("PLAIN", b"\0guest\0secret").as_deref(),
+            Some("secret")
+        );
+        assert_eq!(sasl_password("PLAIN", b"guest"), None);
+
+        let mut table = HashMap::new();
+        table.insert(String::from("LOGIN"), String::from("guest"));
+        table.insert(String::from("PASSWORD"), String::from("secret"));
+        // AMQPLAIN gives the table without its size
+        let response = Encoder::new().table(&table).into_bytes()[4..].to_vec();
+        assert_eq!(
+            sasl_password("AMQPLAIN", &response).as_deref(),
+            Some("secret")
+        );
+        assert_eq!(sasl_password("EXTERNAL", b"secret"), None);
+    }
+}
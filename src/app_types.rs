@@ -1,17 +1,33 @@
+use crate::audit_api::audit_log::AuditLog;
+use crate::auth_api::api_key::ApiKey;
+use crate::blob_store::BlobStore;
+use crate::consumer_api::event_stream::EventStream;
 use crate::exchange_api::exchange::Exchange;
+use crate::metrics_api::metrics::RequestMetrics;
+use crate::namespace_api::namespace::Namespace;
 use crate::queue_api::queue::Queue;
-use aes_gcm::aes::cipher::typenum::bit::{B0, B1};
-use aes_gcm::aes::cipher::typenum::{UInt, UTerm};
-use aes_gcm::aes::Aes256;
-use aes_gcm::AesGcm;
+use crate::webhook_api::webhook::Webhook;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use aes_gcm::Aes256Gcm;
 use futures::lock::Mutex;
 use serde::Serialize;
 use std::collections::HashMap;
+use std::time::Duration;
 
 pub struct AppState {
     pub queues: Mutex<HashMap<String, Queue>>,
     pub exchanges: Mutex<HashMap<String, Exchange>>,
-    pub cipher: Mutex<AesGcm<Aes256, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>>,
+    pub cipher: Mutex<Aes256Gcm>,
+    pub blob_store: BlobStore,
+    pub event_streams: Mutex<HashMap<String, EventStream>>,
+    pub webhooks: Mutex<HashMap<String, Webhook>>,
+    pub api_keys: Mutex<HashMap<String, ApiKey>>, // keyed by the hash of the key
+    pub namespaces: Mutex<HashMap<String, Namespace>>,
+    pub audit_log: Mutex<AuditLog>,
+    pub request_metrics: Mutex<RequestMetrics>,
+    pub require_api_keys: bool, // whether every client must present an API key
 }
 
 impl AppState {
@@ -21,11 +37,59 @@ impl AppState {
     pub fn get_exchanges(&self) -> &Mutex<HashMap<String, Exchange>> {
         &self.exchanges
     }
-    pub fn get_cipher(
-        &self,
-    ) -> &Mutex<AesGcm<Aes256, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>> {
+    pub fn get_cipher(&self) -> &Mutex<Aes256Gcm> {
         &self.cipher
     }
+    pub fn get_blob_store(&self) -> &BlobStore {
+        &self.blob_store
+    }
+    pub fn get_event_streams(&self) -> &Mutex<HashMap<String, EventStream>> {
+        &self.event_streams
+    }
+    pub fn get_webhooks(&self) -> &Mutex<HashMap<String, Webhook>> {
+        &self.webhooks
+    }
+    pub fn get_api_keys(&self) -> &Mutex<HashMap<String, ApiKey>> {
+        &self.api_keys
+    }
+    pub fn get_namespaces(&self) -> &Mutex<HashMap<String, Namespace>> {
+        &self.namespaces
+    }
+    pub fn get_audit_log(&self) -> &Mutex<AuditLog> {
+        &self.audit_log
+    }
+    pub fn get_request_metrics(&self) -> &Mutex<RequestMetrics> {
+        &self.request_metrics
+    }
+    pub fn requires_api_keys(&self) -> bool {
+        self.require_api_keys
+    }
+
+    // An empty instance with just the default namespace, and its own blob directory
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        use crate::namespace_api::namespace::DEFAULT_NAMESPACE;
+        use aes_gcm::aead::{KeyInit, OsRng};
+
+        let blob_dir =
+            std::env::temp_dir().join(format!("rqs-test-blobs-{}", uuid::Uuid::new_v4()));
+        AppState {
+            queues: Mutex::new(HashMap::new()),
+            exchanges: Mutex::new(HashMap::new()),
+            cipher: Mutex::new(Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng))),
+            blob_store: BlobStore::new(blob_dir).unwrap(),
+            event_streams: Mutex::new(HashMap::new()),
+            webhooks: Mutex::new(HashMap::new()),
+            api_keys: Mutex::new(HashMap::new()),
+            namespaces: Mutex::new(HashMap::from([(
+                String::from(DEFAULT_NAMESPACE),
+                Namespace::new(DEFAULT_NAMESPACE, None, None),
+            )])),
+            audit_log: Mutex::new(AuditLog::new(100, None, false).unwrap()),
+            request_metrics: Mutex::new(RequestMetrics::default()),
+            require_api_keys: false,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -39,3 +103,53 @@ impl<T, E> JsonResponse<T, E> {
         JsonResponse { data, error }
     }
 }
+
+// Why an operation failed, and the HTTP status that describes it. Operations return these rather
+// than responses so the REST API and the other protocols can share them.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+    retry_after: Option<Duration>, // when a rate limited request may be tried again
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn get_status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn get_message(&self) -> &String {
+        &self.message
+    }
+
+    // The seconds to wait before retrying, rounded up as Retry-After only takes whole seconds
+    pub fn get_retry_after(&self) -> Option<u64> {
+        self.retry_after
+            .map(|d| d.as_secs_f64().ceil().max(1.0) as u64)
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if let Some(seconds) = self.get_retry_after() {
+            response.insert_header((RETRY_AFTER, seconds.to_string()));
+        }
+        response.json(JsonResponse::new(None::<String>, &self.message))
+    }
+}
@@ -0,0 +1,204 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::app_types::{ApiError, AppState, JsonResponse};
+use crate::auth_api::api_key::ApiKey;
+use crate::auth_api::get_api_key;
+use crate::namespace_api::get_namespace;
+use crate::namespace_api::namespace::{split_id, Namespace};
+use audit_log::{AuditAction, AuditRecord};
+use request::{AuditEntry, AuditQuery};
+
+pub(crate) mod audit_log;
+mod request;
+
+// requests may give their own id in this header, and are otherwise given one in the response's
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// the longest request id a client may give
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// how many entries /admin/audit/list returns when no limit is given
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+
+// The id of a request, for matching it up with its audit records
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+// Gives every request an id, the one in its X-Request-Id header if that is usable, and returns it
+// in the response's
+pub async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(|v| v.to_owned())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    req.extensions_mut()
+        .insert(RequestId(request_id.to_owned()));
+    let mut res = next.call(req).await?;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
+
+// Records what a request did to a queue, exchange, webhook, key or namespace, by its id
+pub async fn audit(
+    data: &AppState,
+    req: &HttpRequest,
+    action: AuditAction,
+    id: &str,
+    detail: Option<String>,
+) {
+    let request_id = match req.extensions().get::<RequestId>() {
+        Some(RequestId(id)) => id.to_owned(),
+        None => Uuid::new_v4().to_string(),
+    };
+    let namespace = get_namespace(req);
+    let api_key = get_api_key(req);
+    audit_as(
+        data,
+        &namespace,
+        api_key.as_ref(),
+        &request_id,
+        action,
+        id,
+        detail,
+    )
+    .await;
+}
+
+// Records what the server did by itself, such as deleting a reply queue that went unused, in the
+// namespace of the queue or exchange it did it to
+pub async fn audit_by_server(
+    data: &AppState,
+    action: AuditAction,
+    id: &str,
+    detail: Option<String>,
+) {
+    let namespace = Namespace::new(split_id(id).0, None, None);
+    let request_id = Uuid::new_v4().to_string();
+    audit_as(data, &namespace, None, &request_id, action, id, detail).await;
+}
+
+// Records what was done in a namespace with a key, or without one when API keys are not required
+pub async fn audit_as(
+    data: &AppState,
+    namespace: &Namespace,
+    api_key: Option<&ApiKey>,
+    request_id: &str,
+    action: AuditAction,
+    id: &str,
+    detail: Option<String>,
+) {
+    let mut audit_log = data.get_audit_log().lock().await;
+    if !audit_log.is_recorded(action) {
+        return;
+    }
+    audit_log.append(AuditRecord {
+        sequence: 0,
+        time: Utc::now(),
+        request_id: request_id.to_owned(),
+        resource_id: namespace.unqualify(id).unwrap_or(id.to_owned()),
+        namespace: namespace.name.to_owned(),
+        api_key: api_key.map(|k| (k.id.to_string(), k.name.to_owned())),
+        action,
+        detail,
+    });
+}
+
+// Lists the most recent audit entries of the request's namespace, oldest first
+pub async fn list_audit_entries(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<AuditQuery>,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT);
+    match find_audit_entries(&req, &data, &query, limit).await {
+        Ok(entries) => HttpResponse::Accepted().json(JsonResponse::new(entries, None::<String>)),
+        Err(e) => e.to_response(),
+    }
+}
+
+// Exports the audit entries of the request's namespace as JSON lines, oldest first
+pub async fn export_audit_log(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<AuditQuery>,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(usize::MAX);
+    let entries = match find_audit_entries(&req, &data, &query, limit).await {
+        Ok(e) => e,
+        Err(e) => return e.to_response(),
+    };
+    let mut body = String::new();
+    for entry in entries.iter() {
+        body.push_str(&serde_json::to_string(entry).unwrap_or_default());
+        body.push('\n');
+    }
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "application/x-ndjson"))
+        .body(body)
+}
+
+async fn find_audit_entries(
+    req: &HttpRequest,
+    data: &AppState,
+    query: &AuditQuery,
+    limit: usize,
+) -> Result<Vec<AuditEntry>, ApiError> {
+    let since = parse_time("since", &query.since)?;
+    let until = parse_time("until", &query.until)?;
+    let namespace = get_namespace(req);
+    let audit_log = data.get_audit_log().lock().await;
+    let mut entries = audit_log
+        .get_records()
+        .iter()
+        .rev()
+        .filter(|r| r.namespace == namespace.name)
+        .filter(|r| query.action.is_none_or(|a| a == r.action))
+        .filter(|r| {
+            query
+                .resource_id
+                .as_ref()
+                .is_none_or(|id| *id == r.resource_id)
+        })
+        .filter(|r| {
+            query
+                .api_key_id
+                .as_ref()
+                .is_none_or(|id| r.api_key.as_ref().is_some_and(|(k, _)| k == id))
+        })
+        .filter(|r| since.is_none_or(|t| r.time >= t))
+        .filter(|r| until.is_none_or(|t| r.time < t))
+        .take(limit)
+        .map(AuditEntry::new)
+        .collect::<Vec<AuditEntry>>();
+    entries.reverse();
+    Ok(entries)
+}
+
+fn parse_time(field: &str, value: &Option<String>) -> Result<Option<DateTime<Utc>>, ApiError> {
+    match value {
+        Some(value) => DateTime::parse_from_rfc3339(value)
+            .map(|t| Some(t.with_timezone(&Utc)))
+            .map_err(|_| {
+                ApiError::bad_request(format!(
+                    "The {} time {} is invalid, it must be RFC 3339",
+                    field, value
+                ))
+            }),
+        None => Ok(None),
+    }
+}
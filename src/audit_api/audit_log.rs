@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::request::AuditEntry;
+
+// What an audited request did
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AuditAction {
+    QueueCreated,
+    QueueUpdated,
+    QueueDeleted,
+    ExchangeCreated,
+    ExchangeUpdated,
+    ExchangeBound,
+    ExchangeDeleted,
+    WebhookCreated,
+    WebhookDeleted,
+    ApiKeyCreated,
+    ApiKeyDeleted,
+    GrantsChanged,
+    NamespaceCreated,
+    NamespaceDeleted,
+    MessagesPublished, // only recorded when messages are audited
+    MessagesDeleted,   // only recorded when messages are audited
+}
+
+impl AuditAction {
+    pub fn is_message_action(&self) -> bool {
+        matches!(
+            self,
+            AuditAction::MessagesPublished | AuditAction::MessagesDeleted
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub sequence: u64, // counts up from 1, so gaps show records that were dropped
+    pub time: DateTime<Utc>,
+    pub request_id: String,
+    pub namespace: String,
+    pub api_key: Option<(String, String)>, // the id and name of the key the request was made with
+    pub action: AuditAction,
+    pub resource_id: String, // as the namespace sees it
+    pub detail: Option<String>,
+}
+
+// Records are only ever added. The oldest are dropped from memory past the max, but are kept in
+// the file, if there is one.
+pub struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    max_records: usize,
+    next_sequence: u64,
+    file: Option<File>,
+    audit_messages: bool, // whether publishing and deleting messages is recorded
+    write_failures: u64,  // records that could not be written to the file
+}
+
+impl AuditLog {
+    pub fn new(max_records: usize, path: Option<&Path>, audit_messages: bool) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?,
+            ),
+            None => None,
+        };
+        Ok(AuditLog {
+            records: VecDeque::new(),
+            max_records,
+            next_sequence: 1,
+            file,
+            audit_messages,
+            write_failures: 0,
+        })
+    }
+
+    pub fn is_recorded(&self, action: AuditAction) -> bool {
+        !action.is_message_action() || self.audit_messages
+    }
+
+    // Adds a record, numbering it and writing it to the file as a JSON line
+    pub fn append(&mut self, mut record: AuditRecord) {
+        record.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        if let Some(file) = &mut self.file {
+            let line = serde_json::to_string(&AuditEntry::new(&record)).unwrap_or_default();
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("The audit log could not be written to: {}", e);
+                self.write_failures += 1;
+            }
+        }
+        self.records.push_back(record);
+        while self.records.len() > self.max_records {
+            self.records.pop_front();
+        }
+    }
+
+    pub fn get_records(&self) -> &VecDeque<AuditRecord> {
+        &self.records
+    }
+
+    pub fn get_write_failures(&self) -> u64 {
+        self.write_failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(action: AuditAction) -> AuditRecord {
+        AuditRecord {
+            sequence: 0,
+            time: Utc::now(),
+            request_id: String::from("request"),
+            namespace: String::from("default"),
+            api_key: None,
+            action,
+            resource_id: String::from("orders"),
+            detail: None,
+        }
+    }
+
+    #[test]
+    fn message_actions_are_only_recorded_when_asked_for() {
+        let audit_log = AuditLog::new(10, None, false).unwrap();
+        assert!(audit_log.is_recorded(AuditAction::QueueDeleted));
+        assert!(!audit_log.is_recorded(AuditAction::MessagesDeleted));
+        let audit_log = AuditLog::new(10, None, true).unwrap();
+        assert!(audit_log.is_recorded(AuditAction::MessagesDeleted));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_writes_are_counted_and_records_kept() {
+        // every write to /dev/full fails
+        let mut audit_log = AuditLog::new(10, Some(Path::new("/dev/full")), false).unwrap();
+        audit_log.append(record(AuditAction::QueueCreated));
+        audit_log.append(record(AuditAction::QueueDeleted));
+        assert_eq!(audit_log.get_write_failures(), 2);
+        assert_eq!(audit_log.get_records().len(), 2);
+    }
+}
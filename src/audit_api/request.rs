@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+use super::audit_log::{AuditAction, AuditRecord};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub resource_id: Option<String>,
+    pub api_key_id: Option<String>,
+    pub since: Option<String>, // RFC 3339
+    pub until: Option<String>, // RFC 3339
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub sequence: u64,
+    pub time: String, // RFC 3339
+    pub request_id: String,
+    pub namespace: String,
+    pub api_key_id: Option<String>,
+    pub api_key_name: Option<String>,
+    pub action: AuditAction,
+    pub resource_id: String,
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(record: &AuditRecord) -> Self {
+        AuditEntry {
+            sequence: record.sequence,
+            time: record.time.to_rfc3339(),
+            request_id: record.request_id.to_owned(),
+            namespace: record.namespace.to_owned(),
+            api_key_id: record.api_key.as_ref().map(|(id, _)| id.to_owned()),
+            api_key_name: record.api_key.as_ref().map(|(_, name)| name.to_owned()),
+            action: record.action,
+            resource_id: record.resource_id.to_owned(),
+            detail: record.detail.to_owned(),
+        }
+    }
+}
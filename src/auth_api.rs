@@ -0,0 +1,485 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+
+use crate::app_types::{ApiError, AppState, JsonResponse};
+use crate::audit_api::audit;
+use crate::audit_api::audit_log::AuditAction;
+use crate::namespace_api::get_namespace;
+use crate::namespace_api::namespace::{Namespace, DEFAULT_NAMESPACE};
+use crate::rate_limit::{Limiter, RateLimitError};
+use crate::tls::ClientCertificate;
+use api_key::{generate_key, hash_key, ApiKey};
+use grant::{Permission, ResourceType};
+use identity::Identity;
+use request::{
+    ApiKeyEntry, DeleteApiKeyRequest, NewApiKeyRequest, NewApiKeyResponse, SetGrantsRequest,
+};
+
+pub(crate) mod api_key;
+pub(crate) mod grant;
+pub(crate) mod identity;
+mod request;
+
+// Checks the bearer token of every request, or else the client certificate it was made over, and
+// hands the key it belongs to on to the handlers
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    // the ping is left open for health checks
+    if req.path() == "/" {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_owned());
+    let certificate = req.conn_data::<ClientCertificate>().cloned();
+    let api_key = match (&token, &certificate, req.app_data::<web::Data<AppState>>()) {
+        (Some(token), _, Some(data)) => data
+            .get_api_keys()
+            .lock()
+            .await
+            .get_mut(&hash_key(token))
+            .map(take_request),
+        (None, Some(certificate), Some(data)) => data
+            .get_api_keys()
+            .lock()
+            .await
+            .values_mut()
+            .find(|k| k.certificate_subject.as_ref() == Some(&certificate.subject))
+            .map(take_request),
+        _ => None,
+    };
+    // keys only work in their own namespace, apart from global admin keys
+    let namespace = req
+        .extensions()
+        .get::<Namespace>()
+        .map(|n| n.name.to_owned());
+    match api_key {
+        Some(Err(e)) => {
+            let response = e.to_api_error("the API key").to_response();
+            Ok(req.into_response(response).map_into_right_body())
+        }
+        Some(Ok(api_key))
+            if !api_key.is_global_admin() && namespace.is_some_and(|n| n != api_key.namespace) =>
+        {
+            let response = ApiError::new(
+                StatusCode::FORBIDDEN,
+                "The API key may not be used in this namespace",
+            )
+            .to_response();
+            Ok(req.into_response(response).map_into_right_body())
+        }
+        Some(Ok(api_key)) => {
+            req.extensions_mut().insert(api_key);
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        None => {
+            let message = match (token, certificate) {
+                (Some(_), _) => String::from("The API key is not valid"),
+                (None, Some(certificate)) => format!(
+                    "The client certificate {} is not mapped to an API key",
+                    certificate.subject
+                ),
+                (None, None) => String::from(
+                    "An API key is required, as a bearer token in the Authorization header",
+                ),
+            };
+            let mut response = ApiError::new(StatusCode::UNAUTHORIZED, message).to_response();
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+// Counts a request against its key's rate limit, returning the key to hand on to the handlers
+fn take_request(api_key: &mut ApiKey) -> Result<ApiKey, RateLimitError> {
+    api_key.limiter.take_request()?;
+    Ok(api_key.clone())
+}
+
+// Checks the key a client of one of the other listeners presented, counting it as a request
+// against the key's rate limit, and works out the namespace the client works in: the one it asked
+// for, or else its key's. Without API keys every client is let in, with a key or without.
+pub async fn authenticate_key(
+    data: &AppState,
+    key: Option<&str>,
+    namespace: Option<&str>,
+) -> Result<Identity, ApiError> {
+    let api_key = match data.requires_api_keys() {
+        true => Some(find_key(data, key).await?),
+        false => None,
+    };
+    let name = match (namespace, &api_key) {
+        (Some(name), _) => name,
+        (None, Some(api_key)) => &api_key.namespace,
+        (None, None) => DEFAULT_NAMESPACE,
+    };
+    let namespace = match data.get_namespaces().lock().await.get(name) {
+        Some(n) => n.clone(),
+        None => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("No namespace named {} was found", name),
+            ))
+        }
+    };
+    // keys only work in their own namespace, apart from global admin keys
+    if let Some(api_key) = &api_key {
+        if !api_key.is_global_admin() && api_key.namespace != namespace.name {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "The API key may not be used in this namespace",
+            ));
+        }
+    }
+    Ok(Identity::new(api_key, namespace))
+}
+
+async fn find_key(data: &AppState, key: Option<&str>) -> Result<ApiKey, ApiError> {
+    let key = match key {
+        Some(k) if !k.is_empty() => k,
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "An API key is required",
+            ))
+        }
+    };
+    let mut api_keys = data.get_api_keys().lock().await;
+    match api_keys.get_mut(&hash_key(key)) {
+        None => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "The API key is not valid",
+        )),
+        Some(api_key) => take_request(api_key).map_err(|e| e.to_api_error("the API key")),
+    }
+}
+
+// Only lets requests made with an admin key through
+pub async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if !may_administer(&req, |k| k.admin) {
+        let response =
+            ApiError::new(StatusCode::FORBIDDEN, "An admin API key is required").to_response();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+// Only lets requests made with an admin key of the default namespace through
+pub async fn require_global_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if !may_administer(&req, ApiKey::is_global_admin) {
+        let response = ApiError::new(
+            StatusCode::FORBIDDEN,
+            "An admin API key of the default namespace is required",
+        )
+        .to_response();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+// Whether a request may use an admin route. Without API keys the REST API is open, and the admin
+// routes are no exception, so every request is let through.
+fn may_administer(req: &ServiceRequest, is_admin: impl Fn(&ApiKey) -> bool) -> bool {
+    let requires_api_keys = req
+        .app_data::<web::Data<AppState>>()
+        .is_none_or(|data| data.requires_api_keys());
+    match req.extensions().get::<ApiKey>() {
+        Some(api_key) => is_admin(api_key),
+        None => !requires_api_keys,
+    }
+}
+
+// Checks that the key a request was made with may act on a queue or exchange. Without API keys
+// every request is let through.
+pub fn authorize(
+    req: &HttpRequest,
+    resource_type: ResourceType,
+    id: &str,
+    permission: Permission,
+) -> Result<(), ApiError> {
+    check_permission(
+        req.extensions().get::<ApiKey>(),
+        resource_type,
+        id,
+        permission,
+    )
+}
+
+// Checks that a key may act on a queue or exchange, letting everything through without a key
+pub fn check_permission(
+    api_key: Option<&ApiKey>,
+    resource_type: ResourceType,
+    id: &str,
+    permission: Permission,
+) -> Result<(), ApiError> {
+    match api_key {
+        Some(api_key) if !api_key.permits(resource_type, id, permission) => Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!(
+                "The API key may not {} the {} {}",
+                permission, resource_type, id
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// The key a request was made with, if API keys are required
+pub fn get_api_key(req: &HttpRequest) -> Option<ApiKey> {
+    req.extensions().get::<ApiKey>().cloned()
+}
+
+// Keys are made in, and only work in, the namespace of the request
+pub async fn new_api_key(
+    data: web::Data<AppState>,
+    post_data: web::Json<NewApiKeyRequest>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if post_data.name.is_empty() {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "An API key must have a name",
+        ));
+    }
+    let key = generate_key();
+    let mut api_key = ApiKey::new(
+        &post_data.name,
+        post_data.admin,
+        post_data.grants.to_owned(),
+        &get_namespace(&req).name,
+    );
+    api_key.certificate_subject = post_data.certificate_subject.to_owned();
+    api_key.limiter = Limiter::new(post_data.rate_limit);
+    let response = NewApiKeyResponse {
+        id: api_key.id.to_string(),
+        key: key.to_owned(),
+    };
+    let mut api_keys = data.get_api_keys().lock().await;
+    if let Some(subject) = &api_key.certificate_subject {
+        if api_keys
+            .values()
+            .any(|k| k.certificate_subject.as_ref() == Some(subject))
+        {
+            return HttpResponse::Conflict().json(JsonResponse::new(
+                None::<String>,
+                format!(
+                    "The certificate subject {} is already mapped to an API key",
+                    subject
+                ),
+            ));
+        }
+    }
+    let detail = Some(api_key.name.to_owned());
+    api_keys.insert(hash_key(&key), api_key);
+    drop(api_keys);
+    audit(
+        &data,
+        &req,
+        AuditAction::ApiKeyCreated,
+        &response.id,
+        detail,
+    )
+    .await;
+    HttpResponse::Accepted().json(JsonResponse::new(response, None::<String>))
+}
+
+pub async fn list_api_keys(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let namespace = get_namespace(&req);
+    let api_keys = data.get_api_keys().lock().await;
+    let entries = api_keys
+        .values()
+        .filter(|k| k.namespace == namespace.name)
+        .map(ApiKeyEntry::new)
+        .collect::<Vec<ApiKeyEntry>>();
+    HttpResponse::Accepted().json(JsonResponse::new(entries, None::<String>))
+}
+
+pub async fn delete_api_key(
+    data: web::Data<AppState>,
+    post_data: web::Json<DeleteApiKeyRequest>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let namespace = get_namespace(&req);
+    let mut api_keys = data.get_api_keys().lock().await;
+    let hash = api_keys
+        .iter()
+        .find(|(_, k)| k.id.to_string() == post_data.id && k.namespace == namespace.name)
+        .map(|(hash, _)| hash.to_owned());
+    match hash {
+        None => HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!("No API key with id {} was found", post_data.id),
+        )),
+        Some(hash) => {
+            let detail = api_keys.remove(&hash).map(|k| k.name);
+            drop(api_keys);
+            audit(
+                &data,
+                &req,
+                AuditAction::ApiKeyDeleted,
+                &post_data.id,
+                detail,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(
+                format!("Successfully deleted API key {}", post_data.id),
+                None::<String>,
+            ))
+        }
+    }
+}
+
+// Replaces the grants of an API key, taking effect on its next request
+pub async fn set_grants(
+    data: web::Data<AppState>,
+    post_data: web::Json<SetGrantsRequest>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let namespace = get_namespace(&req);
+    let mut api_keys = data.get_api_keys().lock().await;
+    match api_keys
+        .values_mut()
+        .find(|k| k.id.to_string() == post_data.id && k.namespace == namespace.name)
+    {
+        None => HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!("No API key with id {} was found", post_data.id),
+        )),
+        Some(api_key) => {
+            api_key.grants = post_data.grants.to_owned();
+            let entry = ApiKeyEntry::new(api_key);
+            drop(api_keys);
+            let detail = serde_json::to_string(&entry.grants).ok();
+            audit(
+                &data,
+                &req,
+                AuditAction::GrantsChanged,
+                &post_data.id,
+                detail,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(entry, None::<String>))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn with_key(namespace: &str) -> AppState {
+        let mut data = AppState::for_tests();
+        data.require_api_keys = true;
+        data.get_namespaces()
+            .lock()
+            .await
+            .insert(String::from("orders"), Namespace::new("orders", None, None));
+        data.get_api_keys().lock().await.insert(
+            hash_key("key"),
+            ApiKey::new("service", false, vec![], namespace),
+        );
+        data
+    }
+
+    #[actix_web::test]
+    async fn listener_clients_work_in_their_key_namespace_unless_they_pick_one() {
+        let data = with_key("orders").await;
+        let identity = authenticate_key(&data, Some("key"), None).await.unwrap();
+        assert_eq!(identity.namespace.name, "orders");
+        let identity = authenticate_key(&data, Some("key"), Some("orders"))
+            .await
+            .unwrap();
+        assert_eq!(identity.namespace.name, "orders");
+    }
+
+    #[actix_web::test]
+    async fn keys_only_work_in_their_own_namespace() {
+        let data = with_key("orders").await;
+        let error = authenticate_key(&data, Some("key"), Some(DEFAULT_NAMESPACE))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.get_status(), StatusCode::FORBIDDEN);
+        let error = authenticate_key(&data, Some("key"), Some("missing"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.get_status(), StatusCode::NOT_FOUND);
+        let error = authenticate_key(&data, Some("other"), None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.get_status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn without_api_keys_any_namespace_may_be_picked() {
+        let mut data = with_key("orders").await;
+        data.require_api_keys = false;
+        let identity = authenticate_key(&data, None, None).await.unwrap();
+        assert_eq!(identity.namespace.name, DEFAULT_NAMESPACE);
+        let identity = authenticate_key(&data, None, Some("orders")).await.unwrap();
+        assert_eq!(identity.namespace.qualify("a").unwrap(), "orders/a");
+        assert!(identity.namespace.qualify("other/a").is_err());
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_are_open_only_without_api_keys() {
+        let request = |data: &web::Data<AppState>, api_key: Option<ApiKey>| {
+            let req = actix_web::test::TestRequest::default()
+                .app_data(data.clone())
+                .to_srv_request();
+            if let Some(api_key) = api_key {
+                req.extensions_mut().insert(api_key);
+            }
+            req
+        };
+        let service = ApiKey::new("service", false, vec![], DEFAULT_NAMESPACE);
+        let admin = ApiKey::new("admin", true, vec![], "orders");
+        let global_admin = ApiKey::new("admin", true, vec![], DEFAULT_NAMESPACE);
+
+        let data = web::Data::new(with_key(DEFAULT_NAMESPACE).await);
+        let req = request(&data, None);
+        assert!(!may_administer(&req, |k| k.admin));
+        assert!(!may_administer(&req, ApiKey::is_global_admin));
+        let req = request(&data, Some(service));
+        assert!(!may_administer(&req, |k| k.admin));
+        let req = request(&data, Some(admin));
+        assert!(may_administer(&req, |k| k.admin));
+        assert!(!may_administer(&req, ApiKey::is_global_admin));
+        let req = request(&data, Some(global_admin));
+        assert!(may_administer(&req, ApiKey::is_global_admin));
+
+        let mut open = AppState::for_tests();
+        open.require_api_keys = false;
+        let req = request(&web::Data::new(open), None);
+        assert!(may_administer(&req, |k| k.admin));
+        assert!(may_administer(&req, ApiKey::is_global_admin));
+    }
+}
@@ -0,0 +1,81 @@
+use chrono::{DateTime, Utc};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use uuid::Uuid;
+
+use super::grant::{Grant, Permission, ResourceType};
+use crate::namespace_api::namespace::{local_id, DEFAULT_NAMESPACE};
+use crate::rate_limit::{Limiter, RateLimit};
+
+// what every generated key starts with, so leaked keys are easy to spot
+const KEY_PREFIX: &str = "rqs_";
+
+// the random bytes in a generated key
+const KEY_BYTES: usize = 32;
+
+// A key clients authenticate with. Only a hash of the key itself is kept.
+#[derive(Clone)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub admin: bool, // admin keys can manage other keys, and may do anything else
+    pub grants: Vec<Grant>,
+    pub namespace: String, // keys only work in the namespace they were made in
+    pub certificate_subject: Option<String>, // clients with this certificate subject use the key
+    pub limiter: Limiter,  // how many requests and messages the key may send a second
+    pub created: DateTime<Utc>,
+}
+
+impl ApiKey {
+    pub fn new(name: &str, admin: bool, grants: Vec<Grant>, namespace: &str) -> Self {
+        ApiKey {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            admin,
+            grants,
+            namespace: namespace.to_owned(),
+            certificate_subject: None,
+            limiter: Limiter::new(RateLimit::default()),
+            created: Utc::now(),
+        }
+    }
+
+    // Admin keys in the default namespace may act in every namespace, and manage the namespaces
+    pub fn is_global_admin(&self) -> bool {
+        self.admin && self.namespace == DEFAULT_NAMESPACE
+    }
+
+    // Takes the id a queue or exchange is stored under, and grants are matched against the id
+    // it has in the key's namespace
+    pub fn permits(&self, resource_type: ResourceType, id: &str, permission: Permission) -> bool {
+        if self.is_global_admin() {
+            return true;
+        }
+        let id = match local_id(&self.namespace, id) {
+            Some(id) => id,
+            None => return false,
+        };
+        self.admin
+            || self
+                .grants
+                .iter()
+                .any(|g| g.permits(resource_type, id, permission))
+    }
+}
+
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; KEY_BYTES];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the system random number generator failed");
+    format!("{}{}", KEY_PREFIX, to_hex(&bytes))
+}
+
+// Keys are long and random, so a plain SHA-256 is enough to keep them from being recovered
+pub fn hash_key(key: &str) -> String {
+    to_hex(digest(&SHA256, key.as_bytes()).as_ref())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[allow(clippy::upper_case_acronyms)] // the variant names are part of the api
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    PUBLISH, // Publish adds messages to queues, or routes them through exchanges
+    CONSUME, // Consume reads messages from queues, and subscribes to them
+    DELETE,  // Delete removes messages from queues once read
+    MANAGE,  // Manage declares queues and exchanges, and binds to exchanges
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::PUBLISH => write!(f, "publish to"),
+            Permission::CONSUME => write!(f, "consume from"),
+            Permission::DELETE => write!(f, "delete from"),
+            Permission::MANAGE => write!(f, "manage"),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)] // the variant names are part of the api
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ResourceType {
+    QUEUE,
+    EXCHANGE,
+}
+
+impl fmt::Display for ResourceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceType::QUEUE => write!(f, "queue"),
+            ResourceType::EXCHANGE => write!(f, "exchange"),
+        }
+    }
+}
+
+// Permissions on the queues or exchanges whose ids match a pattern, where `*` matches any run of
+// characters
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Grant {
+    pub resource_type: ResourceType,
+    pub pattern: String,
+    pub permissions: Vec<Permission>,
+}
+
+impl Grant {
+    pub fn permits(&self, resource_type: ResourceType, id: &str, permission: Permission) -> bool {
+        self.resource_type == resource_type
+            && self.permissions.contains(&permission)
+            && matches_pattern(&self.pattern, id)
+    }
+}
+
+fn matches_pattern(pattern: &str, id: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // the first part is anchored to the start, and without a `*` it must be the whole id
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match id.strip_prefix(first) {
+        Some(r) => r,
+        None => return false,
+    };
+    let parts = parts.collect::<Vec<&str>>();
+    let last = match parts.split_last() {
+        None => return rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            last
+        }
+    };
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_without_a_star_match_only_the_whole_id() {
+        assert!(matches_pattern("orders", "orders"));
+        assert!(!matches_pattern("orders", "orders-eu"));
+        assert!(!matches_pattern("orders", "eu-orders"));
+        assert!(!matches_pattern("orders", ""));
+    }
+
+    #[test]
+    fn stars_match_any_run_of_characters() {
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("orders-*", "orders-eu"));
+        assert!(matches_pattern("orders-*", "orders-"));
+        assert!(!matches_pattern("orders-*", "orders"));
+        assert!(matches_pattern("*-dlq", "orders-dlq"));
+        assert!(!matches_pattern("*-dlq", "orders-dlq-2"));
+        assert!(matches_pattern("orders-*-dlq", "orders-eu-dlq"));
+        assert!(matches_pattern("a*b*c", "a-b-b-c"));
+        assert!(!matches_pattern("a*b*c", "a-c-b"));
+    }
+
+    #[test]
+    fn parts_between_stars_may_not_overlap() {
+        assert!(!matches_pattern("ab*ba", "aba"));
+        assert!(matches_pattern("ab*ba", "abba"));
+        assert!(!matches_pattern("a*aa*a", "aaa"));
+        assert!(matches_pattern("a*aa*a", "aaaa"));
+    }
+
+    #[test]
+    fn grants_need_the_type_and_permission_as_well() {
+        let grant = Grant {
+            resource_type: ResourceType::QUEUE,
+            pattern: String::from("orders-*"),
+            permissions: vec![Permission::CONSUME],
+        };
+        assert!(grant.permits(ResourceType::QUEUE, "orders-eu", Permission::CONSUME));
+        assert!(!grant.permits(ResourceType::QUEUE, "orders-eu", Permission::DELETE));
+        assert!(!grant.permits(ResourceType::EXCHANGE, "orders-eu", Permission::CONSUME));
+        assert!(!grant.permits(ResourceType::QUEUE, "invoices", Permission::CONSUME));
+    }
+}
@@ -0,0 +1,220 @@
+use std::future::Future;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest};
+use uuid::Uuid;
+
+use super::api_key::ApiKey;
+use super::grant::{Permission, ResourceType};
+use super::{check_permission, get_api_key};
+use crate::app_types::{ApiError, AppState};
+use crate::audit_api::audit_as;
+use crate::audit_api::audit_log::AuditAction;
+use crate::audit_api::RequestId;
+use crate::namespace_api::get_namespace;
+use crate::namespace_api::namespace::{Namespace, DEFAULT_NAMESPACE};
+use crate::rate_limit::{admit_messages_as, give_back_messages_as};
+
+// Who the client of a connection that outlives its first request is, and the namespace it works
+// in. The key is looked up again for everything the client does, so deleting it or changing its
+// grants applies to connections that are already open.
+#[derive(Clone)]
+pub struct Identity {
+    api_key: Option<ApiKey>, // None when API keys are not required
+    pub namespace: Namespace,
+    request_id: String, // what the client's audit records are matched up by
+}
+
+impl Identity {
+    // A client of one of the other listeners, whose audit records share an id for the connection,
+    // or the call over gRPC
+    pub fn new(api_key: Option<ApiKey>, namespace: Namespace) -> Self {
+        Identity {
+            api_key,
+            namespace,
+            request_id: Uuid::new_v4().to_string(),
+        }
+    }
+
+    // Who made a REST request, for connections it opens, whose audit records keep its request id
+    pub fn of(req: &HttpRequest) -> Self {
+        let mut identity = Identity::new(get_api_key(req), get_namespace(req));
+        if let Some(RequestId(id)) = req.extensions().get::<RequestId>() {
+            identity.request_id = id.to_owned();
+        }
+        identity
+    }
+
+    // The key as it is now, or None without API keys
+    pub async fn current_key(&self, data: &AppState) -> Result<Option<ApiKey>, ApiError> {
+        let key_id = match &self.api_key {
+            None => return Ok(None),
+            Some(k) => k.id,
+        };
+        let api_keys = data.get_api_keys().lock().await;
+        match api_keys.values().find(|k| k.id == key_id) {
+            Some(api_key) => Ok(Some(api_key.clone())),
+            None => Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "The API key has been deleted",
+            )),
+        }
+    }
+
+    // Checks that the client may act on a queue or exchange
+    pub async fn authorize(
+        &self,
+        data: &AppState,
+        resource_type: ResourceType,
+        id: &str,
+        permission: Permission,
+    ) -> Result<(), ApiError> {
+        let api_key = self.current_key(data).await?;
+        check_permission(api_key.as_ref(), resource_type, id, permission)
+    }
+
+    // Publishes messages the client sends once they are counted against its key's rate limit and
+    // its namespace's daily quota. If the publish fails they are given back, so messages the target
+    // refuses use up neither.
+    pub async fn publish_admitted<T>(
+        &self,
+        data: &AppState,
+        count: usize,
+        publish: impl Future<Output = Result<T, ApiError>>,
+    ) -> Result<T, ApiError> {
+        let (api_key, namespace) = (self.api_key.as_ref(), &self.namespace.name);
+        admit_messages_as(data, api_key, namespace, count).await?;
+        let published = publish.await;
+        if published.is_err() {
+            give_back_messages_as(data, api_key, namespace, count).await;
+        }
+        published
+    }
+
+    // Records what the client did to a queue or exchange, by its stored id
+    pub async fn audit(
+        &self,
+        data: &AppState,
+        action: AuditAction,
+        id: &str,
+        detail: Option<String>,
+    ) {
+        let api_key = self.api_key.as_ref();
+        audit_as(
+            data,
+            &self.namespace,
+            api_key,
+            &self.request_id,
+            action,
+            id,
+            detail,
+        )
+        .await;
+    }
+}
+
+// Clients without a key work in the default namespace until they pick another
+impl Default for Identity {
+    fn default() -> Self {
+        Identity::new(None, Namespace::new(DEFAULT_NAMESPACE, None, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_api::api_key::hash_key;
+    use crate::auth_api::grant::Grant;
+    use crate::message_api::publish_messages;
+    use crate::message_api::request::{NewMessage, NewMessageRequest};
+
+    #[actix_web::test]
+    async fn grants_are_looked_up_again_for_every_operation() {
+        let data = AppState::for_tests();
+        let grant = Grant {
+            resource_type: ResourceType::QUEUE,
+            pattern: String::from("orders"),
+            permissions: vec![Permission::CONSUME],
+        };
+        let api_key = ApiKey::new("consumer", false, vec![grant], DEFAULT_NAMESPACE);
+        data.get_api_keys()
+            .lock()
+            .await
+            .insert(hash_key("key"), api_key.clone());
+        let identity = Identity::new(Some(api_key), Namespace::new(DEFAULT_NAMESPACE, None, None));
+
+        let consume = identity
+            .authorize(&data, ResourceType::QUEUE, "orders", Permission::CONSUME)
+            .await;
+        assert!(consume.is_ok());
+        let delete = identity
+            .authorize(&data, ResourceType::QUEUE, "orders", Permission::DELETE)
+            .await;
+        assert_eq!(delete.unwrap_err().get_status(), StatusCode::FORBIDDEN);
+
+        data.get_api_keys().lock().await.clear();
+        let consume = identity
+            .authorize(&data, ResourceType::QUEUE, "orders", Permission::CONSUME)
+            .await;
+        assert_eq!(consume.unwrap_err().get_status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn messages_that_fail_to_publish_use_none_of_the_quota() {
+        let data = AppState::for_tests();
+        data.get_namespaces()
+            .lock()
+            .await
+            .get_mut(DEFAULT_NAMESPACE)
+            .unwrap()
+            .max_messages_per_day = Some(1);
+        let identity = Identity::new(None, Namespace::new(DEFAULT_NAMESPACE, None, None));
+
+        let request = NewMessageRequest {
+            queue_id: String::from("missing"),
+            messages: vec![NewMessage {
+                message_id: String::from("m"),
+                content: String::from("content"),
+                reply_to: None,
+                correlation_id: None,
+                attributes: Default::default(),
+            }],
+        };
+        let published = identity
+            .publish_admitted(&data, 1, publish_messages(&data, &request))
+            .await;
+        assert_eq!(published.unwrap_err().get_status(), StatusCode::BAD_REQUEST);
+        let namespaces = data.get_namespaces().lock().await;
+        assert_eq!(namespaces[DEFAULT_NAMESPACE].get_messages_today(), 0);
+    }
+
+    #[actix_web::test]
+    async fn audit_records_share_the_connection_request_id() {
+        let data = AppState::for_tests();
+        let api_key = ApiKey::new("service", true, vec![], "tenant");
+        let identity = Identity::new(Some(api_key), Namespace::new("tenant", None, None));
+        identity
+            .audit(&data, AuditAction::QueueCreated, "tenant/orders", None)
+            .await;
+        identity
+            .audit(&data, AuditAction::ExchangeCreated, "tenant/events", None)
+            .await;
+
+        let audit_log = data.get_audit_log().lock().await;
+        let records = audit_log.get_records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].namespace, "tenant");
+        assert_eq!(records[0].resource_id, "orders");
+        assert_eq!(records[0].api_key.as_ref().unwrap().1, "service");
+        assert_eq!(records[0].request_id, records[1].request_id);
+    }
+
+    #[actix_web::test]
+    async fn everything_is_allowed_without_api_keys() {
+        let data = AppState::for_tests();
+        let allowed = Identity::default()
+            .authorize(&data, ResourceType::EXCHANGE, "events", Permission::MANAGE)
+            .await;
+        assert!(allowed.is_ok());
+    }
+}
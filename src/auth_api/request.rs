@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+use super::api_key::ApiKey;
+use super::grant::Grant;
+use crate::rate_limit::RateLimit;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+    pub certificate_subject: Option<String>,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetGrantsRequest {
+    pub id: String,
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteApiKeyRequest {
+    pub id: String,
+}
+
+// The key itself is only ever returned here, when it is created
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewApiKeyResponse {
+    pub id: String,
+    pub key: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyEntry {
+    pub id: String,
+    pub name: String,
+    pub admin: bool,
+    pub grants: Vec<Grant>,
+    pub namespace: String,
+    pub certificate_subject: Option<String>,
+    pub rate_limit: RateLimit,
+    pub created: String, // RFC 3339
+}
+
+impl ApiKeyEntry {
+    pub fn new(api_key: &ApiKey) -> Self {
+        ApiKeyEntry {
+            id: api_key.id.to_string(),
+            name: api_key.name.to_owned(),
+            admin: api_key.admin,
+            grants: api_key.grants.to_owned(),
+            namespace: api_key.namespace.to_owned(),
+            certificate_subject: api_key.certificate_subject.to_owned(),
+            rate_limit: api_key.limiter.get_limit(),
+            created: api_key.created.to_rfc3339(),
+        }
+    }
+}
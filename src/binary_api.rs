@@ -0,0 +1,24 @@
+use std::net::SocketAddr;
+
+use actix_web::rt::net::TcpListener;
+use actix_web::web;
+
+use crate::app_types::AppState;
+
+mod connection;
+mod frame;
+
+// Serves the binary protocol, sharing the REST API's state
+pub async fn serve(data: web::Data<AppState>, address: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        // a failed accept only affects the client being accepted
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(_) => continue,
+        };
+        // requests are small and answered at once, so they are not held back to fill packets
+        let _ = socket.set_nodelay(true);
+        actix_web::rt::spawn(connection::run(data.clone(), socket));
+    }
+}
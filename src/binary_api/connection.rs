@@ -0,0 +1,312 @@
+use actix_web::rt::net::TcpStream;
+use actix_web::web;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+
+use actix_web::http::StatusCode;
+
+use crate::app_types::{ApiError, AppState};
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::authenticate_key;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::identity::Identity;
+use crate::message_api::request::{
+    GetMessageRequest, GetMessageResponse, NewMessage, NewMessageRequest,
+};
+use crate::message_api::{publish_messages, receive_messages_up_to, remove_messages};
+
+use super::frame::{self, read_frame, Decoder, Encoder, Frame, FrameError};
+
+impl From<FrameError> for ApiError {
+    fn from(e: FrameError) -> Self {
+        ApiError::bad_request(e.to_string())
+    }
+}
+
+// Serves one client until it closes the connection or sends a frame that cannot be read.
+// Requests are answered in the order they were sent.
+pub async fn run(data: web::Data<AppState>, socket: TcpStream) {
+    let (reader, writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let hello = match read_frame(&mut reader).await {
+        Ok(f) => f,
+        Err(_) => return,
+    };
+    let response = match hello_ok(&hello) {
+        Ok(payload) => Frame::new(frame::HELLO_OK, hello.request_id, payload),
+        Err(e) => error_frame(hello.request_id, &e),
+    };
+    let accepted = response.opcode == frame::HELLO_OK;
+    if writer.write_all(&response.to_bytes()).await.is_err() || writer.flush().await.is_err() {
+        return;
+    }
+    if !accepted {
+        return;
+    }
+
+    // with API keys, only PING is answered until an AUTH has been accepted
+    let mut identity = match data.requires_api_keys() {
+        true => None,
+        false => Some(Identity::default()),
+    };
+    loop {
+        let response = match read_frame(&mut reader).await {
+            Ok(request) => handle_request(&data, &mut identity, request).await,
+            Err(FrameError::Closed) => break,
+            // the frame boundaries are lost, so the connection cannot go on
+            Err(e) => {
+                let _ = writer
+                    .write_all(&error_frame(0, &ApiError::from(e)).to_bytes())
+                    .await;
+                break;
+            }
+        };
+        if writer.write_all(&response.to_bytes()).await.is_err() {
+            return;
+        }
+        // answers are held back while more requests are already waiting, so a pipelined batch
+        // of requests is answered with as few writes as possible
+        if reader.buffer().is_empty() && writer.flush().await.is_err() {
+            return;
+        }
+    }
+    let _ = writer.flush().await;
+}
+
+fn hello_ok(hello: &Frame) -> Result<Vec<u8>, ApiError> {
+    if hello.opcode != frame::HELLO {
+        return Err(ApiError::bad_request("The first frame must be a HELLO"));
+    }
+    let version = Decoder::new(&hello.payload).byte()?;
+    if version != frame::VERSION {
+        return Err(ApiError::bad_request(format!(
+            "The protocol version {} is not supported, only version {} is",
+            version,
+            frame::VERSION
+        )));
+    }
+    Ok(Encoder::default()
+        .byte(frame::VERSION)
+        .long(frame::MAX_FRAME_SIZE as u32)
+        .into_bytes()?)
+}
+
+async fn handle_request(data: &AppState, identity: &mut Option<Identity>, request: Frame) -> Frame {
+    let result = match (request.opcode, &*identity) {
+        (frame::AUTH, _) => authenticate(data, identity, &request.payload).await,
+        (frame::PING, _) => Ok((frame::PONG, vec![])),
+        (_, None) => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "An API key is required, in an AUTH frame",
+        )),
+        (frame::PUBLISH, Some(identity)) => publish(data, identity, &request.payload).await,
+        (frame::RECEIVE, Some(identity)) => receive(data, identity, &request.payload).await,
+        (frame::DELETE, Some(identity)) => delete(data, identity, &request.payload).await,
+        (frame::HELLO, _) => Err(ApiError::bad_request("HELLO may only be sent once")),
+        (opcode, _) => Err(ApiError::bad_request(format!(
+            "The opcode {} is unknown",
+            opcode
+        ))),
+    };
+    match result {
+        Ok((opcode, payload)) => Frame::new(opcode, request.request_id, payload),
+        Err(e) => error_frame(request.request_id, &e),
+    }
+}
+
+// Checks the API key a client gives, and the namespace it picks, if any. A failed AUTH leaves the
+// connection as it was.
+async fn authenticate(
+    data: &AppState,
+    identity: &mut Option<Identity>,
+    payload: &[u8],
+) -> Result<(u8, Vec<u8>), ApiError> {
+    let mut d = Decoder::new(payload);
+    let key = d.string()?;
+    let namespace = d.optional_string()?;
+    *identity = Some(authenticate_key(data, Some(&key), namespace.as_deref()).await?);
+    Ok((frame::AUTH_OK, vec![]))
+}
+
+// Adds a batch of messages to a queue, answering with their uuids
+async fn publish(
+    data: &AppState,
+    identity: &Identity,
+    payload: &[u8],
+) -> Result<(u8, Vec<u8>), ApiError> {
+    let mut d = Decoder::new(payload);
+    let queue_id = identity.namespace.qualify(&d.string()?)?;
+    identity
+        .authorize(data, ResourceType::QUEUE, &queue_id, Permission::PUBLISH)
+        .await?;
+    let count = d.short()?;
+    let mut messages = vec![];
+    for _ in 0..count {
+        messages.push(NewMessage {
+            message_id: d.string()?,
+            reply_to: d.optional_string()?,
+            correlation_id: d.optional_string()?,
+            attributes: d.attributes()?,
+            content: d.content()?,
+        });
+    }
+    let request = NewMessageRequest { messages, queue_id };
+    let uuids = identity
+        .publish_admitted(data, count as usize, publish_messages(data, &request))
+        .await?;
+    let detail = Some(uuids.join(", "));
+    identity
+        .audit(
+            data,
+            AuditAction::MessagesPublished,
+            &request.queue_id,
+            detail,
+        )
+        .await;
+    let mut encoder = Encoder::default().count(uuids.len());
+    for uuid in uuids.iter() {
+        encoder = encoder.string(uuid);
+    }
+    Ok((frame::PUBLISHED, encoder.into_bytes()?))
+}
+
+// Reads a batch of messages from a queue, hiding them for the read timeout
+async fn receive(
+    data: &AppState,
+    identity: &Identity,
+    payload: &[u8],
+) -> Result<(u8, Vec<u8>), ApiError> {
+    let mut d = Decoder::new(payload);
+    let request = GetMessageRequest {
+        queue_id: identity.namespace.qualify(&d.string()?)?,
+        consumer_token: d.optional_string()?,
+    };
+    identity
+        .authorize(
+            data,
+            ResourceType::QUEUE,
+            &request.queue_id,
+            Permission::CONSUME,
+        )
+        .await?;
+    // a limit of 0 leaves it to the queue's max batch, though no more than a count can hold
+    let limit = match d.short()? {
+        0 => u16::MAX as usize,
+        n => n as usize,
+    };
+    let messages = receive_messages_up_to(data, &request, limit).await?;
+    // the answer holds as many messages as fit in a frame, and the rest are made visible again
+    // straight away. A message that cannot be written at all fails the request once it is first.
+    let mut encoded = vec![];
+    let mut size = 2; // the count
+    let mut result = Ok(());
+    for message in messages.iter() {
+        match encode_message(message) {
+            Ok(bytes) if size + bytes.len() <= frame::MAX_PAYLOAD_SIZE => {
+                size += bytes.len();
+                encoded.push(bytes);
+            }
+            _ if !encoded.is_empty() => break,
+            Ok(_) => {
+                result = Err(FrameError::TooLarge(frame::MAX_FRAME_SIZE));
+                break;
+            }
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    let sent = match result {
+        Ok(()) => encoded.len(),
+        Err(_) => 0,
+    };
+    release(data, &request.queue_id, &messages[sent..]).await;
+    if let Err(e) = result {
+        let uuid = &messages[0].uuid;
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("The message with uuid {} cannot be sent: {}", uuid, e),
+        ));
+    }
+    let mut encoder = Encoder::default().count(encoded.len());
+    for bytes in encoded.iter() {
+        encoder = encoder.append(bytes);
+    }
+    Ok((frame::MESSAGES, encoder.into_bytes()?))
+}
+
+fn encode_message(message: &GetMessageResponse) -> Result<Vec<u8>, FrameError> {
+    Encoder::default()
+        .string(&message.uuid)
+        .string(&message.message_id)
+        .optional_string(&message.reply_to)
+        .optional_string(&message.correlation_id)
+        .attributes(&message.attributes)
+        .content(&message.content)
+        .into_bytes()
+}
+
+// Makes messages that were read but not sent visible again
+async fn release(data: &AppState, queue_id: &String, messages: &[GetMessageResponse]) {
+    if messages.is_empty() {
+        return;
+    }
+    if let Some(queue) = data.get_queues().lock().await.get_mut(queue_id) {
+        for message in messages.iter() {
+            queue.release(&message.uuid);
+        }
+    }
+}
+
+// Deletes a batch of messages from a queue, answering with whether each one was deleted
+async fn delete(
+    data: &AppState,
+    identity: &Identity,
+    payload: &[u8],
+) -> Result<(u8, Vec<u8>), ApiError> {
+    let mut d = Decoder::new(payload);
+    let queue_id = identity.namespace.qualify(&d.string()?)?;
+    identity
+        .authorize(data, ResourceType::QUEUE, &queue_id, Permission::DELETE)
+        .await?;
+    let consumer_token = d.optional_string()?;
+    let count = d.short()?;
+    let mut uuids = vec![];
+    for _ in 0..count {
+        uuids.push(d.string()?);
+    }
+    let deleted = remove_messages(data, &queue_id, &uuids, &consumer_token).await?;
+    let removed = uuids
+        .iter()
+        .zip(deleted.iter())
+        .filter(|(_, d)| **d)
+        .map(|(uuid, _)| uuid.as_str())
+        .collect::<Vec<&str>>();
+    if !removed.is_empty() {
+        let detail = Some(removed.join(", "));
+        identity
+            .audit(data, AuditAction::MessagesDeleted, &queue_id, detail)
+            .await;
+    }
+    let mut encoder = Encoder::default().count(deleted.len());
+    for d in deleted {
+        encoder = encoder.byte(d as u8);
+    }
+    Ok((frame::DELETED, encoder.into_bytes()?))
+}
+
+fn error_frame(request_id: u32, e: &ApiError) -> Frame {
+    let encode = |message: &str| {
+        Encoder::default()
+            .short(e.get_status().as_u16())
+            .string(message)
+            .into_bytes()
+    };
+    // a message quoting a value too long to write is replaced, rather than cut short
+    let payload = encode(e.get_message())
+        .or_else(|_| encode("The error is too long to be sent"))
+        .unwrap_or_default();
+    Frame::new(frame::ERROR, request_id, payload)
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// the only protocol version spoken
+pub const VERSION: u8 = 1;
+
+// the largest frame either side may send, its length prefix excluded
+pub const MAX_FRAME_SIZE: usize = 4_194_304;
+
+// the opcode and request id that precede every frame's payload
+const FRAME_HEADER_SIZE: usize = 5;
+
+// the largest payload a frame may carry
+pub const MAX_PAYLOAD_SIZE: usize = MAX_FRAME_SIZE - FRAME_HEADER_SIZE;
+
+// opcodes clients send
+pub const HELLO: u8 = 0x01;
+pub const PUBLISH: u8 = 0x02;
+pub const RECEIVE: u8 = 0x03;
+pub const DELETE: u8 = 0x04;
+pub const PING: u8 = 0x05;
+pub const AUTH: u8 = 0x06;
+
+// opcodes the server answers with
+pub const HELLO_OK: u8 = 0x81;
+pub const PUBLISHED: u8 = 0x82;
+pub const MESSAGES: u8 = 0x83;
+pub const DELETED: u8 = 0x84;
+pub const PONG: u8 = 0x85;
+pub const AUTH_OK: u8 = 0x86;
+pub const ERROR: u8 = 0xFF;
+
+pub enum FrameError {
+    Closed,
+    TooLarge(usize),
+    Malformed(String),
+    FieldTooLong(String), // a value too long for its length prefix
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Closed => write!(f, "The connection was closed"),
+            FrameError::TooLarge(limit) => {
+                write!(f, "The frame exceeds the limit of {} bytes", limit)
+            }
+            FrameError::Malformed(s) => write!(f, "The frame was malformed: {}", s),
+            FrameError::FieldTooLong(s) => write!(f, "The frame cannot be written: {}", s),
+        }
+    }
+}
+
+// A frame is its length, then an opcode, the id of the request it is for and the payload
+pub struct Frame {
+    pub opcode: u8,
+    pub request_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: u8, request_id: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            opcode,
+            request_id,
+            payload,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let size = (FRAME_HEADER_SIZE + self.payload.len()) as u32;
+        let mut bytes = Vec::with_capacity(4 + size as usize);
+        bytes.extend_from_slice(&size.to_be_bytes());
+        bytes.push(self.opcode);
+        bytes.extend_from_slice(&self.request_id.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame, FrameError> {
+    let size = reader.read_u32().await.map_err(|_| FrameError::Closed)? as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(MAX_FRAME_SIZE));
+    }
+    if size < FRAME_HEADER_SIZE {
+        return Err(FrameError::Malformed(String::from(
+            "the frame is too short to have an opcode and request id",
+        )));
+    }
+    let mut bytes = vec![0u8; size];
+    reader
+        .read_exact(&mut bytes)
+        .await
+        .map_err(|_| FrameError::Closed)?;
+    let request_id = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+    Ok(Frame::new(
+        bytes[0],
+        request_id,
+        bytes.split_off(FRAME_HEADER_SIZE),
+    ))
+}
+
+// Reads the fields of a payload in order
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], FrameError> {
+        if self.position + count > self.bytes.len() {
+            return Err(FrameError::Malformed(String::from(
+                "a field runs past the end of the frame",
+            )));
+        }
+        let taken = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(taken)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, FrameError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn short(&mut self) -> Result<u16, FrameError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    // strings are UTF-8, prefixed with their length as a short
+    pub fn string(&mut self) -> Result<String, FrameError> {
+        let size = self.short()? as usize;
+        String::from_utf8(self.take(size)?.to_vec())
+            .map_err(|_| FrameError::Malformed(String::from("a string is not UTF-8")))
+    }
+
+    // an empty string stands for no value
+    pub fn optional_string(&mut self) -> Result<Option<String>, FrameError> {
+        let value = self.string()?;
+        Ok(Some(value).filter(|v| !v.is_empty()))
+    }
+
+    // message content is prefixed with its length as a long, as it may be large
+    pub fn content(&mut self) -> Result<String, FrameError> {
+        let b = self.take(4)?;
+        let size = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+        String::from_utf8(self.take(size)?.to_vec())
+            .map_err(|_| FrameError::Malformed(String::from("the content is not UTF-8")))
+    }
+
+    pub fn attributes(&mut self) -> Result<HashMap<String, String>, FrameError> {
+        let count = self.short()?;
+        let mut attributes = HashMap::new();
+        for _ in 0..count {
+            attributes.insert(self.string()?, self.string()?);
+        }
+        Ok(attributes)
+    }
+}
+
+// Writes the fields of a payload in order. A value too long for its field is not cut short, but
+// fails the payload when it is taken with into_bytes.
+#[derive(Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+    too_long: Option<String>, // the first value that was too long for its field
+}
+
+impl Encoder {
+    pub fn byte(mut self, value: u8) -> Self {
+        self.bytes.push(value);
+        self
+    }
+
+    pub fn short(mut self, value: u16) -> Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn long(mut self, value: u32) -> Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    // counts of what follows, as of messages or uuids, are at most 65535
+    pub fn count(self, count: usize) -> Self {
+        match u16::try_from(count) {
+            Ok(count) => self.short(count),
+            Err(_) => self.fail(format!("a count of {} exceeds the limit of 65535", count)),
+        }
+    }
+
+    // strings are at most 65535 bytes
+    pub fn string(mut self, value: &str) -> Self {
+        let size = match u16::try_from(value.len()) {
+            Ok(size) => size,
+            Err(_) => {
+                return self.fail(format!(
+                    "a string of {} bytes exceeds the limit of 65535",
+                    value.len()
+                ))
+            }
+        };
+        self.bytes.extend_from_slice(&size.to_be_bytes());
+        self.bytes.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    pub fn optional_string(self, value: &Option<String>) -> Self {
+        self.string(value.as_deref().unwrap_or_default())
+    }
+
+    pub fn content(mut self, value: &str) -> Self {
+        let size = match u32::try_from(value.len()) {
+            Ok(size) => size,
+            Err(_) => return self.fail(String::from("the content is too long")),
+        };
+        self.bytes.extend_from_slice(&size.to_be_bytes());
+        self.bytes.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    pub fn attributes(self, attributes: &HashMap<String, String>) -> Self {
+        let mut encoder = self.count(attributes.len());
+        for (name, value) in attributes.iter() {
+            encoder = encoder.string(name).string(value);
+        }
+        encoder
+    }
+
+    // Appends a payload encoded on its own, such as one message of a batch
+    pub fn append(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    fn fail(mut self, reason: String) -> Self {
+        self.too_long.get_or_insert(reason);
+        self
+    }
+
+    // The payload, unless a value was too long for its field or the payload for a frame
+    pub fn into_bytes(self) -> Result<Vec<u8>, FrameError> {
+        if let Some(reason) = self.too_long {
+            return Err(FrameError::FieldTooLong(reason));
+        }
+        if self.bytes.len() > MAX_PAYLOAD_SIZE {
+            return Err(FrameError::TooLarge(MAX_FRAME_SIZE));
+        }
+        Ok(self.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn frames_read_back_as_written() {
+        let bytes = Frame::new(PUBLISH, 42, vec![9, 8]).to_bytes();
+        assert_eq!(&bytes[..4], &7u32.to_be_bytes());
+        let frame = match read_frame(&mut bytes.as_slice()).await {
+            Ok(f) => f,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(frame.opcode, PUBLISH);
+        assert_eq!(frame.request_id, 42);
+        assert_eq!(frame.payload, vec![9, 8]);
+    }
+
+    #[actix_web::test]
+    async fn bad_frame_lengths_are_refused() {
+        let bytes = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        let result = read_frame(&mut &bytes[..]).await;
+        assert!(matches!(result, Err(FrameError::TooLarge(_))));
+
+        let bytes = [0, 0, 0, 4, PING, 0, 0, 0];
+        let result = read_frame(&mut &bytes[..]).await;
+        assert!(matches!(result, Err(FrameError::Malformed(_))));
+
+        let bytes = [0, 0, 0, 9, PING, 0, 0, 0, 1];
+        let result = read_frame(&mut &bytes[..]).await;
+        assert!(matches!(result, Err(FrameError::Closed)));
+    }
+
+    #[test]
+    fn fields_read_back_as_written() {
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("region"), String::from("eu"));
+        let bytes = Encoder::default()
+            .byte(1)
+            .short(500)
+            .string("orders")
+            .optional_string(&None)
+            .optional_string(&Some(String::from("replies")))
+            .content("hello")
+            .attributes(&attributes)
+            .into_bytes()
+            .ok()
+            .unwrap();
+        let mut d = Decoder::new(&bytes);
+        assert_eq!(d.byte().ok(), Some(1));
+        assert_eq!(d.short().ok(), Some(500));
+        assert_eq!(d.string().ok().as_deref(), Some("orders"));
+        assert_eq!(d.optional_string().ok(), Some(None));
+        assert_eq!(
+            d.optional_string().ok(),
+            Some(Some(String::from("replies")))
+        );
+        assert_eq!(d.content().ok().as_deref(), Some("hello"));
+        assert_eq!(d.attributes().ok(), Some(attributes));
+        assert!(d.byte().is_err());
+    }
+
+    #[test]
+    fn fields_that_run_past_the_payload_or_are_not_utf8_are_refused() {
+        let bytes = Encoder::default().short(10).into_bytes().ok().unwrap();
+        assert!(Decoder::new(&bytes).string().is_err());
+
+        let bytes = Encoder::default().long(2).into_bytes().ok().unwrap();
+        assert!(Decoder::new(&bytes).content().is_err());
+
+        let bytes = [0, 2, 0xC3, 0x28];
+        assert!(Decoder::new(&bytes).string().is_err());
+    }
+
+    #[test]
+    fn values_too_long_for_their_field_or_frame_are_refused() {
+        let value = "a".repeat(65_535);
+        let bytes = Encoder::default().string(&value).into_bytes().ok().unwrap();
+        assert_eq!(Decoder::new(&bytes).string().ok(), Some(value));
+
+        let value = "a".repeat(65_536);
+        let result = Encoder::default().string(&value).byte(1).into_bytes();
+        assert!(matches!(result, Err(FrameError::FieldTooLong(_))));
+
+        let result = Encoder::default().count(65_536).into_bytes();
+        assert!(matches!(result, Err(FrameError::FieldTooLong(_))));
+
+        let value = "a".repeat(MAX_FRAME_SIZE);
+        let result = Encoder::default().content(&value).into_bytes();
+        assert!(matches!(result, Err(FrameError::TooLarge(_))));
+    }
+}
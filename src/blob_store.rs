@@ -0,0 +1,32 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+// Stores oversized message payloads on local disk so queues only hold a reference to them.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    dir: PathBuf, // the directory blobs are written to
+}
+
+impl BlobStore {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(BlobStore { dir })
+    }
+
+    pub fn put(&self, uuid: &Uuid, content: &[u8]) -> io::Result<PathBuf> {
+        let path = self.dir.join(uuid.to_string());
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+
+    pub fn get(&self, path: &PathBuf) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    pub fn remove(&self, path: &PathBuf) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
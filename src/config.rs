@@ -0,0 +1,510 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::app_types::AppState;
+use crate::definitions_api::reconcile;
+use crate::definitions_api::request::Definitions;
+use crate::exchange_api::request::NewExchangeRequest;
+use crate::namespace_api::namespace::{Namespace, DEFAULT_NAMESPACE};
+use crate::queue_api::request::NewQueueRequest;
+
+// the port the REST API listens on
+const DEFAULT_PORT: u16 = 8080;
+
+// the max size of a request body
+const DEFAULT_MAX_REQUEST_BYTES: usize = 262_144;
+
+// the port the gRPC API listens on
+const DEFAULT_GRPC_PORT: u16 = 50051;
+
+// the exchange MQTT publishes are routed through
+const DEFAULT_MQTT_EXCHANGE: &str = "mqtt";
+
+// how many audit records are kept in memory
+const DEFAULT_AUDIT_MAX_ENTRIES: usize = 10_000;
+
+// the shortest admin key accepted
+const MIN_ADMIN_KEY_LENGTH: usize = 16;
+
+// the size of an AES-256 key
+const ENCRYPTION_KEY_BYTES: usize = 32;
+
+// Settings given on the command line, or else in the environment, which override the config
+// file's. The admin key is only taken from the environment or the file, so it stays out of the
+// process list.
+#[derive(Parser)]
+#[command(version, about = "RQS - Rust Queueing Service")]
+pub struct Args {
+    #[arg(long, env = "RQS_CONFIG", help = "A TOML file to read settings from")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "RQS_BIND", help = "The address the REST API listens on")]
+    bind: Option<IpAddr>,
+    #[arg(long, env = "RQS_PORT", help = "The port the REST API listens on")]
+    port: Option<u16>,
+    #[arg(long, env = "RQS_WORKERS", help = "How many REST API workers to run")]
+    workers: Option<usize>,
+    #[arg(
+        long,
+        env = "RQS_MAX_REQUEST_BYTES",
+        help = "The max size of a request body"
+    )]
+    max_request_bytes: Option<usize>,
+    #[arg(
+        long,
+        env = "RQS_BLOB_DIR",
+        help = "Where oversized messages are written"
+    )]
+    blob_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "RQS_ENCRYPTION_KEY_FILE",
+        help = "A file with the message key in hex"
+    )]
+    encryption_key_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "RQS_DEFINITIONS",
+        help = "A JSON definitions file to import"
+    )]
+    definitions: Option<PathBuf>,
+    #[arg(long, env = "RQS_GRPC_ENABLED", help = "Whether to start the gRPC API")]
+    grpc_enabled: Option<bool>,
+    #[arg(
+        long,
+        env = "RQS_GRPC_BIND",
+        help = "The address the gRPC API listens on"
+    )]
+    grpc_bind: Option<IpAddr>,
+    #[arg(long, env = "RQS_GRPC_PORT", help = "The port the gRPC API listens on")]
+    grpc_port: Option<u16>,
+    #[arg(
+        long,
+        env = "RQS_AMQP_BIND",
+        help = "The address the AMQP listener listens on"
+    )]
+    amqp_bind: Option<IpAddr>,
+    #[arg(
+        long,
+        env = "RQS_AMQP_PORT",
+        help = "The port to start an AMQP listener on"
+    )]
+    amqp_port: Option<u16>,
+    #[arg(
+        long,
+        env = "RQS_STOMP_BIND",
+        help = "The address the STOMP listener listens on"
+    )]
+    stomp_bind: Option<IpAddr>,
+    #[arg(
+        long,
+        env = "RQS_STOMP_PORT",
+        help = "The port to start a STOMP listener on"
+    )]
+    stomp_port: Option<u16>,
+    #[arg(
+        long,
+        env = "RQS_BINARY_BIND",
+        help = "The address the binary listener listens on"
+    )]
+    binary_bind: Option<IpAddr>,
+    #[arg(
+        long,
+        env = "RQS_BINARY_PORT",
+        help = "The port to start a binary listener on"
+    )]
+    binary_port: Option<u16>,
+    #[arg(
+        long,
+        env = "RQS_MQTT_BIND",
+        help = "The address the MQTT listener listens on"
+    )]
+    mqtt_bind: Option<IpAddr>,
+    #[arg(
+        long,
+        env = "RQS_MQTT_PORT",
+        help = "The port to start an MQTT listener on"
+    )]
+    mqtt_port: Option<u16>,
+    #[arg(
+        long,
+        env = "RQS_MQTT_EXCHANGE",
+        help = "The exchange MQTT publishes go through"
+    )]
+    mqtt_exchange: Option<String>,
+    #[arg(
+        long,
+        env = "RQS_TLS_CERT",
+        help = "A PEM certificate chain to serve HTTPS with"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "RQS_TLS_KEY",
+        help = "The PEM private key of the certificate"
+    )]
+    tls_key: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "RQS_TLS_CLIENT_CA",
+        help = "PEM CAs to verify client certificates"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "RQS_AUDIT_LOG",
+        help = "A file to append audit records to"
+    )]
+    audit_log: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "RQS_AUDIT_MAX_ENTRIES",
+        help = "How many audit records to keep"
+    )]
+    audit_max_entries: Option<usize>,
+    #[arg(long, env = "RQS_AUDIT_MESSAGES", help = "Whether to audit messages")]
+    audit_messages: Option<bool>,
+}
+
+// The server's settings, from the config file with the command line and environment on top
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub workers: Option<usize>, // one per CPU core if not given
+    pub max_request_bytes: usize,
+    pub blob_dir: PathBuf,
+    pub encryption_key_file: Option<PathBuf>, // a key is generated on start up if not given
+    pub admin_key: Option<String>,
+    pub definitions: Option<PathBuf>, // queues and exchanges to import on start up
+    pub grpc_enabled: bool,
+    pub grpc_bind: IpAddr,
+    pub grpc_port: u16,
+    pub amqp_bind: IpAddr,
+    pub amqp_port: Option<u16>, // the other listeners are only started when given a port
+    pub stomp_bind: IpAddr,
+    pub stomp_port: Option<u16>,
+    pub binary_bind: IpAddr,
+    pub binary_port: Option<u16>,
+    pub mqtt_bind: IpAddr,
+    pub mqtt_port: Option<u16>,
+    pub mqtt_exchange: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_max_entries: usize,
+    pub audit_messages: bool,
+    pub queues: Vec<NewQueueRequest>, // declared on start up, in the default namespace
+    pub exchanges: Vec<NewExchangeRequest>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            workers: None,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            blob_dir: env::temp_dir().join("rqs-blobs"),
+            encryption_key_file: None,
+            admin_key: None,
+            definitions: None,
+            grpc_enabled: true,
+            grpc_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            grpc_port: DEFAULT_GRPC_PORT,
+            amqp_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            amqp_port: None,
+            stomp_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            stomp_port: None,
+            binary_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            binary_port: None,
+            mqtt_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            mqtt_port: None,
+            mqtt_exchange: String::from(DEFAULT_MQTT_EXCHANGE),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            audit_log: None,
+            audit_max_entries: DEFAULT_AUDIT_MAX_ENTRIES,
+            audit_messages: false,
+            queues: vec![],
+            exchanges: vec![],
+        }
+    }
+}
+
+impl Config {
+    // Reads the config file, if one is given, applies the overrides and checks the result
+    pub fn load(args: Args) -> io::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                toml::from_str(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?
+            }
+            None => Config::default(),
+        };
+        config.apply(args);
+        if let Ok(admin_key) = env::var("RQS_ADMIN_KEY") {
+            config.admin_key = Some(admin_key);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, args: Args) {
+        let Args {
+            config: _,
+            bind,
+            port,
+            workers,
+            max_request_bytes,
+            blob_dir,
+            encryption_key_file,
+            definitions,
+            grpc_enabled,
+            grpc_bind,
+            grpc_port,
+            amqp_bind,
+            amqp_port,
+            stomp_bind,
+            stomp_port,
+            binary_bind,
+            binary_port,
+            mqtt_bind,
+            mqtt_port,
+            mqtt_exchange,
+            tls_cert,
+            tls_key,
+            tls_client_ca,
+            audit_log,
+            audit_max_entries,
+            audit_messages,
+        } = args;
+        self.bind = bind.unwrap_or(self.bind);
+        self.port = port.unwrap_or(self.port);
+        self.workers = workers.or(self.workers);
+        self.max_request_bytes = max_request_bytes.unwrap_or(self.max_request_bytes);
+        self.blob_dir = blob_dir.unwrap_or(self.blob_dir.to_owned());
+        self.encryption_key_file = encryption_key_file.or(self.encryption_key_file.take());
+        self.definitions = definitions.or(self.definitions.take());
+        self.grpc_enabled = grpc_enabled.unwrap_or(self.grpc_enabled);
+        self.grpc_bind = grpc_bind.unwrap_or(self.grpc_bind);
+        self.grpc_port = grpc_port.unwrap_or(self.grpc_port);
+        self.amqp_bind = amqp_bind.unwrap_or(self.amqp_bind);
+        self.amqp_port = amqp_port.or(self.amqp_port);
+        self.stomp_bind = stomp_bind.unwrap_or(self.stomp_bind);
+        self.stomp_port = stomp_port.or(self.stomp_port);
+        self.binary_bind = binary_bind.unwrap_or(self.binary_bind);
+        self.binary_port = binary_port.or(self.binary_port);
+        self.mqtt_bind = mqtt_bind.unwrap_or(self.mqtt_bind);
+        self.mqtt_port = mqtt_port.or(self.mqtt_port);
+        self.mqtt_exchange = mqtt_exchange.unwrap_or(self.mqtt_exchange.to_owned());
+        self.tls_cert = tls_cert.or(self.tls_cert.take());
+        self.tls_key = tls_key.or(self.tls_key.take());
+        self.tls_client_ca = tls_client_ca.or(self.tls_client_ca.take());
+        self.audit_log = audit_log.or(self.audit_log.take());
+        self.audit_max_entries = audit_max_entries.unwrap_or(self.audit_max_entries);
+        self.audit_messages = audit_messages.unwrap_or(self.audit_messages);
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.workers == Some(0) {
+            return Err(invalid(String::from("workers must be at least 1")));
+        }
+        if self.max_request_bytes == 0 {
+            return Err(invalid(String::from(
+                "max_request_bytes must be at least 1",
+            )));
+        }
+        if let Some(admin_key) = &self.admin_key {
+            if admin_key.len() < MIN_ADMIN_KEY_LENGTH {
+                return Err(invalid(format!(
+                    "The admin key must be at least {} characters",
+                    MIN_ADMIN_KEY_LENGTH
+                )));
+            }
+        }
+        match (&self.tls_cert, &self.tls_key, &self.tls_client_ca) {
+            (Some(_), Some(_), _) | (None, None, None) => (),
+            _ => {
+                return Err(invalid(String::from(
+                    "tls_cert and tls_key must be given together, and tls_client_ca needs both",
+                )))
+            }
+        }
+        // listeners clash on a port when they share an address, or either listens on all of them
+        let grpc_port = Some(self.grpc_port).filter(|_| self.grpc_enabled);
+        let listeners = [
+            ("port", self.bind, Some(self.port)),
+            ("grpc_port", self.grpc_bind, grpc_port),
+            ("amqp_port", self.amqp_bind, self.amqp_port),
+            ("stomp_port", self.stomp_bind, self.stomp_port),
+            ("binary_port", self.binary_bind, self.binary_port),
+            ("mqtt_port", self.mqtt_bind, self.mqtt_port),
+        ];
+        let listeners = listeners
+            .iter()
+            .filter_map(|(name, bind, port)| port.map(|p| (name, bind, p)))
+            .collect::<Vec<_>>();
+        for (i, (name, bind, port)) in listeners.iter().enumerate() {
+            let clash = listeners[..i].iter().find(|(_, b, p)| {
+                p == port && (b == bind || b.is_unspecified() || bind.is_unspecified())
+            });
+            if let Some((other, _, _)) = clash {
+                return Err(invalid(format!(
+                    "{} and {} are both set to the port {}",
+                    other, name, port
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // The key messages are encrypted with, if one was given in a file
+    pub fn read_encryption_key(&self) -> io::Result<Option<Vec<u8>>> {
+        let path = match &self.encryption_key_file {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        match from_hex(text.trim()) {
+            Some(key) if key.len() == ENCRYPTION_KEY_BYTES => Ok(Some(key)),
+            _ => Err(invalid(format!(
+                "{}: the encryption key must be {} hex characters",
+                path.display(),
+                ENCRYPTION_KEY_BYTES * 2
+            ))),
+        }
+    }
+}
+
+// Imports the config file's queues and exchanges, and then the definitions file's, into the
+// default namespace
+pub async fn declare(data: &AppState, config: &mut Config) -> io::Result<()> {
+    let mut definitions = Definitions {
+        queues: mem::take(&mut config.queues),
+        exchanges: mem::take(&mut config.exchanges),
+    };
+    if let Some(path) = &config.definitions {
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let file: Definitions = serde_json::from_str(&text)
+            .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+        definitions.queues.extend(file.queues);
+        definitions.exchanges.extend(file.exchanges);
+    }
+    let namespace = Namespace::new(DEFAULT_NAMESPACE, None, None);
+    let definitions = definitions
+        .qualify(&namespace)
+        .map_err(|e| invalid(e.get_message().to_owned()))?;
+    reconcile(data, &namespace, definitions)
+        .await
+        .map(|_| ())
+        .map_err(|e| invalid(e.get_message().to_owned()))
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a config file of its own for each test, as tests run at once
+    fn config_file(text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("rqs-test-config-{}.toml", uuid::Uuid::new_v4()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn load(args: &[&str]) -> io::Result<Config> {
+        let args = Args::try_parse_from([&["rqs"], args].concat()).unwrap();
+        Config::load(args)
+    }
+
+    #[test]
+    fn defaults_apply_without_a_file() {
+        let config = load(&[]).unwrap();
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert!(config.grpc_enabled);
+        assert_eq!(config.amqp_bind, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.amqp_port, None);
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_file() {
+        let path = config_file(
+            "port = 9000\nworkers = 2\ngrpc_enabled = false\namqp_bind = \"10.0.0.1\"\n\
+             amqp_port = 5672\n",
+        );
+        let config = load(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--port",
+            "9001",
+            "--amqp-bind",
+            "0.0.0.0",
+        ])
+        .unwrap();
+        assert_eq!(config.port, 9001);
+        assert_eq!(config.workers, Some(2));
+        assert!(!config.grpc_enabled);
+        assert_eq!(config.amqp_bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(config.amqp_port, Some(5672));
+    }
+
+    // the only test to set variables, and on settings no other test looks at
+    #[test]
+    fn the_environment_overrides_the_file_but_not_the_command_line() {
+        env::set_var("RQS_MQTT_BIND", "10.0.0.3");
+        env::set_var("RQS_MQTT_PORT", "2883");
+        let path = config_file("mqtt_bind = \"10.0.0.2\"\nmqtt_port = 1883\n");
+        let config = load(&["--config", path.to_str().unwrap(), "--mqtt-port", "3883"]).unwrap();
+        env::remove_var("RQS_MQTT_BIND");
+        env::remove_var("RQS_MQTT_PORT");
+        assert_eq!(config.mqtt_bind, "10.0.0.3".parse::<IpAddr>().unwrap());
+        assert_eq!(config.mqtt_port, Some(3883));
+    }
+
+    #[test]
+    fn listeners_only_clash_on_a_shared_address() {
+        let path = config_file("amqp_port = 7000\nstomp_port = 7000\n");
+        assert!(load(&["--config", path.to_str().unwrap()]).is_err());
+        let args = ["--stomp-bind", "10.0.0.1"];
+        assert!(load(&[&["--config", path.to_str().unwrap()], &args[..]].concat()).is_ok());
+        let args = ["--stomp-bind", "0.0.0.0"];
+        assert!(load(&[&["--config", path.to_str().unwrap()], &args[..]].concat()).is_err());
+        // a disabled gRPC API doesn't hold its port
+        let path = config_file("binary_port = 50051\n");
+        assert!(load(&["--config", path.to_str().unwrap()]).is_err());
+        let args = ["--grpc-enabled", "false"];
+        assert!(load(&[&["--config", path.to_str().unwrap()], &args[..]].concat()).is_ok());
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        let path = config_file("grpc_bound = \"0.0.0.0\"\n");
+        assert!(load(&["--config", path.to_str().unwrap()]).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+
+use crate::app_types::{AppState, JsonResponse};
+use crate::auth_api::authorize;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::identity::Identity;
+use crate::message_api::exclusive_queue_error;
+use crate::namespace_api::get_namespace;
+use request::SubscribeRequest;
+
+pub(crate) mod event_stream;
+mod request;
+pub(crate) mod wakeup;
+mod websocket;
+
+// Opens a WebSocket that pushes a queue's messages to the consumer as they become visible
+pub async fn subscribe(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<AppState>,
+    query_data: web::Query<SubscribeRequest>,
+) -> HttpResponse {
+    let queue_id = match get_namespace(&req).qualify(&query_data.queue_id) {
+        Ok(id) => id,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(&req, ResourceType::QUEUE, &queue_id, Permission::CONSUME) {
+        return e.to_response();
+    }
+    {
+        let queues = data.get_queues().lock().await;
+        let queue = match queues.get(&queue_id) {
+            None => {
+                return HttpResponse::BadRequest().json(JsonResponse::new(
+                    None::<String>,
+                    format!("No queue with id {} was found", queue_id),
+                ))
+            }
+            Some(q) => q,
+        };
+        if !queue.accepts_consumer(&query_data.consumer_token) {
+            return exclusive_queue_error(&queue_id).to_response();
+        }
+    }
+
+    let (response, session, stream) = match actix_ws::handle(&req, body) {
+        Ok(r) => r,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                "Subscribing requires a WebSocket upgrade request",
+            ))
+        }
+    };
+    let identity = Identity::of(&req);
+    actix_web::rt::spawn(websocket::run(
+        data.clone(),
+        identity,
+        queue_id,
+        session,
+        stream,
+    ));
+    response
+}
+
+// Streams a queue's messages as Server-Sent Events. Messages are dispatched as by /message/get, so
+// they are hidden for the read timeout and must be deleted through /message/delete.
+pub async fn stream(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query_data: web::Query<SubscribeRequest>,
+) -> HttpResponse {
+    let queue_id = match get_namespace(&req).qualify(&query_data.queue_id) {
+        Ok(id) => id,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(&req, ResourceType::QUEUE, &queue_id, Permission::CONSUME) {
+        return e.to_response();
+    }
+    {
+        let queues = data.get_queues().lock().await;
+        let queue = match queues.get(&queue_id) {
+            None => {
+                return HttpResponse::BadRequest().json(JsonResponse::new(
+                    None::<String>,
+                    format!("No queue with id {} was found", queue_id),
+                ))
+            }
+            Some(q) => q,
+        };
+        if !queue.accepts_consumer(&query_data.consumer_token) {
+            return exclusive_queue_error(&queue_id).to_response();
+        }
+    }
+
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let stream_id = event_stream::open(&data, &queue_id, last_event_id).await;
+    let identity = Identity::of(&req);
+    let state = event_stream::StreamState::new(data.clone(), identity, queue_id, stream_id);
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(futures::stream::unfold(state, event_stream::next_frame))
+}
+
+// Forgets streams that can no longer be resumed
+pub async fn remove_expired_streams(data: &AppState) {
+    let mut streams = data.get_event_streams().lock().await;
+    streams.retain(|_, stream| !stream.is_expired());
+}
@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::app_types::{AppState, JsonResponse};
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::identity::Identity;
+use crate::message_api::request::GetMessageResponse;
+
+use super::wakeup::Wakeup;
+// how long a stream may go without events before a comment is sent to keep the connection open
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// how long after its last event a stream can still be resumed, in seconds
+const RESUME_WINDOW: i64 = 300;
+// how long a client should wait before reconnecting, in milliseconds
+const RETRY_AFTER: u32 = 1000;
+
+// What a stream has sent, kept so a client reconnecting with the id of the last event it got can
+// resume the stream. Event ids are `<stream id>:<sequence number>`.
+pub struct EventStream {
+    queue_id: String,
+    next_seq: u64,
+    sent: Vec<(u64, String, DateTime<Utc>)>, // (sequence number, message uuid, when it was sent)
+    last_active: DateTime<Utc>,
+}
+
+impl EventStream {
+    fn new(queue_id: String) -> Self {
+        EventStream {
+            queue_id,
+            next_seq: 1,
+            sent: vec![],
+            last_active: Utc::now(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now() - self.last_active > chrono::Duration::seconds(RESUME_WINDOW)
+    }
+}
+
+// Parses a Last-Event-ID into its stream id and sequence number
+pub fn parse_event_id(event_id: &str) -> Option<(String, u64)> {
+    let (stream_id, seq) = event_id.split_once(':')?;
+    Some((stream_id.to_owned(), seq.parse::<u64>().ok()?))
+}
+
+// Picks up the stream the client was reading, if it can still be resumed. The messages sent after
+// the last event the client got never reached it, so they are made visible again straight away
+// rather than after their read timeout. Otherwise a new stream is started.
+pub async fn open(data: &AppState, queue_id: &String, last_event_id: Option<String>) -> String {
+    let mut queues = data.get_queues().lock().await;
+    let mut streams = data.get_event_streams().lock().await;
+    if let Some((stream_id, last_seq)) = last_event_id.as_deref().and_then(parse_event_id) {
+        if let Some(stream) = streams.get_mut(&stream_id) {
+            if stream.queue_id == *queue_id {
+                if let Some(queue) = queues.get_mut(queue_id) {
+                    for (_, uuid, _) in stream.sent.iter().filter(|(seq, _, _)| *seq > last_seq) {
+                        queue.release(uuid);
+                    }
+                }
+                stream.sent.retain(|(seq, _, _)| *seq <= last_seq);
+                stream.last_active = Utc::now();
+                return stream_id;
+            }
+        }
+    }
+    let stream_id = Uuid::new_v4().to_string();
+    streams.insert(stream_id.to_owned(), EventStream::new(queue_id.to_owned()));
+    stream_id
+}
+
+pub struct StreamState {
+    data: web::Data<AppState>,
+    identity: Identity,
+    queue_id: String,
+    stream_id: String,
+    frames: VecDeque<String>,
+    last_sent: Instant,
+    done: bool,
+}
+
+impl StreamState {
+    pub fn new(
+        data: web::Data<AppState>,
+        identity: Identity,
+        queue_id: String,
+        stream_id: String,
+    ) -> Self {
+        StreamState {
+            data,
+            identity,
+            queue_id,
+            stream_id,
+            frames: VecDeque::from([format!("retry: {}\n\n", RETRY_AFTER)]),
+            last_sent: Instant::now(),
+            done: false,
+        }
+    }
+}
+
+// Produces the next chunk of the response, waiting for messages to become visible if need be.
+// The stream ends once the queue is deleted, or the key may no longer consume from it.
+pub async fn next_frame(
+    mut state: StreamState,
+) -> Option<(Result<web::Bytes, actix_web::Error>, StreamState)> {
+    loop {
+        if let Some(frame) = state.frames.pop_front() {
+            return Some((Ok(web::Bytes::from(frame)), state));
+        }
+        if state.done {
+            return None;
+        }
+        match poll_queue(&state).await {
+            Ok((frames, mut wakeup)) if frames.is_empty() => {
+                let idle = state.last_sent.elapsed();
+                if idle >= KEEP_ALIVE_INTERVAL {
+                    state.frames.push_back(String::from(": keep-alive\n\n"));
+                    state.last_sent = Instant::now();
+                } else {
+                    wakeup.within(KEEP_ALIVE_INTERVAL - idle);
+                    wakeup.wait().await;
+                }
+            }
+            Ok((frames, _)) => {
+                state.frames.extend(frames);
+                state.last_sent = Instant::now();
+            }
+            Err(frame) => {
+                state.frames.push_back(frame);
+                state.done = true;
+            }
+        }
+    }
+}
+
+// Dispatches a batch from the queue as events, with what to wait for before dispatching again, or
+// returns a final error event
+async fn poll_queue(state: &StreamState) -> Result<(Vec<String>, Wakeup), String> {
+    let (data, queue_id, stream_id) = (&state.data, &state.queue_id, &state.stream_id);
+    // the key is checked on every poll, so the stream ends once it is deleted or loses its grant
+    let authorized = state
+        .identity
+        .authorize(data, ResourceType::QUEUE, queue_id, Permission::CONSUME)
+        .await;
+    if let Err(e) = authorized {
+        return Err(error_event(e.get_message().to_owned()));
+    }
+    let mut queues = data.get_queues().lock().await;
+    let queue = match queues.get_mut(queue_id) {
+        None => {
+            return Err(error_event(format!(
+                "The queue with id {} was deleted",
+                queue_id
+            )))
+        }
+        Some(q) => q,
+    };
+    let cipher = data.get_cipher().lock().await;
+    let messages = match queue.dispatch(&cipher) {
+        Ok(m) => m,
+        Err(e) => return Err(error_event(e.to_string())),
+    };
+    let read_timeout = chrono::Duration::seconds(queue.get_read_timeout() as i64);
+    let mut wakeup = Wakeup::new();
+    wakeup.watch(queue, messages.len() == queue.get_max_batch() as usize);
+
+    let mut streams = data.get_event_streams().lock().await;
+    let stream = streams
+        .entry(stream_id.to_owned())
+        .or_insert_with(|| EventStream::new(queue_id.to_owned()));
+    let now = Utc::now();
+    // messages past their read timeout are visible to everyone again, so need no releasing
+    stream
+        .sent
+        .retain(|(_, _, sent_at)| now - *sent_at <= read_timeout);
+    stream.last_active = now;
+    let mut frames = vec![];
+    for message in messages.iter() {
+        let seq = stream.next_seq;
+        stream.next_seq += 1;
+        stream.sent.push((seq, message.get_uuid(), now));
+        let response = JsonResponse::new(GetMessageResponse::new(message), None::<String>);
+        frames.push(format!(
+            "id: {}:{}\nevent: message\ndata: {}\n\n",
+            stream_id,
+            seq,
+            serde_json::to_string(&response).unwrap_or_default()
+        ));
+    }
+    Ok((frames, wakeup))
+}
+
+fn error_event(error: String) -> String {
+    let response = JsonResponse::new(None::<String>, error);
+    format!(
+        "event: error\ndata: {}\n\n",
+        serde_json::to_string(&response).unwrap_or_default()
+    )
+}
@@ -0,0 +1,23 @@
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscribeRequest {
+    pub queue_id: String,
+    pub consumer_token: Option<String>,
+}
+
+#[allow(clippy::upper_case_acronyms)] // the variant names are part of the api
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SubscriptionAction {
+    ACK,  // Ack deletes a message the subscription holds
+    NACK, // Nack returns a message the subscription holds to the queue
+}
+
+// What a consumer sends over its subscription
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionCommand {
+    pub action: SubscriptionAction,
+    pub message_uuid: String,
+}
@@ -0,0 +1,163 @@
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::time::timeout;
+use futures::future::{pending, select, select_all, Either};
+use tokio::sync::Notify;
+
+use crate::queue_api::queue::Queue;
+
+// the longest a push consumer waits before checking its queues again, in case one was deleted
+// before it began waiting
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// What a push consumer waits for before checking its queues again: a message arriving on a queue
+// it has credit for, a hidden message becoming visible, or the messages it holds needing renewal.
+// It is built while the queues are locked, so nothing added after it is missed.
+pub struct Wakeup {
+    arrivals: Vec<Arc<Notify>>,
+    wait: Duration,
+}
+
+impl Wakeup {
+    pub fn new() -> Self {
+        Wakeup {
+            arrivals: vec![],
+            wait: RECHECK_INTERVAL,
+        }
+    }
+
+    // Watches a queue the consumer has credit left for. If the last dispatch filled the consumer's
+    // credit, more messages may be visible already, so the queue is checked again at once.
+    pub fn watch(&mut self, queue: &Queue, filled: bool) {
+        if filled {
+            self.within(Duration::ZERO);
+            return;
+        }
+        self.arrivals.push(queue.get_arrivals());
+        if let Some(wait) = queue.get_next_visible() {
+            self.within(wait);
+        }
+    }
+
+    // Renews the messages the consumer holds from a queue at half its read timeout, so they stay
+    // hidden from other consumers
+    pub fn hold(&mut self, queue: &Queue) {
+        self.within(Duration::from_millis(queue.get_read_timeout() as u64 * 500));
+    }
+
+    // Waits no longer than the given time, as for a heart-beat that is due
+    pub fn within(&mut self, wait: Duration) {
+        self.wait = self.wait.min(wait);
+    }
+
+    // Waits for input from the consumer, returning None instead if its queues need checking first
+    pub async fn wait_for<F: Future>(&self, input: F) -> Option<F::Output> {
+        let arrived = async {
+            match self.arrivals.is_empty() {
+                true => pending::<()>().await,
+                false => {
+                    select_all(self.arrivals.iter().map(|a| Box::pin(a.notified()))).await;
+                }
+            }
+        };
+        let woken = timeout(self.wait, arrived);
+        match select(pin!(input), pin!(woken)).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+
+    // Waits until the consumer's queues need checking, for consumers that send no input
+    pub async fn wait(&self) {
+        self.wait_for(pending::<()>()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use aes_gcm::aead::{KeyInit, OsRng};
+    use aes_gcm::Aes256Gcm;
+    use futures::future::ready;
+
+    use super::*;
+    use crate::blob_store::BlobStore;
+    use crate::queue_api::queue::{MessageProperties, QueueSettings};
+
+    fn queue(read_timeout: u32) -> Queue {
+        let settings = QueueSettings {
+            read_timeout,
+            max_batch: 10,
+            ..Default::default()
+        };
+        let blob_store = BlobStore::new(std::env::temp_dir().join("rqs-test-blobs")).unwrap();
+        Queue::new(String::from("q"), settings, blob_store)
+    }
+
+    fn add(queue: &mut Queue, cipher: &Aes256Gcm) {
+        let (id, content) = (String::from("m"), String::from("content"));
+        queue
+            .add_to_queue(cipher, id, content, MessageProperties::default())
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn input_is_returned_when_it_comes_first() {
+        let mut wakeup = Wakeup::new();
+        wakeup.watch(&queue(30), false);
+        assert_eq!(wakeup.wait_for(ready(7)).await, Some(7));
+    }
+
+    #[actix_web::test]
+    async fn an_arrival_wakes_a_watching_consumer() {
+        let mut queue = queue(30);
+        let mut wakeup = Wakeup::new();
+        wakeup.watch(&queue, false);
+        // added after the wakeup was built but before it waits, as when the queues are unlocked
+        add(
+            &mut queue,
+            &Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng)),
+        );
+        let started = Instant::now();
+        assert!(wakeup.wait_for(pending::<()>()).await.is_none());
+        assert!(started.elapsed() < RECHECK_INTERVAL);
+    }
+
+    #[actix_web::test]
+    async fn deleting_the_queue_wakes_a_watching_consumer() {
+        let queue = queue(30);
+        let mut wakeup = Wakeup::new();
+        wakeup.watch(&queue, false);
+        let started = Instant::now();
+        actix_web::rt::spawn(async move {
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+            drop(queue);
+        });
+        wakeup.wait().await;
+        assert!(started.elapsed() < RECHECK_INTERVAL);
+    }
+
+    #[actix_web::test]
+    async fn consumers_wait_until_a_hidden_message_is_visible_again() {
+        let mut queue = queue(1);
+        let cipher = Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng));
+        add(&mut queue, &cipher);
+        assert_eq!(queue.get_next_visible(), None);
+        queue.dispatch(&cipher).unwrap();
+        // the permit the add left is taken, so only the read timeout can wake the consumer
+        queue.get_arrivals().notified().await;
+        let next_visible = queue.get_next_visible().unwrap();
+        assert!(
+            next_visible > Duration::from_millis(900) && next_visible <= Duration::from_secs(2)
+        );
+
+        let mut wakeup = Wakeup::new();
+        wakeup.watch(&queue, false);
+        wakeup.wait().await;
+        assert_eq!(queue.dispatch(&cipher).unwrap().len(), 1);
+    }
+}
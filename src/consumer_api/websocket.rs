@@ -0,0 +1,194 @@
+use actix_web::web;
+use actix_ws::{CloseCode, CloseReason, Message, MessageStream, Session};
+use futures::StreamExt;
+
+use crate::app_types::{AppState, JsonResponse};
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::identity::Identity;
+use crate::message_api::request::GetMessageResponse;
+
+use super::request::{SubscriptionAction, SubscriptionCommand};
+use super::wakeup::Wakeup;
+
+// Why a subscription ended - None if the consumer went away without saying why
+type Ended = Option<CloseReason>;
+
+// Pushes messages until the consumer or queue goes away. The subscription holds at most
+// `maxBatch` unacked messages, and keeps them hidden from other consumers until it acks or nacks
+// them, and acking needs the key to allow deleting from the queue. Whatever it still holds when it
+// ends is returned to the queue.
+pub async fn run(
+    data: web::Data<AppState>,
+    identity: Identity,
+    queue_id: String,
+    mut session: Session,
+    mut stream: MessageStream,
+) {
+    let mut held = vec![];
+    let ended = loop {
+        let pushed = push_messages(&data, &identity, &queue_id, &mut held, &mut session).await;
+        let wakeup = match pushed {
+            Ok(wakeup) => wakeup,
+            Err(ended) => break ended,
+        };
+        let message = match wakeup.wait_for(stream.next()).await {
+            None => continue, // the queue needs checking again
+            Some(None) => break None,
+            Some(Some(Err(_))) => break Some(CloseReason::from(CloseCode::Protocol)),
+            Some(Some(Ok(m))) => m,
+        };
+        let handled = match message {
+            Message::Text(text) => {
+                handle_command(&data, &identity, &queue_id, &mut held, &mut session, &text).await
+            }
+            Message::Ping(bytes) => session.pong(&bytes).await.map_err(|_| None),
+            Message::Close(reason) => Err(reason),
+            _ => Ok(()),
+        };
+        if let Err(ended) = handled {
+            break ended;
+        }
+    };
+
+    // the queue may have been deleted, in which case there is nothing to return them to
+    let mut queues = data.get_queues().lock().await;
+    if let Some(queue) = queues.get_mut(&queue_id) {
+        for uuid in held.iter() {
+            queue.release(uuid);
+        }
+    }
+    let _ = session.close(ended).await;
+}
+
+// Renews the messages the subscription holds, and pushes new ones while it has credit for them,
+// returning what to wait for before doing so again. The key is checked on every push, so the
+// subscription ends once it may no longer consume from the queue.
+async fn push_messages(
+    data: &AppState,
+    identity: &Identity,
+    queue_id: &String,
+    held: &mut Vec<String>,
+    session: &mut Session,
+) -> Result<Wakeup, Ended> {
+    let authorized = identity
+        .authorize(data, ResourceType::QUEUE, queue_id, Permission::CONSUME)
+        .await;
+    if let Err(e) = authorized {
+        return Err(Some(CloseReason {
+            code: CloseCode::Policy,
+            description: Some(e.get_message().to_owned()),
+        }));
+    }
+    let (frames, wakeup) = {
+        let mut queues = data.get_queues().lock().await;
+        let queue = match queues.get_mut(queue_id) {
+            None => {
+                return Err(Some(CloseReason {
+                    code: CloseCode::Away,
+                    description: Some(format!("The queue with id {} was deleted", queue_id)),
+                }))
+            }
+            Some(q) => q,
+        };
+        queue.renew(held);
+        let credit = (queue.get_max_batch() as usize).saturating_sub(held.len());
+        let cipher = data.get_cipher().lock().await;
+        let messages = match queue.dispatch_up_to(&cipher, credit) {
+            Ok(m) => m,
+            Err(e) => {
+                return Err(Some(CloseReason {
+                    code: CloseCode::Error,
+                    description: Some(e.to_string()),
+                }))
+            }
+        };
+        let mut frames = vec![];
+        for message in messages.iter() {
+            held.push(message.get_uuid());
+            let response = JsonResponse::new(GetMessageResponse::new(message), None::<String>);
+            frames.push(serde_json::to_string(&response).unwrap_or_default());
+        }
+        let mut wakeup = Wakeup::new();
+        if messages.len() < credit {
+            wakeup.watch(queue, false);
+        }
+        if !held.is_empty() {
+            wakeup.hold(queue);
+        }
+        (frames, wakeup)
+    };
+    for frame in frames {
+        session.text(frame).await.map_err(|_| None)?;
+    }
+    Ok(wakeup)
+}
+
+async fn handle_command(
+    data: &AppState,
+    identity: &Identity,
+    queue_id: &String,
+    held: &mut Vec<String>,
+    session: &mut Session,
+    text: &str,
+) -> Result<(), Ended> {
+    let command = match serde_json::from_str::<SubscriptionCommand>(text) {
+        Ok(c) => c,
+        Err(_) => {
+            return send_error(
+                session,
+                "Commands must be JSON with an action of ACK or NACK and a messageUuid",
+            )
+            .await
+        }
+    };
+    let idx = match held.iter().position(|u| *u == command.message_uuid) {
+        Some(idx) => idx,
+        None => {
+            let error = format!(
+                "No message with uuid {} is held by this subscription",
+                command.message_uuid
+            );
+            return send_error(session, &error).await;
+        }
+    };
+    // a message the key may not delete stays held, to be nacked or returned when the subscription
+    // ends
+    if command.action == SubscriptionAction::ACK {
+        let authorized = identity
+            .authorize(data, ResourceType::QUEUE, queue_id, Permission::DELETE)
+            .await;
+        if let Err(e) = authorized {
+            return send_error(session, e.get_message()).await;
+        }
+    }
+    let uuid = held.remove(idx);
+    let settled = match data.get_queues().lock().await.get_mut(queue_id) {
+        None => false,
+        Some(queue) => match command.action {
+            SubscriptionAction::ACK => queue.rem_from_queue(&uuid).is_some(),
+            SubscriptionAction::NACK => queue.release(&uuid),
+        },
+    };
+    // successful acks and nacks are not answered, to keep the socket free for messages
+    match settled {
+        true => {
+            if command.action == SubscriptionAction::ACK {
+                identity
+                    .audit(data, AuditAction::MessagesDeleted, queue_id, Some(uuid))
+                    .await;
+            }
+            Ok(())
+        }
+        false => {
+            let error = format!("The message with uuid {} is no longer held", uuid);
+            send_error(session, &error).await
+        }
+    }
+}
+
+async fn send_error(session: &mut Session, error: &str) -> Result<(), Ended> {
+    let response = JsonResponse::new(None::<String>, error);
+    let frame = serde_json::to_string(&response).unwrap_or_default();
+    session.text(frame).await.map_err(|_| None)
+}
@@ -0,0 +1,478 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+
+use crate::app_types::{ApiError, AppState, JsonResponse};
+use crate::audit_api::audit;
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::authorize;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::exchange_api::build_exchange;
+use crate::exchange_api::exchange::Exchange;
+use crate::exchange_api::request::{ExchangeEntry, NewExchangeRequest};
+use crate::namespace_api::namespace::Namespace;
+use crate::namespace_api::{check_room, count_in, get_namespace};
+use crate::queue_api::queue::{Queue, QueueSettings};
+use crate::queue_api::queue_settings;
+use crate::queue_api::request::NewQueueRequest;
+use request::{Definitions, ImportResponse};
+
+pub(crate) mod request;
+
+// Brings the request's namespace in line with the definitions, leaving queues and exchanges that
+// are not in them alone
+pub async fn import_definitions(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    post_data: web::Json<Definitions>,
+) -> HttpResponse {
+    let namespace = get_namespace(&req);
+    let definitions = match post_data.into_inner().qualify(&namespace) {
+        Ok(d) => d,
+        Err(e) => return e.to_response(),
+    };
+    let queue_ids = definitions.queues.iter().map(|q| &q.queue_id);
+    let exchange_ids = definitions.exchanges.iter().map(|e| &e.id);
+    // queues' dead letters are published to their dead letter queues on the key's behalf
+    let dead_letter_queue_ids = definitions
+        .queues
+        .iter()
+        .filter_map(|q| q.dead_letter_queue_id.as_ref());
+    let checks = queue_ids
+        .map(|id| (ResourceType::QUEUE, id, Permission::MANAGE))
+        .chain(exchange_ids.map(|id| (ResourceType::EXCHANGE, id, Permission::MANAGE)))
+        .chain(dead_letter_queue_ids.map(|id| (ResourceType::QUEUE, id, Permission::PUBLISH)));
+    for (resource_type, id, permission) in checks {
+        if let Err(e) = authorize(&req, resource_type, id, permission) {
+            return e.to_response();
+        }
+    }
+    let response = match reconcile(&data, &namespace, definitions).await {
+        Ok(r) => r,
+        Err(e) => return e.to_response(),
+    };
+    let changes = [
+        (&response.queues.created, AuditAction::QueueCreated),
+        (&response.queues.updated, AuditAction::QueueUpdated),
+        (&response.exchanges.created, AuditAction::ExchangeCreated),
+        (&response.exchanges.updated, AuditAction::ExchangeUpdated),
+    ];
+    for (ids, action) in changes {
+        for id in ids.iter() {
+            let detail = Some(String::from("imported from definitions"));
+            audit(&data, &req, action, id, detail).await;
+        }
+    }
+    HttpResponse::Accepted().json(JsonResponse::new(response, None::<String>))
+}
+
+// Exports the queues and exchanges of the request's namespace in the format import takes
+pub async fn export_definitions(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    let definitions = get_definitions(&data, &get_namespace(&req)).await;
+    HttpResponse::Ok().json(definitions)
+}
+
+// Creates the queues and exchanges that do not exist yet and updates the ones that differ. Every
+// one is checked against what the namespace would be once they all are, before any is applied, so
+// an import that fails changes nothing.
+pub async fn reconcile(
+    data: &AppState,
+    namespace: &Namespace,
+    definitions: Definitions,
+) -> Result<ImportResponse, ApiError> {
+    // the namespace is held for its quotas, then exchanges are locked before queues as ever
+    let namespaces = data.get_namespaces().lock().await;
+    let namespace_record = match namespaces.get(&namespace.name) {
+        Some(n) => n,
+        None => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("No namespace named {} was found", namespace.name),
+            ))
+        }
+    };
+    let mut exchanges = data.get_exchanges().lock().await;
+    let mut queues = data.get_queues().lock().await;
+    let local = |id: &String| namespace.unqualify(id).unwrap_or(id.to_owned());
+    let mut response = ImportResponse::default();
+
+    // dead letter queues may be any of the imported queues, so ones that dead letter to each
+    // other can be imported together
+    let queue_ids = unique_ids("queue", definitions.queues.iter().map(|q| &q.queue_id))?;
+    let queue_exists = |id: &String| queues.contains_key(id) || queue_ids.contains(id);
+    let mut queue_count = count_in(&namespace.name, queues.keys());
+    let mut staged_queues = vec![];
+    for definition in definitions.queues.iter() {
+        let id = &definition.queue_id;
+        let settings = queue_settings(definition, queue_exists)
+            .map_err(|e| not_imported("queue", &local(id), e))?;
+        match queues.get(id) {
+            Some(queue) if queue.get_settings().consumer_token.is_some() => {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    format!(
+                        "The queue {} is a reply queue, so cannot be imported",
+                        local(id)
+                    ),
+                ))
+            }
+            Some(queue) if *queue.get_settings() == settings => {
+                response.queues.unchanged.push(local(id))
+            }
+            Some(queue) => {
+                check_depth(queue, &settings).map_err(|e| not_imported("queue", &local(id), e))?;
+                staged_queues.push((id, settings, true));
+            }
+            None => {
+                check_room(namespace_record, ResourceType::QUEUE, queue_count)?;
+                queue_count += 1;
+                staged_queues.push((id, settings, false));
+            }
+        }
+    }
+
+    // exchanges may be bound to any of the imported exchanges, whatever order they are given in
+    let exchange_ids = unique_ids("exchange", definitions.exchanges.iter().map(|e| &e.id))?;
+    let exchange_exists = |id: &String| exchanges.contains_key(id) || exchange_ids.contains(id);
+    let mut exchange_count = count_in(&namespace.name, exchanges.keys());
+    let mut staged_exchanges = vec![];
+    for definition in definitions.exchanges.iter() {
+        let id = &definition.id;
+        let exchange = build_exchange(definition, exchange_exists, queue_exists)
+            .map_err(|e| not_imported("exchange", &local(id), e))?;
+        match exchanges.get(id) {
+            Some(existing) if ExchangeEntry::new(existing) == ExchangeEntry::new(&exchange) => {
+                response.exchanges.unchanged.push(local(id))
+            }
+            Some(_) => staged_exchanges.push((id, exchange, true)),
+            None => {
+                check_room(namespace_record, ResourceType::EXCHANGE, exchange_count)?;
+                exchange_count += 1;
+                staged_exchanges.push((id, exchange, false));
+            }
+        }
+    }
+    // bindings that would let messages cycle are looked for once every exchange is staged, as
+    // the imported exchanges may be bound to each other
+    let staged = staged_exchanges
+        .iter()
+        .map(|(id, exchange, _)| (*id, exchange))
+        .collect::<HashMap<&String, &Exchange>>();
+    let lookup = |id: &String| staged.get(id).copied().or_else(|| exchanges.get(id));
+    for (id, exchange, _) in staged_exchanges.iter() {
+        if exchange.reaches(id, &lookup) {
+            return Err(ApiError::bad_request(format!(
+                "The exchange {} could not be imported: its bindings would create a cycle",
+                local(id)
+            )));
+        }
+    }
+
+    for (id, settings, exists) in staged_queues {
+        if exists {
+            if let Some(queue) = queues.get_mut(id) {
+                queue.set_settings(settings);
+            }
+            response.queues.updated.push(local(id));
+        } else {
+            let queue = Queue::new(id.to_owned(), settings, data.get_blob_store().clone());
+            queues.insert(id.to_owned(), queue);
+            response.queues.created.push(local(id));
+        }
+    }
+    for (id, mut exchange, exists) in staged_exchanges {
+        if exists {
+            if let Some(existing) = exchanges.get(id) {
+                exchange.uuid = existing.uuid;
+                exchange.keep_counts(existing);
+            }
+            response.exchanges.updated.push(local(id));
+        } else {
+            response.exchanges.created.push(local(id));
+        }
+        exchanges.insert(id.to_owned(), exchange);
+    }
+    Ok(response)
+}
+
+// The ids of the queues or exchanges being imported, each of which may only be given once
+fn unique_ids<'a>(
+    resource: &str,
+    ids: impl Iterator<Item = &'a String>,
+) -> Result<HashSet<&'a String>, ApiError> {
+    let mut unique = HashSet::new();
+    for id in ids {
+        if !unique.insert(id) {
+            return Err(ApiError::bad_request(format!(
+                "The {} {} is given more than once",
+                resource, id
+            )));
+        }
+    }
+    Ok(unique)
+}
+
+// New settings may not hold fewer messages or bytes than the queue already does, as the messages
+// over them would have to be dropped
+fn check_depth(queue: &Queue, settings: &QueueSettings) -> Result<(), ApiError> {
+    let too_many = settings
+        .max_messages
+        .is_some_and(|max| queue.get_size() > max);
+    let too_large = settings
+        .max_bytes
+        .is_some_and(|max| queue.get_bytes() > max);
+    if too_many || too_large {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!(
+                "it holds {} messages and {} bytes, more than its new limits allow",
+                queue.get_size(),
+                queue.get_bytes()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn not_imported(resource: &str, id: &str, e: ApiError) -> ApiError {
+    ApiError::new(
+        e.get_status(),
+        format!(
+            "The {} {} could not be imported: {}",
+            resource,
+            id,
+            e.get_message()
+        ),
+    )
+}
+
+// The namespace's queues and exchanges, ordered so each comes after those it depends on. Reply
+// queues are left out, as they only last as long as their consumer.
+pub async fn get_definitions(data: &AppState, namespace: &Namespace) -> Definitions {
+    let exchanges = data.get_exchanges().lock().await;
+    let queues = data.get_queues().lock().await;
+    let mut queue_definitions = queues
+        .iter()
+        .filter(|(_, q)| q.get_settings().consumer_token.is_none())
+        .filter_map(|(id, q)| NewQueueRequest::new(id, q.get_settings()).unqualify(namespace))
+        .collect::<Vec<NewQueueRequest>>();
+    queue_definitions.sort_by(|a, b| a.queue_id.cmp(&b.queue_id));
+    let mut exchange_definitions = exchanges
+        .values()
+        .filter_map(|e| ExchangeEntry::new(e).unqualify(namespace))
+        .map(NewExchangeRequest::new)
+        .collect::<Vec<NewExchangeRequest>>();
+    exchange_definitions.sort_by(|a, b| a.id.cmp(&b.id));
+    Definitions {
+        queues: in_dependency_order(
+            queue_definitions,
+            |q| &q.queue_id,
+            |q| q.dead_letter_queue_id.iter().cloned().collect(),
+        ),
+        exchanges: in_dependency_order(
+            exchange_definitions,
+            |e| &e.id,
+            |e| {
+                let bound = e.bindings.iter().filter_map(|b| b.exchange_id.to_owned());
+                bound.chain(e.alternate_exchange_id.to_owned()).collect()
+            },
+        ),
+    }
+}
+
+// Orders items so each comes after the items it depends on, keeping the order they were in
+// otherwise. Dependencies on items that are not there are ignored.
+fn in_dependency_order<T>(
+    mut items: Vec<T>,
+    id: impl Fn(&T) -> &String,
+    depends_on: impl Fn(&T) -> Vec<String>,
+) -> Vec<T> {
+    let all = items
+        .iter()
+        .map(|i| id(i).to_owned())
+        .collect::<HashSet<String>>();
+    let mut placed = HashSet::new();
+    let mut ordered = vec![];
+    while !items.is_empty() {
+        let ready = items.iter().position(|i| {
+            depends_on(i)
+                .iter()
+                .all(|d| placed.contains(d) || !all.contains(d) || d == id(i))
+        });
+        // a cycle of dead letter queues can't be ordered, so it is left as it is, which import
+        // takes as it checks queues against all the others being imported
+        let item = items.remove(ready.unwrap_or(0));
+        placed.insert(id(&item).to_owned());
+        ordered.push(item);
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace_api::namespace::DEFAULT_NAMESPACE;
+    use crate::queue_api::queue::MessageProperties;
+    use serde_json::json;
+
+    fn default_namespace() -> Namespace {
+        Namespace::new(DEFAULT_NAMESPACE, None, None)
+    }
+
+    fn definitions(value: serde_json::Value) -> Definitions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn queue(id: &str, dead_letter_queue_id: Option<&str>) -> serde_json::Value {
+        json!({
+            "queueId": id,
+            "readTimeout": 30,
+            "maxBatch": 10,
+            "deadLetterQueueId": dead_letter_queue_id,
+        })
+    }
+
+    fn exchange(id: &str, queue_ids: &[&str], exchange_ids: &[&str]) -> serde_json::Value {
+        json!({
+            "id": id,
+            "exchangeType": "FANOUT",
+            "queueIds": queue_ids,
+            "exchangeIds": exchange_ids,
+        })
+    }
+
+    async fn ids(data: &AppState) -> (Vec<String>, Vec<String>) {
+        let mut queue_ids = data
+            .get_queues()
+            .lock()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut exchange_ids = data
+            .get_exchanges()
+            .lock()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        queue_ids.sort();
+        exchange_ids.sort();
+        (queue_ids, exchange_ids)
+    }
+
+    #[test]
+    fn items_come_after_what_they_depend_on() {
+        let items = vec![("a", vec!["c"]), ("b", vec![]), ("c", vec!["b", "missing"])];
+        let ordered = in_dependency_order(
+            items
+                .into_iter()
+                .map(|(id, deps)| (id.to_owned(), deps))
+                .collect(),
+            |i| &i.0,
+            |i| i.1.iter().map(|d| d.to_string()).collect(),
+        );
+        let ordered = ordered.iter().map(|i| i.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(ordered, vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn cycles_are_left_in_order() {
+        let items = vec![("a", "b"), ("b", "a"), ("c", "c")];
+        let ordered = in_dependency_order(
+            items
+                .into_iter()
+                .map(|(id, dep)| (id.to_owned(), dep.to_owned()))
+                .collect(),
+            |i| &i.0,
+            |i| vec![i.1.to_owned()],
+        );
+        let ordered = ordered.iter().map(|i| i.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(ordered, vec!["c", "a", "b"]);
+    }
+
+    #[actix_web::test]
+    async fn importing_twice_changes_nothing_the_second_time() {
+        let data = AppState::for_tests();
+        let value = json!({
+            "queues": [queue("dlq", None), queue("q", Some("dlq"))],
+            "exchanges": [exchange("e", &["q"], &[])],
+        });
+        let first = reconcile(&data, &default_namespace(), definitions(value.clone()))
+            .await
+            .unwrap();
+        assert_eq!(first.queues.created, vec!["dlq", "q"]);
+        assert_eq!(first.exchanges.created, vec!["e"]);
+        let second = reconcile(&data, &default_namespace(), definitions(value))
+            .await
+            .unwrap();
+        assert!(second.queues.created.is_empty() && second.queues.updated.is_empty());
+        assert!(second.exchanges.created.is_empty() && second.exchanges.updated.is_empty());
+        assert_eq!(second.queues.unchanged, vec!["dlq", "q"]);
+        assert_eq!(second.exchanges.unchanged, vec!["e"]);
+    }
+
+    #[actix_web::test]
+    async fn exports_import_into_an_empty_instance() {
+        let data = AppState::for_tests();
+        let value = json!({
+            "queues": [queue("a", Some("b")), queue("b", Some("a"))],
+            "exchanges": [exchange("e1", &[], &["e2"]), exchange("e2", &["a"], &[])],
+        });
+        reconcile(&data, &default_namespace(), definitions(value))
+            .await
+            .unwrap();
+        let exported = get_definitions(&data, &default_namespace()).await;
+        let empty = AppState::for_tests();
+        reconcile(&empty, &default_namespace(), exported)
+            .await
+            .unwrap();
+        assert_eq!(ids(&empty).await, ids(&data).await);
+    }
+
+    #[actix_web::test]
+    async fn a_failed_import_changes_nothing() {
+        let data = AppState::for_tests();
+        let value = json!({
+            "queues": [queue("q", None), queue("bad", Some("missing"))],
+        });
+        let result = reconcile(&data, &default_namespace(), definitions(value)).await;
+        assert_eq!(result.unwrap_err().get_status(), StatusCode::BAD_REQUEST);
+        assert_eq!(ids(&data).await, (vec![], vec![]));
+
+        let value = json!({
+            "queues": [queue("q", None)],
+            "exchanges": [exchange("e1", &["q"], &["e2"]), exchange("e2", &[], &["e1"])],
+        });
+        let result = reconcile(&data, &default_namespace(), definitions(value)).await;
+        assert!(result.unwrap_err().get_message().contains("cycle"));
+        assert_eq!(ids(&data).await, (vec![], vec![]));
+    }
+
+    #[actix_web::test]
+    async fn limits_cannot_be_lowered_below_what_a_queue_holds() {
+        let data = AppState::for_tests();
+        let value = json!({ "queues": [queue("q", None)] });
+        reconcile(&data, &default_namespace(), definitions(value))
+            .await
+            .unwrap();
+        {
+            let cipher = data.get_cipher().lock().await;
+            let mut queues = data.get_queues().lock().await;
+            let queue = queues.get_mut("q").unwrap();
+            for content in ["a", "b"] {
+                let (id, properties) = (String::from("m"), MessageProperties::default());
+                queue
+                    .add_to_queue(&cipher, id, content.to_owned(), properties)
+                    .unwrap();
+            }
+        }
+        let mut lowered = queue("q", None);
+        lowered["maxMessages"] = json!(1);
+        let value = json!({ "queues": [lowered] });
+        let result = reconcile(&data, &default_namespace(), definitions(value)).await;
+        assert_eq!(result.unwrap_err().get_status(), StatusCode::CONFLICT);
+        let queues = data.get_queues().lock().await;
+        assert_eq!(queues.get("q").unwrap().get_settings().max_messages, None);
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+
+use crate::app_types::ApiError;
+use crate::exchange_api::request::NewExchangeRequest;
+use crate::namespace_api::namespace::Namespace;
+use crate::queue_api::request::NewQueueRequest;
+
+// The queues and exchanges of a namespace, in the order they can be declared in
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Definitions {
+    #[serde(default)]
+    pub queues: Vec<NewQueueRequest>,
+    #[serde(default)]
+    pub exchanges: Vec<NewExchangeRequest>,
+}
+
+impl Definitions {
+    pub fn qualify(self, namespace: &Namespace) -> Result<Self, ApiError> {
+        Ok(Definitions {
+            queues: self
+                .queues
+                .into_iter()
+                .map(|q| q.qualify(namespace))
+                .collect::<Result<_, _>>()?,
+            exchanges: self
+                .exchanges
+                .into_iter()
+                .map(|e| e.qualify(namespace))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+// What importing definitions did, by the ids of the queues or exchanges
+#[derive(Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Reconciled {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+#[derive(Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportResponse {
+    pub queues: Reconciled,
+    pub exchanges: Reconciled,
+}
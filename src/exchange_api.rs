@@ -1,104 +1,437 @@
 use std::collections::hash_map::Entry;
 
-use actix_web::{web, HttpResponse};
-use request::{NewExchangeRequest, NewMessageRequest};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use request::{BindExchangeRequest, NewExchangeRequest, NewMessageRequest};
 
-use crate::app_types::{AppState, JsonResponse};
+use crate::app_types::{ApiError, AppState, JsonResponse};
+use crate::audit_api::audit;
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::api_key::ApiKey;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::{authorize, get_api_key};
+use crate::namespace_api::namespace::Namespace;
+use crate::namespace_api::{check_quota, get_namespace};
+use crate::rate_limit::{admit_messages, give_back_messages};
 
-use exchange::{Exchange, ExchangeType};
+use exchange::{check_weight, Binding, Destination, Exchange};
+use filter::Filter;
 use request::ExchangeEntry;
 
 use self::exchange::ExchangeToQueueError;
 
 pub(crate) mod exchange;
-mod request;
+mod filter;
+mod hash_ring;
+pub(crate) mod request;
 
 pub async fn new_exchange(
+    req: HttpRequest,
     data: web::Data<AppState>,
     post_data: web::Json<NewExchangeRequest>,
 ) -> HttpResponse {
-    let mut queues = data.get_queues().lock().await;
-    for queue_id in post_data.queue_ids.iter() {
-        match queues.entry(queue_id.to_owned()) {
-            Entry::Vacant(_) => {
-                return HttpResponse::BadRequest().json(JsonResponse::new(
-                    None::<String>,
-                    format!("No queue with id {} was found", queue_id),
-                ))
-            }
-            Entry::Occupied(_) => (),
+    let namespace = get_namespace(&req);
+    let post_data = match post_data.into_inner().qualify(&namespace) {
+        Ok(p) => p,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(
+        &req,
+        ResourceType::EXCHANGE,
+        &post_data.id,
+        Permission::MANAGE,
+    ) {
+        return e.to_response();
+    }
+    // held until the exchange is created
+    let _namespaces = match check_quota(&data, &namespace.name, ResourceType::EXCHANGE).await {
+        Ok(n) => n,
+        Err(e) => return e.to_response(),
+    };
+    match create_exchange(&data, &post_data).await {
+        Ok(exchange_uuid) => {
+            let detail = Some(format!("{:?}", post_data.exchange_type));
+            audit(
+                &data,
+                &req,
+                AuditAction::ExchangeCreated,
+                &post_data.id,
+                detail,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(exchange_uuid, None::<String>))
         }
+        Err(e) => e.to_response(),
     }
+}
 
+// Validates and creates an exchange, returning its uuid
+pub async fn create_exchange(
+    data: &AppState,
+    post_data: &NewExchangeRequest,
+) -> Result<String, ApiError> {
+    // always lock exchanges before queues, as publishing through an exchange does
     let mut exchanges = data.get_exchanges().lock().await;
+    let queues = data.get_queues().lock().await;
+    let new_exchange = build_exchange(
+        post_data,
+        |id| exchanges.contains_key(id),
+        |id| queues.contains_key(id),
+    )?;
     match exchanges.entry(post_data.id.to_owned()) {
         Entry::Vacant(_) => {
-            let queue_ids = post_data.queue_ids.to_owned();
-            let new_exchange =
-                Exchange::new(post_data.id.to_owned(), queue_ids, &post_data.exchange_type);
             let exchange_uuid = new_exchange.uuid.to_string();
             exchanges.insert(post_data.id.to_owned(), new_exchange);
-            HttpResponse::Accepted().json(JsonResponse::new(exchange_uuid, None::<String>))
+            Ok(exchange_uuid)
         }
-        Entry::Occupied(_) => HttpResponse::Conflict().json(JsonResponse::new(
-            None::<String>,
+        Entry::Occupied(_) => Err(ApiError::new(
+            StatusCode::CONFLICT,
             format!("An exchange with id {} already exists", &post_data.id),
         )),
     }
 }
 
-pub async fn list_exchanges(data: web::Data<AppState>) -> HttpResponse {
-    let exchanges = data.get_exchanges().lock().await;
-    let mut vec_of_exchanges = vec![];
-    for exchange in exchanges.values() {
-        vec_of_exchanges.push(ExchangeEntry {
-            id: exchange.id.clone(),
-            queue_ids: exchange.queue_ids.clone(),
-            exchange_type: match exchange.exchange_type {
-                ExchangeType::FANOUT => ExchangeType::FANOUT,
-                ExchangeType::ID => ExchangeType::ID,
-            },
-        })
+// Validates a new exchange, whose destinations must be among the queues and exchanges
+pub fn build_exchange(
+    post_data: &NewExchangeRequest,
+    exchange_exists: impl Fn(&String) -> bool,
+    queue_exists: impl Fn(&String) -> bool,
+) -> Result<Exchange, ApiError> {
+    let mut bindings = vec![];
+    for queue_id in post_data.queue_ids.iter() {
+        bindings.push(Binding::new(
+            Destination::Queue(queue_id.to_owned()),
+            1,
+            None,
+            None,
+        ));
     }
+    for exchange_id in post_data.exchange_ids.iter() {
+        bindings.push(Binding::new(
+            Destination::Exchange(exchange_id.to_owned()),
+            1,
+            None,
+            None,
+        ));
+    }
+    for entry in post_data.bindings.iter() {
+        match entry.to_binding() {
+            Ok(b) => bindings.push(b),
+            Err(e) => return Err(ApiError::bad_request(e)),
+        }
+    }
+    for binding in bindings.iter() {
+        match &binding.destination {
+            Destination::Queue(queue_id) => {
+                if !queue_exists(queue_id) {
+                    return Err(ApiError::bad_request(format!(
+                        "No queue with id {} was found",
+                        queue_id
+                    )));
+                }
+            }
+            Destination::Exchange(exchange_id) => {
+                if *exchange_id == post_data.id || !exchange_exists(exchange_id) {
+                    return Err(ApiError::bad_request(format!(
+                        "No exchange with id {} was found",
+                        exchange_id
+                    )));
+                }
+            }
+        }
+    }
+
+    let alternate = match (
+        &post_data.alternate_queue_id,
+        &post_data.alternate_exchange_id,
+    ) {
+        (None, None) => None,
+        (Some(queue_id), None) => {
+            if !queue_exists(queue_id) {
+                return Err(ApiError::bad_request(format!(
+                    "No queue with id {} was found",
+                    queue_id
+                )));
+            }
+            Some(Destination::Queue(queue_id.to_owned()))
+        }
+        (None, Some(exchange_id)) => {
+            if *exchange_id == post_data.id || !exchange_exists(exchange_id) {
+                return Err(ApiError::bad_request(format!(
+                    "No exchange with id {} was found",
+                    exchange_id
+                )));
+            }
+            Some(Destination::Exchange(exchange_id.to_owned()))
+        }
+        (Some(_), Some(_)) => {
+            return Err(ApiError::bad_request(
+                "At most one of an alternate queue id or an alternate exchange id is allowed",
+            ))
+        }
+    };
+
+    Ok(Exchange::new(
+        post_data.id.to_owned(),
+        bindings,
+        &post_data.exchange_type,
+        alternate,
+    ))
+}
+
+// Lists the exchanges in the request's namespace
+pub async fn list_exchanges(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    let namespace = get_namespace(&req);
+    let vec_of_exchanges = get_exchange_entries(&data)
+        .await
+        .into_iter()
+        .filter_map(|e| e.unqualify(&namespace))
+        .collect::<Vec<ExchangeEntry>>();
     HttpResponse::Accepted().json(JsonResponse::new(vec_of_exchanges, None::<String>))
 }
 
-pub async fn add_message_to_exchange(
+pub async fn get_exchange_entries(data: &AppState) -> Vec<ExchangeEntry> {
+    let exchanges = data.get_exchanges().lock().await;
+    exchanges.values().map(ExchangeEntry::new).collect()
+}
+
+pub async fn bind_exchange(
+    req: HttpRequest,
     data: web::Data<AppState>,
-    post_data: web::Json<NewMessageRequest>,
+    post_data: web::Json<BindExchangeRequest>,
 ) -> HttpResponse {
+    let post_data = match post_data.into_inner().qualify(&get_namespace(&req)) {
+        Ok(p) => p,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(
+        &req,
+        ResourceType::EXCHANGE,
+        &post_data.exchange_id,
+        Permission::MANAGE,
+    ) {
+        return e.to_response();
+    }
+    match bind_to_exchange(&data, &post_data).await {
+        Ok(message) => {
+            let detail = binding_detail(&get_namespace(&req), &post_data);
+            audit(
+                &data,
+                &req,
+                AuditAction::ExchangeBound,
+                &post_data.exchange_id,
+                detail,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(message, None::<String>))
+        }
+        Err(e) => e.to_response(),
+    }
+}
+
+// What a binding's audit record says was bound, by its id within the namespace
+pub fn binding_detail(namespace: &Namespace, post_data: &BindExchangeRequest) -> Option<String> {
+    let local = |id: &String| namespace.unqualify(id).unwrap_or(id.to_owned());
+    match (&post_data.queue_id, &post_data.destination_exchange_id) {
+        (Some(id), _) => Some(format!("queue {}", local(id))),
+        (None, Some(id)) => Some(format!("exchange {}", local(id))),
+        (None, None) => None,
+    }
+}
+
+// Binds a queue or exchange to an exchange, returning a success message
+pub async fn bind_to_exchange(
+    data: &AppState,
+    post_data: &BindExchangeRequest,
+) -> Result<String, ApiError> {
     let exchange_id = &post_data.exchange_id;
+    check_weight(post_data.weight).map_err(ApiError::bad_request)?;
+    // filters are parsed once here, so a bad expression is rejected rather than never matching
+    let filter = match &post_data.filter {
+        Some(source) => match Filter::parse(source) {
+            Ok(f) => Some(f),
+            Err(e) => return Err(ApiError::bad_request(e.to_string())),
+        },
+        None => None,
+    };
 
     let mut exchanges = data.get_exchanges().lock().await;
+    let queues = data.get_queues().lock().await;
+    let destination = match (&post_data.queue_id, &post_data.destination_exchange_id) {
+        (Some(queue_id), None) => {
+            if !queues.contains_key(queue_id) {
+                return Err(ApiError::bad_request(format!(
+                    "No queue with id {} was found",
+                    queue_id
+                )));
+            }
+            Destination::Queue(queue_id.to_owned())
+        }
+        (None, Some(destination_id)) => {
+            let destination_exchange = match exchanges.get(destination_id) {
+                None => {
+                    return Err(ApiError::bad_request(format!(
+                        "No exchange with id {} was found",
+                        destination_id
+                    )))
+                }
+                Some(e) => e,
+            };
+            // binding an exchange that can already reach this one would let messages cycle
+            if destination_id == exchange_id
+                || destination_exchange.reaches(exchange_id, &|id| exchanges.get(id))
+            {
+                return Err(ApiError::bad_request(format!(
+                    "Binding exchange {} to exchange {} would create a cycle",
+                    destination_id, exchange_id
+                )));
+            }
+            Destination::Exchange(destination_id.to_owned())
+        }
+        _ => {
+            return Err(ApiError::bad_request(
+                "Exactly one of a queue id or a destination exchange id is required",
+            ))
+        }
+    };
+
     let exchange = match exchanges.get_mut(exchange_id) {
         None => {
-            return HttpResponse::BadRequest().json(JsonResponse::new(
-                None::<String>,
-                format!("No exchange with id {} was found", exchange_id),
-            ))
+            return Err(ApiError::bad_request(format!(
+                "No exchange with id {} was found",
+                exchange_id
+            )))
         }
-        Some(q) => q,
+        Some(e) => e,
     };
-    let messages_to_add = &post_data.messages;
-    let mut messages_to_send = vec![];
-    for message in messages_to_add.iter() {
-        let id = message.message_id.to_owned();
-        let content = message.content.to_owned();
-        let message_added = exchange.dispatch(id, content, &data).await;
-        match message_added {
-            Ok(v) => messages_to_send.extend(v),
-            Err(e) => match e {
-                ExchangeToQueueError::NoMatchingQueueError(_) => {
-                    return HttpResponse::BadRequest()
-                        .json(JsonResponse::new(None::<String>, e.to_string()))
-                }
-                ExchangeToQueueError::UnableToAddError => {
-                    return HttpResponse::InternalServerError()
-                        .json(JsonResponse::new(None::<String>, e.to_string()))
-                }
-            },
+    if exchange.is_bound(&destination, &post_data.key) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!(
+                "{} is already bound to exchange {}",
+                destination.get_id(),
+                exchange_id
+            ),
+        ));
+    }
+    exchange.add_binding(Binding::new(
+        destination,
+        post_data.weight,
+        filter,
+        post_data.key.to_owned(),
+    ));
+    Ok(format!("Successfully bound to exchange {}", exchange_id))
+}
+
+pub async fn add_message_to_exchange(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    post_data: web::Json<NewMessageRequest>,
+) -> HttpResponse {
+    let mut post_data = post_data.into_inner();
+    post_data.exchange_id = match get_namespace(&req).qualify(&post_data.exchange_id) {
+        Ok(id) => id,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(
+        &req,
+        ResourceType::EXCHANGE,
+        &post_data.exchange_id,
+        Permission::PUBLISH,
+    ) {
+        return e.to_response();
+    }
+    if let Err(e) = admit_messages(&data, &req, post_data.messages.len()).await {
+        return e.to_response();
+    }
+    match publish_to_exchange_as(&data, &post_data, get_api_key(&req).as_ref()).await {
+        Ok(messages_to_send) => {
+            let detail = Some(messages_to_send.join(", "));
+            audit(
+                &data,
+                &req,
+                AuditAction::MessagesPublished,
+                &post_data.exchange_id,
+                detail,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(messages_to_send, None::<String>))
+        }
+        Err(e) => {
+            give_back_messages(&data, &req, post_data.messages.len()).await;
+            e.to_response()
         }
     }
+}
+
+// Routes a batch of messages through an exchange, returning the uuids of the messages produced.
+// With a key, messages are only routed to the queues it may publish to.
+pub async fn publish_to_exchange_as(
+    data: &AppState,
+    post_data: &NewMessageRequest,
+    api_key: Option<&ApiKey>,
+) -> Result<Vec<String>, ApiError> {
+    let may_publish = |queue_id: &str| {
+        api_key.is_none_or(|k| k.permits(ResourceType::QUEUE, queue_id, Permission::PUBLISH))
+    };
+    let exchange_id = &post_data.exchange_id;
 
-    HttpResponse::Accepted().json(JsonResponse::new(messages_to_send, None::<String>))
+    let exchanges = data.get_exchanges().lock().await;
+    let exchange = match exchanges.get(exchange_id) {
+        None => {
+            return Err(ApiError::bad_request(format!(
+                "No exchange with id {} was found",
+                exchange_id
+            )))
+        }
+        Some(q) => q,
+    };
+    let mut queues = data.get_queues().lock().await;
+    let cipher = data.get_cipher().lock().await;
+    let messages = post_data
+        .messages
+        .iter()
+        .map(|m| {
+            (
+                m.message_id.to_owned(),
+                m.content.to_owned(),
+                m.get_properties(),
+            )
+        })
+        .collect();
+    // without the mandatory flag, messages that could not be routed are dropped
+    exchange
+        .dispatch_batch(
+            messages,
+            post_data.mandatory,
+            &exchanges,
+            &mut queues,
+            &cipher,
+            &may_publish,
+        )
+        .map_err(|e| ApiError::from(&e))
+}
+
+pub fn exchange_error_response(error: &ExchangeToQueueError) -> HttpResponse {
+    ApiError::from(error).to_response()
+}
+
+impl From<&ExchangeToQueueError> for ApiError {
+    fn from(error: &ExchangeToQueueError) -> Self {
+        match error {
+            ExchangeToQueueError::NoMatchingQueueError(_)
+            | ExchangeToQueueError::NoMatchingExchangeError(_) => {
+                ApiError::bad_request(error.to_string())
+            }
+            ExchangeToQueueError::RoutingLoopError(_) => {
+                ApiError::new(StatusCode::LOOP_DETECTED, error.to_string())
+            }
+            ExchangeToQueueError::QueueRejectedError(e) => ApiError::from(e),
+            ExchangeToQueueError::ForbiddenQueueError(_) => {
+                ApiError::new(StatusCode::FORBIDDEN, error.to_string())
+            }
+            ExchangeToQueueError::UnableToAddError => {
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+            }
+        }
+    }
 }
@@ -1,118 +1,708 @@
-use actix_web::web;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use aes_gcm::Aes256Gcm;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::app_types::AppState;
+use crate::queue_api::publish_to_queue;
+use crate::queue_api::queue::{MessageProperties, Queue, QueueError};
+
+use super::filter::{Filter, FilterContext};
+use super::hash_ring::HashRing;
+
+// the most exchanges a message may pass through before it is considered to be looping
+const MAX_ROUTING_DEPTH: usize = 16;
 
+// the largest weight a binding may have, which also bounds the points it places on a HASH ring
+pub const MAX_WEIGHT: u32 = 1000;
+
+#[allow(clippy::enum_variant_names)]
 pub enum ExchangeToQueueError {
     NoMatchingQueueError(String),
+    NoMatchingExchangeError(String),
+    RoutingLoopError(String),
+    QueueRejectedError(QueueError),
+    ForbiddenQueueError(String),
     UnableToAddError,
 }
 
-impl ExchangeToQueueError {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for ExchangeToQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ExchangeToQueueError::NoMatchingQueueError(s) => {
-                format!("No queue with id {} was found", s)
+                write!(f, "No queue with id {} was found", s)
+            }
+            ExchangeToQueueError::NoMatchingExchangeError(s) => {
+                write!(f, "No exchange with id {} was found", s)
+            }
+            ExchangeToQueueError::RoutingLoopError(s) => {
+                write!(
+                    f,
+                    "The message looped back to or went too deep at exchange {}",
+                    s
+                )
+            }
+            ExchangeToQueueError::QueueRejectedError(e) => write!(f, "{}", e),
+            ExchangeToQueueError::ForbiddenQueueError(s) => {
+                write!(f, "The API key may not publish to the queue {}", s)
             }
             ExchangeToQueueError::UnableToAddError => {
-                String::from("Something went wrong. Please try again.")
+                write!(f, "Something went wrong. Please try again.")
             }
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl From<QueueError> for ExchangeToQueueError {
+    fn from(error: QueueError) -> Self {
+        match error {
+            QueueError::NotFound(queue_id) => ExchangeToQueueError::NoMatchingQueueError(queue_id),
+            QueueError::MessageTooLarge(_, _)
+            | QueueError::QueueFull(_)
+            | QueueError::RateLimited(_) => ExchangeToQueueError::QueueRejectedError(error),
+            QueueError::Encryption | QueueError::Storage | QueueError::Compression => {
+                ExchangeToQueueError::UnableToAddError
+            }
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)] // the variant names are part of the api
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum ExchangeType {
-    FANOUT, // Fanout pushes message to all bound keys
-    ID,     // Id pushes message to queues with particular id
+    FANOUT,     // Fanout pushes message to all bound keys
+    ID,         // Id pushes message to queues with particular id
+    ROUNDROBIN, // Round robin pushes each message to the next binding in turn
+    WEIGHTED,   // Weighted pushes each message to one binding, in proportion to binding weights
+    HASH,       // Hash pushes messages with the same routing key (or id) to the same binding
+    DIRECT,     // Direct pushes message to bindings whose key is the routing key (or id)
+}
+
+// Where an exchange sends the messages it routes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    Queue(String),
+    Exchange(String),
+}
+
+impl Destination {
+    pub fn get_id(&self) -> &String {
+        match self {
+            Destination::Queue(id) | Destination::Exchange(id) => id,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub destination: Destination,
+    pub weight: u32, // the share of messages a WEIGHTED or HASH exchange sends to the destination
+    pub filter: Option<Filter>, // if set, only messages matching it are sent to the destination
+    pub key: Option<String>, // the routing key a DIRECT exchange sends to the destination
+}
+
+// Checks that a binding's weight is one WEIGHTED and HASH exchanges can work with
+pub fn check_weight(weight: u32) -> Result<(), String> {
+    if weight == 0 || weight > MAX_WEIGHT {
+        return Err(format!(
+            "The weight {} is invalid, it must be from 1 to {}",
+            weight, MAX_WEIGHT
+        ));
+    }
+    Ok(())
+}
+
+impl Binding {
+    pub fn new(
+        destination: Destination,
+        weight: u32,
+        filter: Option<Filter>,
+        key: Option<String>,
+    ) -> Self {
+        Binding {
+            destination,
+            weight,
+            filter,
+            key,
+        }
+    }
+
+    fn accepts(&self, context: &FilterContext) -> bool {
+        match &self.filter {
+            Some(f) => f.matches(context),
+            None => true,
+        }
+    }
 }
 
 pub struct Exchange {
-    pub id: String,                  // the exchange id
-    pub uuid: Uuid,                  // inner generated uuid for resource
-    pub queue_ids: Vec<String>,      // the queues that are bound to the exchange
-    pub exchange_type: ExchangeType, // what to do with messages
+    pub id: String,                     // the exchange id
+    pub uuid: Uuid,                     // inner generated uuid for resource
+    bindings: Vec<Binding>,             // the queues and exchanges that are bound to the exchange
+    pub exchange_type: ExchangeType,    // what to do with messages
+    pub alternate: Option<Destination>, // where messages no destination matched are sent
+    next: AtomicUsize,                  // how many messages ROUNDROBIN and WEIGHTED have sent
+    ring: HashRing,                     // where HASH sends each key, rebuilt when bindings change
+    routed: AtomicU64,                  // how many messages published to it reached a queue
+    unroutable: AtomicU64,              // how many messages published to it reached no queue
 }
 
 impl Exchange {
-    pub fn new(id: String, queue_ids: Vec<String>, exchange_type: &ExchangeType) -> Self {
-        Exchange {
+    pub fn new(
+        id: String,
+        bindings: Vec<Binding>,
+        exchange_type: &ExchangeType,
+        alternate: Option<Destination>,
+    ) -> Self {
+        let mut exchange = Exchange {
             id,
             uuid: Uuid::new_v4(),
-            queue_ids,
-            exchange_type: match exchange_type {
-                ExchangeType::FANOUT => ExchangeType::FANOUT,
-                ExchangeType::ID => ExchangeType::ID,
-            },
+            bindings: vec![],
+            exchange_type: *exchange_type,
+            alternate,
+            next: AtomicUsize::new(0),
+            ring: HashRing::default(),
+            routed: AtomicU64::new(0),
+            unroutable: AtomicU64::new(0),
+        };
+        exchange.set_bindings(bindings);
+        exchange
+    }
+
+    // How many messages published to the exchange were routed to a queue, and how many were not
+    pub fn get_counts(&self) -> (u64, u64) {
+        (
+            self.routed.load(Ordering::Relaxed),
+            self.unroutable.load(Ordering::Relaxed),
+        )
+    }
+
+    // Carries the counts of the exchange this one replaces over, so they keep counting up
+    pub fn keep_counts(&self, replaced: &Exchange) {
+        let (routed, unroutable) = replaced.get_counts();
+        self.routed.store(routed, Ordering::Relaxed);
+        self.unroutable.store(unroutable, Ordering::Relaxed);
+    }
+
+    pub fn get_bindings(&self) -> &Vec<Binding> {
+        &self.bindings
+    }
+
+    // a destination may be bound once per key, so a DIRECT exchange can send it several keys
+    pub fn is_bound(&self, destination: &Destination, key: &Option<String>) -> bool {
+        self.bindings
+            .iter()
+            .any(|b| b.destination == *destination && b.key == *key)
+    }
+
+    pub fn add_binding(&mut self, binding: Binding) {
+        let mut bindings = self.bindings.to_owned();
+        bindings.push(binding);
+        self.set_bindings(bindings);
+    }
+
+    fn set_bindings(&mut self, bindings: Vec<Binding>) {
+        // only HASH exchanges route by the ring, so the others don't pay for building one
+        self.ring = match self.exchange_type {
+            ExchangeType::HASH => {
+                let ring_bindings = bindings
+                    .iter()
+                    .map(|b| (b.destination.get_id().as_str(), b.weight))
+                    .collect::<Vec<(&str, u32)>>();
+                HashRing::new(&ring_bindings)
+            }
+            _ => HashRing::default(),
+        };
+        self.bindings = bindings;
+    }
+
+    pub fn get_queue_ids(&self) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter_map(|b| match &b.destination {
+                Destination::Queue(id) => Some(id.to_owned()),
+                Destination::Exchange(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn get_exchange_ids(&self) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter_map(|b| match &b.destination {
+                Destination::Exchange(id) => Some(id.to_owned()),
+                Destination::Queue(_) => None,
+            })
+            .collect()
+    }
+
+    // Whether a message sent to this exchange could reach the exchange with the given id
+    pub fn reaches<'a>(
+        &self,
+        exchange_id: &String,
+        exchanges: &impl Fn(&String) -> Option<&'a Exchange>,
+    ) -> bool {
+        let mut exchange_ids = self.get_exchange_ids();
+        if let Some(Destination::Exchange(id)) = &self.alternate {
+            exchange_ids.push(id.to_owned());
         }
+        exchange_ids.iter().any(|id| {
+            *id == *exchange_id
+                || match exchanges(id) {
+                    Some(e) => e.reaches(exchange_id, exchanges),
+                    None => false,
+                }
+        })
     }
 
-    pub async fn dispatch(
+    // Routes a message and publishes it to the queues it reaches, returning the uuids produced.
+    // The exchanges, queues and cipher are passed in as the caller already holds their locks.
+    #[allow(clippy::too_many_arguments)]
+    pub fn dispatch(
         &self,
         id: String,
         content: String,
-        app_data: &web::Data<AppState>,
+        properties: MessageProperties,
+        exchanges: &HashMap<String, Exchange>,
+        queues: &mut HashMap<String, Queue>,
+        cipher: &Aes256Gcm,
+        may_publish: &dyn Fn(&str) -> bool,
+    ) -> Result<Vec<String>, ExchangeToQueueError> {
+        let messages = vec![(id, content, properties)];
+        self.dispatch_batch(messages, true, exchanges, queues, cipher, may_publish)
+    }
+
+    // Routes a batch of messages and publishes them to the queues they reach, returning the uuids
+    // produced. Every message is routed and every queue checked before any is published to, so
+    // the batch is either published whole or not at all. Unless the batch is mandatory, messages
+    // that reach no queue are dropped rather than failing it.
+    pub fn dispatch_batch(
+        &self,
+        messages: Vec<(String, String, MessageProperties)>,
+        mandatory: bool,
+        exchanges: &HashMap<String, Exchange>,
+        queues: &mut HashMap<String, Queue>,
+        cipher: &Aes256Gcm,
+        may_publish: &dyn Fn(&str) -> bool,
     ) -> Result<Vec<String>, ExchangeToQueueError> {
-        match self.exchange_type {
-            ExchangeType::ID => self.id_dispatch(id, content, app_data).await,
-            ExchangeType::FANOUT => self.fanout_dispatch(id, content, app_data).await,
+        let mut routed = vec![];
+        for (id, content, properties) in messages {
+            let queue_ids = self.queues_for(&id, &content, &properties, exchanges)?;
+            // whatever the exchange type, a message that reaches no queue is unroutable
+            if queue_ids.is_empty() {
+                self.unroutable.fetch_add(1, Ordering::Relaxed);
+                if mandatory {
+                    let unmatched = match (self.exchange_type, &properties.routing_key) {
+                        (ExchangeType::ID, _) | (_, None) => id,
+                        (_, Some(key)) => key.to_owned(),
+                    };
+                    return Err(ExchangeToQueueError::NoMatchingQueueError(unmatched));
+                }
+                continue;
+            }
+            routed.push((id, content, properties, queue_ids));
+        }
+
+        // the batch goes nowhere unless the publisher may publish to every queue it reaches, and
+        // every queue can take all of the messages it is sent
+        let mut sizes = HashMap::<&String, Vec<usize>>::new();
+        for (_, content, _, queue_ids) in routed.iter() {
+            for queue_id in queue_ids.iter() {
+                if !may_publish(queue_id) {
+                    return Err(ExchangeToQueueError::ForbiddenQueueError(
+                        queue_id.to_owned(),
+                    ));
+                }
+                sizes.entry(queue_id).or_default().push(content.len());
+            }
+        }
+        for (queue_id, sizes) in sizes.iter() {
+            match queues.get(*queue_id) {
+                Some(queue) => queue.check_batch(sizes)?,
+                None => return Err(QueueError::NotFound(queue_id.to_string()).into()),
+            }
         }
+
+        let mut messages_produced = vec![];
+        for (id, content, properties, queue_ids) in routed.iter() {
+            for queue_id in queue_ids.iter() {
+                let message = publish_to_queue(
+                    queues,
+                    cipher,
+                    queue_id,
+                    id.to_owned(),
+                    content.to_owned(),
+                    properties.to_owned(),
+                )?;
+                messages_produced.push(message);
+            }
+            self.routed.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(messages_produced)
     }
 
-    async fn id_dispatch(
+    // The ids of the queues a message reaches, each only once even if it is reached through
+    // several bound exchanges
+    fn queues_for(
         &self,
-        id: String,
-        content: String,
-        app_data: &web::Data<AppState>,
+        id: &String,
+        content: &str,
+        properties: &MessageProperties,
+        exchanges: &HashMap<String, Exchange>,
     ) -> Result<Vec<String>, ExchangeToQueueError> {
-        let cipher = app_data.get_cipher().lock().await;
-        for queue_id in self.queue_ids.iter() {
-            // messages are sent to queues with same id as message id
-            if *queue_id == id {
-                let mut queues = app_data.get_queues().lock().await;
-                let queue: &mut crate::queue_api::queue::Queue = match queues.get_mut(queue_id) {
-                    None => {
-                        return Err(ExchangeToQueueError::NoMatchingQueueError(
-                            queue_id.to_owned(),
+        let mut queue_ids = vec![];
+        let key = match &properties.routing_key {
+            Some(k) => k.to_owned(),
+            None => id.to_owned(),
+        };
+        let context = FilterContext::new(&properties.attributes, content);
+        self.route(
+            id,
+            &key,
+            &context,
+            exchanges,
+            &mut vec![self.id.to_owned()],
+            &mut queue_ids,
+        )?;
+        Ok(queue_ids)
+    }
+
+    // Collects the ids of the queues a message should be published to
+    fn route(
+        &self,
+        id: &String,
+        key: &str,
+        context: &FilterContext,
+        exchanges: &HashMap<String, Exchange>,
+        path: &mut Vec<String>,
+        queue_ids: &mut Vec<String>,
+    ) -> Result<(), ExchangeToQueueError> {
+        // bindings whose filter the message does not match are left out of every exchange type
+        let bindings = self
+            .bindings
+            .iter()
+            .filter(|b| b.accepts(context))
+            .collect::<Vec<&Binding>>();
+        let mut destinations = match self.exchange_type {
+            ExchangeType::ID => id_destinations(&bindings, id),
+            ExchangeType::FANOUT => bindings.iter().map(|b| &b.destination).collect(),
+            ExchangeType::ROUNDROBIN => self
+                .round_robin_destination(&bindings)
+                .into_iter()
+                .collect(),
+            ExchangeType::WEIGHTED => self.weighted_destination(&bindings).into_iter().collect(),
+            ExchangeType::HASH => self.hash_destination(key, context).into_iter().collect(),
+            ExchangeType::DIRECT => key_destinations(&bindings, key),
+        };
+        // messages this exchange has nowhere to send may still reach queues through others, so
+        // whether they were routed at all is only known once every exchange has had its turn
+        if destinations.is_empty() {
+            destinations.extend(self.alternate.iter());
+        }
+        for destination in destinations {
+            match destination {
+                Destination::Queue(queue_id) => {
+                    if !queue_ids.contains(queue_id) {
+                        queue_ids.push(queue_id.to_owned());
+                    }
+                }
+                Destination::Exchange(exchange_id) => {
+                    if path.contains(exchange_id) || path.len() >= MAX_ROUTING_DEPTH {
+                        return Err(ExchangeToQueueError::RoutingLoopError(
+                            exchange_id.to_owned(),
                         ));
                     }
-                    Some(q) => q,
-                };
-                let message = queue.add_to_queue(&cipher, id, content);
-                match message {
-                    Ok(m) => return Ok(vec![m]),
-                    Err(_) => return Err(ExchangeToQueueError::UnableToAddError),
+                    let exchange = match exchanges.get(exchange_id) {
+                        None => {
+                            return Err(ExchangeToQueueError::NoMatchingExchangeError(
+                                exchange_id.to_owned(),
+                            ))
+                        }
+                        Some(e) => e,
+                    };
+                    path.push(exchange_id.to_owned());
+                    exchange.route(id, key, context, exchanges, path, queue_ids)?;
+                    path.pop();
                 }
             }
         }
-        return Err(ExchangeToQueueError::NoMatchingQueueError(id));
+        Ok(())
     }
 
-    async fn fanout_dispatch(
-        &self,
-        id: String,
-        content: String,
-        app_data: &web::Data<AppState>,
+    fn round_robin_destination<'a>(&self, bindings: &[&'a Binding]) -> Option<&'a Destination> {
+        if bindings.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(&bindings[next % bindings.len()].destination)
+    }
+
+    // each binding gets `weight` consecutive turns out of every `total weight` messages
+    fn weighted_destination<'a>(&self, bindings: &[&'a Binding]) -> Option<&'a Destination> {
+        let total_weight = bindings.iter().map(|b| b.weight as usize).sum::<usize>();
+        if total_weight == 0 {
+            return None;
+        }
+        let mut turn = self.next.fetch_add(1, Ordering::Relaxed) % total_weight;
+        for binding in bindings.iter() {
+            if turn < binding.weight as usize {
+                return Some(&binding.destination);
+            }
+            turn -= binding.weight as usize;
+        }
+        None
+    }
+
+    // keys owned by a binding the message does not match go to the next matching binding
+    fn hash_destination(&self, key: &str, context: &FilterContext) -> Option<&Destination> {
+        self.ring
+            .get(key, |idx| self.bindings[idx].accepts(context))
+            .map(|idx| &self.bindings[idx].destination)
+    }
+}
+
+// messages are sent to destinations with same id as message id
+fn id_destinations<'a>(bindings: &[&'a Binding], id: &String) -> Vec<&'a Destination> {
+    bindings
+        .iter()
+        .map(|b| &b.destination)
+        .filter(|d| *d.get_id() == *id)
+        .collect()
+}
+
+// messages are sent to destinations bound with the same key as the message's routing key
+fn key_destinations<'a>(bindings: &[&'a Binding], key: &str) -> Vec<&'a Destination> {
+    let mut destinations = vec![];
+    for binding in bindings.iter() {
+        // a destination bound with several keys still only gets the message once
+        if binding.key.as_deref() == Some(key) && !destinations.contains(&&binding.destination) {
+            destinations.push(&binding.destination);
+        }
+    }
+    destinations
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::aead::{KeyInit, OsRng};
+
+    use super::*;
+    use crate::blob_store::BlobStore;
+    use crate::queue_api::queue::QueueSettings;
+
+    fn queue_binding(id: &str, weight: u32) -> Binding {
+        Binding::new(Destination::Queue(id.to_owned()), weight, None, None)
+    }
+
+    // the queues a message with the given id reaches through the exchange
+    fn route(exchange: &Exchange, id: &str) -> Vec<String> {
+        let attributes = HashMap::new();
+        let context = FilterContext::new(&attributes, "");
+        let mut queue_ids = vec![];
+        let result = exchange.route(
+            &id.to_owned(),
+            id,
+            &context,
+            &HashMap::new(),
+            &mut vec![exchange.id.to_owned()],
+            &mut queue_ids,
+        );
+        assert!(result.is_ok());
+        queue_ids
+    }
+
+    #[test]
+    fn weighted_sends_messages_in_proportion_to_weight() {
+        let bindings = vec![queue_binding("a", 1), queue_binding("b", 3)];
+        let exchange = Exchange::new(String::from("e"), bindings, &ExchangeType::WEIGHTED, None);
+        let routed = (0..400)
+            .map(|i| route(&exchange, &i.to_string()))
+            .collect::<Vec<Vec<String>>>();
+        assert!(routed.iter().all(|r| r.len() == 1));
+        let to_a = routed.iter().filter(|r| r[0] == "a").count();
+        assert_eq!(to_a, 100);
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let bindings = vec![queue_binding("a", 1), queue_binding("b", 5)];
+        let exchange = Exchange::new(String::from("e"), bindings, &ExchangeType::ROUNDROBIN, None);
+        let routed = (0..4)
+            .map(|i| route(&exchange, &i.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(routed, vec![vec!["a"], vec!["b"], vec!["a"], vec!["b"]]);
+    }
+
+    #[test]
+    fn hash_sends_the_same_key_to_the_same_queue() {
+        let bindings = vec![queue_binding("a", 1), queue_binding("b", 1)];
+        let exchange = Exchange::new(String::from("e"), bindings, &ExchangeType::HASH, None);
+        for i in 0..50 {
+            assert_eq!(
+                route(&exchange, &i.to_string()),
+                route(&exchange, &i.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn only_hash_exchanges_build_a_ring() {
+        let bindings = vec![queue_binding("a", MAX_WEIGHT)];
+        let exchange = Exchange::new(String::from("e"), bindings, &ExchangeType::FANOUT, None);
+        assert_eq!(exchange.ring.get("key", |_| true), None);
+    }
+
+    fn dispatch(
+        exchange: &Exchange,
+        attributes: &[(&str, &str)],
     ) -> Result<Vec<String>, ExchangeToQueueError> {
-        let mut messages_produced = vec![];
-        let cipher = app_data.get_cipher().lock().await;
-        for queue_id in self.queue_ids.iter() {
-            let mut queues = app_data.get_queues().lock().await;
-            let queue = match queues.get_mut(queue_id) {
-                None => {
-                    return Err(ExchangeToQueueError::NoMatchingQueueError(
-                        queue_id.to_owned(),
-                    ));
-                }
-                Some(q) => q,
-            };
-            let message = match queue.add_to_queue(&cipher, id.to_owned(), content.to_owned()) {
-                Ok(m) => m,
-                Err(_) => return Err(ExchangeToQueueError::UnableToAddError),
-            };
-            messages_produced.push(message);
+        let cipher = Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng));
+        let properties = MessageProperties {
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        exchange.dispatch(
+            String::from("m"),
+            String::from("{}"),
+            properties,
+            &HashMap::new(),
+            &mut HashMap::new(),
+            &cipher,
+            &|_| true,
+        )
+    }
+
+    #[test]
+    fn messages_reaching_no_queue_are_unroutable_for_every_type() {
+        let types = [
+            ExchangeType::FANOUT,
+            ExchangeType::ID,
+            ExchangeType::ROUNDROBIN,
+            ExchangeType::WEIGHTED,
+            ExchangeType::HASH,
+            ExchangeType::DIRECT,
+        ];
+        for exchange_type in types.iter() {
+            let exchange = Exchange::new(String::from("e"), vec![], exchange_type, None);
+            let result = dispatch(&exchange, &[]);
+            assert!(matches!(
+                result,
+                Err(ExchangeToQueueError::NoMatchingQueueError(_))
+            ));
+            assert_eq!(exchange.get_counts(), (0, 1));
         }
-        Ok(messages_produced)
+    }
+
+    #[test]
+    fn messages_no_filter_matches_are_unroutable() {
+        let filter = Filter::parse("region = 'eu'").ok();
+        let binding = Binding::new(Destination::Queue(String::from("q")), 1, filter, None);
+        let exchange = Exchange::new(
+            String::from("e"),
+            vec![binding],
+            &ExchangeType::FANOUT,
+            None,
+        );
+        let result = dispatch(&exchange, &[("region", "us")]);
+        assert!(matches!(
+            result,
+            Err(ExchangeToQueueError::NoMatchingQueueError(_))
+        ));
+        assert_eq!(exchange.get_counts(), (0, 1));
+    }
+
+    #[test]
+    fn batches_are_published_whole_or_not_at_all() {
+        let blob_store = BlobStore::new(std::env::temp_dir().join("rqs-test-blobs")).unwrap();
+        let settings = |max_messages| QueueSettings {
+            read_timeout: 30,
+            max_batch: 10,
+            max_messages,
+            ..Default::default()
+        };
+        let mut queues = HashMap::from([
+            (
+                String::from("roomy"),
+                Queue::new(String::from("roomy"), settings(None), blob_store.clone()),
+            ),
+            (
+                String::from("small"),
+                Queue::new(String::from("small"), settings(Some(1)), blob_store.clone()),
+            ),
+        ]);
+        let bindings = vec![queue_binding("roomy", 1), queue_binding("small", 1)];
+        let exchange = Exchange::new(String::from("e"), bindings, &ExchangeType::FANOUT, None);
+        let cipher = Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng));
+        let message = |id: &str| {
+            (
+                id.to_owned(),
+                String::from("{}"),
+                MessageProperties::default(),
+            )
+        };
+        let batch = vec![message("1"), message("2")];
+        let result =
+            exchange.dispatch_batch(batch, true, &HashMap::new(), &mut queues, &cipher, &|_| {
+                true
+            });
+        assert!(matches!(
+            result,
+            Err(ExchangeToQueueError::QueueRejectedError(_))
+        ));
+        assert_eq!(queues["roomy"].get_size(), 0);
+        assert_eq!(queues["small"].get_size(), 0);
+
+        let result = exchange.dispatch_batch(
+            vec![message("3")],
+            true,
+            &HashMap::new(),
+            &mut queues,
+            &cipher,
+            &|_| true,
+        );
+        assert_eq!(result.ok().map(|uuids| uuids.len()), Some(2));
+        assert_eq!(exchange.get_counts(), (1, 0));
+    }
+
+    #[test]
+    fn unroutable_messages_are_dropped_from_batches_that_are_not_mandatory() {
+        let filter = Filter::parse("region = 'eu'").ok();
+        let binding = Binding::new(Destination::Queue(String::from("q")), 1, filter, None);
+        let exchange = Exchange::new(
+            String::from("e"),
+            vec![binding],
+            &ExchangeType::FANOUT,
+            None,
+        );
+        let cipher = Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng));
+        let batch = vec![(
+            String::from("1"),
+            String::from("{}"),
+            MessageProperties::default(),
+        )];
+        let result = exchange.dispatch_batch(
+            batch,
+            false,
+            &HashMap::new(),
+            &mut HashMap::new(),
+            &cipher,
+            &|_| true,
+        );
+        assert_eq!(result.ok(), Some(vec![]));
+        assert_eq!(exchange.get_counts(), (0, 1));
+    }
+
+    #[test]
+    fn weights_must_be_in_range() {
+        assert!(check_weight(0).is_err());
+        assert!(check_weight(1).is_ok());
+        assert!(check_weight(MAX_WEIGHT).is_ok());
+        assert!(check_weight(MAX_WEIGHT + 1).is_err());
+        assert!(check_weight(1 << 26).is_err());
     }
 }
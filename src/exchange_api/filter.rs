@@ -0,0 +1,540 @@
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::Value as JsonValue;
+
+// the longest filter accepted, in characters
+const MAX_FILTER_LENGTH: usize = 4096;
+
+// how deeply NOTs and parentheses may nest, so parsing a filter can't overflow the stack
+const MAX_FILTER_DEPTH: usize = 64;
+
+// Filters are boolean expressions over a message's attributes and JSON body, for example
+// `region = 'eu' AND amount > 100`. Identifiers name an attribute if the message has one by that
+// name, and otherwise a field of the body, with `.` separating the fields of nested objects.
+
+#[derive(Debug, Clone)]
+pub struct FilterError(String);
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid filter: {}", self.0)
+    }
+}
+
+// What a filter is evaluated against - the body is only parsed if a filter needs it
+pub struct FilterContext<'a> {
+    attributes: &'a HashMap<String, String>,
+    content: &'a str,
+    body: OnceCell<Option<JsonValue>>,
+}
+
+impl<'a> FilterContext<'a> {
+    pub fn new(attributes: &'a HashMap<String, String>, content: &'a str) -> Self {
+        FilterContext {
+            attributes,
+            content,
+            body: OnceCell::new(),
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Value {
+        if let Some(attribute) = self.attributes.get(path) {
+            return Value::Str(attribute.to_owned());
+        }
+        let body = self
+            .body
+            .get_or_init(|| serde_json::from_str(self.content).ok());
+        let mut field = match body {
+            Some(b) => b,
+            None => return Value::Null,
+        };
+        for key in path.split('.') {
+            field = match field.get(key) {
+                Some(f) => f,
+                None => return Value::Null,
+            };
+        }
+        match field {
+            JsonValue::String(s) => Value::Str(s.to_owned()),
+            JsonValue::Number(n) => match n.as_f64() {
+                Some(n) => Value::Num(n),
+                None => Value::Null,
+            },
+            JsonValue::Bool(b) => Value::Bool(*b),
+            JsonValue::Null | JsonValue::Array(_) | JsonValue::Object(_) => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    source: String, // the expression as the user wrote it
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self, FilterError> {
+        if source.chars().count() > MAX_FILTER_LENGTH {
+            return Err(FilterError(format!(
+                "filters may be at most {} characters",
+                MAX_FILTER_LENGTH
+            )));
+        }
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(FilterError(format!("unexpected {:?}", token)));
+        }
+        Ok(Filter {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+
+    pub fn get_source(&self) -> &String {
+        &self.source
+    }
+
+    pub fn matches(&self, context: &FilterContext) -> bool {
+        self.expr.eval(context)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Field(String),
+    Literal(Value),
+}
+
+impl Operand {
+    fn eval(&self, context: &FilterContext) -> Value {
+        match self {
+            Operand::Field(path) => context.lookup(path),
+            Operand::Literal(value) => value.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Op, Operand),
+    In(Operand, Vec<Value>),
+}
+
+impl Expr {
+    fn eval(&self, context: &FilterContext) -> bool {
+        match self {
+            Expr::And(l, r) => l.eval(context) && r.eval(context),
+            Expr::Or(l, r) => l.eval(context) || r.eval(context),
+            Expr::Not(e) => !e.eval(context),
+            Expr::Compare(l, op, r) => compare(&l.eval(context), *op, &r.eval(context)),
+            Expr::In(operand, values) => {
+                let value = operand.eval(context);
+                values.iter().any(|v| compare(&value, Op::Eq, v))
+            }
+        }
+    }
+}
+
+// Attributes are always strings, so they are compared as numbers against numbers when they can be
+fn compare(left: &Value, op: Op, right: &Value) -> bool {
+    let ordering = match (left, right) {
+        (Value::Null, Value::Null) => return matches!(op, Op::Eq),
+        (Value::Null, _) | (_, Value::Null) => return matches!(op, Op::Ne),
+        (Value::Num(l), Value::Num(r)) => l.partial_cmp(r),
+        (Value::Str(l), Value::Num(r)) => l.parse::<f64>().ok().and_then(|l| l.partial_cmp(r)),
+        (Value::Num(l), Value::Str(r)) => r.parse::<f64>().ok().and_then(|r| l.partial_cmp(&r)),
+        (Value::Str(l), Value::Str(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        (Value::Str(s), Value::Bool(b)) | (Value::Bool(b), Value::Str(s)) => {
+            match s.parse::<bool>() {
+                Ok(s) if s == *b => Some(std::cmp::Ordering::Equal),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    let ordering = match ordering {
+        Some(o) => o,
+        // values that cannot be compared are only ever unequal
+        None => return matches!(op, Op::Ne),
+    };
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    In,
+    True,
+    False,
+    Null,
+    LParen,
+    RParen,
+    Comma,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, FilterError> {
+    let chars = source.chars().collect::<Vec<char>>();
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        match c {
+            _ if c.is_whitespace() => pos += 1,
+            '(' | ')' | ',' => {
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    _ => Token::Comma,
+                });
+                pos += 1;
+            }
+            '=' | '!' | '<' | '>' => {
+                let next = chars.get(pos + 1).copied();
+                let (op, len) = match (c, next) {
+                    ('=', _) => (Op::Eq, 1),
+                    ('!', Some('=')) | ('<', Some('>')) => (Op::Ne, 2),
+                    ('<', Some('=')) => (Op::Le, 2),
+                    ('>', Some('=')) => (Op::Ge, 2),
+                    ('<', _) => (Op::Lt, 1),
+                    ('>', _) => (Op::Gt, 1),
+                    _ => return Err(FilterError(format!("unexpected '{}' at {}", c, pos))),
+                };
+                tokens.push(Token::Op(op));
+                pos += len;
+            }
+            '\'' => {
+                // strings are single quoted, with '' standing for a quote
+                let mut value = String::new();
+                pos += 1;
+                loop {
+                    match (chars.get(pos), chars.get(pos + 1)) {
+                        (Some('\''), Some('\'')) => {
+                            value.push('\'');
+                            pos += 2;
+                        }
+                        (Some('\''), _) => {
+                            pos += 1;
+                            break;
+                        }
+                        (Some(ch), _) => {
+                            value.push(*ch);
+                            pos += 1;
+                        }
+                        (None, _) => return Err(FilterError(String::from("unterminated string"))),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            _ if c.is_ascii_digit() || c == '-' => {
+                let start = pos;
+                pos += 1;
+                while pos < chars.len() && (chars[pos].is_ascii_digit() || chars[pos] == '.') {
+                    pos += 1;
+                }
+                let literal = chars[start..pos].iter().collect::<String>();
+                match literal.parse::<f64>() {
+                    Ok(n) => tokens.push(Token::Num(n)),
+                    Err(_) => return Err(FilterError(format!("invalid number {}", literal))),
+                }
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let start = pos;
+                while pos < chars.len()
+                    && (chars[pos].is_alphanumeric() || chars[pos] == '_' || chars[pos] == '.')
+                {
+                    pos += 1;
+                }
+                let word = chars[start..pos].iter().collect::<String>();
+                tokens.push(match word.to_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    "IN" => Token::In,
+                    "TRUE" => Token::True,
+                    "FALSE" => Token::False,
+                    "NULL" => Token::Null,
+                    _ => Token::Ident(word),
+                });
+            }
+            _ => return Err(FilterError(format!("unexpected '{}' at {}", c, pos))),
+        }
+    }
+    Ok(tokens)
+}
+
+// A recursive descent parser, from lowest to highest precedence: OR, AND, NOT, comparisons
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize, // how many NOTs and parentheses the parser is inside
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, FilterError> {
+        let token = match self.tokens.get(self.pos) {
+            Some(t) => t.to_owned(),
+            None => return Err(FilterError(String::from("unexpected end of expression"))),
+        };
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), FilterError> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(FilterError(format!(
+                "expected {:?} but found {:?}",
+                expected, token
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.parse_not()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, FilterError> {
+        if self.peek() == Some(&Token::Not) {
+            self.pos += 1;
+            self.enter()?;
+            let expr = Expr::Not(Box::new(self.parse_not()?));
+            self.depth -= 1;
+            return Ok(expr);
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            self.enter()?;
+            let expr = self.parse_or()?;
+            self.expect(Token::RParen)?;
+            self.depth -= 1;
+            return Ok(expr);
+        }
+        self.parse_comparison()
+    }
+
+    fn enter(&mut self) -> Result<(), FilterError> {
+        self.depth += 1;
+        if self.depth > MAX_FILTER_DEPTH {
+            return Err(FilterError(format!(
+                "NOTs and parentheses may be nested at most {} deep",
+                MAX_FILTER_DEPTH
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, FilterError> {
+        let left = self.parse_operand()?;
+        match self.next()? {
+            Token::Op(op) => Ok(Expr::Compare(left, op, self.parse_operand()?)),
+            Token::In => {
+                self.expect(Token::LParen)?;
+                let mut values = vec![];
+                loop {
+                    match self.parse_operand()? {
+                        Operand::Literal(value) => values.push(value),
+                        Operand::Field(f) => {
+                            return Err(FilterError(format!(
+                                "IN only takes literals, but found {}",
+                                f
+                            )))
+                        }
+                    }
+                    match self.next()? {
+                        Token::Comma => (),
+                        Token::RParen => break,
+                        token => return Err(FilterError(format!("unexpected {:?}", token))),
+                    }
+                }
+                Ok(Expr::In(left, values))
+            }
+            token => Err(FilterError(format!(
+                "expected a comparison but found {:?}",
+                token
+            ))),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, FilterError> {
+        match self.next()? {
+            Token::Ident(path) => Ok(Operand::Field(path)),
+            Token::Str(s) => Ok(Operand::Literal(Value::Str(s))),
+            Token::Num(n) => Ok(Operand::Literal(Value::Num(n))),
+            Token::True => Ok(Operand::Literal(Value::Bool(true))),
+            Token::False => Ok(Operand::Literal(Value::Bool(false))),
+            Token::Null => Ok(Operand::Literal(Value::Null)),
+            token => Err(FilterError(format!(
+                "expected a field or value but found {:?}",
+                token
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(filter: &str, attributes: &[(&str, &str)], content: &str) -> bool {
+        let attributes = attributes
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>();
+        let filter = Filter::parse(filter).unwrap();
+        filter.matches(&FilterContext::new(&attributes, content))
+    }
+
+    #[test]
+    fn compares_attributes_and_body_fields() {
+        let body = r#"{"amount": 150, "customer": {"tier": "gold"}, "paid": true}"#;
+        assert!(matches("region = 'eu'", &[("region", "eu")], body));
+        assert!(!matches("region = 'eu'", &[("region", "us")], body));
+        assert!(matches("amount > 100", &[], body));
+        assert!(matches("customer.tier = 'gold'", &[], body));
+        assert!(matches("paid = true", &[], body));
+        // attributes are strings, but compare as numbers against numbers
+        assert!(matches("priority >= 5", &[("priority", "7")], "not json"));
+    }
+
+    #[test]
+    fn attributes_shadow_body_fields() {
+        assert!(matches(
+            "amount = 1",
+            &[("amount", "1")],
+            r#"{"amount": 2}"#
+        ));
+    }
+
+    #[test]
+    fn missing_fields_are_null() {
+        assert!(matches("missing = NULL", &[], "{}"));
+        assert!(matches("missing != 'x'", &[], "{}"));
+        assert!(!matches("missing = 'x'", &[], "{}"));
+        assert!(!matches("missing > 1", &[], "not json"));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let attributes = [("a", "1"), ("b", "2")];
+        assert!(matches("a = 1 OR a = 2 AND b = 3", &attributes, ""));
+        assert!(!matches("(a = 1 OR a = 2) AND b = 3", &attributes, ""));
+        assert!(matches("NOT a = 2 AND b = 2", &attributes, ""));
+    }
+
+    #[test]
+    fn in_matches_any_literal() {
+        assert!(matches("region IN ('eu', 'uk')", &[("region", "uk")], ""));
+        assert!(!matches("region IN ('eu', 'uk')", &[("region", "us")], ""));
+        assert!(Filter::parse("region IN (other)").is_err());
+    }
+
+    #[test]
+    fn quotes_are_doubled_in_strings() {
+        assert!(matches("name = 'O''Brien'", &[("name", "O'Brien")], ""));
+        assert!(Filter::parse("name = 'unterminated").is_err());
+    }
+
+    #[test]
+    fn keywords_are_case_insensitive() {
+        assert!(matches(
+            "a = 1 and not b = 1",
+            &[("a", "1"), ("b", "2")],
+            ""
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        for filter in [
+            "",
+            "a =",
+            "a = 1 b = 2",
+            "(a = 1",
+            "a = 1)",
+            "a ! 1",
+            "a = 1 AND",
+        ] {
+            assert!(Filter::parse(filter).is_err(), "{} parsed", filter);
+        }
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let nested = format!("{}a = 1{}", "(".repeat(64), ")".repeat(64));
+        assert!(Filter::parse(&nested).is_ok());
+        let too_nested = format!("{}a = 1{}", "(".repeat(65), ")".repeat(65));
+        assert!(Filter::parse(&too_nested).is_err());
+        let nots = format!("{}a = 1", "NOT ".repeat(65));
+        assert!(Filter::parse(&nots).is_err());
+        // deeper than the stack could take, were it not limited
+        assert!(Filter::parse(&"(".repeat(4000)).is_err());
+    }
+
+    #[test]
+    fn rejects_long_filters() {
+        let long = format!("a = '{}'", "x".repeat(MAX_FILTER_LENGTH));
+        assert!(Filter::parse(&long).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+// the number of points each unit of weight places on the ring - more points spread keys more evenly
+const POINTS_PER_WEIGHT: u32 = 64;
+
+// the most points one binding places on the ring, whatever its weight
+const MAX_POINTS: u32 = super::exchange::MAX_WEIGHT * POINTS_PER_WEIGHT;
+
+// A consistent hash ring over an exchange's bindings, so the same key always lands on the same
+// binding and adding or removing a binding only moves the keys of its neighbours
+#[derive(Debug, Default)]
+pub struct HashRing {
+    points: Vec<(u64, usize)>, // (hash, binding index), sorted by hash
+}
+
+impl HashRing {
+    // Builds a ring from the (id, weight) of each binding, in binding order
+    pub fn new(bindings: &[(&str, u32)]) -> Self {
+        let mut points = vec![];
+        for (idx, (id, weight)) in bindings.iter().enumerate() {
+            let count = weight
+                .checked_mul(POINTS_PER_WEIGHT)
+                .map_or(MAX_POINTS, |c| c.min(MAX_POINTS));
+            for point in 0..count {
+                points.push((hash(&format!("{}#{}", id, point)), idx));
+            }
+        }
+        points.sort();
+        HashRing { points }
+    }
+
+    // The index of the binding that owns the given key, skipping over the points of bindings that
+    // are not accepted so their keys move to the next binding along the ring
+    pub fn get(&self, key: &str, accepts: impl Fn(usize) -> bool) -> Option<usize> {
+        let key_hash = hash(key);
+        let start = self.points.partition_point(|(h, _)| *h < key_hash);
+        // keys past the last point wrap around to the first
+        (0..self.points.len())
+            .map(|offset| self.points[(start + offset) % self.points.len()].1)
+            .find(|idx| accepts(*idx))
+    }
+}
+
+// FNV-1a, which unlike the std hasher is stable across releases and restarts, followed by a
+// finalizer so keys differing only in their last bytes still spread across the whole ring
+fn hash(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(ring: &HashRing, keys: usize) -> Vec<Option<usize>> {
+        (0..keys)
+            .map(|k| ring.get(&format!("key-{}", k), |_| true))
+            .collect()
+    }
+
+    #[test]
+    fn keys_always_land_on_the_same_binding() {
+        let ring = HashRing::new(&[("a", 1), ("b", 1), ("c", 1)]);
+        let again = HashRing::new(&[("a", 1), ("b", 1), ("c", 1)]);
+        assert_eq!(owners(&ring, 1000), owners(&again, 1000));
+    }
+
+    #[test]
+    fn keys_spread_by_weight() {
+        let ring = HashRing::new(&[("a", 1), ("b", 3)]);
+        let owners = owners(&ring, 10_000);
+        let to_b = owners.iter().filter(|o| **o == Some(1)).count();
+        assert!((6_500..8_500).contains(&to_b), "{} keys went to b", to_b);
+    }
+
+    #[test]
+    fn adding_a_binding_only_moves_keys_to_it() {
+        let before = owners(&HashRing::new(&[("a", 1), ("b", 1)]), 1000);
+        let after = owners(&HashRing::new(&[("a", 1), ("b", 1), ("c", 1)]), 1000);
+        for (before, after) in before.iter().zip(after.iter()) {
+            assert!(before == after || *after == Some(2));
+        }
+        assert!(after.contains(&Some(2)));
+    }
+
+    #[test]
+    fn skips_bindings_that_are_not_accepted() {
+        let ring = HashRing::new(&[("a", 1), ("b", 1)]);
+        for k in 0..100 {
+            assert_eq!(ring.get(&format!("key-{}", k), |idx| idx == 1), Some(1));
+        }
+        assert_eq!(ring.get("key", |_| false), None);
+    }
+
+    #[test]
+    fn an_empty_ring_owns_nothing() {
+        assert_eq!(HashRing::new(&[]).get("key", |_| true), None);
+        assert_eq!(HashRing::default().get("key", |_| true), None);
+    }
+
+    #[test]
+    fn huge_weights_are_capped() {
+        let ring = HashRing::new(&[("a", u32::MAX)]);
+        assert_eq!(ring.points.len(), MAX_POINTS as usize);
+    }
+}
@@ -1,23 +1,117 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
-use super::exchange::ExchangeType;
+use super::exchange::{check_weight, Binding, Destination, Exchange, ExchangeType};
+use super::filter::Filter;
+use crate::app_types::ApiError;
+use crate::namespace_api::namespace::Namespace;
+use crate::queue_api::queue::MessageProperties;
 
-#[derive(Deserialize)]
+fn default_mandatory() -> bool {
+    true
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NewExchangeRequest {
     pub id: String,
+    #[serde(default)]
     pub queue_ids: Vec<String>,
+    #[serde(default)]
+    pub exchange_ids: Vec<String>,
+    #[serde(default)]
+    pub bindings: Vec<BindingEntry>,
     #[serde(alias = "name")]
     pub exchange_type: ExchangeType,
+    pub alternate_queue_id: Option<String>,
+    pub alternate_exchange_id: Option<String>,
+}
+
+impl NewExchangeRequest {
+    // The request that would make an exchange like the entry, with all of its bindings given
+    // as bindings
+    pub fn new(entry: ExchangeEntry) -> Self {
+        NewExchangeRequest {
+            id: entry.id,
+            queue_ids: vec![],
+            exchange_ids: vec![],
+            bindings: entry.bindings,
+            exchange_type: entry.exchange_type,
+            alternate_queue_id: entry.alternate_queue_id,
+            alternate_exchange_id: entry.alternate_exchange_id,
+        }
+    }
+
+    // Puts the exchange, and everything it is bound to, in a namespace
+    pub fn qualify(mut self, namespace: &Namespace) -> Result<Self, ApiError> {
+        self.id = namespace.qualify(&self.id)?;
+        self.queue_ids = namespace.qualify_all(&self.queue_ids)?;
+        self.exchange_ids = namespace.qualify_all(&self.exchange_ids)?;
+        for binding in self.bindings.iter_mut() {
+            binding.queue_id = namespace.qualify_optional(&binding.queue_id)?;
+            binding.exchange_id = namespace.qualify_optional(&binding.exchange_id)?;
+        }
+        self.alternate_queue_id = namespace.qualify_optional(&self.alternate_queue_id)?;
+        self.alternate_exchange_id = namespace.qualify_optional(&self.alternate_exchange_id)?;
+        Ok(self)
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ExchangeEntry {
     pub id: String,
     pub queue_ids: Vec<String>,
+    pub exchange_ids: Vec<String>,
+    pub bindings: Vec<BindingEntry>,
     #[serde(alias = "name")]
     pub exchange_type: ExchangeType,
+    pub alternate_queue_id: Option<String>,
+    pub alternate_exchange_id: Option<String>,
+}
+
+impl ExchangeEntry {
+    pub fn new(exchange: &Exchange) -> Self {
+        ExchangeEntry {
+            id: exchange.id.to_owned(),
+            queue_ids: exchange.get_queue_ids(),
+            exchange_ids: exchange.get_exchange_ids(),
+            bindings: exchange
+                .get_bindings()
+                .iter()
+                .map(BindingEntry::new)
+                .collect(),
+            exchange_type: exchange.exchange_type,
+            alternate_queue_id: match &exchange.alternate {
+                Some(Destination::Queue(id)) => Some(id.to_owned()),
+                _ => None,
+            },
+            alternate_exchange_id: match &exchange.alternate {
+                Some(Destination::Exchange(id)) => Some(id.to_owned()),
+                _ => None,
+            },
+        }
+    }
+
+    // The entry as a namespace sees it, or None if the exchange is in another namespace
+    pub fn unqualify(mut self, namespace: &Namespace) -> Option<Self> {
+        let local = |id: &String| namespace.unqualify(id).unwrap_or(id.to_owned());
+        self.id = namespace.unqualify(&self.id)?;
+        self.queue_ids = self.queue_ids.iter().map(local).collect();
+        self.exchange_ids = self.exchange_ids.iter().map(local).collect();
+        for binding in self.bindings.iter_mut() {
+            binding.queue_id = binding.queue_id.as_ref().map(local);
+            binding.exchange_id = binding.exchange_id.as_ref().map(local);
+        }
+        self.alternate_queue_id = self.alternate_queue_id.as_ref().map(local);
+        self.alternate_exchange_id = self.alternate_exchange_id.as_ref().map(local);
+        Some(self)
+    }
 }
 
 #[derive(Deserialize)]
@@ -25,6 +119,22 @@ pub struct ExchangeEntry {
 pub struct NewMessage {
     pub message_id: String,
     pub content: String,
+    pub reply_to: Option<String>,
+    pub correlation_id: Option<String>,
+    pub routing_key: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl NewMessage {
+    pub fn get_properties(&self) -> MessageProperties {
+        MessageProperties {
+            reply_to: self.reply_to.to_owned(),
+            correlation_id: self.correlation_id.to_owned(),
+            routing_key: self.routing_key.to_owned(),
+            attributes: self.attributes.to_owned(),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -32,4 +142,77 @@ pub struct NewMessage {
 pub struct NewMessageRequest {
     pub messages: Vec<NewMessage>,
     pub exchange_id: String,
+    #[serde(default = "default_mandatory")]
+    pub mandatory: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BindExchangeRequest {
+    pub exchange_id: String,
+    pub queue_id: Option<String>,
+    pub destination_exchange_id: Option<String>,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub filter: Option<String>,
+    pub key: Option<String>,
+}
+
+impl BindExchangeRequest {
+    pub fn qualify(mut self, namespace: &Namespace) -> Result<Self, ApiError> {
+        self.exchange_id = namespace.qualify(&self.exchange_id)?;
+        self.queue_id = namespace.qualify_optional(&self.queue_id)?;
+        self.destination_exchange_id = namespace.qualify_optional(&self.destination_exchange_id)?;
+        Ok(self)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BindingEntry {
+    pub queue_id: Option<String>,
+    pub exchange_id: Option<String>,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub filter: Option<String>,
+    pub key: Option<String>,
+}
+
+impl BindingEntry {
+    pub fn new(binding: &Binding) -> Self {
+        let (queue_id, exchange_id) = match &binding.destination {
+            Destination::Queue(id) => (Some(id.to_owned()), None),
+            Destination::Exchange(id) => (None, Some(id.to_owned())),
+        };
+        BindingEntry {
+            queue_id,
+            exchange_id,
+            weight: binding.weight,
+            filter: binding.filter.as_ref().map(|f| f.get_source().to_owned()),
+            key: binding.key.to_owned(),
+        }
+    }
+
+    pub fn to_binding(&self) -> Result<Binding, String> {
+        check_weight(self.weight)?;
+        let destination = match (&self.queue_id, &self.exchange_id) {
+            (Some(id), None) => Destination::Queue(id.to_owned()),
+            (None, Some(id)) => Destination::Exchange(id.to_owned()),
+            _ => {
+                return Err(String::from(
+                    "Exactly one of a queue id or an exchange id is required for a binding",
+                ))
+            }
+        };
+        let filter = match &self.filter {
+            Some(source) => Some(Filter::parse(source).map_err(|e| e.to_string())?),
+            None => None,
+        };
+        Ok(Binding::new(
+            destination,
+            self.weight,
+            filter,
+            self.key.to_owned(),
+        ))
+    }
 }
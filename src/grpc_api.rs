@@ -0,0 +1,546 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, web};
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+
+use crate::app_types::{ApiError, AppState};
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::authenticate_key;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::identity::Identity;
+use crate::consumer_api::wakeup::Wakeup;
+use crate::exchange_api::request as exchange_request;
+use crate::exchange_api::{
+    bind_to_exchange, binding_detail, create_exchange, get_exchange_entries, publish_to_exchange_as,
+};
+use crate::message_api::request as message_request;
+use crate::message_api::request::GetMessageResponse;
+use crate::message_api::{publish_messages, receive_messages, remove_message};
+use crate::namespace_api::check_quota;
+use crate::queue_api::create_queue;
+use crate::queue_api::request::{NewQueueRequest, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::rate_limit::RateLimit;
+use service::rqs_server::{Rqs, RqsServer};
+
+mod proto;
+
+mod service {
+    include!(concat!(env!("OUT_DIR"), "/rqs.Rqs.rs"));
+}
+
+// calls are in the namespace this metadata names, or else their key's, as REST requests are with
+// the X-Rqs-Namespace header
+const NAMESPACE_METADATA: &str = "x-rqs-namespace";
+
+// Serves the gRPC API, sharing the REST API's state
+#[allow(clippy::result_large_err)] // tonic's interceptors return a bare Status
+pub async fn serve(
+    data: web::Data<AppState>,
+    address: SocketAddr,
+) -> Result<(), tonic::transport::Error> {
+    let require_api_keys = data.requires_api_keys();
+    let service = RqsServer::with_interceptor(RqsService { data }, move |request| {
+        take_bearer_token(request, require_api_keys)
+    });
+    Server::builder().add_service(service).serve(address).await
+}
+
+// The API key a call was made with, from its metadata
+#[derive(Clone)]
+struct BearerToken(String);
+
+// Moves the bearer token in a call's `authorization` metadata to its extensions, turning away
+// calls without one when API keys are required. The key itself is checked by the handlers, as
+// that needs the keys' lock.
+#[allow(clippy::result_large_err)]
+fn take_bearer_token(
+    mut request: Request<()>,
+    require_api_keys: bool,
+) -> Result<Request<()>, Status> {
+    let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_owned());
+    match token {
+        Some(token) => {
+            request.extensions_mut().insert(BearerToken(token));
+            Ok(request)
+        }
+        None if require_api_keys => Err(Status::unauthenticated(
+            "An API key is required, as a bearer token in the authorization metadata",
+        )),
+        None => Ok(request),
+    }
+}
+
+struct RqsService {
+    data: web::Data<AppState>,
+}
+
+impl RqsService {
+    async fn authenticate<T>(&self, request: &Request<T>) -> Result<Identity, Status> {
+        let token = request
+            .extensions()
+            .get::<BearerToken>()
+            .map(|t| t.0.as_str());
+        let namespace = request
+            .metadata()
+            .get(NAMESPACE_METADATA)
+            .and_then(|v| v.to_str().ok());
+        Ok(authenticate_key(&self.data, token, namespace).await?)
+    }
+
+    async fn authorize(
+        &self,
+        identity: &Identity,
+        resource_type: ResourceType,
+        id: &str,
+        permission: Permission,
+    ) -> Result<(), Status> {
+        Ok(identity
+            .authorize(&self.data, resource_type, id, permission)
+            .await?)
+    }
+}
+
+#[tonic::async_trait]
+impl Rqs for RqsService {
+    type ReceiveStreamStream = Pin<Box<dyn Stream<Item = Result<proto::Message, Status>> + Send>>;
+
+    async fn new_queue(
+        &self,
+        request: Request<proto::NewQueueRequest>,
+    ) -> Result<Response<proto::NewQueueReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let new_queue_request = NewQueueRequest {
+            read_timeout: request.read_timeout,
+            queue_id: request.queue_id,
+            max_batch: request.max_batch,
+            max_message_size: request.max_message_size,
+            offload_large_messages: request.offload_large_messages,
+            compression: match request.compression {
+                Some(c) => Some(parse_enum("compression", c)?),
+                None => None,
+            },
+            compression_threshold: request
+                .compression_threshold
+                .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD),
+            max_messages: request.max_messages,
+            max_bytes: request.max_bytes,
+            overflow_policy: match request.overflow_policy {
+                Some(p) => parse_enum("overflow policy", p)?,
+                None => Default::default(),
+            },
+            dead_letter_queue_id: request.dead_letter_queue_id,
+            rate_limit: RateLimit::default(),
+        }
+        .qualify(&identity.namespace)?;
+        self.authorize(
+            &identity,
+            ResourceType::QUEUE,
+            &new_queue_request.queue_id,
+            Permission::MANAGE,
+        )
+        .await?;
+        // the queue's dead letters are published to its dead letter queue on the key's behalf
+        if let Some(dead_letter_queue_id) = &new_queue_request.dead_letter_queue_id {
+            self.authorize(
+                &identity,
+                ResourceType::QUEUE,
+                dead_letter_queue_id,
+                Permission::PUBLISH,
+            )
+            .await?;
+        }
+        // held until the queue is created
+        let _namespaces =
+            check_quota(&self.data, &identity.namespace.name, ResourceType::QUEUE).await?;
+        let uuid = create_queue(&self.data, &new_queue_request).await?;
+        let queue_id = &new_queue_request.queue_id;
+        identity
+            .audit(&self.data, AuditAction::QueueCreated, queue_id, None)
+            .await;
+        Ok(Response::new(proto::NewQueueReply { uuid }))
+    }
+
+    async fn list_queues(
+        &self,
+        request: Request<proto::ListQueuesRequest>,
+    ) -> Result<Response<proto::ListQueuesReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let queues = self.data.get_queues().lock().await;
+        let queue_ids = queues
+            .keys()
+            .filter_map(|id| identity.namespace.unqualify(id))
+            .collect();
+        Ok(Response::new(proto::ListQueuesReply { queue_ids }))
+    }
+
+    async fn publish(
+        &self,
+        request: Request<proto::PublishRequest>,
+    ) -> Result<Response<proto::PublishReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let new_message_request = message_request::NewMessageRequest {
+            queue_id: identity.namespace.qualify(&request.queue_id)?,
+            messages: request
+                .messages
+                .into_iter()
+                .map(|m| message_request::NewMessage {
+                    message_id: m.message_id,
+                    content: m.content,
+                    reply_to: m.reply_to,
+                    correlation_id: m.correlation_id,
+                    attributes: m.attributes,
+                })
+                .collect(),
+        };
+        self.authorize(
+            &identity,
+            ResourceType::QUEUE,
+            &new_message_request.queue_id,
+            Permission::PUBLISH,
+        )
+        .await?;
+        let count = new_message_request.messages.len();
+        let published = publish_messages(&self.data, &new_message_request);
+        let uuids = identity
+            .publish_admitted(&self.data, count, published)
+            .await?;
+        let detail = Some(uuids.join(", "));
+        let queue_id = &new_message_request.queue_id;
+        identity
+            .audit(&self.data, AuditAction::MessagesPublished, queue_id, detail)
+            .await;
+        Ok(Response::new(proto::PublishReply { uuids }))
+    }
+
+    async fn receive(
+        &self,
+        request: Request<proto::ReceiveRequest>,
+    ) -> Result<Response<proto::ReceiveReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let query = get_message_request(&identity, request.into_inner())?;
+        self.authorize(
+            &identity,
+            ResourceType::QUEUE,
+            &query.queue_id,
+            Permission::CONSUME,
+        )
+        .await?;
+        let messages = receive_messages(&self.data, &query)
+            .await?
+            .into_iter()
+            .map(to_message)
+            .collect();
+        Ok(Response::new(proto::ReceiveReply { messages }))
+    }
+
+    async fn receive_stream(
+        &self,
+        request: Request<proto::ReceiveRequest>,
+    ) -> Result<Response<Self::ReceiveStreamStream>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let query = get_message_request(&identity, request.into_inner())?;
+        self.authorize(
+            &identity,
+            ResourceType::QUEUE,
+            &query.queue_id,
+            Permission::CONSUME,
+        )
+        .await?;
+        // the first batch is read up front, so a bad request fails the call rather than the stream
+        let first = receive_messages(&self.data, &query).await?;
+        let state = (self.data.clone(), query, VecDeque::from(first), false);
+        let stream =
+            futures::stream::unfold(state, |(data, query, mut pending, done)| async move {
+                loop {
+                    if let Some(message) = pending.pop_front() {
+                        return Some((Ok(to_message(message)), (data, query, pending, done)));
+                    }
+                    if done {
+                        return None;
+                    }
+                    match receive_messages(&data, &query).await {
+                        Ok(messages) if messages.is_empty() => {
+                            arrivals(&data, &query.queue_id).await.wait().await
+                        }
+                        Ok(messages) => pending.extend(messages),
+                        // the queue was deleted, or the message could not be read
+                        Err(e) => {
+                            return Some((Err(Status::from(e)), (data, query, pending, true)))
+                        }
+                    }
+                }
+            });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn delete_message(
+        &self,
+        request: Request<proto::DeleteMessageRequest>,
+    ) -> Result<Response<proto::DeleteMessageReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let delete_request = message_request::DeleteMessageRequest {
+            queue_id: identity.namespace.qualify(&request.queue_id)?,
+            message_uuid: request.message_uuid,
+            consumer_token: request.consumer_token,
+        };
+        self.authorize(
+            &identity,
+            ResourceType::QUEUE,
+            &delete_request.queue_id,
+            Permission::DELETE,
+        )
+        .await?;
+        let message = remove_message(&self.data, &delete_request).await?;
+        let detail = Some(delete_request.message_uuid.to_owned());
+        let queue_id = &delete_request.queue_id;
+        identity
+            .audit(&self.data, AuditAction::MessagesDeleted, queue_id, detail)
+            .await;
+        Ok(Response::new(proto::DeleteMessageReply { message }))
+    }
+
+    async fn new_exchange(
+        &self,
+        request: Request<proto::NewExchangeRequest>,
+    ) -> Result<Response<proto::NewExchangeReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let new_exchange_request = exchange_request::NewExchangeRequest {
+            id: request.id,
+            queue_ids: request.queue_ids,
+            exchange_ids: request.exchange_ids,
+            bindings: request
+                .bindings
+                .into_iter()
+                .map(|b| exchange_request::BindingEntry {
+                    queue_id: b.queue_id,
+                    exchange_id: b.exchange_id,
+                    weight: b.weight.unwrap_or(1),
+                    filter: b.filter,
+                    key: b.key,
+                })
+                .collect(),
+            exchange_type: parse_enum("exchange type", request.exchange_type)?,
+            alternate_queue_id: request.alternate_queue_id,
+            alternate_exchange_id: request.alternate_exchange_id,
+        }
+        .qualify(&identity.namespace)?;
+        self.authorize(
+            &identity,
+            ResourceType::EXCHANGE,
+            &new_exchange_request.id,
+            Permission::MANAGE,
+        )
+        .await?;
+        // held until the exchange is created
+        let _namespaces =
+            check_quota(&self.data, &identity.namespace.name, ResourceType::EXCHANGE).await?;
+        let uuid = create_exchange(&self.data, &new_exchange_request).await?;
+        let detail = Some(format!("{:?}", new_exchange_request.exchange_type));
+        let exchange_id = &new_exchange_request.id;
+        identity
+            .audit(
+                &self.data,
+                AuditAction::ExchangeCreated,
+                exchange_id,
+                detail,
+            )
+            .await;
+        Ok(Response::new(proto::NewExchangeReply { uuid }))
+    }
+
+    async fn list_exchanges(
+        &self,
+        request: Request<proto::ListExchangesRequest>,
+    ) -> Result<Response<proto::ListExchangesReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let exchanges = get_exchange_entries(&self.data)
+            .await
+            .into_iter()
+            .filter_map(|e| e.unqualify(&identity.namespace))
+            .map(|e| proto::Exchange {
+                id: e.id,
+                exchange_type: enum_name(&e.exchange_type),
+                queue_ids: e.queue_ids,
+                exchange_ids: e.exchange_ids,
+                bindings: e
+                    .bindings
+                    .into_iter()
+                    .map(|b| proto::Binding {
+                        queue_id: b.queue_id,
+                        exchange_id: b.exchange_id,
+                        weight: Some(b.weight),
+                        filter: b.filter,
+                        key: b.key,
+                    })
+                    .collect(),
+                alternate_queue_id: e.alternate_queue_id,
+                alternate_exchange_id: e.alternate_exchange_id,
+            })
+            .collect();
+        Ok(Response::new(proto::ListExchangesReply { exchanges }))
+    }
+
+    async fn bind_exchange(
+        &self,
+        request: Request<proto::BindExchangeRequest>,
+    ) -> Result<Response<proto::BindExchangeReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let bind_request = exchange_request::BindExchangeRequest {
+            exchange_id: request.exchange_id,
+            queue_id: request.queue_id,
+            destination_exchange_id: request.destination_exchange_id,
+            weight: request.weight.unwrap_or(1),
+            filter: request.filter,
+            key: request.key,
+        }
+        .qualify(&identity.namespace)?;
+        self.authorize(
+            &identity,
+            ResourceType::EXCHANGE,
+            &bind_request.exchange_id,
+            Permission::MANAGE,
+        )
+        .await?;
+        let message = bind_to_exchange(&self.data, &bind_request).await?;
+        let detail = binding_detail(&identity.namespace, &bind_request);
+        let exchange_id = &bind_request.exchange_id;
+        identity
+            .audit(&self.data, AuditAction::ExchangeBound, exchange_id, detail)
+            .await;
+        Ok(Response::new(proto::BindExchangeReply { message }))
+    }
+
+    async fn publish_to_exchange(
+        &self,
+        request: Request<proto::PublishToExchangeRequest>,
+    ) -> Result<Response<proto::PublishReply>, Status> {
+        let identity = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let new_message_request = exchange_request::NewMessageRequest {
+            exchange_id: identity.namespace.qualify(&request.exchange_id)?,
+            messages: request
+                .messages
+                .into_iter()
+                .map(|m| exchange_request::NewMessage {
+                    message_id: m.message_id,
+                    content: m.content,
+                    reply_to: m.reply_to,
+                    correlation_id: m.correlation_id,
+                    routing_key: m.routing_key,
+                    attributes: m.attributes,
+                })
+                .collect(),
+            mandatory: request.mandatory.unwrap_or(true),
+        };
+        self.authorize(
+            &identity,
+            ResourceType::EXCHANGE,
+            &new_message_request.exchange_id,
+            Permission::PUBLISH,
+        )
+        .await?;
+        // messages only reach the queues the key may publish to
+        let api_key = identity.current_key(&self.data).await?;
+        let count = new_message_request.messages.len();
+        let published = publish_to_exchange_as(&self.data, &new_message_request, api_key.as_ref());
+        let uuids = identity
+            .publish_admitted(&self.data, count, published)
+            .await?;
+        let detail = Some(uuids.join(", "));
+        let exchange_id = &new_message_request.exchange_id;
+        identity
+            .audit(
+                &self.data,
+                AuditAction::MessagesPublished,
+                exchange_id,
+                detail,
+            )
+            .await;
+        Ok(Response::new(proto::PublishReply { uuids }))
+    }
+}
+
+impl From<ApiError> for Status {
+    fn from(error: ApiError) -> Self {
+        let code = match error.get_status() {
+            StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => Code::InvalidArgument,
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::NOT_FOUND => Code::NotFound,
+            StatusCode::CONFLICT => Code::AlreadyExists,
+            StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+            StatusCode::LOOP_DETECTED => Code::FailedPrecondition,
+            StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+            _ => Code::Internal,
+        };
+        let mut status = Status::new(code, error.get_message());
+        // rate limited calls say when to retry, as the REST API does with Retry-After
+        if let Some(seconds) = error.get_retry_after() {
+            if let Ok(value) = seconds.to_string().parse() {
+                status.metadata_mut().insert("retry-after", value);
+            }
+        }
+        status
+    }
+}
+
+// Enums take the same names as in the REST API
+fn parse_enum<T: DeserializeOwned>(field: &str, value: String) -> Result<T, ApiError> {
+    serde_json::from_value(serde_json::Value::String(value.to_owned()))
+        .map_err(|_| ApiError::bad_request(format!("The {} {} is invalid", field, value)))
+}
+
+fn enum_name<T: serde::Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+fn get_message_request(
+    identity: &Identity,
+    request: proto::ReceiveRequest,
+) -> Result<message_request::GetMessageRequest, ApiError> {
+    Ok(message_request::GetMessageRequest {
+        queue_id: identity.namespace.qualify(&request.queue_id)?,
+        consumer_token: request.consumer_token,
+    })
+}
+
+fn to_message(message: GetMessageResponse) -> proto::Message {
+    proto::Message {
+        message_id: message.message_id,
+        content: message.content,
+        uuid: message.uuid,
+        reply_to: message.reply_to,
+        correlation_id: message.correlation_id,
+        attributes: message.attributes,
+    }
+}
+
+// What a receive stream that found the queue empty waits for before reading from it again. A
+// deleted queue is read again at once, to end the stream.
+async fn arrivals(data: &AppState, queue_id: &String) -> Wakeup {
+    let mut wakeup = Wakeup::new();
+    match data.get_queues().lock().await.get(queue_id) {
+        Some(queue) => wakeup.watch(queue, false),
+        None => wakeup.within(Duration::ZERO),
+    }
+    wakeup
+}
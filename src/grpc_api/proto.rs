@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+// The messages of proto/rqs.proto, declared by hand so building needs no protoc
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NewQueueRequest {
+    #[prost(string, tag = "1")]
+    pub queue_id: String,
+    #[prost(uint32, tag = "2")]
+    pub max_batch: u32,
+    #[prost(uint32, tag = "3")]
+    pub read_timeout: u32,
+    #[prost(uint32, optional, tag = "4")]
+    pub max_message_size: Option<u32>,
+    #[prost(bool, tag = "5")]
+    pub offload_large_messages: bool,
+    #[prost(string, optional, tag = "6")]
+    pub compression: Option<String>,
+    #[prost(uint32, optional, tag = "7")]
+    pub compression_threshold: Option<u32>,
+    #[prost(uint32, optional, tag = "8")]
+    pub max_messages: Option<u32>,
+    #[prost(uint64, optional, tag = "9")]
+    pub max_bytes: Option<u64>,
+    #[prost(string, optional, tag = "10")]
+    pub overflow_policy: Option<String>,
+    #[prost(string, optional, tag = "11")]
+    pub dead_letter_queue_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NewQueueReply {
+    #[prost(string, tag = "1")]
+    pub uuid: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListQueuesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListQueuesReply {
+    #[prost(string, repeated, tag = "1")]
+    pub queue_ids: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NewMessage {
+    #[prost(string, tag = "1")]
+    pub message_id: String,
+    #[prost(string, tag = "2")]
+    pub content: String,
+    #[prost(string, optional, tag = "3")]
+    pub reply_to: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub correlation_id: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub routing_key: Option<String>,
+    #[prost(map = "string, string", tag = "6")]
+    pub attributes: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(string, tag = "1")]
+    pub message_id: String,
+    #[prost(string, tag = "2")]
+    pub content: String,
+    #[prost(string, tag = "3")]
+    pub uuid: String,
+    #[prost(string, optional, tag = "4")]
+    pub reply_to: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub correlation_id: Option<String>,
+    #[prost(map = "string, string", tag = "6")]
+    pub attributes: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublishRequest {
+    #[prost(string, tag = "1")]
+    pub queue_id: String,
+    #[prost(message, repeated, tag = "2")]
+    pub messages: Vec<NewMessage>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublishReply {
+    #[prost(string, repeated, tag = "1")]
+    pub uuids: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReceiveRequest {
+    #[prost(string, tag = "1")]
+    pub queue_id: String,
+    #[prost(string, optional, tag = "2")]
+    pub consumer_token: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReceiveReply {
+    #[prost(message, repeated, tag = "1")]
+    pub messages: Vec<Message>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteMessageRequest {
+    #[prost(string, tag = "1")]
+    pub queue_id: String,
+    #[prost(string, tag = "2")]
+    pub message_uuid: String,
+    #[prost(string, optional, tag = "3")]
+    pub consumer_token: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteMessageReply {
+    #[prost(string, tag = "1")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Binding {
+    #[prost(string, optional, tag = "1")]
+    pub queue_id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub exchange_id: Option<String>,
+    #[prost(uint32, optional, tag = "3")]
+    pub weight: Option<u32>,
+    #[prost(string, optional, tag = "4")]
+    pub filter: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub key: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NewExchangeRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exchange_type: String,
+    #[prost(string, repeated, tag = "3")]
+    pub queue_ids: Vec<String>,
+    #[prost(string, repeated, tag = "4")]
+    pub exchange_ids: Vec<String>,
+    #[prost(message, repeated, tag = "5")]
+    pub bindings: Vec<Binding>,
+    #[prost(string, optional, tag = "6")]
+    pub alternate_queue_id: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub alternate_exchange_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NewExchangeReply {
+    #[prost(string, tag = "1")]
+    pub uuid: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListExchangesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Exchange {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exchange_type: String,
+    #[prost(string, repeated, tag = "3")]
+    pub queue_ids: Vec<String>,
+    #[prost(string, repeated, tag = "4")]
+    pub exchange_ids: Vec<String>,
+    #[prost(message, repeated, tag = "5")]
+    pub bindings: Vec<Binding>,
+    #[prost(string, optional, tag = "6")]
+    pub alternate_queue_id: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub alternate_exchange_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListExchangesReply {
+    #[prost(message, repeated, tag = "1")]
+    pub exchanges: Vec<Exchange>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BindExchangeRequest {
+    #[prost(string, tag = "1")]
+    pub exchange_id: String,
+    #[prost(string, optional, tag = "2")]
+    pub queue_id: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub destination_exchange_id: Option<String>,
+    #[prost(uint32, optional, tag = "4")]
+    pub weight: Option<u32>,
+    #[prost(string, optional, tag = "5")]
+    pub filter: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub key: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BindExchangeReply {
+    #[prost(string, tag = "1")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublishToExchangeRequest {
+    #[prost(string, tag = "1")]
+    pub exchange_id: String,
+    #[prost(message, repeated, tag = "2")]
+    pub messages: Vec<NewMessage>,
+    #[prost(bool, optional, tag = "3")]
+    pub mandatory: Option<bool>,
+}
@@ -1,54 +1,220 @@
-use actix_web::{error, web, App, HttpResponse, HttpServer};
+use actix_web::middleware::{from_fn, Condition};
+use actix_web::{error, error::JsonPayloadError, web, App, HttpResponse, HttpServer};
 use aes_gcm::{
     aead::{KeyInit, OsRng},
-    Aes256Gcm,
+    Aes256Gcm, Key,
 };
-use app_types::AppState;
-use exchange_api::{add_message_to_exchange, list_exchanges, new_exchange};
+use app_types::{AppState, JsonResponse};
+use audit_api::audit_log::AuditLog;
+use audit_api::{assign_request_id, export_audit_log, list_audit_entries};
+use auth_api::api_key::{hash_key, ApiKey};
+use auth_api::{
+    authenticate, delete_api_key, list_api_keys, new_api_key, require_admin, require_global_admin,
+    set_grants,
+};
+use blob_store::BlobStore;
+use clap::Parser;
+use config::{Args, Config};
+use consumer_api::{remove_expired_streams, stream, subscribe};
+use definitions_api::{export_definitions, import_definitions};
+use exchange_api::{add_message_to_exchange, bind_exchange, list_exchanges, new_exchange};
 use futures::lock::Mutex;
 use general_api::ping;
 use message_api::{add_message_to_queue, delete_message, get_message};
-use queue_api::{list_queues, new_queue};
+use metrics_api::metrics::RequestMetrics;
+use metrics_api::{get_metrics, record_latency};
+use namespace_api::namespace::{Namespace, DEFAULT_NAMESPACE};
+use namespace_api::{delete_namespace, list_namespaces, new_namespace, resolve_namespace};
+use queue_api::{list_queues, new_queue, new_reply_queue, remove_expired_queues};
+use rpc_api::call;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use webhook_api::{delete_webhook, list_webhooks, new_webhook};
 
+mod amqp_api;
 mod app_types;
+mod audit_api;
+mod auth_api;
+mod binary_api;
+mod blob_store;
+mod config;
+mod consumer_api;
+mod definitions_api;
 mod exchange_api;
 mod general_api;
+mod grpc_api;
 mod message_api;
+mod metrics_api;
+mod mqtt_api;
+mod namespace_api;
 mod queue_api;
+mod rate_limit;
+mod rpc_api;
+mod stomp_api;
+mod tls;
+mod webhook_api;
+
+// how often queues that have gone unused past their auto delete period are removed
+const EXPIRED_QUEUE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // generate unique key on start up
-    let key = Aes256Gcm::generate_key(&mut OsRng);
-    let cipher = Aes256Gcm::new(&key);
+    let mut config = Config::load(Args::parse()).unwrap_or_else(|e| exit_with(e));
+
+    // messages are encrypted with the key given, or else one generated on start up
+    let cipher = match config
+        .read_encryption_key()
+        .unwrap_or_else(|e| exit_with(e))
+    {
+        Some(key) => Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        None => Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng)),
+    };
+    let audit_log = AuditLog::new(
+        config.audit_max_entries,
+        config.audit_log.as_deref(),
+        config.audit_messages,
+    )
+    .unwrap_or_else(|e| exit_with(e));
+
+    // the REST API is served over TLS when given a certificate and its private key
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            tls::server_config(cert, key, config.tls_client_ca.as_deref())
+                .unwrap_or_else(|e| exit_with(e)),
+        ),
+        _ => None,
+    };
 
+    // API keys are only checked once an admin key is given, which the other keys are made with
+    let mut api_keys = HashMap::new();
+    if let Some(admin_key) = &config.admin_key {
+        api_keys.insert(
+            hash_key(admin_key),
+            ApiKey::new("admin", true, vec![], DEFAULT_NAMESPACE),
+        );
+    }
+    let require_api_keys = !api_keys.is_empty();
+    let namespaces = HashMap::from([(
+        String::from(DEFAULT_NAMESPACE),
+        Namespace::new(DEFAULT_NAMESPACE, None, None),
+    )]);
+
+    let blob_store = BlobStore::new(config.blob_dir.to_owned()).unwrap_or_else(|e| exit_with(e));
     let queue_data = web::Data::new(AppState {
         queues: Mutex::new(HashMap::new()),
         exchanges: Mutex::new(HashMap::new()),
         cipher: Mutex::new(cipher),
+        blob_store,
+        event_streams: Mutex::new(HashMap::new()),
+        webhooks: Mutex::new(HashMap::new()),
+        api_keys: Mutex::new(api_keys),
+        namespaces: Mutex::new(namespaces),
+        audit_log: Mutex::new(audit_log),
+        request_metrics: Mutex::new(RequestMetrics::default()),
+        require_api_keys,
     });
 
-    HttpServer::new(move || {
+    config::declare(&queue_data, &mut config)
+        .await
+        .unwrap_or_else(|e| exit_with(e));
+
+    let sweep_data = queue_data.clone();
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(EXPIRED_QUEUE_SWEEP_INTERVAL).await;
+            remove_expired_queues(&sweep_data).await;
+            remove_expired_streams(&sweep_data).await;
+        }
+    });
+
+    if config.grpc_enabled {
+        let address = SocketAddr::new(config.grpc_bind, config.grpc_port);
+        let grpc_data = queue_data.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = grpc_api::serve(grpc_data, address).await {
+                eprintln!("The gRPC API stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(amqp_port) = config.amqp_port {
+        let address = SocketAddr::new(config.amqp_bind, amqp_port);
+        let amqp_data = queue_data.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = amqp_api::serve(amqp_data, address).await {
+                eprintln!("The AMQP listener stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(stomp_port) = config.stomp_port {
+        let address = SocketAddr::new(config.stomp_bind, stomp_port);
+        let stomp_data = queue_data.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = stomp_api::serve(stomp_data, address).await {
+                eprintln!("The STOMP listener stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(mqtt_port) = config.mqtt_port {
+        let address = SocketAddr::new(config.mqtt_bind, mqtt_port);
+        let mqtt_data = queue_data.clone();
+        let mqtt_exchange = config.mqtt_exchange.to_owned();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = mqtt_api::serve(mqtt_data, address, mqtt_exchange).await {
+                eprintln!("The MQTT listener stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(binary_port) = config.binary_port {
+        let address = SocketAddr::new(config.binary_bind, binary_port);
+        let binary_data = queue_data.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = binary_api::serve(binary_data, address).await {
+                eprintln!("The binary protocol listener stopped: {}", e);
+            }
+        });
+    }
+
+    let max_request_bytes = config.max_request_bytes;
+    let server = HttpServer::new(move || {
         let json_config = web::JsonConfig::default()
-            .limit(4096)
+            .limit(max_request_bytes)
             .error_handler(|err, _req| {
                 // create custom error response
-                error::InternalError::from_response(
-                    err,
-                    HttpResponse::BadRequest().body("JSON was malformed"),
-                )
-                .into()
+                let response = match err {
+                    JsonPayloadError::Overflow { limit }
+                    | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+                        HttpResponse::PayloadTooLarge().json(JsonResponse::new(
+                            None::<String>,
+                            format!("The request body exceeds the limit of {} bytes", limit),
+                        ))
+                    }
+                    _ => HttpResponse::BadRequest().body("JSON was malformed"),
+                };
+                error::InternalError::from_response(err, response).into()
             });
 
         App::new()
+            .wrap(Condition::new(require_api_keys, from_fn(authenticate)))
+            // wrapped last so it runs first, as keys are checked against the namespace
+            .wrap(from_fn(resolve_namespace))
+            .wrap(from_fn(assign_request_id))
+            // outermost, so the time requests spend being authenticated is counted too
+            .wrap(from_fn(record_latency))
             .app_data(json_config)
             .app_data(queue_data.clone())
             .route("/", web::get().to(ping))
             .service(
                 web::scope("/queue")
                     .route("/list", web::get().to(list_queues))
-                    .route("/new", web::post().to(new_queue)),
+                    .route("/new", web::post().to(new_queue))
+                    .route("/reply", web::post().to(new_reply_queue))
+                    .route("/subscribe", web::get().to(subscribe))
+                    .route("/stream", web::get().to(stream)),
             )
             .service(
                 web::scope("/message")
@@ -60,10 +226,58 @@ async fn main() -> std::io::Result<()> {
                 web::scope("/exchange")
                     .route("/list", web::get().to(list_exchanges))
                     .route("/new", web::post().to(new_exchange))
-                    .route("/add", web::post().to(add_message_to_exchange)),
+                    .route("/add", web::post().to(add_message_to_exchange))
+                    .route("/bind", web::post().to(bind_exchange)),
+            )
+            .service(web::scope("/rpc").route("/call", web::post().to(call)))
+            .service(
+                web::scope("/definitions")
+                    .route("/import", web::post().to(import_definitions))
+                    .route("/export", web::get().to(export_definitions)),
+            )
+            .service(
+                web::scope("/webhook")
+                    .route("/list", web::get().to(list_webhooks))
+                    .route("/new", web::post().to(new_webhook))
+                    .route("/delete", web::post().to(delete_webhook)),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(require_admin))
+                    .route("/key/list", web::get().to(list_api_keys))
+                    .route("/key/new", web::post().to(new_api_key))
+                    .route("/key/delete", web::post().to(delete_api_key))
+                    .route("/key/grants", web::post().to(set_grants))
+                    .route("/audit/list", web::get().to(list_audit_entries))
+                    .route("/audit/export", web::get().to(export_audit_log)),
+            )
+            .service(
+                web::scope("/namespace")
+                    .wrap(from_fn(require_global_admin))
+                    .route("/list", web::get().to(list_namespaces))
+                    .route("/new", web::post().to(new_namespace))
+                    .route("/delete", web::post().to(delete_namespace)),
+            )
+            .service(
+                web::scope("/metrics")
+                    .wrap(from_fn(require_global_admin))
+                    .route("", web::get().to(get_metrics)),
             )
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .on_connect(tls::record_client_certificate);
+    let server = match config.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls_0_23((config.bind, config.port), tls_config)?,
+        None => server.bind((config.bind, config.port))?,
+    };
+    server.run().await
+}
+
+// Settings that are invalid are reported as they are, rather than as the error's debug output
+fn exit_with(e: std::io::Error) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1)
 }
@@ -1,106 +1,280 @@
-use crate::app_types::{AppState, JsonResponse};
-use actix_web::{web, HttpResponse};
+use crate::app_types::{ApiError, AppState, JsonResponse};
+use crate::audit_api::audit;
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::authorize;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::namespace_api::get_namespace;
+use crate::queue_api::publish_to_queue;
+use crate::rate_limit::{admit_messages, give_back_messages};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
 use request::{DeleteMessageRequest, GetMessageRequest, GetMessageResponse, NewMessageRequest};
 
-mod request;
+pub(crate) mod request;
 
 pub async fn add_message_to_queue(
+    req: HttpRequest,
     data: web::Data<AppState>,
     post_data: web::Json<NewMessageRequest>,
 ) -> HttpResponse {
+    let mut post_data = post_data.into_inner();
+    post_data.queue_id = match get_namespace(&req).qualify(&post_data.queue_id) {
+        Ok(id) => id,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(
+        &req,
+        ResourceType::QUEUE,
+        &post_data.queue_id,
+        Permission::PUBLISH,
+    ) {
+        return e.to_response();
+    }
+    if let Err(e) = admit_messages(&data, &req, post_data.messages.len()).await {
+        return e.to_response();
+    }
+    match publish_messages(&data, &post_data).await {
+        Ok(messages_to_send) => {
+            let detail = Some(messages_to_send.join(", "));
+            audit(
+                &data,
+                &req,
+                AuditAction::MessagesPublished,
+                &post_data.queue_id,
+                detail,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(messages_to_send, None::<String>))
+        }
+        Err(e) => {
+            give_back_messages(&data, &req, post_data.messages.len()).await;
+            e.to_response()
+        }
+    }
+}
+
+// Adds a batch of messages to a queue, returning their uuids
+pub async fn publish_messages(
+    data: &AppState,
+    post_data: &NewMessageRequest,
+) -> Result<Vec<String>, ApiError> {
     let queue_id = &post_data.queue_id;
 
     let mut queues = data.get_queues().lock().await;
     let queue = match queues.get_mut(queue_id) {
         None => {
-            return HttpResponse::BadRequest().json(JsonResponse::new(
-                None::<String>,
-                format!("No queue with id {} was found", queue_id),
-            ))
+            return Err(ApiError::bad_request(format!(
+                "No queue with id {} was found",
+                queue_id
+            )))
         }
         Some(q) => q,
     };
+    if let Err(e) = queue.take_request() {
+        return Err(ApiError::from(&e));
+    }
+
+    let messages_to_add = &post_data.messages;
+    // reject the whole batch up front rather than partially adding it
+    let sizes = messages_to_add
+        .iter()
+        .map(|m| m.content.len())
+        .collect::<Vec<usize>>();
+    if let Err(e) = queue.check_batch(&sizes) {
+        return Err(ApiError::from(&e));
+    }
 
     let cipher = data.get_cipher().lock().await;
 
-    let messages_to_add = &post_data.messages;
     let mut messages_to_send = vec![];
     for message in messages_to_add.iter() {
         let id = message.message_id.to_owned();
         let content = message.content.to_owned();
-        let message_added = match queue.add_to_queue(&cipher, id, content) {
-            Ok(s) => s,
-            Err(_) => {
-                return HttpResponse::InternalServerError().json(JsonResponse::new(
-                    None::<String>,
-                    "Something went wrong. Please try again.",
-                ))
-            }
-        };
+        let properties = message.get_properties();
+        let message_added =
+            match publish_to_queue(&mut queues, &cipher, queue_id, id, content, properties) {
+                Ok(s) => s,
+                Err(e) => return Err(ApiError::from(&e)),
+            };
         messages_to_send.push(message_added);
     }
-
-    HttpResponse::Accepted().json(JsonResponse::new(messages_to_send, None::<String>))
+    Ok(messages_to_send)
 }
 
 pub async fn delete_message(
+    req: HttpRequest,
     data: web::Data<AppState>,
     post_data: web::Json<DeleteMessageRequest>,
 ) -> HttpResponse {
+    let mut post_data = post_data.into_inner();
+    post_data.queue_id = match get_namespace(&req).qualify(&post_data.queue_id) {
+        Ok(id) => id,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(
+        &req,
+        ResourceType::QUEUE,
+        &post_data.queue_id,
+        Permission::DELETE,
+    ) {
+        return e.to_response();
+    }
+    match remove_message(&data, &post_data).await {
+        Ok(message) => {
+            let detail = Some(post_data.message_uuid.to_owned());
+            audit(
+                &data,
+                &req,
+                AuditAction::MessagesDeleted,
+                &post_data.queue_id,
+                detail,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(message, None::<String>))
+        }
+        Err(e) => e.to_response(),
+    }
+}
+
+// Deletes a message a consumer has read, returning a success message
+pub async fn remove_message(
+    data: &AppState,
+    post_data: &DeleteMessageRequest,
+) -> Result<String, ApiError> {
     let queue_id = &post_data.queue_id;
     let mut queues = data.get_queues().lock().await;
     let queue = match queues.get_mut(queue_id) {
         None => {
-            return HttpResponse::BadRequest().json(JsonResponse::new(
-                None::<String>,
-                format!("No queue with id {} was found", queue_id),
-            ))
+            return Err(ApiError::bad_request(format!(
+                "No queue with id {} was found",
+                queue_id
+            )))
         }
         Some(q) => q,
     };
+    if let Err(e) = queue.take_request() {
+        return Err(ApiError::from(&e));
+    }
+    if !queue.accepts_consumer(&post_data.consumer_token) {
+        return Err(exclusive_queue_error(queue_id));
+    }
 
     let message_uuid = &post_data.message_uuid;
     match queue.rem_from_queue(message_uuid) {
-        None => HttpResponse::BadRequest().json(JsonResponse::new(
-            None::<String>,
+        None => Err(ApiError::bad_request(
             format!("No message with uuid {} found, or the message is past the set read timeout - you cannot delete a message past its read timeout because another consumer may be using it.", message_uuid),
         )),
-        Some(_) => HttpResponse::Accepted().json(JsonResponse::new(
-            format!("Successfully deleted uuid {}", message_uuid),
-            None::<String>,
-        )),
+        Some(_) => Ok(format!("Successfully deleted uuid {}", message_uuid)),
     }
 }
 
+// Deletes a batch of messages from a queue, returning whether each one was deleted. Messages
+// that are unknown or past their read timeout are left alone.
+pub async fn remove_messages(
+    data: &AppState,
+    queue_id: &String,
+    message_uuids: &[String],
+    consumer_token: &Option<String>,
+) -> Result<Vec<bool>, ApiError> {
+    let mut queues = data.get_queues().lock().await;
+    let queue = match queues.get_mut(queue_id) {
+        None => {
+            return Err(ApiError::bad_request(format!(
+                "No queue with id {} was found",
+                queue_id
+            )))
+        }
+        Some(q) => q,
+    };
+    if let Err(e) = queue.take_request() {
+        return Err(ApiError::from(&e));
+    }
+    if !queue.accepts_consumer(consumer_token) {
+        return Err(exclusive_queue_error(queue_id));
+    }
+    Ok(message_uuids
+        .iter()
+        .map(|uuid| queue.rem_from_queue(uuid).is_some())
+        .collect())
+}
+
 pub async fn get_message(
+    req: HttpRequest,
     data: web::Data<AppState>,
     query_data: web::Query<GetMessageRequest>,
 ) -> HttpResponse {
+    let mut query_data = query_data.into_inner();
+    query_data.queue_id = match get_namespace(&req).qualify(&query_data.queue_id) {
+        Ok(id) => id,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(
+        &req,
+        ResourceType::QUEUE,
+        &query_data.queue_id,
+        Permission::CONSUME,
+    ) {
+        return e.to_response();
+    }
+    match receive_messages(&data, &query_data).await {
+        Ok(messages_to_send) => {
+            HttpResponse::Accepted().json(JsonResponse::new(messages_to_send, None::<String>))
+        }
+        Err(e) => e.to_response(),
+    }
+}
+
+// Reads a batch of visible messages from a queue, hiding them for the read timeout
+pub async fn receive_messages(
+    data: &AppState,
+    query_data: &GetMessageRequest,
+) -> Result<Vec<GetMessageResponse>, ApiError> {
+    receive_messages_up_to(data, query_data, usize::MAX).await
+}
+
+// Like receive_messages, but reads at most `limit` messages, and never more than the queue's
+// max batch
+pub async fn receive_messages_up_to(
+    data: &AppState,
+    query_data: &GetMessageRequest,
+    limit: usize,
+) -> Result<Vec<GetMessageResponse>, ApiError> {
     let queue_id = &query_data.queue_id;
 
     let mut queues = data.get_queues().lock().await;
     let queue = match queues.get_mut(queue_id) {
         None => {
-            return HttpResponse::BadRequest().json(JsonResponse::new(
-                None::<String>,
-                format!("No queue with id {} was found", queue_id),
-            ))
+            return Err(ApiError::bad_request(format!(
+                "No queue with id {} was found",
+                queue_id
+            )))
         }
         Some(q) => q,
     };
+    if let Err(e) = queue.take_request() {
+        return Err(ApiError::from(&e));
+    }
+    if !queue.accepts_consumer(&query_data.consumer_token) {
+        return Err(exclusive_queue_error(queue_id));
+    }
 
     let cipher = data.get_cipher().lock().await;
-    let messages_to_send = match queue.dispatch(cipher) {
+    let limit = limit.min(queue.get_max_batch() as usize);
+    let messages_to_send = match queue.dispatch_up_to(&cipher, limit) {
         Ok(m) => m,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(JsonResponse::new(
-                None::<String>,
-                "Something went wrong. Please try again.",
-            ))
-        }
+        Err(e) => return Err(ApiError::from(&e)),
     }
     .iter()
-    .map(|m| GetMessageResponse::new(m.get_id(), m.get_content(), m.get_uuid()))
+    .map(GetMessageResponse::new)
     .collect::<Vec<GetMessageResponse>>();
-    HttpResponse::Accepted().json(JsonResponse::new(messages_to_send, None::<String>))
+    Ok(messages_to_send)
+}
+
+pub fn exclusive_queue_error(queue_id: &String) -> ApiError {
+    ApiError::new(
+        StatusCode::FORBIDDEN,
+        format!(
+            "The queue with id {} is exclusive to another consumer",
+            queue_id
+        ),
+    )
 }
@@ -1,10 +1,29 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::queue_api::queue::{DecryptedMessage, MessageProperties};
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NewMessage {
     pub message_id: String,
     pub content: String,
+    pub reply_to: Option<String>,
+    pub correlation_id: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl NewMessage {
+    pub fn get_properties(&self) -> MessageProperties {
+        MessageProperties {
+            reply_to: self.reply_to.to_owned(),
+            correlation_id: self.correlation_id.to_owned(),
+            routing_key: None,
+            attributes: self.attributes.to_owned(),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -18,6 +37,7 @@ pub struct NewMessageRequest {
 #[serde(rename_all = "camelCase")]
 pub struct GetMessageRequest {
     pub queue_id: String,
+    pub consumer_token: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -26,14 +46,21 @@ pub struct GetMessageResponse {
     pub message_id: String,
     pub content: String,
     pub uuid: String,
+    pub reply_to: Option<String>,
+    pub correlation_id: Option<String>,
+    pub attributes: HashMap<String, String>,
 }
 
 impl GetMessageResponse {
-    pub fn new(message_id: String, content: String, uuid: String) -> Self {
+    pub fn new(message: &DecryptedMessage) -> Self {
+        let properties = message.get_properties();
         GetMessageResponse {
-            message_id,
-            content,
-            uuid,
+            message_id: message.get_id(),
+            content: message.get_content(),
+            uuid: message.get_uuid(),
+            reply_to: properties.reply_to,
+            correlation_id: properties.correlation_id,
+            attributes: properties.attributes,
         }
     }
 }
@@ -43,4 +70,5 @@ impl GetMessageResponse {
 pub struct DeleteMessageRequest {
     pub queue_id: String,
     pub message_uuid: String,
+    pub consumer_token: Option<String>,
 }
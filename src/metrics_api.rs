@@ -0,0 +1,176 @@
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+
+use crate::app_types::AppState;
+use crate::exchange_api::exchange::Exchange;
+use crate::namespace_api::namespace::split_id;
+use crate::queue_api::queue::Queue;
+use metrics::Exposition;
+
+pub(crate) mod metrics;
+
+// what requests that matched no route are counted under, so unknown paths don't each get a series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+type QueueFamily = (&'static str, &'static str, &'static str, fn(&Queue) -> f64);
+
+// the name, type and help of each metric exported for every queue, and how to get its value
+const QUEUE_FAMILIES: [QueueFamily; 6] = [
+    (
+        "rqs_queue_messages",
+        "gauge",
+        "The messages in the queue",
+        |q| q.get_size() as f64,
+    ),
+    (
+        "rqs_queue_messages_in_flight",
+        "gauge",
+        "The messages handed out and still hidden by the read timeout",
+        |q| q.get_in_flight() as f64,
+    ),
+    (
+        "rqs_queue_oldest_message_age_seconds",
+        "gauge",
+        "How long ago the oldest message in the queue was published",
+        |q| q.get_oldest_age().num_milliseconds() as f64 / 1000.0,
+    ),
+    (
+        "rqs_queue_messages_published_total",
+        "counter",
+        "The messages published to the queue",
+        |q| q.get_counts().published as f64,
+    ),
+    (
+        "rqs_queue_messages_received_total",
+        "counter",
+        "The messages handed out to consumers",
+        |q| q.get_counts().received as f64,
+    ),
+    (
+        "rqs_queue_messages_deleted_total",
+        "counter",
+        "The messages deleted by consumers",
+        |q| q.get_counts().deleted as f64,
+    ),
+];
+
+type ExchangeFamily = (&'static str, &'static str, fn(&Exchange) -> u64);
+
+const EXCHANGE_FAMILIES: [ExchangeFamily; 2] = [
+    (
+        "rqs_exchange_messages_routed_total",
+        "The messages published to the exchange that reached a queue",
+        |e| e.get_counts().0,
+    ),
+    (
+        "rqs_exchange_messages_unroutable_total",
+        "The messages published to the exchange that reached no queue",
+        |e| e.get_counts().1,
+    ),
+];
+
+// Times every request, counting it under its method and the route it matched
+pub async fn record_latency(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let data = req.app_data::<web::Data<AppState>>().cloned();
+    let method = req.method().to_string();
+    let started = Instant::now();
+    let res = next.call(req).await;
+    // the route is read from the response, once the namespace has been taken off the path
+    let route = match &res {
+        Ok(res) => res.request().match_pattern(),
+        Err(_) => None,
+    };
+    if let Some(data) = data {
+        data.get_request_metrics().lock().await.observe(
+            &method,
+            route.as_deref().unwrap_or(UNMATCHED_ROUTE),
+            started.elapsed(),
+        );
+    }
+    res
+}
+
+// Exports the metrics of every queue and exchange, and the request latencies, for Prometheus
+pub async fn get_metrics(data: web::Data<AppState>) -> HttpResponse {
+    let mut exposition = Exposition::default();
+    {
+        let exchanges = data.get_exchanges().lock().await;
+        let queues = data.get_queues().lock().await;
+        let mut queues = queues.iter().collect::<Vec<_>>();
+        queues.sort_by(|a, b| a.0.cmp(b.0));
+        let mut exchanges = exchanges.iter().collect::<Vec<_>>();
+        exchanges.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (name, metric_type, help, value) in QUEUE_FAMILIES {
+            exposition.family(name, metric_type, help);
+            for (id, queue) in queues.iter() {
+                let (namespace, queue_id) = split_id(id);
+                let labels = [("namespace", namespace), ("queue", queue_id)];
+                exposition.sample(name, &labels, value(queue));
+            }
+        }
+
+        let name = "rqs_encryption_errors_total";
+        exposition.family(
+            name,
+            "counter",
+            "The messages that could not be encrypted or decrypted",
+        );
+        for (id, queue) in queues.iter() {
+            let (namespace, queue_id) = split_id(id);
+            let counts = queue.get_counts();
+            let operations = [
+                ("encrypt", counts.encryption_errors),
+                ("decrypt", counts.decryption_errors),
+            ];
+            for (operation, value) in operations {
+                let labels = [
+                    ("namespace", namespace),
+                    ("queue", queue_id),
+                    ("operation", operation),
+                ];
+                exposition.sample(name, &labels, value);
+            }
+        }
+
+        for (name, help, value) in EXCHANGE_FAMILIES {
+            exposition.family(name, "counter", help);
+            for (id, exchange) in exchanges.iter() {
+                let (namespace, exchange_id) = split_id(id);
+                let labels = [("namespace", namespace), ("exchange", exchange_id)];
+                exposition.sample(name, &labels, value(exchange));
+            }
+        }
+    }
+
+    let name = "rqs_audit_write_failures_total";
+    exposition.family(
+        name,
+        "counter",
+        "The audit records that could not be written to the audit log file",
+    );
+    let write_failures = data.get_audit_log().lock().await.get_write_failures();
+    exposition.sample(name, &[], write_failures);
+
+    let name = "rqs_http_request_duration_seconds";
+    exposition.family(name, "histogram", "How long REST requests took");
+    let request_metrics = data.get_request_metrics().lock().await;
+    for ((method, route), histogram) in request_metrics.get_latencies().iter() {
+        let labels = [("method", method.as_str()), ("route", route.as_str())];
+        exposition.histogram(name, &labels, histogram);
+    }
+
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, METRICS_CONTENT_TYPE))
+        .body(exposition.into_text())
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::time::Duration;
+
+// the upper bounds in seconds of the request latency buckets, with +Inf after them
+const LATENCY_BUCKETS: [f64; 13] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+// How long requests took, counted into buckets by the most they could have taken
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()], // how many took at most the bucket's bound, not summed
+    count: u64,
+    sum: f64, // in seconds
+}
+
+impl Histogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|b| seconds <= *b) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+// The latencies of REST requests, by their method and the route they matched
+#[derive(Debug, Default)]
+pub struct RequestMetrics {
+    latencies: BTreeMap<(String, String), Histogram>,
+}
+
+impl RequestMetrics {
+    pub fn observe(&mut self, method: &str, route: &str, duration: Duration) {
+        self.latencies
+            .entry((method.to_owned(), route.to_owned()))
+            .or_default()
+            .observe(duration);
+    }
+
+    pub fn get_latencies(&self) -> &BTreeMap<(String, String), Histogram> {
+        &self.latencies
+    }
+}
+
+// Metrics in the Prometheus text format, written a family at a time
+#[derive(Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn family(&mut self, name: &str, metric_type: &str, help: &str) {
+        self.text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, metric_type
+        ));
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect::<Vec<String>>()
+            .join(",");
+        self.text
+            .push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+    }
+
+    // Writes the buckets of a histogram, which Prometheus wants summed up to each bound
+    pub fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            let bound = bound.to_string();
+            let bucket_labels = [labels, &[("le", bound.as_str())]].concat();
+            self.sample(&bucket_name, &bucket_labels, cumulative);
+        }
+        let bucket_labels = [labels, &[("le", "+Inf")]].concat();
+        self.sample(&bucket_name, &bucket_labels, histogram.count);
+        self.sample(&format!("{}_sum", name), labels, histogram.sum);
+        self.sample(&format!("{}_count", name), labels, histogram.count);
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+// label values are quoted, so quotes, backslashes and line breaks in ids must be escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,30 @@
+use std::net::SocketAddr;
+
+use actix_web::rt::net::TcpListener;
+use actix_web::web;
+
+use crate::app_types::AppState;
+
+mod connection;
+mod packet;
+
+// Serves MQTT 3.1.1 clients, routing what they publish through the given exchange
+pub async fn serve(
+    data: web::Data<AppState>,
+    address: SocketAddr,
+    exchange_id: String,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        // a failed accept only affects the client being accepted
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(_) => continue,
+        };
+        actix_web::rt::spawn(connection::run(
+            data.clone(),
+            exchange_id.to_owned(),
+            socket,
+        ));
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::time::timeout;
+use actix_web::web;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc::{self, Receiver};
+use uuid::Uuid;
+
+use crate::app_types::{ApiError, AppState};
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::authenticate_key;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::identity::Identity;
+use crate::consumer_api::wakeup::Wakeup;
+use crate::exchange_api::publish_to_exchange_as;
+use crate::exchange_api::request::{NewMessage, NewMessageRequest};
+
+use super::packet::{self, read_packet, Packet, PacketError, Will};
+
+// how many packets may be read ahead of the connection handling them
+const PACKET_BUFFER: usize = 64;
+
+// how long a client has to send CONNECT once connected
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the only protocol level spoken, which is MQTT 3.1.1
+const PROTOCOL_LEVEL: u8 = 4;
+
+const CONNECTION_ACCEPTED: u8 = 0;
+const UNACCEPTABLE_PROTOCOL_VERSION: u8 = 1;
+const IDENTIFIER_REJECTED: u8 = 2;
+const BAD_USER_NAME_OR_PASSWORD: u8 = 4;
+const SUBSCRIPTION_FAILURE: u8 = 0x80;
+
+// How a connection ended. A client that did not disconnect has its will published.
+enum Ended {
+    Disconnected,
+    Dropped,
+}
+
+struct Subscription {
+    topic: String,                 // the topic filter, which names the queue to stream from
+    queue_id: String,              // the id the queue is stored under
+    qos: u8,                       // 0 deletes messages as they are sent, 1 once they are acked
+    in_flight: Vec<(u16, String)>, // the packet id and uuid of each message sent and not acked
+}
+
+struct Connection {
+    data: web::Data<AppState>,
+    identity: Identity,
+    exchange_id: String,
+    writer: OwnedWriteHalf,
+    subscriptions: Vec<Subscription>,
+    keep_alive: Option<Duration>,
+    last_received: Instant,
+    last_packet_id: u16,
+}
+
+// Serves one client until it disconnects, goes quiet for longer than its keep alive or breaks
+// the protocol. Messages still in flight when it ends are returned to the queues.
+pub async fn run(data: web::Data<AppState>, exchange_id: String, socket: TcpStream) {
+    let (mut reader, writer) = socket.into_split();
+    let (sender, mut packets) = mpsc::channel(PACKET_BUFFER);
+    // packets are read on their own task, as a read cut short by a wakeup would lose data
+    actix_web::rt::spawn(async move {
+        loop {
+            let packet = read_packet(&mut reader).await;
+            let failed = packet.is_err();
+            if sender.send(packet).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut connection = Connection {
+        data,
+        identity: Identity::default(),
+        exchange_id,
+        writer,
+        subscriptions: vec![],
+        keep_alive: None,
+        last_received: Instant::now(),
+        last_packet_id: 0,
+    };
+    let will = match connection.connect(&mut packets).await {
+        Ok(will) => will,
+        Err(_) => return,
+    };
+    let ended = connection.serve(&mut packets).await;
+    connection.release_all().await;
+    if let (Ended::Dropped, Some(will)) = (ended, will) {
+        let _ = connection.publish(&will.topic, will.payload).await;
+    }
+}
+
+impl Connection {
+    // Accepts the client's CONNECT, returning its will
+    async fn connect(
+        &mut self,
+        packets: &mut Receiver<Result<Packet, PacketError>>,
+    ) -> Result<Option<Will>, Ended> {
+        let packet = match timeout(CONNECT_TIMEOUT, packets.recv()).await {
+            Ok(Some(Ok(p))) => p,
+            _ => return Err(Ended::Dropped),
+        };
+        let (protocol_level, client_id, clean_session, keep_alive, will, user_name, password) =
+            match packet {
+                Packet::Connect {
+                    protocol_level,
+                    client_id,
+                    clean_session,
+                    keep_alive,
+                    will,
+                    user_name,
+                    password,
+                } => (
+                    protocol_level,
+                    client_id,
+                    clean_session,
+                    keep_alive,
+                    will,
+                    user_name,
+                    password,
+                ),
+                _ => return Err(Ended::Dropped),
+            };
+        // the password is the API key, and the user name names the namespace
+        let key = password.and_then(|p| String::from_utf8(p).ok());
+        let namespace = user_name.filter(|n| !n.is_empty());
+        let identity = authenticate_key(&self.data, key.as_deref(), namespace.as_deref()).await;
+        let return_code = match (protocol_level, client_id.is_empty(), clean_session) {
+            (PROTOCOL_LEVEL, true, false) => IDENTIFIER_REJECTED,
+            (PROTOCOL_LEVEL, _, _) if identity.is_err() => BAD_USER_NAME_OR_PASSWORD,
+            (PROTOCOL_LEVEL, _, _) => CONNECTION_ACCEPTED,
+            _ => UNACCEPTABLE_PROTOCOL_VERSION,
+        };
+        self.send(packet::connack(return_code)).await?;
+        match identity {
+            Ok(identity) if return_code == CONNECTION_ACCEPTED => self.identity = identity,
+            _ => return Err(Ended::Dropped),
+        }
+        // the exchange is the one of that name in the client's namespace
+        self.exchange_id = match self.identity.namespace.qualify(&self.exchange_id) {
+            Ok(id) => id,
+            Err(_) => return Err(Ended::Dropped),
+        };
+        // clients that go quiet for one and a half keep alive periods are taken to be gone
+        self.keep_alive = match keep_alive {
+            0 => None,
+            seconds => Some(Duration::from_millis(seconds as u64 * 1500)),
+        };
+        Ok(will)
+    }
+
+    async fn serve(&mut self, packets: &mut Receiver<Result<Packet, PacketError>>) -> Ended {
+        loop {
+            let mut wakeup = match self.push_messages().await {
+                Ok(wakeup) => wakeup,
+                Err(ended) => return ended,
+            };
+            if let Some(keep_alive) = self.keep_alive {
+                if self.last_received.elapsed() > keep_alive {
+                    return Ended::Dropped;
+                }
+                wakeup.within(keep_alive.saturating_sub(self.last_received.elapsed()));
+            }
+            let packet = match wakeup.wait_for(packets.recv()).await {
+                None => continue, // the subscriptions or keep alive need seeing to
+                Some(Some(Ok(p))) => p,
+                Some(_) => return Ended::Dropped,
+            };
+            self.last_received = Instant::now();
+            if let Err(ended) = self.handle_packet(packet).await {
+                return ended;
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: Packet) -> Result<(), Ended> {
+        match packet {
+            Packet::Publish {
+                qos,
+                topic,
+                packet_id,
+                payload,
+            } => {
+                // QoS 2 is not supported, and there is no way to refuse a single message
+                if qos > 1 {
+                    return Err(Ended::Dropped);
+                }
+                if self.publish(&topic, payload).await.is_err() {
+                    return Err(Ended::Dropped);
+                }
+                if let Some(packet_id) = packet_id {
+                    self.send(packet::puback(packet_id)).await?;
+                }
+            }
+            Packet::PubAck { packet_id } => self.acknowledge(packet_id).await,
+            Packet::Subscribe { packet_id, topics } => {
+                let mut return_codes = vec![];
+                for (topic, qos) in topics {
+                    return_codes.push(self.subscribe(topic, qos).await);
+                }
+                self.send(packet::suback(packet_id, &return_codes)).await?;
+            }
+            Packet::Unsubscribe { packet_id, topics } => {
+                let (removed, kept) = self
+                    .subscriptions
+                    .drain(..)
+                    .partition::<Vec<Subscription>, _>(|s| topics.contains(&s.topic));
+                self.subscriptions = kept;
+                for subscription in removed.iter() {
+                    release(&self.data, subscription).await;
+                }
+                self.send(packet::unsuback(packet_id)).await?;
+            }
+            Packet::PingReq => self.send(packet::pingresp()).await?,
+            Packet::Disconnect => return Err(Ended::Disconnected),
+            Packet::Connect { .. } | Packet::Unsupported => return Err(Ended::Dropped),
+        }
+        Ok(())
+    }
+
+    // Publishes a message through the configured exchange, with the topic as its routing key
+    async fn publish(&self, topic: &String, payload: Vec<u8>) -> Result<(), ApiError> {
+        self.identity
+            .authorize(
+                &self.data,
+                ResourceType::EXCHANGE,
+                &self.exchange_id,
+                Permission::PUBLISH,
+            )
+            .await?;
+        let content = match String::from_utf8(payload) {
+            Ok(c) => c,
+            Err(_) => return Err(ApiError::bad_request("Payloads must be UTF-8 text")),
+        };
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("topic"), topic.to_owned());
+        let request = NewMessageRequest {
+            exchange_id: self.exchange_id.to_owned(),
+            messages: vec![NewMessage {
+                message_id: Uuid::new_v4().to_string(),
+                content,
+                reply_to: None,
+                correlation_id: None,
+                routing_key: Some(topic.to_owned()),
+                attributes,
+            }],
+            // devices cannot be told a message went nowhere, so it is dropped
+            mandatory: false,
+        };
+        let api_key = self.identity.current_key(&self.data).await?;
+        let published = publish_to_exchange_as(&self.data, &request, api_key.as_ref());
+        let uuids = self
+            .identity
+            .publish_admitted(&self.data, 1, published)
+            .await?;
+        let detail = Some(uuids.join(", "));
+        self.identity
+            .audit(
+                &self.data,
+                AuditAction::MessagesPublished,
+                &self.exchange_id,
+                detail,
+            )
+            .await;
+        Ok(())
+    }
+
+    // Subscribes to the queue a topic filter names, returning the QoS granted or a failure
+    async fn subscribe(&mut self, topic: String, qos: u8) -> u8 {
+        let queue_id = match self.identity.namespace.qualify(&topic) {
+            Ok(id) => id,
+            Err(_) => return SUBSCRIPTION_FAILURE,
+        };
+        if qos > 2 || !self.may(&queue_id, Permission::CONSUME).await {
+            return SUBSCRIPTION_FAILURE;
+        }
+        // QoS 2 is downgraded to 1, which the client must accept
+        let qos = qos.min(1);
+        // QoS 0 deletes messages as they are sent
+        if qos == 0 && !self.may(&queue_id, Permission::DELETE).await {
+            return SUBSCRIPTION_FAILURE;
+        }
+        let accepts = self
+            .data
+            .get_queues()
+            .lock()
+            .await
+            .get(&queue_id)
+            .map(|q| q.accepts_consumer(&None));
+        if accepts != Some(true) {
+            return SUBSCRIPTION_FAILURE;
+        }
+        // subscribing to the same filter again replaces the subscription
+        match self
+            .subscriptions
+            .iter_mut()
+            .find(|s| s.queue_id == queue_id)
+        {
+            Some(subscription) => subscription.qos = qos,
+            None => self.subscriptions.push(Subscription {
+                topic,
+                queue_id,
+                qos,
+                in_flight: vec![],
+            }),
+        }
+        qos
+    }
+
+    // Deletes the message a PUBACK acknowledges, or returns it to the queue if the client may not
+    // delete it. Unknown packet ids are ignored.
+    async fn acknowledge(&mut self, packet_id: u16) {
+        let found = self.subscriptions.iter_mut().find_map(|s| {
+            let idx = s.in_flight.iter().position(|(id, _)| *id == packet_id)?;
+            Some((s.queue_id.to_owned(), s.in_flight.remove(idx).1))
+        });
+        let (queue_id, uuid) = match found {
+            None => return,
+            Some(f) => f,
+        };
+        let may_delete = self.may(&queue_id, Permission::DELETE).await;
+        let deleted = {
+            let mut queues = self.data.get_queues().lock().await;
+            match (queues.get_mut(&queue_id), may_delete) {
+                (Some(queue), true) => queue.rem_from_queue(&uuid).is_some(),
+                (Some(queue), false) => {
+                    queue.release(&uuid);
+                    false
+                }
+                (None, _) => false,
+            }
+        };
+        if deleted {
+            self.identity
+                .audit(
+                    &self.data,
+                    AuditAction::MessagesDeleted,
+                    &queue_id,
+                    Some(uuid),
+                )
+                .await;
+        }
+    }
+
+    // Whether the client may act on a queue, as there is no way to tell it why it may not
+    async fn may(&self, queue_id: &str, permission: Permission) -> bool {
+        self.identity
+            .authorize(&self.data, ResourceType::QUEUE, queue_id, permission)
+            .await
+            .is_ok()
+    }
+
+    // Renews the messages in flight, and pushes new ones while subscriptions have credit. The key
+    // is checked on every push, so the connection is dropped once it may no longer consume, or
+    // delete for QoS 0, from a subscribed queue.
+    async fn push_messages(&mut self) -> Result<Wakeup, Ended> {
+        for subscription in self.subscriptions.iter() {
+            let may_consume = self.may(&subscription.queue_id, Permission::CONSUME).await;
+            if !may_consume
+                || (subscription.qos == 0
+                    && !self.may(&subscription.queue_id, Permission::DELETE).await)
+            {
+                return Err(Ended::Dropped);
+            }
+        }
+        let mut packets = vec![];
+        let mut deleted = vec![]; // the queue id and uuids of messages sent at QoS 0
+        let mut wakeup = Wakeup::new();
+        {
+            let mut packet_ids_in_use = self
+                .subscriptions
+                .iter()
+                .flat_map(|s| s.in_flight.iter().map(|(id, _)| *id))
+                .collect::<HashSet<u16>>();
+            let mut queues = self.data.get_queues().lock().await;
+            let cipher = self.data.get_cipher().lock().await;
+            for subscription in self.subscriptions.iter_mut() {
+                // a deleted queue can only be reported by dropping the connection
+                let queue = match queues.get_mut(&subscription.queue_id) {
+                    None => return Err(Ended::Dropped),
+                    Some(q) => q,
+                };
+                let uuids = subscription
+                    .in_flight
+                    .iter()
+                    .map(|(_, uuid)| uuid.to_owned())
+                    .collect::<Vec<String>>();
+                queue.renew(&uuids);
+                let credit =
+                    (queue.get_max_batch() as usize).saturating_sub(subscription.in_flight.len());
+                let messages = match queue.dispatch_up_to(&cipher, credit) {
+                    Ok(m) => m,
+                    Err(_) => return Err(Ended::Dropped),
+                };
+                let mut sent = vec![];
+                for message in messages.iter() {
+                    let packet_id = match subscription.qos {
+                        0 => {
+                            queue.rem_from_queue(&message.get_uuid());
+                            sent.push(message.get_uuid());
+                            None
+                        }
+                        _ => {
+                            let packet_id =
+                                next_packet_id(&mut self.last_packet_id, &packet_ids_in_use);
+                            packet_ids_in_use.insert(packet_id);
+                            subscription.in_flight.push((packet_id, message.get_uuid()));
+                            Some(packet_id)
+                        }
+                    };
+                    packets.push(packet::publish(
+                        &subscription.topic,
+                        packet_id,
+                        message.get_content().as_bytes(),
+                    ));
+                }
+                if !sent.is_empty() {
+                    deleted.push((subscription.queue_id.to_owned(), sent));
+                }
+                if subscription.in_flight.len() < queue.get_max_batch() as usize {
+                    wakeup.watch(queue, messages.len() == credit);
+                }
+                if !subscription.in_flight.is_empty() {
+                    wakeup.hold(queue);
+                }
+            }
+        }
+        for (queue_id, uuids) in deleted {
+            let detail = Some(uuids.join(", "));
+            self.identity
+                .audit(&self.data, AuditAction::MessagesDeleted, &queue_id, detail)
+                .await;
+        }
+        for packet in packets {
+            self.send(packet).await?;
+        }
+        Ok(wakeup)
+    }
+
+    async fn release_all(&mut self) {
+        for subscription in self.subscriptions.iter() {
+            release(&self.data, subscription).await;
+        }
+    }
+
+    async fn send(&mut self, packet: Vec<u8>) -> Result<(), Ended> {
+        self.writer
+            .write_all(&packet)
+            .await
+            .map_err(|_| Ended::Dropped)
+    }
+}
+
+// Makes the messages a subscription has in flight visible again
+async fn release(data: &AppState, subscription: &Subscription) {
+    let mut queues = data.get_queues().lock().await;
+    if let Some(queue) = queues.get_mut(&subscription.queue_id) {
+        for (_, uuid) in subscription.in_flight.iter() {
+            queue.release(uuid);
+        }
+    }
+}
+
+// packet ids are never 0, and are not reused while the message they were given to is in flight
+fn next_packet_id(last: &mut u16, in_use: &HashSet<u16>) -> u16 {
+    loop {
+        *last = last.wrapping_add(1);
+        if *last != 0 && !in_use.contains(last) {
+            return *last;
+        }
+    }
+}
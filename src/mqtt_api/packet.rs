@@ -0,0 +1,429 @@
+use std::fmt;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// the largest packet a client may send, fixed header excluded
+pub const MAX_PACKET_SIZE: usize = 1_048_576;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+pub enum PacketError {
+    Closed,
+    TooLarge(usize),
+    Malformed(String),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Closed => write!(f, "The connection was closed"),
+            PacketError::TooLarge(limit) => {
+                write!(f, "The packet exceeds the limit of {} bytes", limit)
+            }
+            PacketError::Malformed(s) => write!(f, "The packet was malformed: {}", s),
+        }
+    }
+}
+
+// A message a client leaves to be published if it goes away without disconnecting
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+// The packets a client may send. Fields RQS has no use for are read past and dropped.
+pub enum Packet {
+    Connect {
+        protocol_level: u8,
+        client_id: String,
+        clean_session: bool,
+        keep_alive: u16,
+        will: Option<Will>,
+        user_name: Option<String>,
+        password: Option<Vec<u8>>,
+    },
+    Publish {
+        qos: u8,
+        topic: String,
+        packet_id: Option<u16>,
+        payload: Vec<u8>,
+    },
+    PubAck {
+        packet_id: u16,
+    },
+    Subscribe {
+        packet_id: u16,
+        topics: Vec<(String, u8)>, // each topic filter with the QoS asked for
+    },
+    Unsubscribe {
+        packet_id: u16,
+        topics: Vec<String>,
+    },
+    PingReq,
+    Disconnect,
+    Unsupported, // packets only a server sends, and the QoS 2 flow, which is refused
+}
+
+pub async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Packet, PacketError> {
+    let first = read_byte(reader).await?;
+    // the remaining length takes up to four bytes, seven bits at a time
+    let mut size = 0usize;
+    for i in 0..4 {
+        let byte = read_byte(reader).await?;
+        size |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if i == 3 {
+            return Err(PacketError::Malformed(String::from(
+                "the remaining length is too long",
+            )));
+        }
+    }
+    if size > MAX_PACKET_SIZE {
+        return Err(PacketError::TooLarge(MAX_PACKET_SIZE));
+    }
+    let mut body = vec![0u8; size];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|_| PacketError::Closed)?;
+    Packet::decode(first >> 4, first & 0x0F, &body)
+}
+
+async fn read_byte<R: AsyncRead + Unpin>(reader: &mut R) -> Result<u8, PacketError> {
+    reader.read_u8().await.map_err(|_| PacketError::Closed)
+}
+
+impl Packet {
+    fn decode(packet_type: u8, flags: u8, body: &[u8]) -> Result<Self, PacketError> {
+        let mut d = Decoder { body, position: 0 };
+        let packet = match packet_type {
+            CONNECT => {
+                let protocol_name = d.string()?;
+                if protocol_name != "MQTT" {
+                    return Err(PacketError::Malformed(format!(
+                        "the protocol {} is unknown",
+                        protocol_name
+                    )));
+                }
+                let protocol_level = d.byte()?;
+                let connect_flags = d.byte()?;
+                let keep_alive = d.short()?;
+                let client_id = d.string()?;
+                let will = match connect_flags & 0x04 != 0 {
+                    true => Some(Will {
+                        topic: d.string()?,
+                        payload: d.binary()?,
+                    }),
+                    false => None,
+                };
+                let user_name = match connect_flags & 0x80 != 0 {
+                    true => Some(d.string()?),
+                    false => None,
+                };
+                let password = match connect_flags & 0x40 != 0 {
+                    true => Some(d.binary()?),
+                    false => None,
+                };
+                Packet::Connect {
+                    protocol_level,
+                    client_id,
+                    clean_session: connect_flags & 0x02 != 0,
+                    keep_alive,
+                    will,
+                    user_name,
+                    password,
+                }
+            }
+            PUBLISH => {
+                let qos = (flags >> 1) & 0x03;
+                let topic = d.string()?;
+                let packet_id = match qos {
+                    0 => None,
+                    _ => Some(d.short()?),
+                };
+                Packet::Publish {
+                    qos,
+                    topic,
+                    packet_id,
+                    payload: d.rest(),
+                }
+            }
+            PUBACK => Packet::PubAck {
+                packet_id: d.short()?,
+            },
+            SUBSCRIBE => {
+                let packet_id = d.short()?;
+                let mut topics = vec![];
+                while !d.is_empty() {
+                    topics.push((d.string()?, d.byte()?));
+                }
+                Packet::Subscribe { packet_id, topics }
+            }
+            UNSUBSCRIBE => {
+                let packet_id = d.short()?;
+                let mut topics = vec![];
+                while !d.is_empty() {
+                    topics.push(d.string()?);
+                }
+                Packet::Unsubscribe { packet_id, topics }
+            }
+            PINGREQ => Packet::PingReq,
+            DISCONNECT => Packet::Disconnect,
+            _ => Packet::Unsupported,
+        };
+        Ok(packet)
+    }
+}
+
+struct Decoder<'a> {
+    body: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], PacketError> {
+        if self.position + count > self.body.len() {
+            return Err(PacketError::Malformed(String::from(
+                "a field runs past the end of the packet",
+            )));
+        }
+        let taken = &self.body[self.position..self.position + count];
+        self.position += count;
+        Ok(taken)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.body.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, PacketError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn short(&mut self) -> Result<u16, PacketError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn binary(&mut self) -> Result<Vec<u8>, PacketError> {
+        let size = self.short()? as usize;
+        Ok(self.take(size)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, PacketError> {
+        String::from_utf8(self.binary()?)
+            .map_err(|_| PacketError::Malformed(String::from("a string is not UTF-8")))
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.body[self.position..].to_vec();
+        self.position = self.body.len();
+        rest
+    }
+}
+
+fn encode(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![first];
+    let mut size = body.len();
+    loop {
+        let mut byte = (size & 0x7F) as u8;
+        size >>= 7;
+        if size > 0 {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+        if size == 0 {
+            break;
+        }
+    }
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+pub fn connack(return_code: u8) -> Vec<u8> {
+    // sessions are never kept, so there is never one present
+    encode(CONNACK << 4, &[0, return_code])
+}
+
+pub fn publish(topic: &str, packet_id: Option<u16>, payload: &[u8]) -> Vec<u8> {
+    let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+    body.extend_from_slice(topic.as_bytes());
+    let qos = match packet_id {
+        Some(id) => {
+            body.extend_from_slice(&id.to_be_bytes());
+            1
+        }
+        None => 0,
+    };
+    body.extend_from_slice(payload);
+    encode(PUBLISH << 4 | qos << 1, &body)
+}
+
+pub fn puback(packet_id: u16) -> Vec<u8> {
+    encode(PUBACK << 4, &packet_id.to_be_bytes())
+}
+
+pub fn suback(packet_id: u16, return_codes: &[u8]) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    body.extend_from_slice(return_codes);
+    encode(SUBACK << 4, &body)
+}
+
+pub fn unsuback(packet_id: u16) -> Vec<u8> {
+    encode(UNSUBACK << 4, &packet_id.to_be_bytes())
+}
+
+pub fn pingresp() -> Vec<u8> {
+    encode(PINGRESP << 4, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    async fn read(bytes: &[u8]) -> Packet {
+        match read_packet(&mut &bytes[..]).await {
+            Ok(p) => p,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[actix_web::test]
+    async fn connect_packets_carry_the_user_name_and_password() {
+        let mut body = string("MQTT");
+        body.extend([4, 0x80 | 0x40 | 0x04 | 0x02, 0, 30]);
+        body.extend(string("device-1"));
+        body.extend(string("status"));
+        body.extend(string("offline"));
+        body.extend(string("tenant"));
+        body.extend(string("secret"));
+        match read(&encode(CONNECT << 4, &body)).await {
+            Packet::Connect {
+                protocol_level,
+                client_id,
+                clean_session,
+                keep_alive,
+                will,
+                user_name,
+                password,
+            } => {
+                assert_eq!(protocol_level, 4);
+                assert_eq!(client_id, "device-1");
+                assert!(clean_session);
+                assert_eq!(keep_alive, 30);
+                let will = will.unwrap();
+                assert_eq!(will.topic, "status");
+                assert_eq!(will.payload, b"offline");
+                assert_eq!(user_name.as_deref(), Some("tenant"));
+                assert_eq!(password.as_deref(), Some(&b"secret"[..]));
+            }
+            _ => panic!("expected CONNECT"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn connect_packets_without_credentials() {
+        let mut body = string("MQTT");
+        body.extend([4, 0, 0, 0]);
+        body.extend(string(""));
+        match read(&encode(CONNECT << 4, &body)).await {
+            Packet::Connect {
+                clean_session,
+                will,
+                user_name,
+                password,
+                ..
+            } => assert!(
+                !clean_session && will.is_none() && user_name.is_none() && password.is_none()
+            ),
+            _ => panic!("expected CONNECT"),
+        }
+
+        let mut body = string("MQIsdp");
+        body.extend([3, 0, 0, 0]);
+        let result = read_packet(&mut &encode(CONNECT << 4, &body)[..]).await;
+        assert!(matches!(result, Err(PacketError::Malformed(_))));
+    }
+
+    #[actix_web::test]
+    async fn publish_packets_read_back_as_written() {
+        let payload = vec![b'x'; 200]; // a remaining length of two bytes
+        match read(&publish("orders", Some(7), &payload)).await {
+            Packet::Publish {
+                qos,
+                topic,
+                packet_id,
+                payload: read_payload,
+            } => {
+                assert_eq!(qos, 1);
+                assert_eq!(topic, "orders");
+                assert_eq!(packet_id, Some(7));
+                assert_eq!(read_payload, payload);
+            }
+            _ => panic!("expected PUBLISH"),
+        }
+        match read(&publish("orders", None, b"hi")).await {
+            Packet::Publish { qos, packet_id, .. } => assert!(qos == 0 && packet_id.is_none()),
+            _ => panic!("expected PUBLISH"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn subscribe_packets_list_each_filter_with_its_qos() {
+        let mut body = 5u16.to_be_bytes().to_vec();
+        body.extend(string("orders"));
+        body.push(1);
+        body.extend(string("events"));
+        body.push(0);
+        match read(&encode(SUBSCRIBE << 4 | 0x02, &body)).await {
+            Packet::Subscribe { packet_id, topics } => {
+                assert_eq!(packet_id, 5);
+                assert_eq!(
+                    topics,
+                    vec![(String::from("orders"), 1), (String::from("events"), 0)]
+                );
+            }
+            _ => panic!("expected SUBSCRIBE"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn bad_lengths_are_refused() {
+        let result = read_packet(&mut &[PINGREQ << 4, 0xFF, 0xFF, 0xFF, 0xFF][..]).await;
+        assert!(matches!(result, Err(PacketError::Malformed(_))));
+
+        let result = read_packet(&mut &[PUBLISH << 4, 0x81, 0x80, 0x40][..]).await;
+        assert!(matches!(result, Err(PacketError::TooLarge(_))));
+
+        let result = read_packet(&mut &[PUBACK << 4, 2, 0][..]).await;
+        assert!(matches!(result, Err(PacketError::Closed)));
+
+        let result = read_packet(&mut &[PUBACK << 4, 1, 0][..]).await;
+        assert!(matches!(result, Err(PacketError::Malformed(_))));
+    }
+
+    #[test]
+    fn server_packets_are_encoded() {
+        assert_eq!(connack(4), vec![0x20, 2, 0, 4]);
+        assert_eq!(suback(9, &[1, 0x80]), vec![0x90, 4, 0, 9, 1, 0x80]);
+        assert_eq!(pingresp(), vec![0xD0, 0]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse, Url};
+use actix_web::http::{StatusCode, Uri};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use futures::lock::MutexGuard;
+
+use crate::app_types::{ApiError, AppState, JsonResponse};
+use crate::audit_api::audit;
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::grant::ResourceType;
+use namespace::{local_id, Namespace, DEFAULT_NAMESPACE};
+use request::{DeleteNamespaceRequest, NamespaceEntry, NewNamespaceRequest};
+
+pub(crate) mod namespace;
+mod request;
+
+// requests under `/ns/<name>` are in that namespace, as are requests with this header
+const NAMESPACE_PATH: &str = "/ns/";
+const NAMESPACE_HEADER: &str = "X-Rqs-Namespace";
+
+// Works out which namespace a request is in and hands it on to the handlers. A `/ns/<name>`
+// prefix is taken off the path, so the request is routed as if it had none.
+pub async fn resolve_namespace(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let (name, path) = match req.path().strip_prefix(NAMESPACE_PATH) {
+        Some(rest) => {
+            let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+            (name.to_owned(), Some(format!("/{}", path)))
+        }
+        None => {
+            let name = req
+                .headers()
+                .get(NAMESPACE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or(DEFAULT_NAMESPACE);
+            (name.to_owned(), None)
+        }
+    };
+    let namespace = match req.app_data::<web::Data<AppState>>() {
+        Some(data) => data.get_namespaces().lock().await.get(&name).cloned(),
+        None => None,
+    };
+    let namespace = match namespace {
+        Some(n) => n,
+        None => {
+            let response = ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("No namespace named {} was found", name),
+            )
+            .to_response();
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+    if let Some(path) = path {
+        let path_and_query = match req.query_string() {
+            "" => path,
+            query => format!("{}?{}", path, query),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = Uri::from_parts(parts) {
+            req.match_info_mut().set(Url::new(uri.clone()));
+            req.head_mut().uri = uri;
+        }
+    }
+    req.extensions_mut().insert(namespace);
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+// The namespace a request is in
+pub fn get_namespace(req: &HttpRequest) -> Namespace {
+    match req.extensions().get::<Namespace>() {
+        Some(namespace) => namespace.clone(),
+        None => Namespace::new(DEFAULT_NAMESPACE, None, None),
+    }
+}
+
+// Checks a namespace has room for another queue or exchange. The namespaces stay locked until the
+// guard returned is dropped, so concurrent requests cannot both take the last place.
+pub async fn check_quota<'a>(
+    data: &'a AppState,
+    name: &str,
+    resource_type: ResourceType,
+) -> Result<MutexGuard<'a, HashMap<String, Namespace>>, ApiError> {
+    let namespaces = data.get_namespaces().lock().await;
+    let namespace = match namespaces.get(name) {
+        Some(n) => n,
+        None => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("No namespace named {} was found", name),
+            ))
+        }
+    };
+    let count = match resource_type {
+        ResourceType::QUEUE => count_in(name, data.get_queues().lock().await.keys()),
+        ResourceType::EXCHANGE => count_in(name, data.get_exchanges().lock().await.keys()),
+    };
+    check_room(namespace, resource_type, count)?;
+    Ok(namespaces)
+}
+
+// Checks a namespace with `count` queues or exchanges has room for another
+pub fn check_room(
+    namespace: &Namespace,
+    resource_type: ResourceType,
+    count: usize,
+) -> Result<(), ApiError> {
+    let limit = match resource_type {
+        ResourceType::QUEUE => namespace.max_queues,
+        ResourceType::EXCHANGE => namespace.max_exchanges,
+    };
+    match limit {
+        Some(limit) if count >= limit as usize => Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "The namespace {} has reached its quota of {} {}s",
+                namespace.name, limit, resource_type
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn count_in<'a>(name: &str, ids: impl Iterator<Item = &'a String>) -> usize {
+    ids.filter(|id| local_id(name, id).is_some()).count()
+}
+
+// The stored ids of the queues, exchanges or webhooks in a namespace
+fn ids_in<'a>(name: &str, ids: impl Iterator<Item = &'a String>) -> Vec<String> {
+    ids.filter(|id| local_id(name, id).is_some())
+        .cloned()
+        .collect()
+}
+
+pub async fn new_namespace(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    post_data: web::Json<NewNamespaceRequest>,
+) -> HttpResponse {
+    let name = &post_data.name;
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if name.is_empty() || !valid {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!(
+                "The namespace name {} is invalid, it must be letters, digits, - and _",
+                name
+            ),
+        ));
+    }
+    let mut namespaces = data.get_namespaces().lock().await;
+    match namespaces.entry(name.to_owned()) {
+        Entry::Vacant(entry) => {
+            let mut namespace = Namespace::new(name, post_data.max_queues, post_data.max_exchanges);
+            namespace.max_messages_per_day = post_data.max_messages_per_day;
+            entry.insert(namespace);
+            drop(namespaces);
+            audit(&data, &req, AuditAction::NamespaceCreated, name, None).await;
+            HttpResponse::Accepted().json(JsonResponse::new(name, None::<String>))
+        }
+        Entry::Occupied(_) => HttpResponse::Conflict().json(JsonResponse::new(
+            None::<String>,
+            format!("A namespace named {} already exists", name),
+        )),
+    }
+}
+
+pub async fn list_namespaces(data: web::Data<AppState>) -> HttpResponse {
+    let namespaces = data.get_namespaces().lock().await;
+    let exchanges = data.get_exchanges().lock().await;
+    let queues = data.get_queues().lock().await;
+    let entries = namespaces
+        .values()
+        .map(|n| {
+            NamespaceEntry::new(
+                n,
+                count_in(&n.name, queues.keys()),
+                count_in(&n.name, exchanges.keys()),
+            )
+        })
+        .collect::<Vec<NamespaceEntry>>();
+    HttpResponse::Accepted().json(JsonResponse::new(entries, None::<String>))
+}
+
+// Deletes a namespace with its queues, exchanges, webhooks and API keys
+pub async fn delete_namespace(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    post_data: web::Json<DeleteNamespaceRequest>,
+) -> HttpResponse {
+    let name = &post_data.name;
+    if name == DEFAULT_NAMESPACE {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The default namespace cannot be deleted",
+        ));
+    }
+    let (exchange_ids, queue_ids) = {
+        let mut namespaces = data.get_namespaces().lock().await;
+        if namespaces.remove(name).is_none() {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No namespace named {} was found", name),
+            ));
+        }
+        let mut exchanges = data.get_exchanges().lock().await;
+        let exchange_ids = ids_in(name, exchanges.keys());
+        exchanges.retain(|id, _| local_id(name, id).is_none());
+        let mut queues = data.get_queues().lock().await;
+        let queue_ids = ids_in(name, queues.keys());
+        queues.retain(|id, _| local_id(name, id).is_none());
+        (exchange_ids, queue_ids)
+    };
+    // webhook workers stop once their webhook is gone
+    let webhook_ids = {
+        let mut webhooks = data.get_webhooks().lock().await;
+        let webhook_ids = ids_in(name, webhooks.keys());
+        webhooks.retain(|queue_id, _| local_id(name, queue_id).is_none());
+        webhook_ids
+    };
+    let api_key_ids = {
+        let mut api_keys = data.get_api_keys().lock().await;
+        let api_key_ids = api_keys
+            .values()
+            .filter(|k| k.namespace == *name)
+            .map(|k| (k.id.to_string(), k.name.to_owned()))
+            .collect::<Vec<(String, String)>>();
+        api_keys.retain(|_, api_key| api_key.namespace != *name);
+        api_key_ids
+    };
+
+    // what went with the namespace is recorded by its stored id, `<namespace>/<id>`
+    let cascaded = [
+        (exchange_ids, AuditAction::ExchangeDeleted),
+        (queue_ids, AuditAction::QueueDeleted),
+        (webhook_ids, AuditAction::WebhookDeleted),
+    ];
+    for (ids, action) in cascaded {
+        for id in ids.iter() {
+            let detail = Some(format!("with the namespace {}", name));
+            audit(&data, &req, action, id, detail).await;
+        }
+    }
+    for (id, key_name) in api_key_ids {
+        let detail = Some(format!("{}, with the namespace {}", key_name, name));
+        audit(&data, &req, AuditAction::ApiKeyDeleted, &id, detail).await;
+    }
+    audit(&data, &req, AuditAction::NamespaceDeleted, name, None).await;
+    HttpResponse::Accepted().json(JsonResponse::new(
+        format!("Successfully deleted namespace {}", name),
+        None::<String>,
+    ))
+}
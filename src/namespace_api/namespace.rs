@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use chrono::{DateTime, NaiveDate, Timelike, Utc};
+
+use crate::app_types::ApiError;
+
+// the namespace requests without one are in, whose ids are left as they are
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+// Ids in other namespaces are stored as `<namespace>/<id>`, so ids may not have one themselves
+const SEPARATOR: char = '/';
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+// A namespace isolates its queues, exchanges and API keys from those of other namespaces
+#[derive(Clone)]
+pub struct Namespace {
+    pub name: String,
+    pub max_queues: Option<u32>,
+    pub max_exchanges: Option<u32>,
+    pub max_messages_per_day: Option<u64>, // the most messages published a day, by UTC
+    pub messages_today: u64,
+    pub today: NaiveDate, // the day messages_today counts for
+    pub created: DateTime<Utc>,
+}
+
+impl Namespace {
+    pub fn new(name: &str, max_queues: Option<u32>, max_exchanges: Option<u32>) -> Self {
+        Namespace {
+            name: name.to_owned(),
+            max_queues,
+            max_exchanges,
+            max_messages_per_day: None,
+            messages_today: 0,
+            today: Utc::now().date_naive(),
+            created: Utc::now(),
+        }
+    }
+
+    pub fn get_messages_today(&self) -> u64 {
+        if self.today == Utc::now().date_naive() {
+            self.messages_today
+        } else {
+            0
+        }
+    }
+
+    // Checks published messages fit in the daily quota, which starts over at midnight UTC, without
+    // counting them
+    pub fn check_daily_quota(&mut self, count: usize) -> Result<(), ApiError> {
+        let now = Utc::now();
+        if now.date_naive() != self.today {
+            self.today = now.date_naive();
+            self.messages_today = 0;
+        }
+        let limit = match self.max_messages_per_day {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        if self.messages_today + count as u64 > limit {
+            let retry_after = SECONDS_PER_DAY - now.num_seconds_from_midnight() as u64;
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "The namespace {} has used {} of its quota of {} messages for today",
+                    self.name, self.messages_today, limit
+                ),
+            )
+            .with_retry_after(Duration::from_secs(retry_after)));
+        }
+        Ok(())
+    }
+
+    // Counts published messages against the daily quota
+    pub fn take_daily_quota(&mut self, count: usize) -> Result<(), ApiError> {
+        self.check_daily_quota(count)?;
+        self.messages_today += count as u64;
+        Ok(())
+    }
+
+    // Gives back the quota taken for messages that were not published after all
+    pub fn give_back_daily_quota(&mut self, count: usize) {
+        self.messages_today = self.messages_today.saturating_sub(count as u64);
+    }
+
+    // What ids in the namespace are stored with in front of them
+    pub fn prefix(&self) -> String {
+        match self.name.as_str() {
+            DEFAULT_NAMESPACE => String::new(),
+            name => format!("{}{}", name, SEPARATOR),
+        }
+    }
+
+    // Turns an id given in a request into the id it is stored under
+    pub fn qualify(&self, id: &str) -> Result<String, ApiError> {
+        if id.contains(SEPARATOR) {
+            return Err(ApiError::bad_request(format!(
+                "The id {} is invalid, ids may not contain {}",
+                id, SEPARATOR
+            )));
+        }
+        Ok(format!("{}{}", self.prefix(), id))
+    }
+
+    pub fn qualify_all(&self, ids: &[String]) -> Result<Vec<String>, ApiError> {
+        ids.iter().map(|id| self.qualify(id)).collect()
+    }
+
+    pub fn qualify_optional(&self, id: &Option<String>) -> Result<Option<String>, ApiError> {
+        id.as_ref().map(|id| self.qualify(id)).transpose()
+    }
+
+    // Turns a stored id back into the id the namespace knows it by, or None if it is in another
+    // namespace
+    pub fn unqualify(&self, id: &str) -> Option<String> {
+        local_id(&self.name, id).map(String::from)
+    }
+}
+
+pub fn local_id<'a>(namespace: &str, id: &'a str) -> Option<&'a str> {
+    let local = match namespace {
+        DEFAULT_NAMESPACE => id,
+        name => id.strip_prefix(name)?.strip_prefix(SEPARATOR)?,
+    };
+    Some(local).filter(|l| !l.contains(SEPARATOR))
+}
+
+// Splits a stored id into the namespace it is in and the id that namespace knows it by
+pub fn split_id(id: &str) -> (&str, &str) {
+    id.split_once(SEPARATOR).unwrap_or((DEFAULT_NAMESPACE, id))
+}
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use super::namespace::Namespace;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewNamespaceRequest {
+    pub name: String,
+    pub max_queues: Option<u32>,
+    pub max_exchanges: Option<u32>,
+    pub max_messages_per_day: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteNamespaceRequest {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceEntry {
+    pub name: String,
+    pub max_queues: Option<u32>,
+    pub max_exchanges: Option<u32>,
+    pub max_messages_per_day: Option<u64>,
+    pub messages_today: u64,
+    pub queues: usize,
+    pub exchanges: usize,
+    pub created: String, // RFC 3339
+}
+
+impl NamespaceEntry {
+    pub fn new(namespace: &Namespace, queues: usize, exchanges: usize) -> Self {
+        NamespaceEntry {
+            name: namespace.name.to_owned(),
+            max_queues: namespace.max_queues,
+            max_exchanges: namespace.max_exchanges,
+            max_messages_per_day: namespace.max_messages_per_day,
+            messages_today: namespace.get_messages_today(),
+            queues,
+            exchanges,
+            created: namespace.created.to_rfc3339(),
+        }
+    }
+}
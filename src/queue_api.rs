@@ -1,52 +1,307 @@
-use crate::app_types::{AppState, JsonResponse};
-use actix_web::{web, HttpResponse};
-use queue::Queue;
-use request::NewQueueRequest;
+use crate::app_types::{ApiError, AppState, JsonResponse};
+use crate::audit_api::audit_log::AuditAction;
+use crate::audit_api::{audit, audit_by_server};
+use crate::auth_api::authorize;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::blob_store::BlobStore;
+use crate::namespace_api::{check_quota, get_namespace};
+use crate::rate_limit::RateLimit;
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use aes_gcm::Aes256Gcm;
+use queue::{MessageProperties, OverflowPolicy, Queue, QueueError, QueueSettings};
+use request::{NewQueueRequest, NewReplyQueueRequest, ReplyQueueResponse};
 use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use uuid::Uuid;
 
+mod compression;
 pub(crate) mod queue;
-mod request;
+pub(crate) mod request;
 
 pub async fn new_queue(
+    req: HttpRequest,
     data: web::Data<AppState>,
     post_data: web::Json<NewQueueRequest>,
 ) -> HttpResponse {
-    if post_data.max_batch <= 0 {
-        return HttpResponse::BadRequest().json(JsonResponse::new(
-            None::<String>,
-            format!(
-                "The max number of messages to send and receive at once {} is invalid",
-                post_data.max_batch
-            ),
-        ));
+    let namespace = get_namespace(&req);
+    let post_data = match post_data.into_inner().qualify(&namespace) {
+        Ok(p) => p,
+        Err(e) => return e.to_response(),
+    };
+    // the queue's dead letters are published to its dead letter queue on the key's behalf
+    let mut checks = vec![(&post_data.queue_id, Permission::MANAGE)];
+    if let Some(dead_letter_queue_id) = &post_data.dead_letter_queue_id {
+        checks.push((dead_letter_queue_id, Permission::PUBLISH));
     }
-    if post_data.read_timeout <= 0 {
-        return HttpResponse::BadRequest().json(JsonResponse::new(
-            None::<String>,
-            format!("The read timeout {} is invalid", post_data.read_timeout),
-        ));
+    for (queue_id, permission) in checks {
+        if let Err(e) = authorize(&req, ResourceType::QUEUE, queue_id, permission) {
+            return e.to_response();
+        }
+    }
+    // held until the queue is created
+    let _namespaces = match check_quota(&data, &namespace.name, ResourceType::QUEUE).await {
+        Ok(n) => n,
+        Err(e) => return e.to_response(),
+    };
+    match create_queue(&data, &post_data).await {
+        Ok(queue_uuid) => {
+            audit(
+                &data,
+                &req,
+                AuditAction::QueueCreated,
+                &post_data.queue_id,
+                None,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(queue_uuid, None::<String>))
+        }
+        Err(e) => e.to_response(),
     }
+}
+
+// Validates and creates a queue, returning its uuid
+pub async fn create_queue(
+    data: &AppState,
+    post_data: &NewQueueRequest,
+) -> Result<String, ApiError> {
+    let mut queues = data.get_queues().lock().await;
+    let settings = queue_settings(post_data, |id| queues.contains_key(id))?;
     let queue = Queue::new(
         post_data.queue_id.to_owned(),
-        post_data.read_timeout,
-        post_data.max_batch,
+        settings,
+        data.get_blob_store().clone(),
     );
-    let mut queues = data.get_queues().lock().await;
-    let queue_uuid = &queue.get_uuid();
+    let queue_uuid = queue.get_uuid();
     match queues.entry(post_data.queue_id.to_owned()) {
         Entry::Vacant(_) => {
             queues.insert(post_data.queue_id.to_owned(), queue);
-            HttpResponse::Accepted().json(JsonResponse::new(queue_uuid, None::<String>))
+            Ok(queue_uuid)
         }
-        Entry::Occupied(_) => HttpResponse::Conflict().json(JsonResponse::new(
-            None::<String>,
+        Entry::Occupied(_) => Err(ApiError::new(
+            StatusCode::CONFLICT,
             format!("A queue with id {} already exists", post_data.queue_id),
         )),
     }
 }
 
-pub async fn list_queues(data: web::Data<AppState>) -> HttpResponse {
+// Validates the settings of a new queue, whose dead letter queue must be one of the queues
+pub fn queue_settings(
+    post_data: &NewQueueRequest,
+    queue_exists: impl Fn(&String) -> bool,
+) -> Result<QueueSettings, ApiError> {
+    if post_data.max_batch == 0 {
+        return Err(ApiError::bad_request(format!(
+            "The max number of messages to send and receive at once {} is invalid",
+            post_data.max_batch
+        )));
+    }
+    if post_data.read_timeout == 0 {
+        return Err(ApiError::bad_request(format!(
+            "The read timeout {} is invalid",
+            post_data.read_timeout
+        )));
+    }
+    if post_data.max_message_size == Some(0) {
+        return Err(ApiError::bad_request("The max message size 0 is invalid"));
+    }
+    if post_data.offload_large_messages && post_data.max_message_size.is_none() {
+        return Err(ApiError::bad_request(
+            "Offloading large messages requires a max message size",
+        ));
+    }
+    if post_data.max_messages == Some(0) || post_data.max_bytes == Some(0) {
+        return Err(ApiError::bad_request(
+            "The max number of messages and max bytes must be greater than 0",
+        ));
+    }
+    if post_data.overflow_policy == OverflowPolicy::DeadLetter
+        && post_data.dead_letter_queue_id.is_none()
+    {
+        return Err(ApiError::bad_request(
+            "The DEAD_LETTER overflow policy requires a dead letter queue id",
+        ));
+    }
+    if let Some(dead_letter_queue_id) = &post_data.dead_letter_queue_id {
+        if *dead_letter_queue_id == post_data.queue_id || !queue_exists(dead_letter_queue_id) {
+            return Err(ApiError::bad_request(format!(
+                "No dead letter queue with id {} was found",
+                dead_letter_queue_id
+            )));
+        }
+    }
+    Ok(QueueSettings {
+        read_timeout: post_data.read_timeout,
+        max_batch: post_data.max_batch,
+        max_message_size: post_data.max_message_size,
+        offload_large_messages: post_data.offload_large_messages,
+        compression: post_data.compression,
+        compression_threshold: post_data.compression_threshold,
+        max_messages: post_data.max_messages,
+        max_bytes: post_data.max_bytes,
+        overflow_policy: post_data.overflow_policy,
+        dead_letter_queue_id: post_data.dead_letter_queue_id.to_owned(),
+        consumer_token: None,
+        auto_delete_after: None,
+        rate_limit: post_data.rate_limit,
+    })
+}
+
+pub async fn new_reply_queue(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    post_data: web::Json<NewReplyQueueRequest>,
+) -> HttpResponse {
+    if post_data.max_batch == 0 || post_data.read_timeout == 0 || post_data.auto_delete_after == 0 {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The read timeout, max batch and auto delete period must be greater than 0",
+        ));
+    }
+    let namespace = get_namespace(&req);
+    let queue_id = reply_queue_id(&namespace.prefix());
+    if let Err(e) = authorize(&req, ResourceType::QUEUE, &queue_id, Permission::MANAGE) {
+        return e.to_response();
+    }
+    let _namespaces = match check_quota(&data, &namespace.name, ResourceType::QUEUE).await {
+        Ok(n) => n,
+        Err(e) => return e.to_response(),
+    };
+    let mut queues = data.get_queues().lock().await;
+    let consumer_token = create_reply_queue(
+        &mut queues,
+        data.get_blob_store(),
+        &queue_id,
+        post_data.read_timeout,
+        post_data.max_batch,
+        post_data.auto_delete_after,
+    );
+    let detail = Some(String::from("a reply queue"));
+    audit(&data, &req, AuditAction::QueueCreated, &queue_id, detail).await;
+    let queue_id = namespace.unqualify(&queue_id).unwrap_or(queue_id);
+    HttpResponse::Accepted().json(JsonResponse::new(
+        ReplyQueueResponse::new(queue_id, consumer_token),
+        None::<String>,
+    ))
+}
+
+// Lists the ids of the queues in the request's namespace
+pub async fn list_queues(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    let namespace = get_namespace(&req);
     let queues = data.get_queues().lock().await;
-    let queue_uuids = queues.keys().collect::<Vec<&String>>();
+    let queue_uuids = queues
+        .keys()
+        .filter_map(|id| namespace.unqualify(id))
+        .collect::<Vec<String>>();
     HttpResponse::Accepted().json(JsonResponse::new(queue_uuids, None::<String>))
 }
+
+// A fresh id for a reply queue. The id is given `id_prefix`, which puts the queue in a namespace.
+pub fn reply_queue_id(id_prefix: &str) -> String {
+    format!("{}reply-{}", id_prefix, Uuid::new_v4())
+}
+
+// Creates an exclusive queue that is deleted once unused, returning its consumer token
+pub fn create_reply_queue(
+    queues: &mut HashMap<String, Queue>,
+    blob_store: &BlobStore,
+    queue_id: &str,
+    read_timeout: u32,
+    max_batch: u32,
+    auto_delete_after: u32,
+) -> String {
+    let consumer_token = Uuid::new_v4().to_string();
+    let settings = QueueSettings {
+        read_timeout,
+        max_batch,
+        max_message_size: None,
+        offload_large_messages: false,
+        compression: None,
+        compression_threshold: 0,
+        max_messages: None,
+        max_bytes: None,
+        overflow_policy: OverflowPolicy::Reject,
+        dead_letter_queue_id: None,
+        consumer_token: Some(consumer_token.to_owned()),
+        auto_delete_after: Some(auto_delete_after),
+        rate_limit: RateLimit::default(),
+    };
+    queues.insert(
+        queue_id.to_owned(),
+        Queue::new(queue_id.to_owned(), settings, blob_store.clone()),
+    );
+    consumer_token
+}
+
+// Deletes queues that have gone unused for longer than their auto delete period
+pub async fn remove_expired_queues(data: &AppState) {
+    let expired = {
+        let mut queues = data.get_queues().lock().await;
+        let expired = queues
+            .values()
+            .filter(|queue| queue.is_expired())
+            .map(|queue| queue.get_id())
+            .collect::<Vec<String>>();
+        queues.retain(|_, queue| !queue.is_expired());
+        expired
+    };
+    for queue_id in expired.iter() {
+        let detail = Some(String::from("a reply queue that went unused"));
+        audit_by_server(data, AuditAction::QueueDeleted, queue_id, detail).await;
+    }
+}
+
+// Adds a message to a queue, moving any messages it evicted to its dead letter queue
+pub fn publish_to_queue(
+    queues: &mut HashMap<String, Queue>,
+    cipher: &Aes256Gcm,
+    queue_id: &String,
+    id: String,
+    content: String,
+    properties: MessageProperties,
+) -> Result<String, QueueError> {
+    let queue = match queues.get_mut(queue_id) {
+        None => return Err(QueueError::NotFound(queue_id.to_owned())),
+        Some(q) => q,
+    };
+    let message_uuid = queue.add_to_queue(cipher, id, content, properties)?;
+    let dead_letters = queue.take_dead_letters();
+    if dead_letters.is_empty() {
+        return Ok(message_uuid);
+    }
+    let dead_letter_queue_id = queue.get_dead_letter_queue_id();
+    match dead_letter_queue_id.and_then(|id| queues.get_mut(&id)) {
+        Some(dead_letter_queue) => {
+            for message in dead_letters {
+                dead_letter_queue.add_dead_letter(message);
+            }
+        }
+        // the dead letter queue may have been removed, in which case the messages are dropped
+        None => {
+            if let Some(queue) = queues.get(queue_id) {
+                for message in dead_letters {
+                    queue.discard(message);
+                }
+            }
+        }
+    }
+    Ok(message_uuid)
+}
+
+pub fn queue_error_response(error: &QueueError) -> HttpResponse {
+    ApiError::from(error).to_response()
+}
+
+impl From<&QueueError> for ApiError {
+    fn from(error: &QueueError) -> Self {
+        let status = match error {
+            QueueError::MessageTooLarge(_, _) => StatusCode::PAYLOAD_TOO_LARGE,
+            QueueError::QueueFull(_) => StatusCode::TOO_MANY_REQUESTS,
+            QueueError::NotFound(_) => StatusCode::BAD_REQUEST,
+            QueueError::RateLimited(e) => return e.to_api_error("the queue"),
+            QueueError::Encryption | QueueError::Storage | QueueError::Compression => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        ApiError::new(status, error.to_string())
+    }
+}
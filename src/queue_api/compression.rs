@@ -0,0 +1,38 @@
+use std::io::{self, Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[allow(clippy::upper_case_acronyms)] // the variant names are part of the api
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    GZIP,
+    ZSTD,
+}
+
+impl Compression {
+    pub fn compress(&self, content: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::GZIP => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+            Compression::ZSTD => zstd::encode_all(content, ZSTD_LEVEL),
+        }
+    }
+
+    pub fn decompress(&self, content: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::GZIP => {
+                let mut decompressed = vec![];
+                GzDecoder::new(content).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            Compression::ZSTD => zstd::decode_all(content),
+        }
+    }
+}
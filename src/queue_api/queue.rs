@@ -1,43 +1,130 @@
-use std::error::Error;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use aes_gcm::aead::Aead;
-use aes_gcm::aes::Aes256;
-use aes_gcm::AesGcm;
-use chrono::{DateTime, Duration, Utc};
-use futures::lock::MutexGuard;
-use uuid::Uuid;
-
 use aes_gcm::aes::cipher::typenum::bit::{B0, B1};
 use aes_gcm::aes::cipher::typenum::{UInt, UTerm};
 use aes_gcm::{
     aead::{generic_array::GenericArray, AeadCore, OsRng},
     Aes256Gcm,
 };
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::blob_store::BlobStore;
+use crate::rate_limit::{Limiter, RateLimit, RateLimitError};
+
+use super::compression::Compression;
+
+type Nonce = GenericArray<u8, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>;
 
 #[derive(Debug)]
-pub struct EncryptionError;
+pub enum QueueError {
+    Encryption,
+    MessageTooLarge(usize, u32),
+    Storage,
+    Compression,
+    QueueFull(String),
+    NotFound(String),
+    RateLimited(RateLimitError),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::MessageTooLarge(size, limit) => write!(
+                f,
+                "The message is {} bytes, which exceeds the maximum message size of {} bytes for this queue",
+                size, limit
+            ),
+            QueueError::QueueFull(reason) => write!(f, "The queue is full: {}", reason),
+            QueueError::NotFound(id) => write!(f, "No queue with id {} was found", id),
+            QueueError::RateLimited(e) => write!(f, "{} for the queue", e),
+            QueueError::Encryption | QueueError::Storage | QueueError::Compression => {
+                write!(f, "Something went wrong. Please try again.")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Payload {
+    Inline(Vec<u8>), // the encrypted content, held in memory
+    Blob(PathBuf),   // the encrypted content was offloaded to the blob store
+}
+
+// What a queue does with a new message when it is already at its max depth
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OverflowPolicy {
+    #[default]
+    Reject, // refuse the new message
+    DropOldest, // discard the oldest messages to make room
+    DeadLetter, // move the oldest messages to the dead letter queue to make room
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct QueueSettings {
+    pub read_timeout: u32, // the amount of time a message is hidden from consumers
+    pub max_batch: u32,    // the max number of messages to insert and return at once
+    pub max_message_size: Option<u32>, // the max size in bytes of a single message's content
+    pub offload_large_messages: bool, // send messages over max_message_size to the blob store
+    pub compression: Option<Compression>, // how to compress message content, if at all
+    pub compression_threshold: u32, // the min size in bytes of content worth compressing
+    pub max_messages: Option<u32>, // the max number of messages the queue holds
+    pub max_bytes: Option<u64>, // the max total size in bytes of the content the queue holds
+    pub overflow_policy: OverflowPolicy, // what to do when max_messages or max_bytes is hit
+    pub dead_letter_queue_id: Option<String>, // where DeadLetter sends evicted messages
+    pub consumer_token: Option<String>, // if set, only consumers presenting it may read or delete
+    pub auto_delete_after: Option<u32>, // if set, the seconds unused before the queue is deleted
+    pub rate_limit: RateLimit, // how many requests and messages the queue takes a second
+}
+
+// Metadata that travels with a message unencrypted
+#[derive(Debug, Clone, Default)]
+pub struct MessageProperties {
+    pub reply_to: Option<String>, // the queue a consumer should send its reply to
+    pub correlation_id: Option<String>, // ties a reply to the request it answers
+    pub routing_key: Option<String>, // what HASH exchanges route on instead of the message id
+    pub attributes: HashMap<String, String>, // what binding filters match on besides the body
+}
 
 #[derive(Debug)]
 pub struct Message {
     id: String,
-    content: Vec<u8>,
+    added: DateTime<Utc>, // when the message was first published, kept if it is dead lettered
+    content: Payload,
     last_read: Option<DateTime<Utc>>,
     uuid: Uuid,
-    nonce: GenericArray<u8, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>,
+    nonce: Nonce,
+    compression: Option<Compression>, // how the content was compressed before encryption, if at all
+    size: usize,                      // the size in bytes of the original content
+    properties: MessageProperties,
 }
 
 impl Message {
-    pub fn new(
+    fn new(
         id: String,
-        content: Vec<u8>,
-        nonce: GenericArray<u8, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>,
+        content: Payload,
+        nonce: Nonce,
+        compression: Option<Compression>,
+        size: usize,
+        properties: MessageProperties,
     ) -> Self {
         Message {
             id,
+            added: Utc::now(),
             content,
             last_read: None,
             uuid: Uuid::new_v4(),
             nonce,
+            compression,
+            size,
+            properties,
         }
     }
 
@@ -64,11 +151,17 @@ pub struct DecryptedMessage {
     id: String,
     content: String,
     uuid: Uuid,
+    properties: MessageProperties,
 }
 
 impl DecryptedMessage {
-    pub fn new(id: String, content: String, uuid: Uuid) -> Self {
-        DecryptedMessage { id, content, uuid }
+    pub fn new(id: String, content: String, uuid: Uuid, properties: MessageProperties) -> Self {
+        DecryptedMessage {
+            id,
+            content,
+            uuid,
+            properties,
+        }
     }
     pub fn get_uuid(&self) -> String {
         self.uuid.to_string()
@@ -81,27 +174,54 @@ impl DecryptedMessage {
     pub fn get_content(&self) -> String {
         (*self.content).to_owned()
     }
+
+    pub fn get_properties(&self) -> MessageProperties {
+        self.properties.clone()
+    }
+}
+
+// How many messages a queue has taken, handed out and deleted since it was created, for metrics
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueueCounts {
+    pub published: u64,
+    pub received: u64,
+    pub deleted: u64,
+    pub encryption_errors: u64, // messages that could not be encrypted as they were added
+    pub decryption_errors: u64, // messages that could not be decrypted as they were handed out
 }
 
 #[derive(Debug)]
 pub struct Queue {
-    queue: Vec<Message>, // the actual queue
-    read_timeout: u32,   // the amount of time a message is hidden from consumers
-    size: u32,           // should always be the same as queue.len()
-    uuid: Uuid,          // unique uuid
-    id: String,          // user id for queue - also unique
-    max_batch: u32,      // the max number of messages to insert and return at once
+    queue: Vec<Message>,        // the actual queue
+    size: u32,                  // should always be the same as queue.len()
+    bytes: u64,                 // the total size of the content of the messages in the queue
+    uuid: Uuid,                 // unique uuid
+    id: String,                 // user id for queue - also unique
+    settings: QueueSettings,    // user provided configuration
+    blob_store: BlobStore,      // where offloaded messages are kept
+    dead_letters: Vec<Message>, // messages evicted for the dead letter queue, not yet moved
+    last_used: DateTime<Utc>,   // the last time a message was read from or added to the queue
+    limiter: Limiter,           // enforces settings.rate_limit
+    counts: QueueCounts,
+    arrivals: Arc<Notify>, // woken as messages are added or released, for whoever waits on the queue
 }
 
 impl Queue {
-    pub fn new(id: String, read_timeout: u32, max_batch: u32) -> Self {
+    pub fn new(id: String, settings: QueueSettings, blob_store: BlobStore) -> Self {
+        let limiter = Limiter::new(settings.rate_limit);
         Queue {
             queue: vec![],
-            read_timeout,
             size: 0,
+            bytes: 0,
             uuid: Uuid::new_v4(),
             id,
-            max_batch,
+            settings,
+            blob_store,
+            dead_letters: vec![],
+            last_used: Utc::now(),
+            limiter,
+            counts: QueueCounts::default(),
+            arrivals: Arc::new(Notify::new()),
         }
     }
 
@@ -113,76 +233,642 @@ impl Queue {
         self.id.to_owned()
     }
 
+    pub fn get_size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn get_bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn get_read_timeout(&self) -> u32 {
+        self.settings.read_timeout
+    }
+
+    pub fn get_max_batch(&self) -> u32 {
+        self.settings.max_batch
+    }
+
+    pub fn get_dead_letter_queue_id(&self) -> Option<String> {
+        self.settings.dead_letter_queue_id.to_owned()
+    }
+
+    // Notified when a message is added or released. A message added while no one waits leaves a permit, so
+    // the next wait returns at once rather than missing it.
+    pub fn get_arrivals(&self) -> Arc<Notify> {
+        self.arrivals.clone()
+    }
+
+    // How long until the first message hidden by its read timeout becomes visible again, if any
+    // is hidden. Nothing is notified when that happens, so consumers wait no longer than this.
+    pub fn get_next_visible(&self) -> Option<std::time::Duration> {
+        let read_timeout = Duration::seconds(self.settings.read_timeout as i64);
+        // a message is visible once strictly past its read timeout, hence the extra millisecond
+        let visible_at = self
+            .queue
+            .iter()
+            .filter(|m| !m.is_visible(self.settings.read_timeout))
+            .filter_map(|m| m.last_read)
+            .map(|last_read| last_read + read_timeout + Duration::milliseconds(1))
+            .min()?;
+        Some((visible_at - Utc::now()).to_std().unwrap_or_default())
+    }
+
+    pub fn get_counts(&self) -> QueueCounts {
+        self.counts
+    }
+
+    // How many messages are hidden by their read timeout, having been handed out but not deleted
+    pub fn get_in_flight(&self) -> usize {
+        let read_timeout = self.settings.read_timeout;
+        self.queue
+            .iter()
+            .filter(|m| !m.is_visible(read_timeout))
+            .count()
+    }
+
+    // How long the oldest message has been in the queue, or zero if it is empty
+    pub fn get_oldest_age(&self) -> Duration {
+        match self.queue.iter().map(|m| m.added).min() {
+            Some(added) => Utc::now() - added,
+            None => Duration::zero(),
+        }
+    }
+
+    pub fn get_settings(&self) -> &QueueSettings {
+        &self.settings
+    }
+
+    // Changes the queue's settings. Messages already in the queue are left as they are.
+    pub fn set_settings(&mut self, settings: QueueSettings) {
+        if settings.rate_limit != self.settings.rate_limit {
+            self.limiter = Limiter::new(settings.rate_limit);
+        }
+        self.settings = settings;
+    }
+
+    // Whether a consumer presenting the given token may read and delete messages
+    pub fn accepts_consumer(&self, consumer_token: &Option<String>) -> bool {
+        match &self.settings.consumer_token {
+            None => true,
+            Some(token) => Some(token) == consumer_token.as_ref(),
+        }
+    }
+
+    // Whether the queue has gone unused for longer than its auto delete period
+    pub fn is_expired(&self) -> bool {
+        match self.settings.auto_delete_after {
+            None => false,
+            Some(seconds) => Utc::now() - self.last_used > Duration::seconds(seconds as i64),
+        }
+    }
+
+    // Checks whether a message of the given size can be added, either inline or via the blob store
+    pub fn check_message_size(&self, size: usize) -> Result<(), QueueError> {
+        match self.settings.max_message_size {
+            Some(limit) if size > limit as usize && !self.settings.offload_large_messages => {
+                Err(QueueError::MessageTooLarge(size, limit))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Counts a request to read, add or delete messages against the queue's rate limit
+    pub fn take_request(&mut self) -> Result<(), QueueError> {
+        self.limiter.take_request().map_err(QueueError::RateLimited)
+    }
+
+    // Checks whether a batch of messages of the given sizes can be added without being rejected
+    pub fn check_batch(&self, sizes: &[usize]) -> Result<(), QueueError> {
+        for size in sizes.iter() {
+            self.check_message_size(*size)?;
+        }
+        self.limiter
+            .check_messages(sizes.len())
+            .map_err(QueueError::RateLimited)?;
+        if self.settings.overflow_policy != OverflowPolicy::Reject {
+            return Ok(());
+        }
+        let batch_bytes = sizes.iter().sum::<usize>() as u64;
+        let over_max_messages = match self.settings.max_messages {
+            Some(max) => self.size as usize + sizes.len() > max as usize,
+            None => false,
+        };
+        let over_max_bytes = match self.settings.max_bytes {
+            Some(max) => self.bytes + batch_bytes > max,
+            None => false,
+        };
+        if over_max_messages || over_max_bytes {
+            return Err(QueueError::QueueFull(format!(
+                "it holds {} messages and {} bytes, and cannot take {} more messages of {} bytes",
+                self.size,
+                self.bytes,
+                sizes.len(),
+                batch_bytes
+            )));
+        }
+        Ok(())
+    }
+
     pub fn add_to_queue(
         &mut self,
-        cipher: &MutexGuard<AesGcm<Aes256, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>>,
+        cipher: &Aes256Gcm,
         id: String,
         content: String,
-    ) -> Result<String, EncryptionError> {
+        properties: MessageProperties,
+    ) -> Result<String, QueueError> {
+        self.check_message_size(content.len())?;
+        // a message the queue has no room for doesn't use up its rate limit
+        self.limiter
+            .check_messages(1)
+            .map_err(QueueError::RateLimited)?;
+        self.check_room(content.len())?;
+        // nothing is evicted until the content is stored, so a failed add leaves the queue as it was
+        let (compression, nonce, payload) = self.seal(cipher, &content)?;
+        if let Err(e) = self.limiter.take_messages(1) {
+            self.remove_payload(&payload);
+            return Err(QueueError::RateLimited(e));
+        }
+        self.make_room(content.len());
+        let message = Message::new(id, payload, nonce, compression, content.len(), properties);
+        let uuid = message.get_uuid();
+        self.push(message);
+        self.last_used = Utc::now();
+        self.counts.published += 1;
+        Ok(uuid)
+    }
+
+    // Compresses, encrypts and, if it is over max_message_size, offloads a message's content
+    fn seal(
+        &mut self,
+        cipher: &Aes256Gcm,
+        content: &str,
+    ) -> Result<(Option<Compression>, Nonce, Payload), QueueError> {
+        // compress before encrypting, as ciphertext does not compress
+        let (compression, plain_content) = match self.settings.compression {
+            Some(c) if content.len() >= self.settings.compression_threshold as usize => {
+                match c.compress(content.as_bytes()) {
+                    Ok(compressed) => (Some(c), compressed),
+                    Err(_) => return Err(QueueError::Compression),
+                }
+            }
+            _ => (None, content.as_bytes().to_vec()),
+        };
         let nonce = Aes256Gcm::generate_nonce(&mut OsRng); // 96-bits; unique per message
-        let ciphered_content = match cipher.encrypt(&nonce, content.as_ref()) {
+        let ciphered_content = match cipher.encrypt(&nonce, plain_content.as_ref()) {
             Ok(s) => s,
-            Err(_) => return Err(EncryptionError),
+            Err(_) => {
+                self.counts.encryption_errors += 1;
+                return Err(QueueError::Encryption);
+            }
         };
-        let message = Message::new(id, ciphered_content, nonce);
-        let uuid = message.get_uuid();
-        self.queue.push(message);
-        self.incr_size();
-        Ok(uuid)
+        let payload = match self.settings.max_message_size {
+            Some(limit) if content.len() > limit as usize => {
+                match self.blob_store.put(&Uuid::new_v4(), &ciphered_content) {
+                    Ok(path) => Payload::Blob(path),
+                    Err(_) => return Err(QueueError::Storage),
+                }
+            }
+            _ => Payload::Inline(ciphered_content),
+        };
+        Ok((compression, nonce, payload))
     }
 
-    pub fn dispatch(
+    // Takes a message evicted from another queue, making room by dropping the oldest messages.
+    // A message larger than the queue's max_bytes is dropped instead.
+    pub fn add_dead_letter(&mut self, mut message: Message) {
+        if self.check_fits(message.size).is_err() {
+            self.discard(message);
+            return;
+        }
+        while self.size > 0 && !self.has_room_for(message.size) {
+            let oldest = self.pop_oldest();
+            self.discard(oldest);
+        }
+        message.last_read = None;
+        self.push(message);
+    }
+
+    pub fn take_dead_letters(&mut self) -> Vec<Message> {
+        self.dead_letters.drain(..).collect()
+    }
+
+    pub fn dispatch(&mut self, cipher: &Aes256Gcm) -> Result<Vec<DecryptedMessage>, QueueError> {
+        let max_batch = self.settings.max_batch as usize;
+        self.dispatch_up_to(cipher, max_batch)
+    }
+
+    // Like dispatch, but hands out at most `limit` messages
+    pub fn dispatch_up_to(
         &mut self,
-        cipher: MutexGuard<AesGcm<Aes256, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>>,
-    ) -> Result<Vec<DecryptedMessage>, EncryptionError> {
-        if self.size == 0 {
+        cipher: &Aes256Gcm,
+        limit: usize,
+    ) -> Result<Vec<DecryptedMessage>, QueueError> {
+        self.last_used = Utc::now();
+        if self.size == 0 || limit == 0 {
             return Ok(vec![]);
         }
         let mut messages_to_dispatch = vec![];
         for message in self.queue.iter_mut() {
-            if message.is_visible(self.read_timeout) {
+            if message.is_visible(self.settings.read_timeout) {
+                let ciphered_content = match &message.content {
+                    Payload::Inline(c) => c.to_owned(),
+                    Payload::Blob(path) => match self.blob_store.get(path) {
+                        Ok(c) => c,
+                        Err(_) => return Err(QueueError::Storage),
+                    },
+                };
                 // uncipher the message
                 let unciphered_content =
-                    match cipher.decrypt(&message.nonce, message.content.as_ref()) {
+                    match cipher.decrypt(&message.nonce, ciphered_content.as_ref()) {
                         Ok(s) => s,
-                        Err(_) => return Err(EncryptionError),
+                        Err(_) => {
+                            self.counts.decryption_errors += 1;
+                            return Err(QueueError::Encryption);
+                        }
                     };
+                let unciphered_content = match message.compression {
+                    Some(c) => match c.decompress(&unciphered_content) {
+                        Ok(s) => s,
+                        Err(_) => return Err(QueueError::Compression),
+                    },
+                    None => unciphered_content,
+                };
                 let content = match String::from_utf8(unciphered_content) {
                     Ok(s) => s,
-                    Err(_) => return Err(EncryptionError),
+                    Err(_) => return Err(QueueError::Encryption),
                 };
 
-                let decrypted_message =
-                    DecryptedMessage::new(message.id.clone(), content, message.uuid);
+                let decrypted_message = DecryptedMessage::new(
+                    message.id.clone(),
+                    content,
+                    message.uuid,
+                    message.properties.clone(),
+                );
                 message.last_read = Some(Utc::now());
                 messages_to_dispatch.push(decrypted_message);
+                self.counts.received += 1;
             }
-            if messages_to_dispatch.len() == self.max_batch as usize {
+            if messages_to_dispatch.len() == limit {
                 break;
             }
         }
         Ok(messages_to_dispatch)
     }
 
+    // Restarts the read timeout of messages a consumer still holds, so they stay hidden from others
+    pub fn renew(&mut self, uuids: &[String]) {
+        self.last_used = Utc::now();
+        for message in self.queue.iter_mut() {
+            if uuids.contains(&message.get_uuid())
+                && !message.is_visible(self.settings.read_timeout)
+            {
+                message.last_read = Some(Utc::now());
+            }
+        }
+    }
+
+    // Makes a message that is hidden by its read timeout visible again, for consumers giving it up
+    pub fn release(&mut self, uuid: &String) -> bool {
+        for message in self.queue.iter_mut() {
+            if message.uuid_matches(uuid) && !message.is_visible(self.settings.read_timeout) {
+                message.last_read = None;
+                self.arrivals.notify_one();
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn rem_from_queue(&mut self, uuid: &String) -> Option<Message> {
+        let message = self.take_from_queue(uuid)?;
+        self.remove_blob(&message);
+        self.counts.deleted += 1;
+        Some(message)
+    }
+
+    // Removes a message that is hidden by its read timeout but keeps its payload, so it can be
+    // moved to another queue
+    pub fn take_from_queue(&mut self, uuid: &String) -> Option<Message> {
         if self.size == 0 {
             return None;
         }
         for (idx, message) in self.queue.iter().enumerate() {
-            if message.uuid_matches(uuid) && !message.is_visible(self.read_timeout) {
+            if message.uuid_matches(uuid) && !message.is_visible(self.settings.read_timeout) {
                 let message_to_return = self.queue.remove(idx);
-                self.decr_size();
+                self.decr_size(message_to_return.size);
                 return Some(message_to_return);
             }
         }
         None
     }
 
-    fn incr_size(&mut self) {
+    fn has_room_for(&self, size: usize) -> bool {
+        let under_max_messages = match self.settings.max_messages {
+            Some(max) => self.size < max,
+            None => true,
+        };
+        let under_max_bytes = match self.settings.max_bytes {
+            Some(max) => self.bytes + size as u64 <= max,
+            None => true,
+        };
+        under_max_messages && under_max_bytes
+    }
+
+    // Checks a message of the given size could fit in the queue were it empty
+    fn check_fits(&self, size: usize) -> Result<(), QueueError> {
+        match self.settings.max_bytes {
+            Some(max) if size as u64 > max => Err(QueueError::QueueFull(format!(
+                "the message is {} bytes but the queue holds at most {} bytes",
+                size, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // Checks the overflow policy can make room for a message of the given size
+    fn check_room(&self, size: usize) -> Result<(), QueueError> {
+        self.check_fits(size)?;
+        if self.settings.overflow_policy == OverflowPolicy::Reject && !self.has_room_for(size) {
+            return Err(QueueError::QueueFull(format!(
+                "it holds {} messages and {} bytes",
+                self.size, self.bytes
+            )));
+        }
+        Ok(())
+    }
+
+    // Applies the overflow policy until a message of the given size fits. Must only be called
+    // once check_room has passed.
+    fn make_room(&mut self, size: usize) {
+        while self.size > 0 && !self.has_room_for(size) {
+            match self.settings.overflow_policy {
+                OverflowPolicy::Reject => return,
+                OverflowPolicy::DropOldest => {
+                    let oldest = self.pop_oldest();
+                    self.discard(oldest);
+                }
+                OverflowPolicy::DeadLetter => {
+                    let oldest = self.pop_oldest();
+                    self.dead_letters.push(oldest);
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, message: Message) {
+        self.incr_size(message.size);
+        self.queue.push(message);
+        self.arrivals.notify_one();
+    }
+
+    // must only be called on a non-empty queue
+    fn pop_oldest(&mut self) -> Message {
+        let message = self.queue.remove(0);
+        self.decr_size(message.size);
+        message
+    }
+
+    // Drops a message taken from the queue, along with its offloaded content
+    pub fn discard(&self, message: Message) {
+        self.remove_blob(&message);
+    }
+
+    fn remove_blob(&self, message: &Message) {
+        self.remove_payload(&message.content);
+    }
+
+    fn remove_payload(&self, payload: &Payload) {
+        if let Payload::Blob(path) = payload {
+            // the message is gone either way, so a stale blob is not worth failing over
+            let _ = self.blob_store.remove(path);
+        }
+    }
+
+    fn incr_size(&mut self, bytes: usize) {
         self.size += 1;
+        self.bytes += bytes as u64;
     }
 
-    fn decr_size(&mut self) {
+    fn decr_size(&mut self, bytes: usize) {
         self.size -= 1;
+        self.bytes -= bytes as u64;
+    }
+}
+
+// However a queue is removed, the content its messages offloaded to the blob store goes with it,
+// and whoever waits on the queue is woken to find it gone
+impl Drop for Queue {
+    fn drop(&mut self) {
+        self.arrivals.notify_waiters();
+        for message in self.queue.iter().chain(self.dead_letters.iter()) {
+            self.remove_blob(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::aead::KeyInit;
+
+    use super::*;
+    use crate::queue_api::publish_to_queue;
+
+    fn queue(settings: QueueSettings) -> Queue {
+        Queue::new(String::from("q"), settings, blob_store(&blob_dir()))
+    }
+
+    // each test gets its own blob directory, so it can count the blobs left in it
+    fn blob_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("rqs-test-blobs-{}", Uuid::new_v4()))
+    }
+
+    fn blob_store(dir: &PathBuf) -> BlobStore {
+        BlobStore::new(dir.to_owned()).unwrap()
+    }
+
+    fn blob_count(dir: &PathBuf) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    fn offloading(max_messages: Option<u32>, overflow_policy: OverflowPolicy) -> QueueSettings {
+        QueueSettings {
+            read_timeout: 30,
+            max_batch: 10,
+            max_message_size: Some(4),
+            offload_large_messages: true,
+            max_messages,
+            overflow_policy,
+            dead_letter_queue_id: Some(String::from("dlq")),
+            ..Default::default()
+        }
+    }
+
+    fn cipher() -> Aes256Gcm {
+        Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng))
+    }
+
+    fn add(queue: &mut Queue, cipher: &Aes256Gcm, content: &str) -> Result<String, QueueError> {
+        let id = String::from("m");
+        queue.add_to_queue(cipher, id, content.to_owned(), MessageProperties::default())
+    }
+
+    #[test]
+    fn rejected_messages_do_not_use_up_the_rate_limit() {
+        let mut queue = queue(QueueSettings {
+            read_timeout: 30,
+            max_batch: 10,
+            max_messages: Some(1),
+            rate_limit: RateLimit {
+                requests_per_second: None,
+                messages_per_second: Some(2),
+            },
+            ..Default::default()
+        });
+        let cipher = cipher();
+        assert!(add(&mut queue, &cipher, "a").is_ok());
+        assert!(matches!(
+            add(&mut queue, &cipher, "b"),
+            Err(QueueError::QueueFull(_))
+        ));
+        let uuid = queue.dispatch(&cipher).unwrap()[0].get_uuid();
+        queue.rem_from_queue(&uuid);
+        // the full queue turned the second message away, so there is a token left for this one
+        assert!(add(&mut queue, &cipher, "c").is_ok());
+        assert!(matches!(
+            add(&mut queue, &cipher, "d"),
+            Err(QueueError::RateLimited(_))
+        ));
+    }
+
+    #[test]
+    fn offloaded_content_is_removed_with_its_queue() {
+        let dir = blob_dir();
+        let cipher = cipher();
+        let mut queue = Queue::new(
+            String::from("q"),
+            offloading(None, OverflowPolicy::Reject),
+            blob_store(&dir),
+        );
+        add(&mut queue, &cipher, "large content").unwrap();
+        add(&mut queue, &cipher, "more large content").unwrap();
+        add(&mut queue, &cipher, "tiny").unwrap();
+        assert_eq!(blob_count(&dir), 2);
+        let uuid = queue.dispatch(&cipher).unwrap()[0].get_uuid();
+        queue.rem_from_queue(&uuid);
+        assert_eq!(blob_count(&dir), 1);
+        drop(queue);
+        assert_eq!(blob_count(&dir), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn offloaded_content_moves_with_dead_letters() {
+        let dir = blob_dir();
+        let cipher = cipher();
+        let store = blob_store(&dir);
+        let mut queues = HashMap::from([
+            (
+                String::from("q"),
+                Queue::new(
+                    String::from("q"),
+                    offloading(Some(1), OverflowPolicy::DeadLetter),
+                    store.clone(),
+                ),
+            ),
+            (
+                String::from("dlq"),
+                Queue::new(
+                    String::from("dlq"),
+                    offloading(None, OverflowPolicy::Reject),
+                    store.clone(),
+                ),
+            ),
+        ]);
+        let q = String::from("q");
+        for content in ["first large", "second large"] {
+            let (id, content) = (String::from("m"), content.to_owned());
+            publish_to_queue(&mut queues, &cipher, &q, id, content, Default::default()).unwrap();
+        }
+        assert_eq!(queues["dlq"].get_size(), 1);
+        assert_eq!(blob_count(&dir), 2);
+
+        // once the dead letter queue is gone, evicted messages are dropped with their content
+        queues.remove("dlq");
+        assert_eq!(blob_count(&dir), 1);
+        let (id, content) = (String::from("m"), String::from("third large"));
+        publish_to_queue(&mut queues, &cipher, &q, id, content, Default::default()).unwrap();
+        assert_eq!(queues["q"].get_size(), 1);
+        assert_eq!(blob_count(&dir), 1);
+        drop(queues);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_failed_add_evicts_nothing() {
+        let dir = blob_dir();
+        let cipher = cipher();
+        let mut queue = Queue::new(
+            String::from("q"),
+            offloading(Some(1), OverflowPolicy::DropOldest),
+            blob_store(&dir),
+        );
+        add(&mut queue, &cipher, "tiny").unwrap();
+        // with the blob directory gone, offloading the next message fails
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            add(&mut queue, &cipher, "large content"),
+            Err(QueueError::Storage)
+        ));
+        assert_eq!(queue.get_size(), 1);
+        assert_eq!(queue.dispatch(&cipher).unwrap()[0].get_content(), "tiny");
+    }
+
+    #[test]
+    fn dead_letters_are_held_to_max_bytes() {
+        let cipher = cipher();
+        let store = blob_store(&blob_dir());
+        let settings = |max_bytes| QueueSettings {
+            read_timeout: 30,
+            max_batch: 10,
+            max_messages: Some(1),
+            max_bytes,
+            overflow_policy: OverflowPolicy::DeadLetter,
+            dead_letter_queue_id: Some(String::from("dlq")),
+            ..Default::default()
+        };
+        let mut queues = HashMap::from([
+            (
+                String::from("q"),
+                Queue::new(String::from("q"), settings(None), store.clone()),
+            ),
+            (
+                String::from("dlq"),
+                Queue::new(String::from("dlq"), settings(Some(8)), store.clone()),
+            ),
+        ]);
+        let q = String::from("q");
+        for content in ["small", "larger than eight", "last"] {
+            let (id, content) = (String::from("m"), content.to_owned());
+            publish_to_queue(&mut queues, &cipher, &q, id, content, Default::default()).unwrap();
+        }
+        // "larger than eight" could never fit, so it was dropped rather than pushing out "small"
+        assert_eq!(queues["dlq"].get_size(), 1);
+        assert_eq!(queues["dlq"].get_bytes(), 5);
+    }
+
+    #[actix_web::test]
+    async fn adding_a_message_wakes_a_waiter_even_if_it_is_not_waiting_yet() {
+        let mut queue = queue(QueueSettings {
+            read_timeout: 30,
+            max_batch: 10,
+            ..Default::default()
+        });
+        let arrivals = queue.get_arrivals();
+        add(&mut queue, &cipher(), "reply").unwrap();
+        let woken =
+            actix_web::rt::time::timeout(std::time::Duration::from_secs(1), arrivals.notified())
+                .await;
+        assert!(woken.is_ok());
     }
 }
@@ -1,9 +1,109 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize)]
+use super::compression::Compression;
+use super::queue::{OverflowPolicy, QueueSettings};
+use crate::app_types::ApiError;
+use crate::namespace_api::namespace::Namespace;
+use crate::rate_limit::RateLimit;
+
+// content smaller than this rarely gets any smaller when compressed
+pub const DEFAULT_COMPRESSION_THRESHOLD: u32 = 256;
+
+fn default_compression_threshold() -> u32 {
+    DEFAULT_COMPRESSION_THRESHOLD
+}
+
+fn default_reply_read_timeout() -> u32 {
+    30
+}
+
+fn default_reply_max_batch() -> u32 {
+    10
+}
+
+fn default_auto_delete_after() -> u32 {
+    60
+}
+
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NewQueueRequest {
     pub read_timeout: u32,
     pub queue_id: String,
-    pub max_batch: u32, 
+    pub max_batch: u32,
+    pub max_message_size: Option<u32>,
+    #[serde(default)]
+    pub offload_large_messages: bool,
+    pub compression: Option<Compression>,
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: u32,
+    pub max_messages: Option<u32>,
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub overflow_policy: OverflowPolicy,
+    pub dead_letter_queue_id: Option<String>,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+impl NewQueueRequest {
+    // The request that would make a queue with the given settings
+    pub fn new(queue_id: &str, settings: &QueueSettings) -> Self {
+        NewQueueRequest {
+            read_timeout: settings.read_timeout,
+            queue_id: queue_id.to_owned(),
+            max_batch: settings.max_batch,
+            max_message_size: settings.max_message_size,
+            offload_large_messages: settings.offload_large_messages,
+            compression: settings.compression,
+            compression_threshold: settings.compression_threshold,
+            max_messages: settings.max_messages,
+            max_bytes: settings.max_bytes,
+            overflow_policy: settings.overflow_policy,
+            dead_letter_queue_id: settings.dead_letter_queue_id.to_owned(),
+            rate_limit: settings.rate_limit,
+        }
+    }
+
+    pub fn qualify(mut self, namespace: &Namespace) -> Result<Self, ApiError> {
+        self.queue_id = namespace.qualify(&self.queue_id)?;
+        self.dead_letter_queue_id = namespace.qualify_optional(&self.dead_letter_queue_id)?;
+        Ok(self)
+    }
+
+    // The request as a namespace sees it, or None if the queue is in another namespace
+    pub fn unqualify(mut self, namespace: &Namespace) -> Option<Self> {
+        self.queue_id = namespace.unqualify(&self.queue_id)?;
+        self.dead_letter_queue_id = self
+            .dead_letter_queue_id
+            .map(|id| namespace.unqualify(&id).unwrap_or(id));
+        Some(self)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewReplyQueueRequest {
+    #[serde(default = "default_reply_read_timeout")]
+    pub read_timeout: u32,
+    #[serde(default = "default_reply_max_batch")]
+    pub max_batch: u32,
+    #[serde(default = "default_auto_delete_after")]
+    pub auto_delete_after: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplyQueueResponse {
+    pub queue_id: String,
+    pub consumer_token: String,
+}
+
+impl ReplyQueueResponse {
+    pub fn new(queue_id: String, consumer_token: String) -> Self {
+        ReplyQueueResponse {
+            queue_id,
+            consumer_token,
+        }
+    }
 }
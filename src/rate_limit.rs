@@ -0,0 +1,411 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest};
+use serde::{Deserialize, Serialize};
+
+use crate::app_types::{ApiError, AppState};
+use crate::auth_api::api_key::ApiKey;
+use crate::namespace_api::get_namespace;
+
+// How fast an API key or a queue may be used. Limits that are not given are not enforced.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    pub requests_per_second: Option<u32>,
+    pub messages_per_second: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    Exceeded(u32, &'static str, Duration), // the limit, what it counts and when to retry
+    BatchTooLarge(usize, u32),             // a batch bigger than the limit can never be let through
+}
+
+impl RateLimitError {
+    // Describes the error for `limited`, as in "the API key"
+    pub fn to_api_error(&self, limited: &str) -> ApiError {
+        let message = format!("{} for {}", self, limited);
+        match self {
+            RateLimitError::Exceeded(_, _, retry_after) => {
+                ApiError::new(StatusCode::TOO_MANY_REQUESTS, message).with_retry_after(*retry_after)
+            }
+            RateLimitError::BatchTooLarge(_, _) => ApiError::bad_request(message),
+        }
+    }
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::Exceeded(limit, unit, _) => {
+                write!(
+                    f,
+                    "The rate limit of {} {} a second was exceeded",
+                    limit, unit
+                )
+            }
+            RateLimitError::BatchTooLarge(size, limit) => write!(
+                f,
+                "A batch of {} messages exceeds the rate limit of {} messages a second",
+                size, limit
+            ),
+        }
+    }
+}
+
+// Tokens refill continuously at the limit, and up to a second's worth can be saved up
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    limit: u32,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: u32) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn available(&self) -> f64 {
+        let refilled = self.updated.elapsed().as_secs_f64() * self.limit as f64;
+        (self.tokens + refilled).min(self.limit as f64)
+    }
+
+    fn check(&self, count: usize, unit: &'static str) -> Result<(), RateLimitError> {
+        if count > self.limit as usize {
+            return Err(RateLimitError::BatchTooLarge(count, self.limit));
+        }
+        let missing = count as f64 - self.available();
+        if missing > 0.0 {
+            let retry_after = Duration::from_secs_f64(missing / self.limit as f64);
+            return Err(RateLimitError::Exceeded(self.limit, unit, retry_after));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, count: usize, unit: &'static str) -> Result<(), RateLimitError> {
+        self.check(count, unit)?;
+        self.tokens = self.available() - count as f64;
+        self.updated = Instant::now();
+        Ok(())
+    }
+
+    fn give_back(&mut self, count: usize) {
+        self.tokens = (self.available() + count as f64).min(self.limit as f64);
+        self.updated = Instant::now();
+    }
+}
+
+// Enforces a rate limit, for whatever holds it
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    limit: RateLimit,
+    requests: Option<TokenBucket>,
+    messages: Option<TokenBucket>,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Limiter {
+            limit,
+            requests: limit.requests_per_second.map(TokenBucket::new),
+            messages: limit.messages_per_second.map(TokenBucket::new),
+        }
+    }
+
+    pub fn get_limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    pub fn take_request(&mut self) -> Result<(), RateLimitError> {
+        match &mut self.requests {
+            Some(bucket) => bucket.take(1, "requests"),
+            None => Ok(()),
+        }
+    }
+
+    // Checks a batch of messages would be let through, without counting them
+    pub fn check_messages(&self, count: usize) -> Result<(), RateLimitError> {
+        match &self.messages {
+            Some(bucket) => bucket.check(count, "messages"),
+            None => Ok(()),
+        }
+    }
+
+    pub fn take_messages(&mut self, count: usize) -> Result<(), RateLimitError> {
+        match &mut self.messages {
+            Some(bucket) => bucket.take(count, "messages"),
+            None => Ok(()),
+        }
+    }
+
+    // Gives back the tokens taken for messages that were not published after all
+    pub fn give_back_messages(&mut self, count: usize) {
+        if let Some(bucket) = &mut self.messages {
+            bucket.give_back(count);
+        }
+    }
+}
+
+// Counts the messages a REST request publishes against its API key's rate limit, and its
+// namespace's daily quota
+pub async fn admit_messages(
+    data: &AppState,
+    req: &HttpRequest,
+    count: usize,
+) -> Result<(), ApiError> {
+    let api_key = req.extensions().get::<ApiKey>().cloned();
+    admit_messages_as(data, api_key.as_ref(), &get_namespace(req).name, count).await
+}
+
+// Gives back what `admit_messages` took, for messages the target refused
+pub async fn give_back_messages(data: &AppState, req: &HttpRequest, count: usize) {
+    let api_key = req.extensions().get::<ApiKey>().cloned();
+    give_back_messages_as(data, api_key.as_ref(), &get_namespace(req).name, count).await
+}
+
+// Counts the messages a key publishes in a namespace against the key's rate limit, and the
+// namespace's daily quota. Both are checked before either is taken from, so a batch one of them
+// refuses counts against neither.
+pub async fn admit_messages_as(
+    data: &AppState,
+    api_key: Option<&ApiKey>,
+    namespace: &str,
+    count: usize,
+) -> Result<(), ApiError> {
+    let mut namespaces = data.get_namespaces().lock().await;
+    let mut api_keys = data.get_api_keys().lock().await;
+    let mut limiter = api_key.and_then(|api_key| {
+        api_keys
+            .values_mut()
+            .find(|k| k.id == api_key.id)
+            .map(|k| &mut k.limiter)
+    });
+    let mut namespace = namespaces.get_mut(namespace);
+    if let Some(limiter) = &limiter {
+        if let Err(e) = limiter.check_messages(count) {
+            return Err(e.to_api_error("the API key"));
+        }
+    }
+    if let Some(namespace) = &mut namespace {
+        namespace.check_daily_quota(count)?;
+    }
+    // both locks are held, so neither can fail once checked
+    if let Some(limiter) = &mut limiter {
+        if let Err(e) = limiter.take_messages(count) {
+            return Err(e.to_api_error("the API key"));
+        }
+    }
+    match namespace {
+        Some(namespace) => namespace.take_daily_quota(count),
+        None => Ok(()),
+    }
+}
+
+// Gives back what `admit_messages_as` took, for messages the target refused, so a publish that
+// fails does not use up the key's rate limit or the namespace's quota
+pub async fn give_back_messages_as(
+    data: &AppState,
+    api_key: Option<&ApiKey>,
+    namespace: &str,
+    count: usize,
+) {
+    let mut namespaces = data.get_namespaces().lock().await;
+    if let Some(namespace) = namespaces.get_mut(namespace) {
+        namespace.give_back_daily_quota(count);
+    }
+    if let Some(key_id) = api_key.map(|k| k.id) {
+        let mut api_keys = data.get_api_keys().lock().await;
+        if let Some(api_key) = api_keys.values_mut().find(|k| k.id == key_id) {
+            api_key.limiter.give_back_messages(count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_api::api_key::hash_key;
+    use crate::namespace_api::namespace::DEFAULT_NAMESPACE;
+
+    // A bucket last taken from `ago`, with `tokens` left then
+    fn bucket(limit: u32, tokens: f64, ago: Duration) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens,
+            updated: Instant::now() - ago,
+        }
+    }
+
+    #[test]
+    fn buckets_start_full_and_let_a_second_through_at_once() {
+        let mut bucket = TokenBucket::new(5);
+        for _ in 0..5 {
+            assert!(bucket.take(1, "requests").is_ok());
+        }
+        match bucket.take(1, "requests") {
+            Err(RateLimitError::Exceeded(5, "requests", retry_after)) => {
+                assert!(retry_after > Duration::ZERO);
+                assert!(retry_after <= Duration::from_millis(200));
+            }
+            other => panic!("expected the limit to be exceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn buckets_refill_at_the_limit_up_to_a_second_worth() {
+        let bucket_half_refilled = bucket(10, 0.0, Duration::from_millis(500));
+        let available = bucket_half_refilled.available();
+        assert!((5.0..6.0).contains(&available));
+        assert!(bucket_half_refilled.check(5, "messages").is_ok());
+        assert!(bucket_half_refilled.check(7, "messages").is_err());
+
+        let idle = bucket(10, 0.0, Duration::from_secs(60));
+        assert_eq!(idle.available(), 10.0);
+    }
+
+    #[test]
+    fn checking_takes_nothing() {
+        let mut bucket = TokenBucket::new(2);
+        assert!(bucket.check(2, "messages").is_ok());
+        assert!(bucket.check(2, "messages").is_ok());
+        assert!(bucket.take(2, "messages").is_ok());
+        assert!(bucket.check(1, "messages").is_err());
+    }
+
+    #[test]
+    fn batches_larger_than_the_limit_never_get_through() {
+        let bucket = TokenBucket::new(3);
+        assert!(matches!(
+            bucket.check(4, "messages"),
+            Err(RateLimitError::BatchTooLarge(4, 3))
+        ));
+        let error = RateLimitError::BatchTooLarge(4, 3).to_api_error("the queue");
+        assert_eq!(error.get_status(), StatusCode::BAD_REQUEST);
+        let error = RateLimitError::Exceeded(3, "messages", Duration::from_secs(1));
+        let error = error.to_api_error("the queue");
+        assert_eq!(error.get_status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn limits_that_are_not_given_are_not_enforced() {
+        let mut limiter = Limiter::new(RateLimit {
+            requests_per_second: Some(1),
+            messages_per_second: None,
+        });
+        assert!(limiter.take_messages(1_000_000).is_ok());
+        assert!(limiter.take_request().is_ok());
+        assert!(limiter.take_request().is_err());
+    }
+
+    #[actix_web::test]
+    async fn messages_count_against_the_key_and_the_namespace() {
+        let data = AppState::for_tests();
+        let mut api_key = ApiKey::new("publisher", false, vec![], DEFAULT_NAMESPACE);
+        api_key.limiter = Limiter::new(RateLimit {
+            requests_per_second: None,
+            messages_per_second: Some(10),
+        });
+        data.get_api_keys()
+            .lock()
+            .await
+            .insert(hash_key("key"), api_key.clone());
+        data.get_namespaces()
+            .lock()
+            .await
+            .get_mut(DEFAULT_NAMESPACE)
+            .unwrap()
+            .max_messages_per_day = Some(12);
+
+        let admitted = admit_messages_as(&data, Some(&api_key), DEFAULT_NAMESPACE, 10).await;
+        assert!(admitted.is_ok());
+        let refused = admit_messages_as(&data, Some(&api_key), DEFAULT_NAMESPACE, 1).await;
+        assert_eq!(
+            refused.unwrap_err().get_status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // without a key only the namespace's quota applies, which has 2 messages left today
+        assert!(admit_messages_as(&data, None, DEFAULT_NAMESPACE, 2)
+            .await
+            .is_ok());
+        assert!(admit_messages_as(&data, None, DEFAULT_NAMESPACE, 1)
+            .await
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn a_batch_either_limit_refuses_counts_against_neither() {
+        let data = AppState::for_tests();
+        let mut api_key = ApiKey::new("publisher", false, vec![], DEFAULT_NAMESPACE);
+        api_key.limiter = Limiter::new(RateLimit {
+            requests_per_second: None,
+            messages_per_second: Some(10),
+        });
+        data.get_api_keys()
+            .lock()
+            .await
+            .insert(hash_key("key"), api_key.clone());
+        data.get_namespaces()
+            .lock()
+            .await
+            .get_mut(DEFAULT_NAMESPACE)
+            .unwrap()
+            .max_messages_per_day = Some(5);
+
+        // the namespace refuses the batch, so the key's tokens are left alone
+        assert!(
+            admit_messages_as(&data, Some(&api_key), DEFAULT_NAMESPACE, 8)
+                .await
+                .is_err()
+        );
+        assert!(admit_messages_as(&data, Some(&api_key), "elsewhere", 10)
+            .await
+            .is_ok());
+        // and the key refuses this one, so the namespace's quota is left alone
+        assert!(
+            admit_messages_as(&data, Some(&api_key), DEFAULT_NAMESPACE, 1)
+                .await
+                .is_err()
+        );
+        let namespaces = data.get_namespaces().lock().await;
+        assert_eq!(namespaces[DEFAULT_NAMESPACE].get_messages_today(), 0);
+    }
+
+    #[actix_web::test]
+    async fn messages_given_back_can_be_published_again() {
+        let data = AppState::for_tests();
+        let mut api_key = ApiKey::new("publisher", false, vec![], DEFAULT_NAMESPACE);
+        api_key.limiter = Limiter::new(RateLimit {
+            requests_per_second: None,
+            messages_per_second: Some(10),
+        });
+        data.get_api_keys()
+            .lock()
+            .await
+            .insert(hash_key("key"), api_key.clone());
+        data.get_namespaces()
+            .lock()
+            .await
+            .get_mut(DEFAULT_NAMESPACE)
+            .unwrap()
+            .max_messages_per_day = Some(10);
+
+        assert!(
+            admit_messages_as(&data, Some(&api_key), DEFAULT_NAMESPACE, 10)
+                .await
+                .is_ok()
+        );
+        give_back_messages_as(&data, Some(&api_key), DEFAULT_NAMESPACE, 10).await;
+        assert!(
+            admit_messages_as(&data, Some(&api_key), DEFAULT_NAMESPACE, 10)
+                .await
+                .is_ok()
+        );
+    }
+}
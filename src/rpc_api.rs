@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix_web::{rt::time::timeout, web, HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+use crate::app_types::{AppState, JsonResponse};
+use crate::audit_api::audit;
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::api_key::ApiKey;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::{authorize, get_api_key};
+use crate::exchange_api::exchange_error_response;
+use crate::message_api::request::GetMessageResponse;
+use crate::namespace_api::check_quota;
+use crate::namespace_api::get_namespace;
+use crate::namespace_api::namespace::Namespace;
+use crate::queue_api::queue::{MessageProperties, Queue};
+use crate::queue_api::{
+    create_reply_queue, publish_to_queue, queue_error_response, reply_queue_id,
+};
+use crate::rate_limit::{admit_messages, give_back_messages};
+use request::CallRequest;
+
+mod request;
+
+// the longest a caller may wait for a reply, in seconds
+const MAX_TIMEOUT: u32 = 300;
+// how long a reply queue outlives the call, so late replies don't fail to publish
+const REPLY_QUEUE_GRACE_PERIOD: u32 = 60;
+const REPLY_QUEUE_MAX_BATCH: u32 = 10;
+
+pub async fn call(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    post_data: web::Json<CallRequest>,
+) -> HttpResponse {
+    if post_data.timeout == 0 || post_data.timeout > MAX_TIMEOUT {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!(
+                "The timeout {} is invalid, it must be between 1 and {} seconds",
+                post_data.timeout, MAX_TIMEOUT
+            ),
+        ));
+    }
+
+    let namespace = get_namespace(&req);
+    let post_data = match post_data.into_inner().qualify(&namespace) {
+        Ok(p) => p,
+        Err(e) => return e.to_response(),
+    };
+
+    let target = match (&post_data.queue_id, &post_data.exchange_id) {
+        (Some(queue_id), None) => Some((ResourceType::QUEUE, queue_id)),
+        (None, Some(exchange_id)) => Some((ResourceType::EXCHANGE, exchange_id)),
+        _ => None,
+    };
+    if let Some((resource_type, id)) = target {
+        if let Err(e) = authorize(&req, resource_type, id, Permission::PUBLISH) {
+            return e.to_response();
+        }
+    }
+    if let Err(e) = admit_messages(&data, &req, 1).await {
+        return e.to_response();
+    }
+
+    let correlation_id = Uuid::new_v4().to_string();
+    let api_key = get_api_key(&req);
+    let reply_queue_id = match publish_request(
+        &data,
+        &post_data,
+        &namespace,
+        &correlation_id,
+        api_key.as_ref(),
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(response) => {
+            give_back_messages(&data, &req, 1).await;
+            return response;
+        }
+    };
+    if let Some((_, id)) = target {
+        let detail = Some(format!("a request with correlation id {}", correlation_id));
+        audit(&data, &req, AuditAction::MessagesPublished, id, detail).await;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(post_data.timeout as u64);
+    loop {
+        let arrivals = {
+            let mut queues = data.get_queues().lock().await;
+            let reply_queue = match queues.get_mut(&reply_queue_id) {
+                None => {
+                    return HttpResponse::InternalServerError().json(JsonResponse::new(
+                        None::<String>,
+                        "Something went wrong. Please try again.",
+                    ))
+                }
+                Some(q) => q,
+            };
+            let cipher = data.get_cipher().lock().await;
+            let replies = match reply_queue.dispatch(&cipher) {
+                Ok(m) => m,
+                Err(e) => {
+                    queues.remove(&reply_queue_id);
+                    return queue_error_response(&e);
+                }
+            };
+            // replies without the matching correlation id are stray and dropped with the queue
+            let reply = replies
+                .iter()
+                .find(|m| m.get_properties().correlation_id.as_ref() == Some(&correlation_id));
+            if let Some(reply) = reply {
+                let response = GetMessageResponse::new(reply);
+                queues.remove(&reply_queue_id);
+                return HttpResponse::Accepted().json(JsonResponse::new(response, None::<String>));
+            }
+            if Instant::now() >= deadline {
+                queues.remove(&reply_queue_id);
+                return HttpResponse::GatewayTimeout().json(JsonResponse::new(
+                    None::<String>,
+                    format!("No reply was received within {} seconds", post_data.timeout),
+                ));
+            }
+            reply_queue.get_arrivals()
+        };
+        // the queue is checked again once a message arrives, or finally at the deadline
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let _ = timeout(remaining, arrivals.notified()).await;
+    }
+}
+
+// Publishes the request with a fresh reply queue in the caller's namespace, returning the reply
+// queue's id
+async fn publish_request(
+    data: &web::Data<AppState>,
+    post_data: &CallRequest,
+    namespace: &Namespace,
+    correlation_id: &str,
+    api_key: Option<&ApiKey>,
+) -> Result<String, HttpResponse> {
+    let may_publish = |queue_id: &str| {
+        api_key.is_none_or(|k| k.permits(ResourceType::QUEUE, queue_id, Permission::PUBLISH))
+    };
+    // the reply queue counts towards the namespace's quota, which stays locked until it is made
+    let _namespaces = check_quota(data, &namespace.name, ResourceType::QUEUE)
+        .await
+        .map_err(|e| e.to_response())?;
+    let id = post_data.message_id.to_owned();
+    let content = post_data.content.to_owned();
+    match (&post_data.queue_id, &post_data.exchange_id) {
+        (Some(queue_id), None) => {
+            let mut queues = data.get_queues().lock().await;
+            let reply_queue_id = new_reply_queue(data, &mut queues, namespace, post_data.timeout);
+            let properties =
+                reply_properties(namespace, &reply_queue_id, correlation_id, post_data);
+            let cipher = data.get_cipher().lock().await;
+            match publish_to_queue(&mut queues, &cipher, queue_id, id, content, properties) {
+                Ok(_) => Ok(reply_queue_id),
+                Err(e) => {
+                    queues.remove(&reply_queue_id);
+                    Err(queue_error_response(&e))
+                }
+            }
+        }
+        (None, Some(exchange_id)) => {
+            let exchanges = data.get_exchanges().lock().await;
+            let exchange = match exchanges.get(exchange_id) {
+                None => {
+                    return Err(HttpResponse::BadRequest().json(JsonResponse::new(
+                        None::<String>,
+                        format!("No exchange with id {} was found", exchange_id),
+                    )))
+                }
+                Some(e) => e,
+            };
+            let mut queues = data.get_queues().lock().await;
+            let reply_queue_id = new_reply_queue(data, &mut queues, namespace, post_data.timeout);
+            let properties =
+                reply_properties(namespace, &reply_queue_id, correlation_id, post_data);
+            let cipher = data.get_cipher().lock().await;
+            let dispatched = exchange.dispatch(
+                id,
+                content,
+                properties,
+                &exchanges,
+                &mut queues,
+                &cipher,
+                &may_publish,
+            );
+            match dispatched {
+                Ok(_) => Ok(reply_queue_id),
+                Err(e) => {
+                    queues.remove(&reply_queue_id);
+                    Err(exchange_error_response(&e))
+                }
+            }
+        }
+        _ => Err(HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "Exactly one of a queue id or an exchange id is required",
+        ))),
+    }
+}
+
+fn new_reply_queue(
+    data: &web::Data<AppState>,
+    queues: &mut HashMap<String, Queue>,
+    namespace: &Namespace,
+    timeout: u32,
+) -> String {
+    let queue_id = reply_queue_id(&namespace.prefix());
+    create_reply_queue(
+        queues,
+        data.get_blob_store(),
+        &queue_id,
+        timeout,
+        REPLY_QUEUE_MAX_BATCH,
+        timeout + REPLY_QUEUE_GRACE_PERIOD,
+    );
+    queue_id
+}
+
+// Replies are published from within the namespace, so the reply queue is given by its id there
+fn reply_properties(
+    namespace: &Namespace,
+    reply_queue_id: &str,
+    correlation_id: &str,
+    post_data: &CallRequest,
+) -> MessageProperties {
+    MessageProperties {
+        reply_to: namespace.unqualify(reply_queue_id),
+        correlation_id: Some(correlation_id.to_owned()),
+        routing_key: post_data.routing_key.to_owned(),
+        attributes: post_data.attributes.to_owned(),
+    }
+}
@@ -0,0 +1,32 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::app_types::ApiError;
+use crate::namespace_api::namespace::Namespace;
+
+fn default_timeout() -> u32 {
+    30
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallRequest {
+    pub queue_id: Option<String>,
+    pub exchange_id: Option<String>,
+    pub message_id: String,
+    pub content: String,
+    pub routing_key: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    #[serde(default = "default_timeout")]
+    pub timeout: u32,
+}
+
+impl CallRequest {
+    pub fn qualify(mut self, namespace: &Namespace) -> Result<Self, ApiError> {
+        self.queue_id = namespace.qualify_optional(&self.queue_id)?;
+        self.exchange_id = namespace.qualify_optional(&self.exchange_id)?;
+        Ok(self)
+    }
+}
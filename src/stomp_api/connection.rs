@@ -0,0 +1,674 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix_web::rt::net::TcpStream;
+use actix_web::web;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc::{self, Receiver};
+use uuid::Uuid;
+
+use crate::app_types::{ApiError, AppState};
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::authenticate_key;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::auth_api::identity::Identity;
+use crate::consumer_api::wakeup::Wakeup;
+use crate::exchange_api::publish_to_exchange_as;
+use crate::exchange_api::request as exchange_request;
+use crate::message_api::request as message_request;
+use crate::message_api::{exclusive_queue_error, publish_messages};
+use crate::namespace_api::namespace::Namespace;
+use crate::queue_api::queue::DecryptedMessage;
+
+use super::frame::{Frame, FrameError};
+
+// how many frames may be read ahead of the connection handling them
+const FRAME_BUFFER: usize = 64;
+
+// the shortest interval the server sends heart-beats at, in milliseconds
+const HEARTBEAT_MILLIS: u64 = 10_000;
+
+// headers of a SEND frame that are not copied to the message's attributes
+const SEND_HEADERS: [&str; 9] = [
+    "destination",
+    "content-length",
+    "content-type",
+    "receipt",
+    "transaction",
+    "message-id",
+    "correlation-id",
+    "reply-to",
+    "mandatory",
+];
+
+// How a subscription's messages are acknowledged
+#[derive(PartialEq)]
+enum AckMode {
+    Auto,             // messages are deleted as they are sent
+    Client,           // an ACK or NACK settles the message and every message sent before it
+    ClientIndividual, // an ACK or NACK settles just the message
+}
+
+struct Subscription {
+    id: String,
+    destination: String, // as the client gave it
+    queue_id: String,
+    ack_mode: AckMode,
+    prefetch_count: usize, // the most unacked messages it may hold, or 0 for the queue's max batch
+    unacked: Vec<String>,  // the uuids of the messages sent and not yet settled, oldest first
+}
+
+impl Subscription {
+    // How many more messages may be pushed to the subscription at once
+    fn credit(&self, max_batch: u32) -> usize {
+        match self.prefetch_count {
+            0 => max_batch as usize,
+            n => n,
+        }
+        .saturating_sub(self.unacked.len())
+    }
+}
+
+// Why a connection ended - None if the client went away or has already been told why
+type Ended = Option<String>;
+
+struct Connection {
+    data: web::Data<AppState>,
+    identity: Identity,
+    writer: OwnedWriteHalf,
+    subscriptions: Vec<Subscription>,
+    heartbeat: Option<Duration>,
+    last_sent: Instant,
+}
+
+// Serves one client until it disconnects or breaks the protocol. Whatever its subscriptions still
+// hold when it ends is returned to the queues.
+pub async fn run(data: web::Data<AppState>, socket: TcpStream) {
+    let (mut reader, writer) = socket.into_split();
+    let (sender, mut frames) = mpsc::channel(FRAME_BUFFER);
+    // frames are read on their own task, as a read cut short by a wakeup would lose data
+    actix_web::rt::spawn(async move {
+        let mut buffer = vec![];
+        let mut bytes = [0u8; 8192];
+        loop {
+            let frame = Frame::parse(&mut buffer);
+            let failed = frame.is_err();
+            match frame {
+                Ok(None) => match reader.read(&mut bytes).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => buffer.extend_from_slice(&bytes[..n]),
+                },
+                frame => {
+                    if sender.send(frame).await.is_err() || failed {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut connection = Connection {
+        data,
+        identity: Identity::default(),
+        writer,
+        subscriptions: vec![],
+        heartbeat: None,
+        last_sent: Instant::now(),
+    };
+    let ended = connection.serve(&mut frames).await;
+    connection.release_all().await;
+    if let Err(Some(message)) = ended {
+        // the client is told why before the connection is closed, as STOMP requires
+        let _ = connection
+            .send(Frame::new("ERROR").header("message", &message))
+            .await;
+    }
+}
+
+impl Connection {
+    async fn serve(
+        &mut self,
+        frames: &mut Receiver<Result<Option<Frame>, FrameError>>,
+    ) -> Result<(), Ended> {
+        let connect = match frames.recv().await {
+            Some(Ok(Some(frame))) => frame,
+            Some(Err(e)) => return Err(Some(e.to_string())),
+            _ => return Err(None),
+        };
+        self.connect(&connect).await?;
+        loop {
+            let mut wakeup = self.push_messages().await?;
+            self.send_heartbeat().await?;
+            if let Some(interval) = self.heartbeat {
+                wakeup.within(interval.saturating_sub(self.last_sent.elapsed()));
+            }
+            let frame = match wakeup.wait_for(frames.recv()).await {
+                None => continue, // the subscriptions or heart-beat need seeing to
+                Some(Some(Ok(Some(frame)))) => frame,
+                Some(Some(Err(e))) => return Err(Some(e.to_string())),
+                Some(_) => return Err(None),
+            };
+            match self.handle_frame(&frame).await {
+                Ok(true) => self.send_receipt(&frame).await?,
+                Ok(false) => {
+                    // the receipt of a DISCONNECT confirms everything before it was handled
+                    self.send_receipt(&frame).await?;
+                    return Ok(());
+                }
+                Err(e) => {
+                    let mut error = Frame::new("ERROR").header("message", e.get_message());
+                    if let Some(receipt) = frame.get_header("receipt") {
+                        error = error.header("receipt-id", receipt);
+                    }
+                    self.send(error).await?;
+                    return Err(None);
+                }
+            }
+        }
+    }
+
+    async fn connect(&mut self, frame: &Frame) -> Result<(), Ended> {
+        if frame.command != "CONNECT" && frame.command != "STOMP" {
+            return Err(Some(String::from(
+                "The first frame must be CONNECT or STOMP",
+            )));
+        }
+        let supported = match frame.get_header("accept-version") {
+            Some(versions) => versions.split(',').any(|v| v.trim() == "1.2"),
+            None => false,
+        };
+        if !supported {
+            let error = Frame::new("ERROR")
+                .header("version", "1.2")
+                .header("message", "Only STOMP 1.2 is supported");
+            self.send(error).await?;
+            return Err(None);
+        }
+        // the API key is the passcode, or the login for clients that only send one
+        let key = frame
+            .get_header("passcode")
+            .or(frame.get_header("login"))
+            .map(|k| k.as_str());
+        let namespace = frame.get_header("namespace").map(|n| n.as_str());
+        self.identity = match authenticate_key(&self.data, key, namespace).await {
+            Ok(identity) => identity,
+            Err(e) => return Err(Some(e.get_message().to_owned())),
+        };
+        // the client says how often it wants heart-beats, and the server sends them no more often
+        // than it is able to
+        let wanted = frame
+            .get_header("heart-beat")
+            .and_then(|h| h.split_once(','))
+            .and_then(|(_, wanted)| wanted.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        self.heartbeat = match wanted {
+            0 => None,
+            millis => Some(Duration::from_millis(millis.max(HEARTBEAT_MILLIS))),
+        };
+        let connected = Frame::new("CONNECTED")
+            .header("version", "1.2")
+            .header("server", "RQS")
+            .header("heart-beat", &format!("{},0", HEARTBEAT_MILLIS));
+        self.send(connected).await
+    }
+
+    // Handles a frame from the client, returning false once it has disconnected
+    async fn handle_frame(&mut self, frame: &Frame) -> Result<bool, ApiError> {
+        match frame.command.as_str() {
+            "SEND" => self.send_message(frame).await?,
+            "SUBSCRIBE" => self.subscribe(frame).await?,
+            "UNSUBSCRIBE" => {
+                // messages the subscription still holds are returned to the queue
+                let id = required_header(frame, "id")?;
+                let idx = match self.subscriptions.iter().position(|s| s.id == *id) {
+                    None => {
+                        return Err(ApiError::bad_request(format!(
+                            "No subscription with id {} was found",
+                            id
+                        )))
+                    }
+                    Some(idx) => idx,
+                };
+                let subscription = self.subscriptions.remove(idx);
+                settle(
+                    &self.data,
+                    &subscription.queue_id,
+                    &subscription.unacked,
+                    true,
+                )
+                .await;
+            }
+            "ACK" => self.acknowledge(frame, false).await?,
+            "NACK" => self.acknowledge(frame, true).await?,
+            "DISCONNECT" => return Ok(false),
+            "BEGIN" | "COMMIT" | "ABORT" => {
+                return Err(ApiError::bad_request("Transactions are not supported"))
+            }
+            command => {
+                return Err(ApiError::bad_request(format!(
+                    "The command {} is unknown",
+                    command
+                )))
+            }
+        }
+        Ok(true)
+    }
+
+    async fn send_message(&mut self, frame: &Frame) -> Result<(), ApiError> {
+        let destination = required_header(frame, "destination")?;
+        let content = match String::from_utf8(frame.body.to_owned()) {
+            Ok(c) => c,
+            Err(_) => return Err(ApiError::bad_request("Message bodies must be UTF-8 text")),
+        };
+        let message_id = match frame.get_header("message-id") {
+            Some(id) => id.to_owned(),
+            None => Uuid::new_v4().to_string(),
+        };
+        let mut attributes = HashMap::new();
+        for (name, value) in frame.get_headers().iter() {
+            if !SEND_HEADERS.contains(&name.as_str()) && !attributes.contains_key(name) {
+                attributes.insert(name.to_owned(), value.to_owned());
+            }
+        }
+        let reply_to = frame.get_header("reply-to").cloned();
+        let correlation_id = frame.get_header("correlation-id").cloned();
+
+        match parse_destination(destination, &self.identity.namespace)? {
+            Destination::Queue(queue_id) => {
+                self.identity
+                    .authorize(
+                        &self.data,
+                        ResourceType::QUEUE,
+                        &queue_id,
+                        Permission::PUBLISH,
+                    )
+                    .await?;
+                let request = message_request::NewMessageRequest {
+                    queue_id,
+                    messages: vec![message_request::NewMessage {
+                        message_id,
+                        content,
+                        reply_to,
+                        correlation_id,
+                        attributes,
+                    }],
+                };
+                let uuids = self
+                    .identity
+                    .publish_admitted(&self.data, 1, publish_messages(&self.data, &request))
+                    .await?;
+                let detail = Some(uuids.join(", "));
+                self.identity
+                    .audit(
+                        &self.data,
+                        AuditAction::MessagesPublished,
+                        &request.queue_id,
+                        detail,
+                    )
+                    .await;
+            }
+            Destination::Exchange(exchange_id, routing_key) => {
+                self.identity
+                    .authorize(
+                        &self.data,
+                        ResourceType::EXCHANGE,
+                        &exchange_id,
+                        Permission::PUBLISH,
+                    )
+                    .await?;
+                let request = exchange_request::NewMessageRequest {
+                    exchange_id,
+                    messages: vec![exchange_request::NewMessage {
+                        message_id,
+                        content,
+                        reply_to,
+                        correlation_id,
+                        routing_key,
+                        attributes,
+                    }],
+                    // unroutable messages are dropped unless asked otherwise, as errors end the
+                    // whole connection
+                    mandatory: frame.get_header("mandatory").map(|m| m.as_str()) == Some("true"),
+                };
+                // messages only reach the queues the key may publish to
+                let api_key = self.identity.current_key(&self.data).await?;
+                let published = publish_to_exchange_as(&self.data, &request, api_key.as_ref());
+                let uuids = self
+                    .identity
+                    .publish_admitted(&self.data, 1, published)
+                    .await?;
+                let detail = Some(uuids.join(", "));
+                self.identity
+                    .audit(
+                        &self.data,
+                        AuditAction::MessagesPublished,
+                        &request.exchange_id,
+                        detail,
+                    )
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, frame: &Frame) -> Result<(), ApiError> {
+        let id = required_header(frame, "id")?;
+        let destination = required_header(frame, "destination")?;
+        let queue_id = match parse_destination(destination, &self.identity.namespace)? {
+            Destination::Queue(queue_id) => queue_id,
+            Destination::Exchange(_, _) => {
+                return Err(ApiError::bad_request(
+                    "Only /queue/ destinations can be subscribed to",
+                ))
+            }
+        };
+        let ack_mode = match frame.get_header("ack").map(|a| a.as_str()) {
+            None | Some("auto") => AckMode::Auto,
+            Some("client") => AckMode::Client,
+            Some("client-individual") => AckMode::ClientIndividual,
+            Some(mode) => {
+                return Err(ApiError::bad_request(format!(
+                    "The ack mode {} is invalid",
+                    mode
+                )))
+            }
+        };
+        let prefetch_count = match frame.get_header("prefetch-count") {
+            None => 0,
+            Some(count) => count.parse::<usize>().map_err(|_| {
+                ApiError::bad_request(format!("The prefetch count {} is invalid", count))
+            })?,
+        };
+        if self.subscriptions.iter().any(|s| s.id == *id) {
+            return Err(ApiError::bad_request(format!(
+                "The subscription id {} is already in use",
+                id
+            )));
+        }
+        self.authorize_queue(&queue_id, Permission::CONSUME).await?;
+        // auto subscriptions delete messages as they are sent
+        if ack_mode == AckMode::Auto {
+            self.authorize_queue(&queue_id, Permission::DELETE).await?;
+        }
+        let accepts = self
+            .data
+            .get_queues()
+            .lock()
+            .await
+            .get(&queue_id)
+            .map(|q| q.accepts_consumer(&None));
+        match accepts {
+            None => Err(ApiError::bad_request(format!(
+                "No queue with id {} was found",
+                queue_id
+            ))),
+            Some(false) => Err(exclusive_queue_error(&queue_id)),
+            Some(true) => {
+                self.subscriptions.push(Subscription {
+                    id: id.to_owned(),
+                    destination: destination.to_owned(),
+                    queue_id,
+                    ack_mode,
+                    prefetch_count,
+                    unacked: vec![],
+                });
+                Ok(())
+            }
+        }
+    }
+
+    // Settles the message an ACK or NACK names - along with the ones sent before it for `client`
+    // subscriptions. Acked messages are deleted and nacked messages become visible again.
+    async fn acknowledge(&mut self, frame: &Frame, nack: bool) -> Result<(), ApiError> {
+        let uuid = required_header(frame, "id")?;
+        let idx = self
+            .subscriptions
+            .iter()
+            .position(|s| s.unacked.contains(uuid));
+        let idx = match idx {
+            None => {
+                return Err(ApiError::bad_request(format!(
+                    "No message with ack id {} is held by this connection",
+                    uuid
+                )))
+            }
+            Some(idx) => idx,
+        };
+        // acked messages are deleted, which the key must allow
+        if !nack {
+            let queue_id = self.subscriptions[idx].queue_id.to_owned();
+            self.authorize_queue(&queue_id, Permission::DELETE).await?;
+        }
+        let subscription = &mut self.subscriptions[idx];
+        let position = subscription
+            .unacked
+            .iter()
+            .position(|u| u == uuid)
+            .unwrap_or_default();
+        let settled = match subscription.ack_mode {
+            AckMode::Client => subscription.unacked.drain(..=position).collect(),
+            _ => vec![subscription.unacked.remove(position)],
+        };
+        let queue_id = subscription.queue_id.to_owned();
+        let deleted = settle(&self.data, &queue_id, &settled, nack).await;
+        if !deleted.is_empty() {
+            let detail = Some(deleted.join(", "));
+            self.identity
+                .audit(&self.data, AuditAction::MessagesDeleted, &queue_id, detail)
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn authorize_queue(
+        &self,
+        queue_id: &str,
+        permission: Permission,
+    ) -> Result<(), ApiError> {
+        self.identity
+            .authorize(&self.data, ResourceType::QUEUE, queue_id, permission)
+            .await
+    }
+
+    // Renews the messages the subscriptions hold, and pushes new ones while they have credit. The
+    // key is checked on every push, so the connection ends once it may no longer consume, or delete
+    // for auto acks, from a subscribed queue.
+    async fn push_messages(&mut self) -> Result<Wakeup, Ended> {
+        for subscription in self.subscriptions.iter() {
+            let mut permissions = vec![Permission::CONSUME];
+            if subscription.ack_mode == AckMode::Auto {
+                permissions.push(Permission::DELETE);
+            }
+            for permission in permissions {
+                self.identity
+                    .authorize(
+                        &self.data,
+                        ResourceType::QUEUE,
+                        &subscription.queue_id,
+                        permission,
+                    )
+                    .await
+                    .map_err(|e| Some(e.get_message().to_owned()))?;
+            }
+        }
+        let mut frames = vec![];
+        let mut deleted = vec![]; // the queue id and uuids of messages sent with auto acks
+        let mut wakeup = Wakeup::new();
+        {
+            let mut queues = self.data.get_queues().lock().await;
+            let cipher = self.data.get_cipher().lock().await;
+            for subscription in self.subscriptions.iter_mut() {
+                let queue = match queues.get_mut(&subscription.queue_id) {
+                    None => {
+                        return Err(Some(format!(
+                            "The queue with id {} was deleted",
+                            subscription.queue_id
+                        )))
+                    }
+                    Some(q) => q,
+                };
+                queue.renew(&subscription.unacked);
+                let credit = subscription.credit(queue.get_max_batch());
+                let messages = match queue.dispatch_up_to(&cipher, credit) {
+                    Ok(m) => m,
+                    Err(e) => return Err(Some(e.to_string())),
+                };
+                let mut sent = vec![];
+                for message in messages.iter() {
+                    match subscription.ack_mode {
+                        AckMode::Auto => {
+                            queue.rem_from_queue(&message.get_uuid());
+                            sent.push(message.get_uuid());
+                        }
+                        _ => subscription.unacked.push(message.get_uuid()),
+                    }
+                    frames.push(message_frame(subscription, message));
+                }
+                if !sent.is_empty() {
+                    deleted.push((subscription.queue_id.to_owned(), sent));
+                }
+                if subscription.credit(queue.get_max_batch()) > 0 {
+                    wakeup.watch(queue, messages.len() == credit);
+                }
+                if !subscription.unacked.is_empty() {
+                    wakeup.hold(queue);
+                }
+            }
+        }
+        for (queue_id, uuids) in deleted {
+            let detail = Some(uuids.join(", "));
+            self.identity
+                .audit(&self.data, AuditAction::MessagesDeleted, &queue_id, detail)
+                .await;
+        }
+        for frame in frames {
+            self.send(frame).await?;
+        }
+        Ok(wakeup)
+    }
+
+    async fn release_all(&mut self) {
+        for subscription in self.subscriptions.iter() {
+            settle(
+                &self.data,
+                &subscription.queue_id,
+                &subscription.unacked,
+                true,
+            )
+            .await;
+        }
+    }
+
+    async fn send_receipt(&mut self, frame: &Frame) -> Result<(), Ended> {
+        match frame.get_header("receipt") {
+            Some(receipt) => {
+                let receipt = Frame::new("RECEIPT").header("receipt-id", receipt);
+                self.send(receipt).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), Ended> {
+        match self.heartbeat {
+            Some(interval) if self.last_sent.elapsed() >= interval => self.write(b"\n").await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<(), Ended> {
+        self.write(&frame.to_bytes()).await
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Ended> {
+        self.writer.write_all(bytes).await.map_err(|_| None)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+enum Destination {
+    Queue(String),
+    Exchange(String, Option<String>), // the exchange id and the routing key, if any
+}
+
+// Destinations are /queue/<queue id>, /exchange/<exchange id> or
+// /exchange/<exchange id>/<routing key>, with ids in the client's namespace
+fn parse_destination(destination: &str, namespace: &Namespace) -> Result<Destination, ApiError> {
+    if let Some(queue_id) = destination.strip_prefix("/queue/") {
+        return Ok(Destination::Queue(namespace.qualify(queue_id)?));
+    }
+    if let Some(exchange) = destination.strip_prefix("/exchange/") {
+        return Ok(match exchange.split_once('/') {
+            Some((exchange_id, key)) => {
+                Destination::Exchange(namespace.qualify(exchange_id)?, Some(key.to_owned()))
+            }
+            None => Destination::Exchange(namespace.qualify(exchange)?, None),
+        });
+    }
+    Err(ApiError::bad_request(format!(
+        "The destination {} must start with /queue/ or /exchange/",
+        destination
+    )))
+}
+
+fn message_frame(subscription: &Subscription, message: &DecryptedMessage) -> Frame {
+    let uuid = message.get_uuid();
+    let properties = message.get_properties();
+    let mut frame = Frame::new("MESSAGE")
+        .header("subscription", &subscription.id)
+        .header("message-id", &uuid)
+        .header("destination", &subscription.destination)
+        .header("rqs-message-id", &message.get_id());
+    if subscription.ack_mode != AckMode::Auto {
+        frame = frame.header("ack", &uuid);
+    }
+    if let Some(correlation_id) = &properties.correlation_id {
+        frame = frame.header("correlation-id", correlation_id);
+    }
+    if let Some(reply_to) = &properties.reply_to {
+        frame = frame.header("reply-to", reply_to);
+    }
+    for (name, value) in properties.attributes.iter() {
+        frame = frame.header(name, value);
+    }
+    frame.body(message.get_content().into_bytes())
+}
+
+// Deletes settled messages from their queue, or returns them to be delivered again, returning
+// the uuids of those deleted
+async fn settle(
+    data: &AppState,
+    queue_id: &String,
+    uuids: &[String],
+    requeue: bool,
+) -> Vec<String> {
+    let mut deleted = vec![];
+    let mut queues = data.get_queues().lock().await;
+    if let Some(queue) = queues.get_mut(queue_id) {
+        for uuid in uuids.iter() {
+            match requeue {
+                true => {
+                    queue.release(uuid);
+                }
+                false => {
+                    if queue.rem_from_queue(uuid).is_some() {
+                        deleted.push(uuid.to_owned());
+                    }
+                }
+            }
+        }
+    }
+    deleted
+}
+
+fn required_header<'a>(frame: &'a Frame, name: &str) -> Result<&'a String, ApiError> {
+    match frame.get_header(name) {
+        Some(value) => Ok(value),
+        None => Err(ApiError::bad_request(format!(
+            "The {} frame requires a {} header",
+            frame.command, name
+        ))),
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+
+// the largest frame a client may send, headers included
+pub const MAX_FRAME_SIZE: usize = 1_048_576;
+
+pub enum FrameError {
+    TooLarge(usize),
+    Malformed(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge(limit) => {
+                write!(f, "The frame exceeds the limit of {} bytes", limit)
+            }
+            FrameError::Malformed(s) => write!(f, "The frame was malformed: {}", s),
+        }
+    }
+}
+
+pub struct Frame {
+    pub command: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(command: &str) -> Self {
+        Frame {
+            command: command.to_owned(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    // repeated headers are allowed, and only the first one counts
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_headers(&self) -> &Vec<(String, String)> {
+        &self.headers
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.command.as_bytes().to_vec();
+        bytes.push(b'\n');
+        let escaped = self.command != "CONNECTED";
+        for (name, value) in self.headers.iter() {
+            bytes.extend(encode_header(name, escaped).as_bytes());
+            bytes.push(b':');
+            bytes.extend(encode_header(value, escaped).as_bytes());
+            bytes.push(b'\n');
+        }
+        bytes.extend(format!("content-length:{}\n\n", self.body.len()).as_bytes());
+        bytes.extend_from_slice(&self.body);
+        bytes.push(0);
+        bytes
+    }
+
+    // Takes the next frame from the start of the buffer, or returns None if it is not all there
+    // yet. The end of line heart-beats clients send between frames are skipped.
+    pub fn parse(buffer: &mut Vec<u8>) -> Result<Option<Frame>, FrameError> {
+        let start = buffer
+            .iter()
+            .position(|b| *b != b'\n' && *b != b'\r')
+            .unwrap_or(buffer.len());
+        buffer.drain(..start);
+
+        // the command and headers end with an empty line
+        let head_end = match find(buffer, b"\n\n")
+            .map(|i| (i, i + 2))
+            .into_iter()
+            .chain(find(buffer, b"\n\r\n").map(|i| (i, i + 3)))
+            .min()
+        {
+            Some(end) => end,
+            None if buffer.len() > MAX_FRAME_SIZE => {
+                return Err(FrameError::TooLarge(MAX_FRAME_SIZE))
+            }
+            None => return Ok(None),
+        };
+        let head = match std::str::from_utf8(&buffer[..head_end.0]) {
+            Ok(h) => h,
+            Err(_) => {
+                return Err(FrameError::Malformed(String::from(
+                    "the command and headers must be UTF-8",
+                )))
+            }
+        };
+        let mut lines = head.split('\n').map(|l| l.strip_suffix('\r').unwrap_or(l));
+        let command = lines.next().unwrap_or_default().to_owned();
+        // CONNECT frames are left unescaped so 1.0 clients can still connect
+        let escaped = command != "CONNECT" && command != "STOMP";
+        let mut frame = Frame::new(&command);
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some(h) => h,
+                None => {
+                    return Err(FrameError::Malformed(format!(
+                        "the header {} has no value",
+                        line
+                    )))
+                }
+            };
+            frame.headers.push((
+                decode_header(name, escaped)?,
+                decode_header(value, escaped)?,
+            ));
+        }
+
+        let body_start = head_end.1;
+        let body_end = match frame.get_header("content-length") {
+            Some(length) => {
+                let length = length.parse::<usize>().map_err(|_| {
+                    FrameError::Malformed(format!("the content length {} is invalid", length))
+                })?;
+                // a length near usize::MAX would overflow past the limit
+                let body_end = match body_start.checked_add(length) {
+                    Some(end) if end <= MAX_FRAME_SIZE => end,
+                    _ => return Err(FrameError::TooLarge(MAX_FRAME_SIZE)),
+                };
+                if buffer.len() <= body_end {
+                    return Ok(None);
+                }
+                if buffer[body_end] != 0 {
+                    return Err(FrameError::Malformed(String::from(
+                        "the body is longer than its content length",
+                    )));
+                }
+                body_end
+            }
+            None => match buffer[body_start..].iter().position(|b| *b == 0) {
+                Some(i) => body_start + i,
+                None if buffer.len() > MAX_FRAME_SIZE => {
+                    return Err(FrameError::TooLarge(MAX_FRAME_SIZE))
+                }
+                None => return Ok(None),
+            },
+        };
+        frame.body = buffer[body_start..body_end].to_vec();
+        buffer.drain(..=body_end);
+        Ok(Some(frame))
+    }
+}
+
+fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    bytes.windows(pattern.len()).position(|w| w == pattern)
+}
+
+fn encode_header(value: &str, escaped: bool) -> String {
+    if !escaped {
+        return value.to_owned();
+    }
+    value
+        .replace('\\', "\\\\")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+        .replace(':', "\\c")
+}
+
+fn decode_header(value: &str, escaped: bool) -> Result<String, FrameError> {
+    if !escaped {
+        return Ok(value.to_owned());
+    }
+    let mut decoded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => decoded.push('\r'),
+            Some('n') => decoded.push('\n'),
+            Some('c') => decoded.push(':'),
+            Some('\\') => decoded.push('\\'),
+            _ => {
+                return Err(FrameError::Malformed(format!(
+                    "the header {} has an invalid escape",
+                    value
+                )))
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(buffer: &mut Vec<u8>) -> Option<Frame> {
+        match Frame::parse(buffer) {
+            Ok(f) => f,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn frames_read_back_as_written() {
+        let frame = Frame::new("SEND")
+            .header("destination", "/queue/orders")
+            .header("note", "a:b\nc\\d")
+            .body(b"hello\0world".to_vec());
+        let mut buffer = frame.to_bytes();
+        let parsed = parse(&mut buffer).unwrap();
+        assert_eq!(parsed.command, "SEND");
+        assert_eq!(parsed.get_header("destination").unwrap(), "/queue/orders");
+        assert_eq!(parsed.get_header("note").unwrap(), "a:b\nc\\d");
+        assert_eq!(parsed.get_header("content-length").unwrap(), "11");
+        assert_eq!(parsed.body, b"hello\0world");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn frames_are_taken_one_at_a_time_once_complete() {
+        let mut buffer = b"\n\r\nSEND\r\ndestination:/queue/a\r\n\r\nfirst\0SEND\ndest".to_vec();
+        let first = parse(&mut buffer).unwrap();
+        assert_eq!(first.get_header("destination").unwrap(), "/queue/a");
+        assert_eq!(first.body, b"first");
+        assert!(parse(&mut buffer).is_none());
+
+        buffer.extend(b"ination:/queue/b\ncontent-length:6\n\nsec");
+        assert!(parse(&mut buffer).is_none());
+        buffer.extend(b"ond\0");
+        let second = parse(&mut buffer).unwrap();
+        assert_eq!(second.body, b"second");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn only_the_first_of_repeated_headers_counts() {
+        let mut buffer = b"SEND\nfoo:1\nfoo:2\n\n\0".to_vec();
+        let frame = parse(&mut buffer).unwrap();
+        assert_eq!(frame.get_header("foo").unwrap(), "1");
+        assert_eq!(frame.get_headers().len(), 2);
+    }
+
+    #[test]
+    fn connect_frames_are_not_unescaped() {
+        let mut buffer = b"CONNECT\npasscode:a\\b:c\n\n\0".to_vec();
+        let frame = parse(&mut buffer).unwrap();
+        assert_eq!(frame.get_header("passcode").unwrap(), "a\\b:c");
+
+        let connected = Frame::new("CONNECTED").header("server", "rqs:1");
+        assert!(String::from_utf8(connected.to_bytes())
+            .unwrap()
+            .contains("server:rqs:1\n"));
+    }
+
+    #[test]
+    fn malformed_frames_are_refused() {
+        let bad = [
+            &b"SEND\nno-value\n\n\0"[..],
+            b"SEND\nbad:\\t\n\n\0",
+            b"SEND\ncontent-length:x\n\n\0",
+            b"SEND\ncontent-length:2\n\nabc\0",
+        ];
+        for bytes in bad {
+            let result = Frame::parse(&mut bytes.to_vec());
+            assert!(matches!(result, Err(FrameError::Malformed(_))));
+        }
+    }
+
+    #[test]
+    fn frames_past_the_limit_are_refused() {
+        let mut buffer = format!("SEND\ncontent-length:{}\n\n", MAX_FRAME_SIZE).into_bytes();
+        let result = Frame::parse(&mut buffer);
+        assert!(matches!(result, Err(FrameError::TooLarge(_))));
+
+        let mut buffer = vec![b'a'; MAX_FRAME_SIZE + 1];
+        let result = Frame::parse(&mut buffer);
+        assert!(matches!(result, Err(FrameError::TooLarge(_))));
+
+        let mut buffer = format!("SEND\ncontent-length:{}\n\n", usize::MAX).into_bytes();
+        let result = Frame::parse(&mut buffer);
+        assert!(matches!(result, Err(FrameError::TooLarge(_))));
+    }
+}
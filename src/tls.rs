@@ -0,0 +1,263 @@
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+// The verified certificate a client connected with, kept with the connection
+#[derive(Clone)]
+pub struct ClientCertificate {
+    pub subject: String, // as in "CN=orders, O=Acme", in the certificate's order
+}
+
+// Builds the TLS config for the REST API. With a client CA, clients may authenticate with a
+// certificate it issued, and certificates it did not issue are refused.
+pub fn server_config(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> io::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!(
+            "No certificates were found in {}",
+            cert_path.display()
+        )));
+    }
+    let key = match rustls_pemfile::private_key(&mut open(key_path)?)? {
+        Some(k) => k,
+        None => {
+            return Err(invalid(format!(
+                "No private key was found in {}",
+                key_path.display()
+            )))
+        }
+    };
+    let builder = match client_ca_path {
+        None => ServerConfig::builder().with_no_client_auth(),
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut open(path)?) {
+                roots
+                    .add(cert?)
+                    .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+            }
+            // clients without a certificate are let through, to authenticate with an API key
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+    };
+    builder
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+// Records the certificate a client connected with, for HttpServer::on_connect. Certificates have
+// already been verified against the client CA by the time the connection is made.
+pub fn record_client_certificate(connection: &dyn Any, extensions: &mut Extensions) {
+    let stream = match connection.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(s) => s,
+        None => return,
+    };
+    let cert = match stream.get_ref().1.peer_certificates() {
+        Some([cert, ..]) => cert,
+        _ => return,
+    };
+    if let Ok((_, cert)) = X509Certificate::from_der(cert) {
+        extensions.insert(ClientCertificate {
+            subject: cert.subject().to_string(),
+        });
+    }
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use actix_web::{web, App, HttpRequest, HttpServer};
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair,
+    };
+
+    use super::*;
+
+    // A certificate authority that issues the server's and clients' certificates
+    struct Authority {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl Authority {
+        fn new(name: &str) -> Self {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Authority { cert, key }
+        }
+
+        // Issues a certificate, returning it and its key as PEM
+        fn issue(&self, params: CertificateParams) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn issue_server(&self) -> (String, String) {
+            self.issue(CertificateParams::new(vec![String::from("localhost")]).unwrap())
+        }
+
+        fn issue_client(&self, common_name: &str) -> (String, String) {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            self.issue(params)
+        }
+    }
+
+    // Writes PEM files to a directory of their own
+    fn write(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rqs-test-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    // Serves the subject of the client's certificate, or "none", over TLS on a free local port
+    fn serve(config: ServerConfig) -> (u16, actix_web::dev::ServerHandle) {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/",
+                web::get().to(|req: HttpRequest| async move {
+                    match req.conn_data::<ClientCertificate>() {
+                        Some(cert) => cert.subject.to_owned(),
+                        None => String::from("none"),
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .on_connect(record_client_certificate)
+        .bind_rustls_0_23(("127.0.0.1", 0), config)
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        (port, handle)
+    }
+
+    // Makes a request as a client trusting the authority, with a certificate and key if given
+    async fn get(
+        port: u16,
+        authority: &Authority,
+        identity: Option<(String, String)>,
+    ) -> reqwest::Result<String> {
+        let ca = reqwest::Certificate::from_pem(authority.cert.pem().as_bytes())?;
+        let mut builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .add_root_certificate(ca);
+        if let Some((cert, key)) = identity {
+            let pem = format!("{}{}", cert, key);
+            builder = builder.identity(reqwest::Identity::from_pem(pem.as_bytes())?);
+        }
+        let url = format!("https://localhost:{}/", port);
+        builder.build()?.get(url).send().await?.text().await
+    }
+
+    fn mtls_config(authority: &Authority) -> ServerConfig {
+        let (cert, key) = authority.issue_server();
+        let ca = authority.cert.pem();
+        let dir = write(&[("cert.pem", &cert), ("key.pem", &key), ("ca.pem", &ca)]);
+        server_config(
+            &dir.join("cert.pem"),
+            &dir.join("key.pem"),
+            Some(&dir.join("ca.pem")),
+        )
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn certificates_the_client_ca_issued_are_recorded() {
+        let authority = Authority::new("RQS test CA");
+        let (port, server) = serve(mtls_config(&authority));
+
+        let identity = Some(authority.issue_client("orders"));
+        let subject = get(port, &authority, identity).await.unwrap();
+        assert_eq!(subject, "CN=orders");
+
+        // clients without a certificate still connect, to authenticate with an API key
+        let subject = get(port, &authority, None).await.unwrap();
+        assert_eq!(subject, "none");
+        server.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn certificates_from_another_issuer_are_refused() {
+        let authority = Authority::new("RQS test CA");
+        let (port, server) = serve(mtls_config(&authority));
+
+        let other = Authority::new("Another CA");
+        let identity = Some(other.issue_client("orders"));
+        assert!(get(port, &authority, identity).await.is_err());
+        server.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn client_certificates_are_not_asked_for_without_a_client_ca() {
+        let authority = Authority::new("RQS test CA");
+        let (cert, key) = authority.issue_server();
+        let dir = write(&[("cert.pem", &cert), ("key.pem", &key)]);
+        let config = server_config(&dir.join("cert.pem"), &dir.join("key.pem"), None).unwrap();
+        let (port, server) = serve(config);
+
+        let identity = Some(authority.issue_client("orders"));
+        let subject = get(port, &authority, identity).await.unwrap();
+        assert_eq!(subject, "none");
+        server.stop(true).await;
+    }
+
+    #[test]
+    fn files_without_a_certificate_or_key_are_refused() {
+        let authority = Authority::new("RQS test CA");
+        let (cert, key) = authority.issue_server();
+        let dir = write(&[("cert.pem", &cert), ("key.pem", &key), ("empty.pem", "")]);
+
+        let error = server_config(&dir.join("empty.pem"), &dir.join("key.pem"), None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("No certificates were found"));
+
+        let error = server_config(&dir.join("cert.pem"), &dir.join("cert.pem"), None).unwrap_err();
+        assert!(error.to_string().contains("No private key was found"));
+
+        let error =
+            server_config(&dir.join("missing.pem"), &dir.join("key.pem"), None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::hash_map::Entry;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+use crate::app_types::{AppState, JsonResponse};
+use crate::audit_api::audit;
+use crate::audit_api::audit_log::AuditAction;
+use crate::auth_api::authorize;
+use crate::auth_api::grant::{Permission, ResourceType};
+use crate::message_api::exclusive_queue_error;
+use crate::namespace_api::get_namespace;
+use request::{DeleteWebhookRequest, NewWebhookRequest, WebhookEntry};
+use webhook::Webhook;
+
+mod delivery;
+mod request;
+pub(crate) mod webhook;
+
+pub async fn new_webhook(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    post_data: web::Json<NewWebhookRequest>,
+) -> HttpResponse {
+    let post_data = match post_data.into_inner().qualify(&get_namespace(&req)) {
+        Ok(p) => p,
+        Err(e) => return e.to_response(),
+    };
+    // a webhook reads and deletes the queue's messages, and dead letters the ones it gives up on
+    let mut checks = vec![
+        (&post_data.queue_id, Permission::CONSUME),
+        (&post_data.queue_id, Permission::DELETE),
+    ];
+    if let Some(dead_letter_queue_id) = &post_data.dead_letter_queue_id {
+        checks.push((dead_letter_queue_id, Permission::PUBLISH));
+    }
+    for (queue_id, permission) in checks {
+        if let Err(e) = authorize(&req, ResourceType::QUEUE, queue_id, permission) {
+            return e.to_response();
+        }
+    }
+    match reqwest::Url::parse(&post_data.url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+        _ => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("The url {} is not a valid http or https url", post_data.url),
+            ))
+        }
+    }
+    if post_data.max_attempts == 0 || post_data.initial_backoff == 0 {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The max attempts and initial backoff must be greater than 0",
+        ));
+    }
+    if post_data.max_backoff < post_data.initial_backoff {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The max backoff must be at least the initial backoff",
+        ));
+    }
+
+    let queue_id = &post_data.queue_id;
+    {
+        let queues = data.get_queues().lock().await;
+        let queue = match queues.get(queue_id) {
+            None => {
+                return HttpResponse::BadRequest().json(JsonResponse::new(
+                    None::<String>,
+                    format!("No queue with id {} was found", queue_id),
+                ))
+            }
+            Some(q) => q,
+        };
+        if !queue.accepts_consumer(&post_data.consumer_token) {
+            return exclusive_queue_error(queue_id).to_response();
+        }
+        if let Some(dead_letter_queue_id) = &post_data.dead_letter_queue_id {
+            if dead_letter_queue_id == queue_id || !queues.contains_key(dead_letter_queue_id) {
+                return HttpResponse::BadRequest().json(JsonResponse::new(
+                    None::<String>,
+                    format!(
+                        "No dead letter queue with id {} was found",
+                        dead_letter_queue_id
+                    ),
+                ));
+            }
+        }
+    }
+
+    let webhook = Webhook {
+        uuid: Uuid::new_v4(),
+        url: post_data.url.to_owned(),
+        max_attempts: post_data.max_attempts,
+        initial_backoff: post_data.initial_backoff,
+        max_backoff: post_data.max_backoff,
+        dead_letter_queue_id: post_data.dead_letter_queue_id.to_owned(),
+    };
+    let mut webhooks = data.get_webhooks().lock().await;
+    match webhooks.entry(queue_id.to_owned()) {
+        Entry::Vacant(_) => {
+            webhooks.insert(queue_id.to_owned(), webhook.clone());
+            let webhook_uuid = webhook.uuid.to_string();
+            let detail = Some(webhook.url.to_owned());
+            actix_web::rt::spawn(delivery::run(data.clone(), queue_id.to_owned(), webhook));
+            drop(webhooks);
+            audit(&data, &req, AuditAction::WebhookCreated, queue_id, detail).await;
+            HttpResponse::Accepted().json(JsonResponse::new(webhook_uuid, None::<String>))
+        }
+        Entry::Occupied(_) => HttpResponse::Conflict().json(JsonResponse::new(
+            None::<String>,
+            format!("The queue with id {} already has a webhook", queue_id),
+        )),
+    }
+}
+
+// Lists the webhooks of the queues in the request's namespace
+pub async fn list_webhooks(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    let namespace = get_namespace(&req);
+    let webhooks = data.get_webhooks().lock().await;
+    let entries = webhooks
+        .iter()
+        .filter_map(|(queue_id, webhook)| {
+            let queue_id = namespace.unqualify(queue_id)?;
+            Some(WebhookEntry::new(&queue_id, webhook, &namespace))
+        })
+        .collect::<Vec<WebhookEntry>>();
+    HttpResponse::Accepted().json(JsonResponse::new(entries, None::<String>))
+}
+
+// The delivery worker stops on its next poll, returning the messages it holds to the queue
+pub async fn delete_webhook(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    post_data: web::Json<DeleteWebhookRequest>,
+) -> HttpResponse {
+    let mut post_data = post_data.into_inner();
+    post_data.queue_id = match get_namespace(&req).qualify(&post_data.queue_id) {
+        Ok(id) => id,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(e) = authorize(
+        &req,
+        ResourceType::QUEUE,
+        &post_data.queue_id,
+        Permission::CONSUME,
+    ) {
+        return e.to_response();
+    }
+    let mut webhooks = data.get_webhooks().lock().await;
+    match webhooks.remove(&post_data.queue_id) {
+        None => HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!("The queue with id {} has no webhook", post_data.queue_id),
+        )),
+        Some(_) => {
+            drop(webhooks);
+            audit(
+                &data,
+                &req,
+                AuditAction::WebhookDeleted,
+                &post_data.queue_id,
+                None,
+            )
+            .await;
+            HttpResponse::Accepted().json(JsonResponse::new(
+                format!(
+                    "Successfully deleted the webhook of queue {}",
+                    post_data.queue_id
+                ),
+                None::<String>,
+            ))
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use futures::future::join_all;
+use uuid::Uuid;
+
+use crate::app_types::AppState;
+use crate::audit_api::audit_by_server;
+use crate::audit_api::audit_log::AuditAction;
+use crate::message_api::request::GetMessageResponse;
+use crate::queue_api::queue::Queue;
+
+use super::webhook::Webhook;
+
+// how often the queue is checked for messages to deliver and retries that are due
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how long the webhook has to answer before the attempt counts as failed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A message the worker holds until it is delivered or given up on
+struct Delivery {
+    message: GetMessageResponse,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+// Delivers a queue's messages to its webhook until the webhook or queue is deleted. Like a
+// WebSocket subscription, the worker holds at most `maxBatch` messages and keeps them hidden from
+// other consumers until they are delivered, dead lettered or returned to the queue.
+pub async fn run(data: web::Data<AppState>, queue_id: String, webhook: Webhook) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    let mut held: Vec<Delivery> = vec![];
+    loop {
+        if !is_registered(&data, &queue_id, &webhook.uuid).await {
+            break;
+        }
+        if !fill(&data, &queue_id, &mut held).await {
+            return; // the queue was deleted, so there is nothing to return messages to
+        }
+
+        let now = Instant::now();
+        let attempts = held
+            .iter()
+            .filter(|d| d.next_attempt <= now)
+            .map(|d| post(&client, &webhook, &queue_id, d));
+        let delivered = join_all(attempts)
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<Vec<String>>();
+
+        let mut failed = vec![];
+        for delivery in held.iter_mut().filter(|d| d.next_attempt <= now) {
+            delivery.attempts += 1;
+            if delivered.contains(&delivery.message.uuid) {
+                continue;
+            }
+            if delivery.attempts >= webhook.max_attempts {
+                failed.push(delivery.message.uuid.to_owned());
+            } else {
+                delivery.next_attempt = Instant::now() + webhook.backoff(delivery.attempts);
+            }
+        }
+        if !delivered.is_empty() || !failed.is_empty() {
+            settle(&data, &queue_id, &webhook, &delivered, &failed).await;
+            held.retain(|d| {
+                !delivered.contains(&d.message.uuid) && !failed.contains(&d.message.uuid)
+            });
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+
+    let mut queues = data.get_queues().lock().await;
+    if let Some(queue) = queues.get_mut(&queue_id) {
+        for delivery in held.iter() {
+            queue.release(&delivery.message.uuid);
+        }
+    }
+}
+
+async fn is_registered(data: &AppState, queue_id: &String, uuid: &Uuid) -> bool {
+    let webhooks = data.get_webhooks().lock().await;
+    match webhooks.get(queue_id) {
+        Some(w) => w.uuid == *uuid,
+        None => false,
+    }
+}
+
+// Renews the messages the worker holds and takes new ones while it has room, returning false if
+// the queue no longer exists
+async fn fill(data: &AppState, queue_id: &String, held: &mut Vec<Delivery>) -> bool {
+    let mut queues = data.get_queues().lock().await;
+    let queue = match queues.get_mut(queue_id) {
+        None => return false,
+        Some(q) => q,
+    };
+    let uuids = held
+        .iter()
+        .map(|d| d.message.uuid.to_owned())
+        .collect::<Vec<String>>();
+    queue.renew(&uuids);
+    let credit = (queue.get_max_batch() as usize).saturating_sub(held.len());
+    let cipher = data.get_cipher().lock().await;
+    // messages that cannot be read now are left for the next poll
+    if let Ok(messages) = queue.dispatch_up_to(&cipher, credit) {
+        for message in messages.iter() {
+            held.push(Delivery {
+                message: GetMessageResponse::new(message),
+                attempts: 0,
+                next_attempt: Instant::now(),
+            });
+        }
+    }
+    true
+}
+
+// POSTs a message to the webhook, returning its uuid if the webhook answered with a 2xx
+async fn post(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    queue_id: &str,
+    delivery: &Delivery,
+) -> Option<String> {
+    let body = serde_json::to_string(&delivery.message).ok()?;
+    let response = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Rqs-Queue-Id", queue_id)
+        .header("X-Rqs-Attempt", (delivery.attempts + 1).to_string())
+        .body(body)
+        .send()
+        .await
+        .ok()?;
+    match response.status().is_success() {
+        true => Some(delivery.message.uuid.to_owned()),
+        false => None,
+    }
+}
+
+// Deletes delivered messages, and moves the ones that ran out of attempts to the dead letter queue
+async fn settle(
+    data: &AppState,
+    queue_id: &String,
+    webhook: &Webhook,
+    delivered: &[String],
+    failed: &[String],
+) {
+    let dropped = {
+        let mut queues = data.get_queues().lock().await;
+        match settle_in(&mut queues, queue_id, webhook, delivered, failed) {
+            Some(d) => d,
+            None => return,
+        }
+    };
+    if !delivered.is_empty() {
+        let detail = Some(format!("{}, delivered to a webhook", delivered.join(", ")));
+        audit_by_server(data, AuditAction::MessagesDeleted, queue_id, detail).await;
+    }
+    if dropped {
+        let detail = Some(format!(
+            "{}, which a webhook failed to take",
+            failed.join(", ")
+        ));
+        audit_by_server(data, AuditAction::MessagesDeleted, queue_id, detail).await;
+    }
+}
+
+// Settles messages in the queues, returning whether the failed ones were dropped, or None if the
+// queue is gone
+fn settle_in(
+    queues: &mut HashMap<String, Queue>,
+    queue_id: &String,
+    webhook: &Webhook,
+    delivered: &[String],
+    failed: &[String],
+) -> Option<bool> {
+    // without a dead letter queue, messages that could not be delivered are dropped
+    let dead_letter_queue_id = match queues.get(queue_id) {
+        None => return None,
+        Some(q) => webhook
+            .dead_letter_queue_id
+            .to_owned()
+            .or_else(|| q.get_dead_letter_queue_id())
+            .filter(|id| queues.contains_key(id)),
+    };
+    let queue = queues.get_mut(queue_id)?;
+    for uuid in delivered.iter() {
+        queue.rem_from_queue(uuid);
+    }
+    let mut dead_letters = vec![];
+    for uuid in failed.iter() {
+        match dead_letter_queue_id {
+            Some(_) => dead_letters.extend(queue.take_from_queue(uuid)),
+            None => {
+                queue.rem_from_queue(uuid);
+            }
+        }
+    }
+    if let Some(id) = &dead_letter_queue_id {
+        if let Some(dead_letter_queue) = queues.get_mut(id) {
+            for message in dead_letters {
+                dead_letter_queue.add_dead_letter(message);
+            }
+        }
+    }
+    Some(dead_letter_queue_id.is_none() && !failed.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::dev::ServerHandle;
+    use actix_web::http::StatusCode;
+    use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
+
+    use super::*;
+    use crate::audit_api::audit_log::AuditLog;
+    use crate::queue_api::publish_to_queue;
+    use crate::queue_api::queue::{MessageProperties, QueueSettings};
+
+    // what the stand-in webhook was sent: the queue id and attempt headers, and the content
+    type Received = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    // Serves a webhook on a free local port, answering every POST with `status`
+    fn stand_in(status: Arc<AtomicU16>) -> (String, Received, ServerHandle) {
+        let received: Received = Arc::new(Mutex::new(vec![]));
+        let recorded = received.clone();
+        let server = HttpServer::new(move || {
+            let recorded = recorded.clone();
+            let status = status.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, body: String| {
+                let recorded = recorded.clone();
+                let status = status.clone();
+                async move {
+                    let header = |name: &str| {
+                        req.headers()
+                            .get(name)
+                            .and_then(|v| v.to_str().ok())
+                            .unwrap_or_default()
+                            .to_owned()
+                    };
+                    let message = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+                    let content = message["content"].as_str().unwrap_or_default().to_owned();
+                    recorded.lock().unwrap().push((
+                        header("X-Rqs-Queue-Id"),
+                        header("X-Rqs-Attempt"),
+                        content,
+                    ));
+                    let status = StatusCode::from_u16(status.load(Ordering::SeqCst)).unwrap();
+                    HttpResponse::build(status).finish()
+                }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        (url, received, handle)
+    }
+
+    async fn state_with_queues(ids: &[&str]) -> web::Data<AppState> {
+        let data = web::Data::new(AppState::for_tests());
+        *data.get_audit_log().lock().await = AuditLog::new(100, None, true).unwrap();
+        let mut queues = data.get_queues().lock().await;
+        for id in ids {
+            let settings = QueueSettings {
+                read_timeout: 30,
+                max_batch: 10,
+                ..Default::default()
+            };
+            let queue = Queue::new(id.to_string(), settings, data.get_blob_store().clone());
+            queues.insert(id.to_string(), queue);
+        }
+        drop(queues);
+        data
+    }
+
+    async fn publish(data: &AppState, queue_id: &str, content: &str) {
+        let mut queues = data.get_queues().lock().await;
+        let cipher = data.get_cipher().lock().await;
+        let id = Uuid::new_v4().to_string();
+        let properties = MessageProperties::default();
+        publish_to_queue(
+            &mut queues,
+            &cipher,
+            &queue_id.to_owned(),
+            id,
+            content.to_owned(),
+            properties,
+        )
+        .unwrap();
+    }
+
+    fn webhook(url: String, dead_letter_queue_id: Option<String>) -> Webhook {
+        Webhook {
+            uuid: Uuid::new_v4(),
+            url,
+            max_attempts: 2,
+            initial_backoff: 0,
+            max_backoff: 0,
+            dead_letter_queue_id,
+        }
+    }
+
+    async fn size(data: &AppState, queue_id: &str) -> u32 {
+        let queues = data.get_queues().lock().await;
+        queues
+            .get(queue_id)
+            .map(|q| q.get_size())
+            .unwrap_or_default()
+    }
+
+    // Runs a worker for the webhook until `done` holds, or fails the test after a few seconds
+    async fn deliver_until<F>(data: &web::Data<AppState>, webhook: Webhook, done: F)
+    where
+        F: AsyncFn() -> bool,
+    {
+        data.get_webhooks()
+            .lock()
+            .await
+            .insert(String::from("orders"), webhook.clone());
+        let worker = actix_web::rt::spawn(run(data.clone(), String::from("orders"), webhook));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done().await {
+            assert!(
+                Instant::now() < deadline,
+                "the webhook was not delivered to"
+            );
+            sleep(Duration::from_millis(20)).await;
+        }
+        // the worker stops once its webhook is deleted
+        data.get_webhooks().lock().await.clear();
+        worker.await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn delivered_messages_are_deleted_and_audited() {
+        let (url, received, server) = stand_in(Arc::new(AtomicU16::new(200)));
+        let data = state_with_queues(&["orders"]).await;
+        publish(&data, "orders", "first").await;
+        publish(&data, "orders", "second").await;
+
+        deliver_until(&data, webhook(url, None), async || {
+            size(&data, "orders").await == 0
+        })
+        .await;
+
+        let mut received = received.lock().unwrap().clone();
+        received.sort();
+        assert_eq!(
+            received,
+            vec![
+                (
+                    String::from("orders"),
+                    String::from("1"),
+                    String::from("first")
+                ),
+                (
+                    String::from("orders"),
+                    String::from("1"),
+                    String::from("second")
+                ),
+            ]
+        );
+        let audit_log = data.get_audit_log().lock().await;
+        let deleted = audit_log
+            .get_records()
+            .iter()
+            .filter(|r| r.action == AuditAction::MessagesDeleted)
+            .collect::<Vec<_>>();
+        assert!(!deleted.is_empty());
+        assert!(deleted.iter().all(|r| r.api_key.is_none()));
+        server.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn undeliverable_messages_are_retried_then_dead_lettered() {
+        let (url, received, server) = stand_in(Arc::new(AtomicU16::new(500)));
+        let data = state_with_queues(&["orders", "failed"]).await;
+        publish(&data, "orders", "lost").await;
+
+        let webhook = webhook(url, Some(String::from("failed")));
+        deliver_until(&data, webhook, async || size(&data, "failed").await == 1).await;
+
+        assert_eq!(size(&data, "orders").await, 0);
+        let attempts = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, attempt, _)| attempt.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(attempts, vec![String::from("1"), String::from("2")]);
+        server.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn held_messages_are_returned_when_the_webhook_is_deleted() {
+        let (url, received, server) = stand_in(Arc::new(AtomicU16::new(503)));
+        let data = state_with_queues(&["orders"]).await;
+        publish(&data, "orders", "waiting").await;
+
+        // with a long backoff, the message is held after its first failed attempt
+        let mut webhook = webhook(url, None);
+        webhook.initial_backoff = 60;
+        webhook.max_backoff = 60;
+        deliver_until(&data, webhook, async || {
+            !received.lock().unwrap().is_empty()
+        })
+        .await;
+
+        {
+            let mut queues = data.get_queues().lock().await;
+            let cipher = data.get_cipher().lock().await;
+            let visible = queues.get_mut("orders").unwrap().dispatch(&cipher).unwrap();
+            assert_eq!(visible.len(), 1);
+        }
+        server.stop(true).await;
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use super::webhook::Webhook;
+use crate::app_types::ApiError;
+use crate::namespace_api::namespace::Namespace;
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_initial_backoff() -> u32 {
+    1
+}
+
+fn default_max_backoff() -> u32 {
+    60
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewWebhookRequest {
+    pub queue_id: String,
+    pub url: String,
+    pub consumer_token: Option<String>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff: u32,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u32,
+    pub dead_letter_queue_id: Option<String>,
+}
+
+impl NewWebhookRequest {
+    pub fn qualify(mut self, namespace: &Namespace) -> Result<Self, ApiError> {
+        self.queue_id = namespace.qualify(&self.queue_id)?;
+        self.dead_letter_queue_id = namespace.qualify_optional(&self.dead_letter_queue_id)?;
+        Ok(self)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteWebhookRequest {
+    pub queue_id: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookEntry {
+    pub queue_id: String,
+    pub uuid: String,
+    pub url: String,
+    pub max_attempts: u32,
+    pub initial_backoff: u32,
+    pub max_backoff: u32,
+    pub dead_letter_queue_id: Option<String>,
+}
+
+impl WebhookEntry {
+    // Takes the ids as the namespace listing the webhook knows them
+    pub fn new(queue_id: &str, webhook: &Webhook, namespace: &Namespace) -> Self {
+        let local = |id: &String| namespace.unqualify(id).unwrap_or(id.to_owned());
+        WebhookEntry {
+            queue_id: queue_id.to_owned(),
+            uuid: webhook.uuid.to_string(),
+            url: webhook.url.to_owned(),
+            max_attempts: webhook.max_attempts,
+            initial_backoff: webhook.initial_backoff,
+            max_backoff: webhook.max_backoff,
+            dead_letter_queue_id: webhook.dead_letter_queue_id.as_ref().map(local),
+        }
+    }
+}
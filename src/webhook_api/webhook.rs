@@ -0,0 +1,24 @@
+use std::time::Duration;
+
+use uuid::Uuid;
+
+// A URL a queue's messages are POSTed to, instead of being read by consumers
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub uuid: Uuid,                           // inner generated uuid for resource
+    pub url: String,                          // where messages are POSTed
+    pub max_attempts: u32,                    // how many times a message is tried before giving up
+    pub initial_backoff: u32,                 // the seconds to wait after the first failure
+    pub max_backoff: u32,                     // the most seconds to wait between attempts
+    pub dead_letter_queue_id: Option<String>, // where messages that could not be delivered go
+}
+
+impl Webhook {
+    // The wait before the next attempt, doubling after every failed attempt
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let seconds = (self.initial_backoff as u64)
+            .saturating_mul(1u64 << attempts.saturating_sub(1).min(32))
+            .min(self.max_backoff as u64);
+        Duration::from_secs(seconds)
+    }
+}